@@ -0,0 +1,216 @@
+use std::{mem, slice, thread, time::Duration};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use windows::{
+    core::{implement, ComInterface, Result, HRESULT},
+    Win32::{
+        Foundation::HWND,
+        Media::Audio::{
+            ActivateAudioInterfaceAsync, IActivateAudioInterfaceAsyncOperation,
+            IActivateAudioInterfaceCompletionHandler,
+            IActivateAudioInterfaceCompletionHandler_Impl, IAudioCaptureClient, IAudioClient,
+            AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_SHAREMODE_SHARED,
+            AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM, AUDCLNT_STREAMFLAGS_LOOPBACK,
+            AUDIOCLIENT_ACTIVATION_PARAMS, AUDIOCLIENT_ACTIVATION_PARAMS_0,
+            AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK, AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS,
+            PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+            VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, WAVEFORMATEX,
+        },
+        System::{
+            Com::{
+                CoInitializeEx, IAgileObject, IAgileObject_Impl, StructuredStorage::PROPVARIANT,
+                BLOB, COINIT_MULTITHREADED,
+            },
+            Variant::VT_BLOB,
+        },
+        UI::WindowsAndMessaging::GetWindowThreadProcessId,
+    },
+};
+
+// プロセスループバックではミックスフォーマットを取れないので、こちらで決めた形式に変換させる
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: u16 = 2;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+// 100 ns 単位
+const BUFFER_DURATION: i64 = 2_000_000;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct AudioChunk {
+    pub hwnd: HWND,
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+pub enum AudioCaptureCommand {
+    Quit,
+}
+
+pub enum AudioCaptureMessage {
+    Output { message: String },
+    Closed { hwnd: HWND },
+}
+
+pub struct AudioCapture {
+    rx_cmd: Receiver<AudioCaptureCommand>,
+    tx_msg: Sender<AudioCaptureMessage>,
+    hwnd: HWND,
+    tx_chunk: Sender<AudioChunk>,
+}
+
+impl AudioCapture {
+    pub fn new(
+        hwnd: HWND,
+        tx_chunk: Sender<AudioChunk>,
+    ) -> (
+        AudioCapture,
+        Sender<AudioCaptureCommand>,
+        Receiver<AudioCaptureMessage>,
+    ) {
+        let (tx_cmd, rx_cmd) = unbounded();
+        let (tx_msg, rx_msg) = unbounded();
+
+        (
+            AudioCapture {
+                rx_cmd,
+                tx_msg,
+                hwnd,
+                tx_chunk,
+            },
+            tx_cmd,
+            rx_msg,
+        )
+    }
+
+    pub fn run(self) {
+        if let Err(e) = self.capture() {
+            let _ = self.tx_msg.send(AudioCaptureMessage::Output {
+                message: format!("[{}] failed to capture audio: {e}", self.hwnd.0),
+            });
+            let _ = self
+                .tx_msg
+                .send(AudioCaptureMessage::Closed { hwnd: self.hwnd });
+        }
+    }
+
+    fn capture(&self) -> Result<()> {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED)? };
+
+        let mut pid = 0;
+        unsafe { GetWindowThreadProcessId(self.hwnd, Some(&mut pid)) };
+        let client = activate_process_loopback(pid)?;
+
+        let format = WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_IEEE_FLOAT,
+            nChannels: CHANNELS,
+            nSamplesPerSec: SAMPLE_RATE,
+            nAvgBytesPerSec: SAMPLE_RATE * CHANNELS as u32 * mem::size_of::<f32>() as u32,
+            nBlockAlign: CHANNELS * mem::size_of::<f32>() as u16,
+            wBitsPerSample: 32,
+            cbSize: 0,
+        };
+        let capture_client: IAudioCaptureClient = unsafe {
+            client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                AUDCLNT_STREAMFLAGS_LOOPBACK | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+                BUFFER_DURATION,
+                0,
+                &format,
+                None,
+            )?;
+            client.GetService()?
+        };
+
+        unsafe { client.Start()? };
+        loop {
+            if let Ok(AudioCaptureCommand::Quit) = self.rx_cmd.try_recv() {
+                break;
+            }
+
+            while unsafe { capture_client.GetNextPacketSize()? } > 0 {
+                let mut data = std::ptr::null_mut();
+                let mut frames = 0;
+                let mut flags = 0;
+                unsafe {
+                    capture_client.GetBuffer(&mut data, &mut frames, &mut flags, None, None)?
+                };
+
+                let len = frames as usize * CHANNELS as usize;
+                let samples = if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+                    vec![0.0; len]
+                } else {
+                    unsafe { slice::from_raw_parts(data as *const f32, len) }.to_vec()
+                };
+                unsafe { capture_client.ReleaseBuffer(frames)? };
+
+                let _ = self.tx_chunk.send(AudioChunk {
+                    hwnd: self.hwnd,
+                    samples,
+                    sample_rate: SAMPLE_RATE,
+                    channels: CHANNELS as u8,
+                });
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+        unsafe { client.Stop()? };
+
+        Ok(())
+    }
+}
+
+// プロセス単位のループバックは ActivateAudioInterfaceAsync でしか作れない
+fn activate_process_loopback(pid: u32) -> Result<IAudioClient> {
+    let params = AUDIOCLIENT_ACTIVATION_PARAMS {
+        ActivationType: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+        Anonymous: AUDIOCLIENT_ACTIVATION_PARAMS_0 {
+            ProcessLoopbackParams: AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS {
+                TargetProcessId: pid,
+                ProcessLoopbackMode: PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+            },
+        },
+    };
+    let mut prop = PROPVARIANT::default();
+    unsafe {
+        let inner = &mut *prop.Anonymous.Anonymous;
+        inner.vt = VT_BLOB;
+        inner.Anonymous.blob = BLOB {
+            cbSize: mem::size_of_val(&params) as u32,
+            pBlobData: &params as *const _ as *mut u8,
+        };
+    }
+
+    let (tx_done, rx_done) = bounded(1);
+    let handler: IActivateAudioInterfaceCompletionHandler = ActivationHandler { tx_done }.into();
+    let operation = unsafe {
+        ActivateAudioInterfaceAsync(
+            VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+            &IAudioClient::IID,
+            Some(&prop),
+            &handler,
+        )?
+    };
+    let _ = rx_done.recv();
+
+    let mut result = HRESULT(0);
+    let mut activated = None;
+    unsafe { operation.GetActivateResult(&mut result, &mut activated)? };
+    result.ok()?;
+
+    activated.unwrap().cast()
+}
+
+#[implement(IActivateAudioInterfaceCompletionHandler, IAgileObject)]
+struct ActivationHandler {
+    tx_done: Sender<()>,
+}
+
+impl IActivateAudioInterfaceCompletionHandler_Impl for ActivationHandler {
+    fn ActivateCompleted(&self, _: Option<&IActivateAudioInterfaceAsyncOperation>) -> Result<()> {
+        let _ = self.tx_done.send(());
+        Ok(())
+    }
+}
+
+impl IAgileObject_Impl for ActivationHandler {}
@@ -0,0 +1,54 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::audio_capture::AudioChunk;
+
+// 取りに来ない相手のために溜め込みすぎないよう、サンプル数で上限を設ける (48 kHz ステレオで 1 秒)
+const MAX_BUFFERED_SAMPLES: usize = 48000 * 2;
+
+pub enum AudioOutputCommand {
+    Chunk(AudioChunk),
+    Quit,
+}
+
+// ドライバから届いた音声を溜めておき、ミキサーなど後段がバッファから取り出して使う
+pub struct AudioOutput {
+    rx_cmd: Receiver<AudioOutputCommand>,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+}
+
+impl AudioOutput {
+    pub fn new() -> (Self, Sender<AudioOutputCommand>) {
+        let (tx_cmd, rx_cmd) = unbounded();
+
+        (
+            Self {
+                rx_cmd,
+                buffer: Arc::new(Mutex::new(VecDeque::new())),
+            },
+            tx_cmd,
+        )
+    }
+
+    pub fn buffer(&self) -> Arc<Mutex<VecDeque<f32>>> {
+        self.buffer.clone()
+    }
+
+    pub fn run(self) {
+        while let Ok(cmd) = self.rx_cmd.recv() {
+            match cmd {
+                AudioOutputCommand::Quit => break,
+                AudioOutputCommand::Chunk(chunk) => {
+                    let mut buffer = self.buffer.lock().unwrap();
+                    buffer.extend(chunk.samples);
+                    let excess = buffer.len().saturating_sub(MAX_BUFFERED_SAMPLES);
+                    buffer.drain(..excess);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{Receiver, Sender};
+use windows::Win32::Foundation::HWND;
+
+use crate::window_capture::{
+    CaptureOptions, CaptureStopper, CapturedFrame, WindowCapture, WindowCaptureCommand,
+    WindowCaptureMessage,
+};
+
+// Driver がキャプチャを始めるたびに呼ばれ、スレッドを立ち上げて操作用のチャンネルを返す
+pub trait CaptureFactory {
+    fn spawn(
+        &mut self,
+        hwnd: HWND,
+        options: CaptureOptions,
+        tx_frame: Sender<CapturedFrame>,
+    ) -> SpawnedCapture;
+}
+
+pub struct SpawnedCapture {
+    pub tx_cmd: Sender<WindowCaptureCommand>,
+    pub rx_msg: Receiver<WindowCaptureMessage>,
+    pub stopper: CaptureStopper,
+    pub thread: JoinHandle<()>,
+}
+
+pub struct RealCaptureFactory;
+
+impl CaptureFactory for RealCaptureFactory {
+    fn spawn(
+        &mut self,
+        hwnd: HWND,
+        options: CaptureOptions,
+        tx_frame: Sender<CapturedFrame>,
+    ) -> SpawnedCapture {
+        let (capture, tx_cmd, rx_msg) = WindowCapture::new(hwnd, options, tx_frame);
+        let stopper = capture.stopper();
+        let thread = thread::spawn(move || capture.run());
+
+        SpawnedCapture {
+            tx_cmd,
+            rx_msg,
+            stopper,
+            thread,
+        }
+    }
+}
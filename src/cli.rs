@@ -0,0 +1,72 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use clap::{ArgGroup, Parser, Subcommand};
+use windows::Win32::Foundation::HWND;
+
+use crate::window_capture::WindowCapture;
+
+#[derive(Parser)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    // ドライバを立ち上げずに、指定したウィンドウを 1 枚だけキャプチャして PNG に保存する
+    #[command(group(ArgGroup::new("target").required(true).args(["hwnd", "title"])))]
+    Screenshot {
+        #[arg(long)]
+        hwnd: Option<isize>,
+        // タイトルにこの文字列を含む最初のウィンドウ
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long)]
+        output: PathBuf,
+    },
+    // 指定したウィンドウを数秒キャプチャしてみて、問題になりそうなことを表示する
+    #[command(group(ArgGroup::new("target").required(true).args(["hwnd", "title"])))]
+    DryRun {
+        #[arg(long)]
+        hwnd: Option<isize>,
+        #[arg(long)]
+        title: Option<String>,
+    },
+}
+
+pub fn screenshot(
+    hwnd: Option<isize>,
+    title: Option<String>,
+    output: PathBuf,
+) -> Result<(), String> {
+    let hwnd = find_target(hwnd, title)?;
+    let frame = WindowCapture::test_capture(hwnd)
+        .map_err(|e| format!("failed to capture {}: {e}", hwnd.0))?;
+
+    let file =
+        File::create(&output).map_err(|e| format!("failed to create {}: {e}", output.display()))?;
+    frame
+        .serialize_png_to_writer(&mut BufWriter::new(file))
+        .map_err(|e| format!("failed to write {}: {e}", output.display()))
+}
+
+pub fn dry_run(hwnd: Option<isize>, title: Option<String>) -> Result<(), String> {
+    let hwnd = find_target(hwnd, title)?;
+    let report =
+        WindowCapture::dry_run(hwnd).map_err(|e| format!("failed to capture {}: {e}", hwnd.0))?;
+    print!("{report}");
+
+    Ok(())
+}
+
+fn find_target(hwnd: Option<isize>, title: Option<String>) -> Result<HWND, String> {
+    match (hwnd, title) {
+        (Some(hwnd), _) => Ok(HWND(hwnd)),
+        (None, Some(title)) => WindowCapture::list_capturable_windows()
+            .into_iter()
+            .find(|(_, window_title)| window_title.contains(&title))
+            .map(|(hwnd, _)| hwnd)
+            .ok_or_else(|| format!("no capturable window matches '{title}'")),
+        (None, None) => Err("either --hwnd or --title is required".into()),
+    }
+}
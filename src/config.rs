@@ -0,0 +1,483 @@
+use std::{fmt, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hotkey_watcher::PresetHotkeys,
+    shared_memory_output::SharedMemoryOptions,
+    window_capture::{CaptureInterval, CaptureOptions},
+};
+
+// これより長くウォームアップで捨て続ける設定は、映らないまま待たされているようにしか見えない
+const MAX_WARMUP_SECS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum FrameChannelKind {
+    Bounded(usize),
+    Unbounded,
+    Rendezvous,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SceneRule {
+    pub title_pattern: String,
+    pub scene_name: String,
+}
+
+// プリセットとして読み込むとき、書かれていない項目は既定値にする
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DriverConfig {
+    pub capture: CaptureOptions,
+    pub shared_memory: Option<SharedMemoryOptions>,
+    pub cooldown_ms: u64,
+    // 前面のウィンドウが変わってから、この時間ほかに変わらなければ切り替える。0 ならすぐ切り替える
+    pub debounce_ms: u64,
+    // キャプチャ側での作り直しでも GPU が戻らなかったとき、ドライバが改めてキャプチャを始めるまで
+    // 待つ時間
+    pub device_removed_retry_delay_ms: u64,
+    pub frame_channel: FrameChannelKind,
+    pub scene_rules: Vec<SceneRule>,
+    pub max_concurrent_captures: usize,
+    // 起動したらすぐ、タイトルにこれを含む最初のウィンドウをキャプチャする
+    pub startup_window_title: Option<String>,
+    pub preset_hotkeys: Option<PresetHotkeys>,
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            capture: CaptureOptions::default(),
+            shared_memory: None,
+            cooldown_ms: 1000,
+            debounce_ms: 0,
+            device_removed_retry_delay_ms: 5000,
+            frame_channel: FrameChannelKind::Bounded(5),
+            scene_rules: vec![],
+            max_concurrent_captures: 16,
+            startup_window_title: None,
+            preset_hotkeys: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValidationError {
+    ZeroFps,
+    WarmupTooLong { warmup_frames: u32, fps: u64 },
+    ZeroCaptureInterval,
+    ZeroMaxFrameAge,
+    ZeroMaxConcurrentCaptures,
+    ZeroBoundedChannel,
+    EmptyBurst,
+    InvalidJitterWarning(f32),
+    ZeroMinFrameBytes,
+    EmptySharedMemoryName,
+    SharedMemoryTooSmall(usize),
+    EmptySceneRulePattern { index: usize },
+    EmptyStartupWindowTitle,
+    NoHotkeyModifiers,
+}
+
+impl DriverConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let config: DriverConfig = toml::from_str(&toml)
+            .map_err(|e| format!("failed to parse {}: {e}", path.display()))?;
+
+        config.validate().map_err(|errors| {
+            let mut message = format!("invalid config in {}:", path.display());
+            for error in errors {
+                message.push_str(&format!("\n| {error}"));
+            }
+            message
+        })?;
+
+        Ok(config)
+    }
+
+    // 見つかった問題は最初の 1 つで止めずに全部返す
+    pub fn validate(&self) -> Result<(), Vec<ConfigValidationError>> {
+        let mut errors = vec![];
+        let capture = &self.capture;
+
+        if capture.fps == 0 {
+            errors.push(ConfigValidationError::ZeroFps);
+        } else if u64::from(capture.warmup_frames) > capture.fps * MAX_WARMUP_SECS {
+            errors.push(ConfigValidationError::WarmupTooLong {
+                warmup_frames: capture.warmup_frames,
+                fps: capture.fps,
+            });
+        }
+        if let Some(CaptureInterval::Fps(0) | CaptureInterval::EveryMs(0)) =
+            capture.capture_interval
+        {
+            errors.push(ConfigValidationError::ZeroCaptureInterval);
+        }
+        if capture.max_frame_age_ms == 0 {
+            errors.push(ConfigValidationError::ZeroMaxFrameAge);
+        }
+        if capture
+            .burst
+            .is_some_and(|burst| burst.duration_ms == 0 || burst.max_frames == 0)
+        {
+            errors.push(ConfigValidationError::EmptyBurst);
+        }
+        if capture.jitter_warning_ms.is_nan() || capture.jitter_warning_ms <= 0.0 {
+            errors.push(ConfigValidationError::InvalidJitterWarning(
+                capture.jitter_warning_ms,
+            ));
+        }
+        if capture.min_frame_bytes == Some(0) {
+            errors.push(ConfigValidationError::ZeroMinFrameBytes);
+        }
+
+        if self.max_concurrent_captures == 0 {
+            errors.push(ConfigValidationError::ZeroMaxConcurrentCaptures);
+        }
+        if let FrameChannelKind::Bounded(0) = self.frame_channel {
+            errors.push(ConfigValidationError::ZeroBoundedChannel);
+        }
+
+        if let Some(shared_memory) = &self.shared_memory {
+            if shared_memory.name.is_empty() {
+                errors.push(ConfigValidationError::EmptySharedMemoryName);
+            }
+            if shared_memory.capacity == 0 {
+                errors.push(ConfigValidationError::SharedMemoryTooSmall(
+                    shared_memory.capacity,
+                ));
+            }
+        }
+
+        for (index, rule) in self.scene_rules.iter().enumerate() {
+            if rule.title_pattern.is_empty() {
+                errors.push(ConfigValidationError::EmptySceneRulePattern { index });
+            }
+        }
+
+        if self
+            .startup_window_title
+            .as_ref()
+            .is_some_and(|title| title.is_empty())
+        {
+            errors.push(ConfigValidationError::EmptyStartupWindowTitle);
+        }
+
+        if self
+            .preset_hotkeys
+            .as_ref()
+            .is_some_and(|hotkeys| hotkeys.modifiers.is_empty())
+        {
+            errors.push(ConfigValidationError::NoHotkeyModifiers);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl fmt::Display for ConfigValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValidationError::ZeroFps => write!(f, "capture.fps must be at least 1"),
+            ConfigValidationError::WarmupTooLong { warmup_frames, fps } => write!(
+                f,
+                "capture.warmup_frames ({warmup_frames}) would skip over {MAX_WARMUP_SECS}s of \
+                 frames at {fps} fps"
+            ),
+            ConfigValidationError::ZeroCaptureInterval => {
+                write!(f, "capture.capture_interval must not be zero")
+            }
+            ConfigValidationError::ZeroMaxFrameAge => {
+                write!(f, "capture.max_frame_age_ms of 0 drops every frame")
+            }
+            ConfigValidationError::ZeroMaxConcurrentCaptures => {
+                write!(f, "max_concurrent_captures of 0 never starts a capture")
+            }
+            ConfigValidationError::ZeroBoundedChannel => {
+                write!(
+                    f,
+                    "frame_channel Bounded(0) is a rendezvous; use Rendezvous instead"
+                )
+            }
+            ConfigValidationError::EmptyBurst => {
+                write!(
+                    f,
+                    "capture.burst needs a non-zero duration_ms and max_frames"
+                )
+            }
+            ConfigValidationError::InvalidJitterWarning(value) => {
+                write!(
+                    f,
+                    "capture.jitter_warning_ms must be positive (got {value})"
+                )
+            }
+            ConfigValidationError::ZeroMinFrameBytes => {
+                write!(
+                    f,
+                    "capture.min_frame_bytes of 0 checks nothing; use none instead"
+                )
+            }
+            ConfigValidationError::EmptySharedMemoryName => {
+                write!(f, "shared_memory.name must not be empty")
+            }
+            ConfigValidationError::SharedMemoryTooSmall(capacity) => write!(
+                f,
+                "shared_memory.capacity ({capacity}) cannot hold any frame"
+            ),
+            ConfigValidationError::EmptySceneRulePattern { index } => write!(
+                f,
+                "scene rule {index} has an empty title_pattern and would match every window"
+            ),
+            ConfigValidationError::EmptyStartupWindowTitle => write!(
+                f,
+                "startup_window_title is empty and would match every window; use none instead"
+            ),
+            ConfigValidationError::NoHotkeyModifiers => write!(
+                f,
+                "preset_hotkeys needs at least one modifier, or the bare digit keys stop typing"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window_capture::BurstConfig;
+
+    fn errors_of(config: &DriverConfig) -> Vec<ConfigValidationError> {
+        config.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(DriverConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn config_survives_a_toml_round_trip() {
+        let mut config = DriverConfig {
+            cooldown_ms: 250,
+            frame_channel: FrameChannelKind::Unbounded,
+            scene_rules: vec![SceneRule {
+                title_pattern: "Game".into(),
+                scene_name: "Gaming".into(),
+            }],
+            startup_window_title: Some("Editor".into()),
+            ..DriverConfig::default()
+        };
+        config.capture.fps = 30;
+        config.capture.capture_interval = Some(CaptureInterval::EveryMs(100));
+
+        let toml = toml::to_string(&config).unwrap();
+        let parsed: DriverConfig = toml::from_str(&toml).unwrap();
+        assert_eq!(toml::to_string(&parsed).unwrap(), toml);
+        assert_eq!(parsed.validate(), Ok(()));
+    }
+
+    #[test]
+    fn missing_fields_fall_back_to_defaults() {
+        let config: DriverConfig = toml::from_str("cooldown_ms = 10").unwrap();
+        assert_eq!(config.cooldown_ms, 10);
+        assert_eq!(
+            config.max_concurrent_captures,
+            DriverConfig::default().max_concurrent_captures
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_invalid_fps_range() {
+        let mut config = DriverConfig::default();
+        config.capture.fps = 0;
+        assert_eq!(errors_of(&config), vec![ConfigValidationError::ZeroFps]);
+
+        config.capture.fps = 2;
+        config.capture.warmup_frames = 2 * MAX_WARMUP_SECS as u32 + 1;
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::WarmupTooLong {
+                warmup_frames: 2 * MAX_WARMUP_SECS as u32 + 1,
+                fps: 2,
+            }]
+        );
+
+        config.capture.warmup_frames = 2 * MAX_WARMUP_SECS as u32;
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn config_validation_rejects_zero_capture_interval() {
+        for interval in [CaptureInterval::Fps(0), CaptureInterval::EveryMs(0)] {
+            let mut config = DriverConfig::default();
+            config.capture.capture_interval = Some(interval);
+            assert_eq!(
+                errors_of(&config),
+                vec![ConfigValidationError::ZeroCaptureInterval]
+            );
+        }
+    }
+
+    #[test]
+    fn config_validation_rejects_zero_max_frame_age() {
+        let mut config = DriverConfig::default();
+        config.capture.max_frame_age_ms = 0;
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::ZeroMaxFrameAge]
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_empty_burst() {
+        for (duration_ms, max_frames) in [(0, 10), (1000, 0)] {
+            let mut config = DriverConfig::default();
+            config.capture.burst = Some(BurstConfig {
+                duration_ms,
+                max_frames,
+            });
+            assert_eq!(errors_of(&config), vec![ConfigValidationError::EmptyBurst]);
+        }
+    }
+
+    #[test]
+    fn config_validation_rejects_non_positive_jitter_warning() {
+        for value in [0.0, -1.0] {
+            let mut config = DriverConfig::default();
+            config.capture.jitter_warning_ms = value;
+            assert_eq!(
+                errors_of(&config),
+                vec![ConfigValidationError::InvalidJitterWarning(value)]
+            );
+        }
+
+        // NaN どうしは等しくならないので、種類だけ見る
+        let mut config = DriverConfig::default();
+        config.capture.jitter_warning_ms = f32::NAN;
+        assert!(matches!(
+            errors_of(&config)[..],
+            [ConfigValidationError::InvalidJitterWarning(value)] if value.is_nan()
+        ));
+    }
+
+    #[test]
+    fn config_validation_rejects_zero_min_frame_bytes() {
+        let mut config = DriverConfig::default();
+        config.capture.min_frame_bytes = Some(0);
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::ZeroMinFrameBytes]
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_zero_max_concurrent_captures() {
+        let config = DriverConfig {
+            max_concurrent_captures: 0,
+            ..DriverConfig::default()
+        };
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::ZeroMaxConcurrentCaptures]
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_zero_bounded_channel() {
+        let config = DriverConfig {
+            frame_channel: FrameChannelKind::Bounded(0),
+            ..DriverConfig::default()
+        };
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::ZeroBoundedChannel]
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_bad_shared_memory() {
+        let config = DriverConfig {
+            shared_memory: Some(SharedMemoryOptions {
+                name: String::new(),
+                capacity: 0,
+            }),
+            ..DriverConfig::default()
+        };
+        assert_eq!(
+            errors_of(&config),
+            vec![
+                ConfigValidationError::EmptySharedMemoryName,
+                ConfigValidationError::SharedMemoryTooSmall(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_empty_scene_rule_pattern() {
+        let config = DriverConfig {
+            scene_rules: vec![
+                SceneRule {
+                    title_pattern: "Game".into(),
+                    scene_name: "Gaming".into(),
+                },
+                SceneRule {
+                    title_pattern: String::new(),
+                    scene_name: "Everything".into(),
+                },
+            ],
+            ..DriverConfig::default()
+        };
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::EmptySceneRulePattern { index: 1 }]
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_empty_startup_window_title() {
+        let config = DriverConfig {
+            startup_window_title: Some(String::new()),
+            ..DriverConfig::default()
+        };
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::EmptyStartupWindowTitle]
+        );
+    }
+
+    #[test]
+    fn config_validation_rejects_preset_hotkeys_without_modifiers() {
+        let config = DriverConfig {
+            preset_hotkeys: Some(PresetHotkeys { modifiers: vec![] }),
+            ..DriverConfig::default()
+        };
+        assert_eq!(
+            errors_of(&config),
+            vec![ConfigValidationError::NoHotkeyModifiers]
+        );
+    }
+
+    #[test]
+    fn config_validation_reports_every_problem() {
+        let mut config = DriverConfig {
+            max_concurrent_captures: 0,
+            startup_window_title: Some(String::new()),
+            ..DriverConfig::default()
+        };
+        config.capture.fps = 0;
+        assert_eq!(
+            errors_of(&config),
+            vec![
+                ConfigValidationError::ZeroFps,
+                ConfigValidationError::ZeroMaxConcurrentCaptures,
+                ConfigValidationError::EmptyStartupWindowTitle,
+            ]
+        );
+    }
+}
@@ -1,42 +1,269 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fmt::Write,
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    mem,
     thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crossbeam_channel::{bounded, Receiver, Sender};
-use windows::Win32::Foundation::HWND;
+use crossbeam_channel::{bounded, never, unbounded, Receiver, Sender};
+use windows::{
+    core::{w, HSTRING, PCWSTR},
+    Win32::{
+        Foundation::HWND,
+        UI::{
+            Shell::ShellExecuteW,
+            WindowsAndMessaging::{IsWindow, SW_SHOWNORMAL},
+        },
+    },
+};
 
+#[cfg(feature = "event-log")]
+use crate::event_log::EventLog;
 use crate::{
-    foreground_watcher::{ForegroundWatcherCommand, ForegroundWatcherMessage},
+    audio_capture::{AudioCapture, AudioCaptureCommand, AudioCaptureMessage, AudioChunk},
+    audio_output::AudioOutputCommand,
+    capture_factory::{CaptureFactory, RealCaptureFactory, SpawnedCapture},
+    config::{DriverConfig, FrameChannelKind, SceneRule},
+    foreground_watcher::{
+        window_title, ForegroundWatcher, ForegroundWatcherCommand, ForegroundWatcherMessage,
+    },
+    frame_plugin::FramePlugin,
+    hotkey_watcher::{HotkeyWatcherCommand, HotkeyWatcherMessage},
     image_viewer::{ImageViewerCommand, ImageViewerMessage},
+    log_level::LogLevel,
+    pixel_format::PixelFormat,
+    preset_manager::PresetManager,
+    profiler::Profiler,
+    shared_memory_output::SharedMemoryOutput,
+    stats::{CaptureHealth, CaptureStats, ProcessMemory},
     stdin_shell::{StdinShellCommand, StdinShellMessage},
-    window_capture::{CapturedFrame, WindowCapture, WindowCaptureCommand, WindowCaptureMessage},
+    virtual_camera::{VirtualCameraOutput, VirtualCameraSink},
+    window_capture::{
+        CaptureInterval, CaptureStopper, CapturedFrame, WindowCaptureCommand, WindowCaptureError,
+        WindowCaptureMessage,
+    },
 };
 
 struct WindowCaptureInterop {
-    _tx_cmd: Sender<WindowCaptureCommand>,
+    tx_cmd: Sender<WindowCaptureCommand>,
+    stopper: CaptureStopper,
     rx_msg: Receiver<WindowCaptureMessage>,
     rx_frame: Receiver<CapturedFrame>,
     thread: JoinHandle<()>,
+    stats: CaptureStats,
+    health: CaptureHealth,
+    deduplicate: bool,
+    // 大きいほど先にフレームを処理する
+    priority: u8,
+    // キャプチャを始めたときの設定での送る間隔
+    capture_interval: CaptureInterval,
+    // 受け取る側が追いつかずに落としたフレームレート (落としていなければ None)
+    effective_fps: Option<u64>,
+    // 最後に受け取ったフレームの (幅, 高さ)。次のフレームを待たずに大きさを知るため
+    last_frame_dimensions: Option<(u32, u32)>,
+}
+
+impl WindowCaptureInterop {
+    // Quit はフレームを処理するときに読まれ、CaptureStopper はフレームが来なくても止める。
+    // 両方届いても WM_QUIT は 1 度しか送られない
+    fn request_stop(&self) {
+        let _ = self.tx_cmd.send(WindowCaptureCommand::Quit);
+        self.stopper.stop();
+    }
+}
+
+struct AudioCaptureInterop {
+    tx_cmd: Sender<AudioCaptureCommand>,
+    rx_msg: Receiver<AudioCaptureMessage>,
+    rx_chunk: Receiver<AudioChunk>,
+    thread: JoinHandle<()>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowChangeEvent {
+    Activated,
+    Minimized,
+    EnteredFullscreen,
+    ExitedFullscreen,
+}
+
+const SLOW_FRAME_LATENCY: Duration = Duration::from_millis(100);
+const SLOW_FRAMES_BEFORE_WARNING: u32 = 3;
+
+const PRESET_DIR: &str = "presets";
+
+const DEFAULT_PRIORITY: u8 = 128;
+
+// これより長いと、戻ってきたウィンドウがいつまでも映らないように見える
+const MAX_COOLDOWN_MS: u64 = 60_000;
+
+// これより長いと、切り替えたことに気づかないまま待たされる
+const MAX_DEBOUNCE_MS: u64 = 10_000;
+
+const MAX_PROFILE_MS: u64 = 60_000;
+
+// GPU が戻らないまま作り直し続けないよう、フレームが届かないまま続けて作り直すのはここまで
+const MAX_DEVICE_REMOVED_RECONNECTS: u32 = 5;
+
+// 開きっぱなしでもウィンドウの履歴が増え続けないよう、古いものから捨てる
+const MAX_WINDOW_HISTORY: usize = 1000;
+
+// キャプチャが振る番号とぶつからないよう、テストフレームには上の方の番号を使う
+const TEST_FRAME_SEQUENCE_BASE: u64 = 1 << 63;
+const TEST_FRAME_SIZE: (u32, u32) = (256, 256);
+
+pub struct WindowInfo {
+    pub hwnd: isize,
+    pub title: String,
+    pub stats: CaptureStats,
+    pub health: CaptureHealth,
+    pub deduplicate: bool,
+    pub priority: u8,
+    pub last_dimensions: Option<(u32, u32)>,
+}
+
+pub struct DriverStatus {
+    pub current_hwnd: Option<isize>,
+    pub capture_count: usize,
+    pub log_level: LogLevel,
+    pub windows: Vec<WindowInfo>,
+}
+
+// プラグインなど、コンポーネント以外からドライバに届くイベント
+pub enum PluginEvent {
+    SceneChange { hwnd: HWND, delta: f64 },
+    Output { message: String },
 }
 
+// ドライバの外に知らせるイベント。subscribe した全員に同じものが届く
+#[derive(Clone, Debug, PartialEq)]
+pub enum DriverEvent {
+    WindowChanged {
+        hwnd: HWND,
+        event: WindowChangeEvent,
+    },
+    CaptureStarted {
+        hwnd: HWND,
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+    },
+    CaptureStopped {
+        hwnd: HWND,
+    },
+    CaptureRestarted {
+        hwnd: HWND,
+    },
+    FrameDropped {
+        hwnd: HWND,
+        sequence: u64,
+    },
+    Throttled {
+        hwnd: HWND,
+        effective_fps: u64,
+    },
+    HealthChanged {
+        hwnd: HWND,
+        old: CaptureHealth,
+        new: CaptureHealth,
+    },
+    SceneSwitch {
+        hwnd: HWND,
+        scene_name: String,
+    },
+}
+
+// subscribe_to_window_events で、そのウィンドウの分だけ届くイベント
+#[derive(Clone, Debug, PartialEq)]
+pub enum WindowEvent {
+    Frame {
+        sequence: u64,
+        width: u32,
+        height: u32,
+        captured_at: Instant,
+    },
+    SizeChanged {
+        width: u32,
+        height: u32,
+    },
+    HealthChanged {
+        old: CaptureHealth,
+        new: CaptureHealth,
+    },
+    // これを最後に、そのウィンドウのイベントは届かなくなる
+    Stopped,
+}
+
+pub type WindowChangeHook = Box<dyn Fn(HWND, WindowChangeEvent) + Send + Sync>;
+
 pub struct Driver {
+    config: DriverConfig,
+
     im_tx_cmd: Sender<ImageViewerCommand>,
     im_rx_msg: Receiver<ImageViewerMessage>,
+    // false の間は im_tx_cmd / im_rx_msg がどこにもつながっていない
+    viewer_attached: bool,
+    // ビューアを付け直したときに出し直す、最後に表示したフレーム
+    last_presented: Option<CapturedFrame>,
     fw_tx_cmd: Sender<ForegroundWatcherCommand>,
     fw_rx_msg: Receiver<ForegroundWatcherMessage>,
+    // start_capturing_on_focus でドライバ自身が立てたウォッチャー
+    watcher_thread: Option<JoinHandle<()>>,
+    profiler_thread: Option<JoinHandle<()>>,
     sh_tx_cmd: Sender<StdinShellCommand>,
     sh_rx_msg: Receiver<StdinShellMessage>,
+    tx_event: Sender<PluginEvent>,
+    rx_event: Receiver<PluginEvent>,
 
+    capture_factory: Box<dyn CaptureFactory + Send>,
     caps: BTreeMap<isize, WindowCaptureInterop>,
+    audio_caps: BTreeMap<isize, AudioCaptureInterop>,
+    ao_tx_cmd: Option<Sender<AudioOutputCommand>>,
+    hotkeys: Option<(Sender<HotkeyWatcherCommand>, Receiver<HotkeyWatcherMessage>)>,
+    presets: PresetManager,
+    titles: BTreeMap<isize, String>,
+    // 前面になったウィンドウの履歴。新しいものが後ろ
+    window_history: VecDeque<(isize, Instant)>,
+    stopped_at: BTreeMap<isize, Instant>,
+    // (再開する時刻, HWND)。同じ時刻に再開するウィンドウがあっても上書きしないよう、HWND も鍵に含める
+    pending_restarts: BTreeSet<(Instant, isize)>,
+    // 前面に来たが、debounce_ms が過ぎるまで切り替えずに待っているウィンドウと、来た時刻
+    pending_foreground: Option<(HWND, Instant)>,
+    // DeviceRemoved のあと、フレームが届かないまま作り直した回数
+    device_removed_reconnects: BTreeMap<isize, u32>,
+    // 同時にキャプチャできる数を超えたので、空きが出るのを待っているウィンドウ
+    pending_captures: VecDeque<HWND>,
+    // 設定の frame_channel の代わりに使うチャンネルの容量
+    channel_capacities: BTreeMap<isize, usize>,
+    shared_memory: Option<SharedMemoryOutput>,
+    // ビューアに送ったがまだ描画の報告が来ていないフレーム (frame_id, HWND, キャプチャ時刻)
+    in_flight_frames: VecDeque<(u64, isize, Instant)>,
+    // 描画されたら shell に知らせる、注入したテストフレームの番号
+    test_frames: BTreeSet<u64>,
+    next_test_sequence: u64,
+    plugins: Vec<Box<dyn FramePlugin>>,
+    // 外してはいないが、今はフレームを渡さないプラグインの名前
+    disabled_plugins: BTreeSet<String>,
+    on_window_change: Option<WindowChangeHook>,
+    subscribers: Vec<Sender<DriverEvent>>,
+    window_subscribers: BTreeMap<isize, Vec<Sender<WindowEvent>>>,
+    #[cfg(feature = "event-log")]
+    event_log: Option<EventLog>,
     allowed_hwnds: BTreeSet<isize>,
     current_hwnd: Option<HWND>,
+    // 固定されている間は前面のウィンドウが変わっても current_hwnd を動かさない
+    pinned: bool,
+    globally_paused: bool,
     is_running: bool,
 }
 
 impl Driver {
     pub fn new(
+        config: DriverConfig,
         im_tx_cmd: Sender<ImageViewerCommand>,
         im_rx_msg: Receiver<ImageViewerMessage>,
         fw_tx_cmd: Sender<ForegroundWatcherCommand>,
@@ -44,158 +271,2199 @@ impl Driver {
         sh_tx_cmd: Sender<StdinShellCommand>,
         sh_rx_msg: Receiver<StdinShellMessage>,
     ) -> Self {
+        let shared_memory = open_shared_memory(&config, &sh_tx_cmd);
+        let (tx_event, rx_event) = unbounded();
+
         Self {
+            config,
+
             im_tx_cmd,
             im_rx_msg,
+            viewer_attached: true,
+            last_presented: None,
             fw_tx_cmd,
             fw_rx_msg,
+            watcher_thread: None,
+            profiler_thread: None,
             sh_tx_cmd,
             sh_rx_msg,
+            tx_event,
+            rx_event,
 
+            capture_factory: Box::new(RealCaptureFactory),
             caps: BTreeMap::new(),
+            audio_caps: BTreeMap::new(),
+            ao_tx_cmd: None,
+            hotkeys: None,
+            presets: PresetManager::new(PRESET_DIR),
+            titles: BTreeMap::new(),
+            window_history: VecDeque::new(),
+            stopped_at: BTreeMap::new(),
+            pending_restarts: BTreeSet::new(),
+            pending_foreground: None,
+            device_removed_reconnects: BTreeMap::new(),
+            pending_captures: VecDeque::new(),
+            channel_capacities: BTreeMap::new(),
+            shared_memory,
+            in_flight_frames: VecDeque::new(),
+            test_frames: BTreeSet::new(),
+            next_test_sequence: TEST_FRAME_SEQUENCE_BASE,
+            plugins: vec![],
+            disabled_plugins: BTreeSet::new(),
+            on_window_change: None,
+            subscribers: vec![],
+            window_subscribers: BTreeMap::new(),
+            #[cfg(feature = "event-log")]
+            event_log: None,
             allowed_hwnds: BTreeSet::new(),
             current_hwnd: None,
+            pinned: false,
+            globally_paused: false,
             is_running: false,
         }
     }
 
-    pub fn run(mut self) {
+    pub fn run(&mut self) {
         self.is_running = true;
+        self.capture_startup_window();
         while self.is_running {
-            if let Ok(msg) = self.im_rx_msg.try_recv() {
-                self.handle_image_viewer_message(msg);
-            }
+            self.step();
+        }
+    }
 
-            if let Ok(msg) = self.fw_rx_msg.try_recv() {
-                self.handle_foreground_watcher_message(msg);
-            }
+    // run と違って待たずに、届いているものを処理し終えたら戻る。Driver は Send ではないので、
+    // テストでは別スレッドで run する代わりにこれを呼んで進める。
+    #[cfg(any(test, feature = "testing"))]
+    pub fn run_until_idle(&mut self) {
+        self.step();
+        while self.has_pending_input() {
+            self.step();
+        }
+    }
 
-            if let Ok(msg) = self.sh_rx_msg.try_recv() {
-                self.handle_stdin_shell_message(msg);
-            }
+    #[cfg(any(test, feature = "testing"))]
+    fn has_pending_input(&self) -> bool {
+        !self.im_rx_msg.is_empty()
+            || !self.fw_rx_msg.is_empty()
+            || !self.sh_rx_msg.is_empty()
+            || !self.rx_event.is_empty()
+            || self.hotkeys.as_ref().is_some_and(|(_, rx)| !rx.is_empty())
+            || self
+                .caps
+                .values()
+                .any(|cap| !cap.rx_msg.is_empty() || !cap.rx_frame.is_empty())
+            || self
+                .audio_caps
+                .values()
+                .any(|cap| !cap.rx_msg.is_empty() || !cap.rx_chunk.is_empty())
+    }
+
+    fn step(&mut self) {
+        if let Ok(msg) = self.im_rx_msg.try_recv() {
+            self.handle_image_viewer_message(msg);
+        }
+
+        if let Ok(msg) = self.fw_rx_msg.try_recv() {
+            self.handle_foreground_watcher_message(msg);
+        }
+
+        if let Ok(msg) = self.sh_rx_msg.try_recv() {
+            self.handle_stdin_shell_message(msg);
+        }
+
+        if let Ok(event) = self.rx_event.try_recv() {
+            self.handle_driver_event(event);
+        }
+
+        if let Some(msg) = self.hotkeys.as_ref().and_then(|(_, rx)| rx.try_recv().ok()) {
+            self.handle_hotkey_watcher_message(msg);
+        }
+
+        self.handle_captures_message();
+
+        self.handle_captures_frames();
+
+        self.handle_audio_captures();
+
+        self.handle_pending_restarts();
+
+        self.handle_pending_foreground();
+
+        self.cleanup_threads();
+    }
+
+    // フォーカスが移るのを待たずに、見つかったウィンドウが前面に来たものとして扱う
+    fn capture_startup_window(&mut self) {
+        let Some(pattern) = self.config.startup_window_title.clone() else {
+            return;
+        };
+
+        let found = ForegroundWatcher::enumerate_windows()
+            .into_iter()
+            .find(|(_, title)| title.contains(&pattern));
+        let Some((hwnd, title)) = found else {
+            let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                message: format!("no window matches startup title {pattern:?}"),
+            });
+            return;
+        };
+
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+            message: format!("[{}] capturing {title} at startup", hwnd.0),
+        });
+        self.allowed_hwnds.insert(hwnd.0);
+        self.handle_foreground_watcher_message(ForegroundWatcherMessage::WindowChanged { hwnd });
+    }
+
+    // ForegroundWatcher を自前で立てて、フォーカスが移ったウィンドウをキャプチャするようにする。
+    // new に渡したウォッチャーは止める。
+    pub fn start_capturing_on_focus(&mut self) {
+        let (watcher, fw_tx_cmd, fw_rx_msg) = ForegroundWatcher::new();
+        let thread = thread::spawn(move || watcher.run());
+
+        let _ = self.fw_tx_cmd.send(ForegroundWatcherCommand::Quit);
+        self.fw_tx_cmd = fw_tx_cmd;
+        self.fw_rx_msg = fw_rx_msg;
+        if let Some(old) = self.watcher_thread.replace(thread) {
+            let _ = old.join();
+        }
+    }
+
+    // 音声の出力先があるときだけ、映像と一緒に音声もキャプチャする
+    pub fn set_audio_output(&mut self, ao_tx_cmd: Sender<AudioOutputCommand>) {
+        self.ao_tx_cmd = Some(ao_tx_cmd);
+    }
+
+    // 設定の preset_hotkeys に従って、修飾キー + 1..9 でプリセットを読み込めるようにする
+    pub fn set_hotkey_watcher(
+        &mut self,
+        hk_tx_cmd: Sender<HotkeyWatcherCommand>,
+        hk_rx_msg: Receiver<HotkeyWatcherMessage>,
+    ) {
+        self.hotkeys = Some((hk_tx_cmd, hk_rx_msg));
+        self.send_preset_hotkeys();
+    }
+
+    // broadcast するイベントをすべて残しておく
+    #[cfg(feature = "event-log")]
+    pub fn set_event_log(&mut self, event_log: EventLog) {
+        self.event_log = Some(event_log);
+    }
+
+    // テスト用のキャプチャや別の実装に差し替える。すでに始まっているキャプチャはそのまま
+    pub fn set_capture_factory(&mut self, factory: impl CaptureFactory + Send + 'static) {
+        self.capture_factory = Box::new(factory);
+    }
+
+    // ビューアを閉じて開き直すときのために、キャプチャは止めずにビューアだけ外す。外している間に
+    // 来たフレームはビューアには送らずに捨てる。
+    pub fn detach_image_viewer(&mut self) {
+        let (im_tx_cmd, _) = unbounded();
+        self.im_tx_cmd = im_tx_cmd;
+        self.im_rx_msg = never();
+        self.viewer_attached = false;
+        self.forget_in_flight_frames();
+    }
+
+    pub fn attach_image_viewer(
+        &mut self,
+        im_tx_cmd: Sender<ImageViewerCommand>,
+        im_rx_msg: Receiver<ImageViewerMessage>,
+    ) {
+        self.im_tx_cmd = im_tx_cmd;
+        self.im_rx_msg = im_rx_msg;
+        self.viewer_attached = true;
+        self.forget_in_flight_frames();
+
+        // 新しいビューアは何も表示していないので、次のフレームを待たずに今のウィンドウを出す
+        let Some(frame) = self
+            .last_presented
+            .as_ref()
+            .filter(|frame| Some(frame.hwnd) == self.current_hwnd)
+            .cloned()
+        else {
+            return;
+        };
+        let _ = self.im_tx_cmd.send(ImageViewerCommand::Resize {
+            width: frame.width,
+            height: frame.height,
+        });
+        self.send_to_viewer(frame);
+    }
+
+    // 前のビューアからはもう描画の報告が来ない
+    fn forget_in_flight_frames(&mut self) {
+        self.in_flight_frames.clear();
+        for sequence in mem::take(&mut self.test_frames) {
+            self.report_test_frame_not_rendered(sequence);
+        }
+    }
+
+    fn drop_test_frame(&mut self, sequence: u64) {
+        if self.test_frames.remove(&sequence) {
+            self.report_test_frame_not_rendered(sequence);
+        }
+    }
+
+    fn report_test_frame_not_rendered(&self, sequence: u64) {
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+            message: format!("test frame {sequence} not rendered"),
+        });
+    }
+
+    pub fn event_sender(&self) -> Sender<PluginEvent> {
+        self.tx_event.clone()
+    }
+
+    // 前面にならなくても、表示や移動を知りたいウィンドウを指定する
+    pub fn watch_specific_windows(&self, hwnds: Vec<HWND>) {
+        let _ = self
+            .fw_tx_cmd
+            .send(ForegroundWatcherCommand::WatchSpecific(hwnds));
+    }
+
+    // 許可されたウィンドウが作られたらすぐキャプチャを始め、壊されたらすぐ止める
+    pub fn monitor_window_creation(&self, enabled: bool) {
+        let _ = self
+            .fw_tx_cmd
+            .send(ForegroundWatcherCommand::MonitorCreation(enabled));
+    }
+
+    // 受け取る側が捨てられたら、次に送るときに購読をやめる
+    pub fn subscribe(&mut self) -> Receiver<DriverEvent> {
+        let (tx, rx) = unbounded();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    // DriverEvent をすべて見なくても済むよう、1 つのウィンドウのイベントだけを受け取る。
+    // まだキャプチャしていないウィンドウでも、始まってからのイベントが届く。キャプチャが止まると
+    // Stopped が届いて終わるので、また受け取るには購読し直す。
+    pub fn subscribe_to_window_events(&mut self, hwnd: HWND) -> Receiver<WindowEvent> {
+        let (tx, rx) = unbounded();
+        self.window_subscribers.entry(hwnd.0).or_default().push(tx);
+        rx
+    }
+
+    // run を使わずに自分の都合でフレームを取りに来る場合用
+    pub fn try_recv_frame(&self, hwnd: HWND) -> Option<CapturedFrame> {
+        self.caps.get(&hwnd.0)?.rx_frame.try_recv().ok()
+    }
+
+    pub fn add_plugin(&mut self, plugin: Box<dyn FramePlugin>) {
+        self.plugins.push(plugin);
+    }
+
+    // 登録だけしておき、shell から enable されるまではかけない
+    pub fn add_disabled_plugin(&mut self, plugin: Box<dyn FramePlugin>) {
+        self.disabled_plugins.insert(plugin.name().to_string());
+        self.add_plugin(plugin);
+    }
+
+    // 他のプラグインをかけ終えたフレームを渡せるよう、登録済みのプラグインより後ろに並べる
+    pub fn add_virtual_camera(&mut self, sink: impl VirtualCameraSink + 'static) {
+        let tx_event = self.event_sender();
+        self.add_plugin(Box::new(VirtualCameraOutput::new(sink, tx_event)));
+    }
+
+    pub fn set_on_window_change(&mut self, hook: WindowChangeHook) {
+        self.on_window_change = Some(hook);
+    }
+
+    pub fn current_hwnd(&self) -> Option<HWND> {
+        self.current_hwnd
+    }
+
+    pub fn set_current_hwnd_pinned(&mut self, hwnd: HWND) {
+        self.pinned = true;
+        if self.current_hwnd != Some(hwnd) {
+            self.current_hwnd = Some(hwnd);
+            self.push_history(hwnd.0);
+            self.fire_window_change(hwnd, WindowChangeEvent::Activated);
+        }
+        if !self.caps.contains_key(&hwnd.0) {
+            self.request_capture_for(hwnd);
+        }
+    }
+
+    pub fn unpin_current_hwnd(&mut self) {
+        self.pinned = false;
+    }
+
+    pub fn windows(&self) -> Vec<HWND> {
+        self.caps.keys().map(|&hwnd_id| HWND(hwnd_id)).collect()
+    }
+
+    pub fn capture_count(&self) -> usize {
+        self.caps.len()
+    }
+
+    pub fn export_dot_graph(&self) -> String {
+        let mut buf = String::new();
+        writeln!(buf, "digraph driver {{").unwrap();
+        writeln!(buf, "    rankdir=LR;").unwrap();
+        writeln!(buf, "    node [shape=box];").unwrap();
+        writeln!(
+            buf,
+            "    driver [label=\"Driver\\ncaptures: {}\"];",
+            self.caps.len()
+        )
+        .unwrap();
+
+        for (hwnd_id, cap) in &self.caps {
+            writeln!(
+                buf,
+                "    \"capture_{hwnd_id}\" [label=\"WindowCapture\\n{hwnd_id}\\nfps: {}\\nframes: {}\"];",
+                self.config.capture.fps, cap.stats.frames_received
+            )
+            .unwrap();
+            writeln!(buf, "    \"capture_{hwnd_id}\" -> driver;").unwrap();
+        }
+
+        // プラグインは登録順に直列にかかる
+        let mut last = "driver".to_string();
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            writeln!(
+                buf,
+                "    plugin_{i} [label=\"FramePlugin\\n{}\"];",
+                plugin.name()
+            )
+            .unwrap();
+            writeln!(buf, "    {last} -> plugin_{i};").unwrap();
+            last = format!("plugin_{i}");
+        }
 
-            self.handle_captures_message();
+        writeln!(buf, "    viewer [label=\"ImageViewer\"];").unwrap();
+        writeln!(buf, "    {last} -> viewer;").unwrap();
+        if let Some(options) = &self.config.shared_memory {
+            writeln!(
+                buf,
+                "    shared_memory [label=\"SharedMemoryOutput\\n{}\"];",
+                options.name
+            )
+            .unwrap();
+            writeln!(buf, "    {last} -> shared_memory;").unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
 
-            self.handle_captures_frames();
+        buf
+    }
 
-            self.cleanup_threads();
+    pub fn status(&self) -> DriverStatus {
+        DriverStatus {
+            current_hwnd: self.current_hwnd.map(|hwnd| hwnd.0),
+            capture_count: self.capture_count(),
+            log_level: self.config.capture.log_level_filter,
+            windows: self
+                .caps
+                .iter()
+                .map(|(&hwnd, cap)| WindowInfo {
+                    hwnd,
+                    title: self.titles.get(&hwnd).cloned().unwrap_or_default(),
+                    stats: cap.stats.clone(),
+                    health: cap.health.clone(),
+                    deduplicate: cap.deduplicate,
+                    priority: cap.priority,
+                    last_dimensions: cap.last_frame_dimensions,
+                })
+                .collect(),
         }
     }
 
     fn handle_image_viewer_message(&mut self, msg: ImageViewerMessage) {
         match msg {
             ImageViewerMessage::Closed => self.quit(),
+            ImageViewerMessage::FrameRendered {
+                hwnd,
+                frame_id,
+                render_time_us,
+            } => {
+                // ビューアは送った順に描画するので、それより前のものは捨てられたとみなす
+                while let Some((id, hwnd_id, captured_at)) = self.in_flight_frames.pop_front() {
+                    if (id, hwnd_id) != (frame_id, hwnd.0) {
+                        self.drop_test_frame(id);
+                        continue;
+                    }
+
+                    if self.test_frames.remove(&id) {
+                        let _ = self.sh_tx_cmd.send(StdinShellCommand::TestFrameAck {
+                            sequence: id,
+                            latency_us: captured_at.elapsed().as_micros() as u64,
+                        });
+                        break;
+                    }
+
+                    let Some(cap) = self.caps.get_mut(&hwnd_id) else {
+                        break;
+                    };
+                    let latency = captured_at.elapsed();
+                    cap.stats.last_render_time_us = render_time_us;
+                    cap.stats.last_latency_us = latency.as_micros() as u64;
+                    if latency > SLOW_FRAME_LATENCY {
+                        cap.stats.slow_frames_in_a_row += 1;
+                    } else {
+                        cap.stats.slow_frames_in_a_row = 0;
+                    }
+
+                    if cap.stats.slow_frames_in_a_row == SLOW_FRAMES_BEFORE_WARNING + 1 {
+                        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                            message: format!(
+                                "[{hwnd_id}] end-to-end latency is over {} ms ({} ms)",
+                                SLOW_FRAME_LATENCY.as_millis(),
+                                latency.as_millis()
+                            ),
+                        });
+                    }
+                    break;
+                }
+            }
         }
     }
 
     fn handle_foreground_watcher_message(&mut self, msg: ForegroundWatcherMessage) {
         match msg {
             ForegroundWatcherMessage::WindowChanged { hwnd } => {
-                if self.allowed_hwnds.contains(&hwnd.0) {
-                    self.current_hwnd = Some(hwnd);
-                    if !self.caps.contains_key(&hwnd.0) {
-                        self.start_capture_for(hwnd);
-                    }
+                // タイトルは変わりうるので、前面に来たときに取り直しておく
+                self.titles.insert(hwnd.0, window_title(hwnd));
+                if self.config.debounce_ms == 0 {
+                    self.activate_foreground(hwnd);
                 } else {
-                    let hwnd_id = hwnd.0;
+                    // Alt+Tab で通り過ぎただけのウィンドウには切り替えない
+                    self.pending_foreground = Some((hwnd, Instant::now()));
+                }
+            }
+            ForegroundWatcherMessage::SnapLayoutDetected { primary, secondary } => {
+                for hwnd in [Some(primary), secondary].into_iter().flatten() {
+                    if self.allowed_hwnds.contains(&hwnd.0) && !self.caps.contains_key(&hwnd.0) {
+                        self.request_capture_for(hwnd);
+                    }
+                }
+            }
+            ForegroundWatcherMessage::WindowMinimized { hwnd } => {
+                if self
+                    .pending_foreground
+                    .is_some_and(|(pending, _)| pending == hwnd)
+                {
+                    self.pending_foreground = None;
+                }
+                if Some(hwnd) == self.current_hwnd && !self.pinned {
+                    self.current_hwnd = None;
+                    self.fire_window_change(hwnd, WindowChangeEvent::Minimized);
+                    let _ = self.im_tx_cmd.send(ImageViewerCommand::ShowPlaceholder(
+                        "Window minimized".into(),
+                    ));
+                }
+            }
+            ForegroundWatcherMessage::DesktopSwitch {
+                new_desktop_id,
+                foreground_hwnd,
+            } => {
+                let message = match foreground_hwnd {
+                    Some(hwnd) => format!("switched to desktop {new_desktop_id:?} ({})", hwnd.0),
+                    None => format!("switched to desktop {new_desktop_id:?}"),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            ForegroundWatcherMessage::WindowEnteredFullscreen { hwnd } => {
+                // ビューアの大きさは、キャプチャの解像度が変わったときに ResolutionChanged で合わせる
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("[{}] entered fullscreen", hwnd.0),
+                });
+                self.fire_window_change(hwnd, WindowChangeEvent::EnteredFullscreen);
+            }
+            ForegroundWatcherMessage::WindowExitedFullscreen { hwnd } => {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("[{}] exited fullscreen", hwnd.0),
+                });
+                self.fire_window_change(hwnd, WindowChangeEvent::ExitedFullscreen);
+            }
+            ForegroundWatcherMessage::WindowCreated { hwnd } => {
+                if self.allowed_hwnds.contains(&hwnd.0) && !self.caps.contains_key(&hwnd.0) {
+                    self.request_capture_for(hwnd);
+                }
+            }
+            ForegroundWatcherMessage::WindowDestroyed { hwnd } => {
+                // 閉じたウィンドウはもう始め直せない
+                self.pending_restarts
+                    .retain(|&(_, pending)| pending != hwnd.0);
+                self.pending_captures.retain(|&pending| pending != hwnd);
+                if self
+                    .pending_foreground
+                    .is_some_and(|(pending, _)| pending == hwnd)
+                {
+                    self.pending_foreground = None;
+                }
+                // Closed が届くのを待たずに止める
+                if let Some(cap) = self.caps.get(&hwnd.0) {
+                    cap.request_stop();
+                    self.remove_capture(hwnd.0);
                     let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
-                        message: format!("[{hwnd_id}] not allowed"),
+                        message: format!("[{}] window destroyed", hwnd.0),
                     });
                 }
+                self.stopped_at.remove(&hwnd.0);
+                self.device_removed_reconnects.remove(&hwnd.0);
+                // 履歴に残っている間は、タイトルも表示に使う
+                if !self.window_history.iter().any(|&(id, _)| id == hwnd.0) {
+                    self.titles.remove(&hwnd.0);
+                }
+            }
+            ForegroundWatcherMessage::WindowEvent { hwnd, event } => {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("[{}] {event:?}", hwnd.0),
+                });
             }
         }
     }
 
-    fn handle_stdin_shell_message(&mut self, msg: StdinShellMessage) {
+    fn handle_hotkey_watcher_message(&mut self, msg: HotkeyWatcherMessage) {
         match msg {
-            StdinShellMessage::QuitRequested => self.quit(),
-            StdinShellMessage::AllowHWND(hwnds) => {
-                self.allowed_hwnds.extend(hwnds.iter().map(|hwnd| hwnd.0))
+            HotkeyWatcherMessage::Output { message } => {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
             }
-            StdinShellMessage::ListRequested => {
-                let mut buf = String::new();
-                writeln!(buf, "Got allowed HWNDs:").unwrap();
-                for hwnd_id in &self.allowed_hwnds {
-                    writeln!(buf, "| {}", hwnd_id).unwrap();
+            HotkeyWatcherMessage::HotkeyPressed { id } => {
+                if let Some(name) = self.presets.preset_for(id).map(str::to_string) {
+                    self.load_preset(&name);
                 }
-
-                let _ = self
-                    .sh_tx_cmd
-                    .send(StdinShellCommand::Output { message: buf });
             }
         }
     }
 
-    fn handle_captures_message(&mut self) {
-        let mut to_remove = vec![];
-        for WindowCaptureInterop { rx_msg, .. } in self.caps.values_mut() {
-            if let Ok(msg) = rx_msg.try_recv() {
-                match msg {
-                    WindowCaptureMessage::Closed { hwnd } => {
-                        to_remove.push(hwnd);
-                    }
-                    WindowCaptureMessage::Output { message } => {
-                        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
-                    }
-                }
+    fn load_preset(&mut self, name: &str) {
+        let message = match self.presets.load(name) {
+            Ok(config) => {
+                self.apply_config(config);
+                format!("preset {name} loaded")
             }
+            Err(e) => e,
+        };
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+    }
+
+    // キャプチャの設定はキャプチャを始めるときにしか渡せないので、全部止めて今のウィンドウからやり直す
+    fn apply_config(&mut self, config: DriverConfig) {
+        self.stop_all_captures();
+        self.shared_memory = None;
+        self.shared_memory = open_shared_memory(&config, &self.sh_tx_cmd);
+        self.config = config;
+        self.send_scene_rules();
+        self.send_preset_hotkeys();
+
+        if let Some(hwnd) = self.current_hwnd {
+            self.request_capture_for(hwnd);
         }
+    }
 
-        // すでに閉じられたウィンドウを削除する
-        for hwnd in to_remove {
-            if let Some(cap) = self.caps.remove(&hwnd.0) {
-                let _ = cap.thread.join();
-            }
+    fn push_history(&mut self, hwnd_id: isize) {
+        self.window_history.push_back((hwnd_id, Instant::now()));
+        if self.window_history.len() <= MAX_WINDOW_HISTORY {
+            return;
+        }
+
+        let Some((evicted, _)) = self.window_history.pop_front() else {
+            return;
+        };
+        // 開いているウィンドウのタイトルは title_of で取り直せる
+        if !self.window_history.iter().any(|&(id, _)| id == evicted) {
+            self.titles.remove(&evicted);
         }
     }
 
-    fn handle_captures_frames(&mut self) {
-        for WindowCaptureInterop { rx_frame, .. } in self.caps.values_mut() {
-            if let Ok(frame) = rx_frame.try_recv() {
-                if Some(frame.hwnd) == self.current_hwnd {
-                    let _ = self.im_tx_cmd.send(ImageViewerCommand::Update(frame));
-                }
+    fn title_of(&mut self, hwnd_id: isize) -> String {
+        self.titles
+            .entry(hwnd_id)
+            .or_insert_with(|| window_title(HWND(hwnd_id)))
+            .clone()
+    }
+
+    fn fire_window_change(&mut self, hwnd: HWND, event: WindowChangeEvent) {
+        if let Some(hook) = &self.on_window_change {
+            hook(hwnd, event);
+        }
+        self.broadcast(DriverEvent::WindowChanged { hwnd, event });
+
+        if event == WindowChangeEvent::Activated {
+            let title = self.title_of(hwnd.0);
+            let scene_name = self
+                .config
+                .scene_rules
+                .iter()
+                .find(|rule| title.contains(&rule.title_pattern))
+                .map(|rule| rule.scene_name.clone());
+            if let Some(scene_name) = scene_name {
+                self.broadcast(DriverEvent::SceneSwitch { hwnd, scene_name });
             }
         }
     }
 
-    fn start_capture_for(&mut self, hwnd: HWND) {
-        let (tx_frame, rx_frame) = bounded(5);
-        let (capture, tx_cmd, rx_msg) = WindowCapture::new(hwnd, tx_frame);
-        let thread = thread::spawn(move || capture.run());
-        self.caps.insert(
-            hwnd.0,
-            WindowCaptureInterop {
-                _tx_cmd: tx_cmd,
-                rx_msg,
-                rx_frame,
-                thread,
-            },
-        );
+    #[cfg(feature = "event-log")]
+    fn query_events(&self, sql: &str) -> String {
+        match &self.event_log {
+            Some(event_log) => event_log
+                .query(sql)
+                .unwrap_or_else(|e| format!("query failed: {e}")),
+            None => "event log is not open".into(),
+        }
     }
 
-    fn cleanup_threads(&mut self) {
-        let mut to_remove = vec![];
-        for (hwnd_id, WindowCaptureInterop { thread, .. }) in &self.caps {
-            if thread.is_finished() {
-                to_remove.push(*hwnd_id);
-            }
+    #[cfg(not(feature = "event-log"))]
+    fn query_events(&self, _sql: &str) -> String {
+        "built without the event-log feature".into()
+    }
+
+    fn notify_window(&mut self, hwnd_id: isize, event: WindowEvent) {
+        let Some(subscribers) = self.window_subscribers.get_mut(&hwnd_id) else {
+            return;
+        };
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        if subscribers.is_empty() {
+            self.window_subscribers.remove(&hwnd_id);
         }
+    }
 
-        for hwnd_id in to_remove {
-            let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
-                message: format!("[{hwnd_id}] thread is finished"),
-            });
-            if let Some(cap) = self.caps.remove(&hwnd_id) {
-                let _ = cap.thread.join();
+    fn broadcast(&mut self, event: DriverEvent) {
+        #[cfg(feature = "event-log")]
+        if let Some(event_log) = &self.event_log {
+            if let Err(e) = event_log.record(&event) {
+                // 書けなくなったら、以降のイベントごとに同じエラーを出さないよう諦める
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("event log disabled: {e}"),
+                });
+                self.event_log = None;
             }
         }
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
     }
 
-    fn quit(&mut self) {
-        self.is_running = false;
-        let _ = self.im_tx_cmd.send(ImageViewerCommand::Quit);
-        let _ = self.fw_tx_cmd.send(ForegroundWatcherCommand::Quit);
-        let _ = self.sh_tx_cmd.send(StdinShellCommand::Quit);
+    fn handle_stdin_shell_message(&mut self, msg: StdinShellMessage) {
+        match msg {
+            StdinShellMessage::QuitRequested => self.quit(),
+            StdinShellMessage::AllowHWND(hwnds) => {
+                self.allowed_hwnds.extend(hwnds.iter().map(|hwnd| hwnd.0))
+            }
+            StdinShellMessage::ListRequested => {
+                let mut buf = String::new();
+                writeln!(buf, "Got allowed HWNDs:").unwrap();
+                for hwnd_id in self.allowed_hwnds.clone() {
+                    writeln!(buf, "| {} {}", hwnd_id, self.title_of(hwnd_id)).unwrap();
+                }
+                writeln!(buf, "Capturing HWNDs:").unwrap();
+                for hwnd in self.windows() {
+                    let Some(cap) = self.caps.get(&hwnd.0) else {
+                        continue;
+                    };
+                    let configured = match cap.capture_interval {
+                        CaptureInterval::Fps(fps) => format!("{fps} fps"),
+                        CaptureInterval::EveryMs(ms) => format!("every {ms} ms"),
+                        CaptureInterval::OnDemand => "on demand".into(),
+                    };
+                    let effective = match cap.effective_fps {
+                        Some(fps) => format!("{fps} fps"),
+                        None => configured.clone(),
+                    };
+                    let title = self.title_of(hwnd.0);
+                    writeln!(
+                        buf,
+                        "| {} {title} (configured: {configured}, effective: {effective})",
+                        hwnd.0
+                    )
+                    .unwrap();
+                }
+
+                let _ = self
+                    .sh_tx_cmd
+                    .send(StdinShellCommand::Output { message: buf });
+            }
+            StdinShellMessage::StatusRequested => {
+                let status = self.status();
+
+                let mut buf = String::new();
+                writeln!(buf, "Driver status:").unwrap();
+                match status.current_hwnd {
+                    Some(hwnd_id) => writeln!(buf, "| current: {hwnd_id}").unwrap(),
+                    None => writeln!(buf, "| current: none").unwrap(),
+                }
+                writeln!(buf, "| captures: {}", status.capture_count).unwrap();
+                writeln!(buf, "| log level: {}", status.log_level).unwrap();
+                for WindowInfo {
+                    hwnd,
+                    title,
+                    stats,
+                    health,
+                    deduplicate,
+                    priority,
+                    last_dimensions,
+                } in &status.windows
+                {
+                    let size = match last_dimensions {
+                        Some((width, height)) => format!("{width}x{height}"),
+                        None => "-".into(),
+                    };
+                    writeln!(
+                        buf,
+                        "| [{hwnd}] {title}: {health}, size: {size}, frames: {}, dropped: {}, \
+                         latency: {} us, jitter: {:.1} +/- {:.1} ms, dedup: {}, \
+                         priority: {priority}",
+                        stats.frames_received,
+                        stats.frames_dropped,
+                        stats.last_latency_us,
+                        stats.jitter_mean_ms,
+                        stats.jitter_std_ms,
+                        if *deduplicate { "on" } else { "off" }
+                    )
+                    .unwrap();
+                }
+
+                let _ = self
+                    .sh_tx_cmd
+                    .send(StdinShellCommand::Output { message: buf });
+            }
+            StdinShellMessage::SetPluginParam {
+                plugin,
+                name,
+                value,
+            } => self.set_plugin_param(&plugin, &name, &value),
+            // 書式には空白が入るので、param とは別に受け付けている
+            StdinShellMessage::SetTimestampFormat(format) => {
+                self.set_plugin_param("timestamp", "format", &format)
+            }
+            StdinShellMessage::SetWarmupFrames {
+                hwnd,
+                frames,
+                reset_warmup,
+            } => {
+                let message = match self.caps.get(&hwnd.0) {
+                    Some(cap) => {
+                        let _ = cap.tx_cmd.send(WindowCaptureCommand::SetWarmupFrames {
+                            frames,
+                            reset_warmup,
+                        });
+                        format!("[{}] warmup frames set to {frames}", hwnd.0)
+                    }
+                    None => format!("[{}] not capturing", hwnd.0),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::ToggleDeduplication(hwnd) => {
+                let message = match self.caps.get_mut(&hwnd.0) {
+                    Some(cap) => {
+                        cap.deduplicate = !cap.deduplicate;
+                        let _ = cap
+                            .tx_cmd
+                            .send(WindowCaptureCommand::SetDeduplicate(cap.deduplicate));
+                        let state = if cap.deduplicate { "on" } else { "off" };
+                        format!("[{}] deduplication {state}", hwnd.0)
+                    }
+                    None => format!("[{}] not capturing", hwnd.0),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::CaptureNow(hwnd) => {
+                let message = match self.caps.get(&hwnd.0) {
+                    Some(cap) => {
+                        let _ = cap.tx_cmd.send(WindowCaptureCommand::CaptureNow);
+                        format!("[{}] capture requested", hwnd.0)
+                    }
+                    None => format!("[{}] not capturing", hwnd.0),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::SetPriority { hwnd, priority } => {
+                let message = match self.caps.get_mut(&hwnd.0) {
+                    Some(cap) => {
+                        cap.priority = priority;
+                        format!("[{}] priority set to {priority}", hwnd.0)
+                    }
+                    None => format!("[{}] not capturing", hwnd.0),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::SetChannelCapacity { hwnd, capacity } => {
+                let message = self.restart_with_channel_capacity(hwnd, capacity);
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::SavePreset(name) => {
+                let message = match self.presets.save(&name, &self.config) {
+                    Ok(path) => format!("preset {name} saved to {}", path.display()),
+                    Err(e) => e,
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::LoadPreset(name) => self.load_preset(&name),
+            StdinShellMessage::InjectTestFrame(hwnd) => {
+                let (width, height) = TEST_FRAME_SIZE;
+                let sequence = self.next_test_sequence;
+                self.next_test_sequence += 1;
+                self.test_frames.insert(sequence);
+                self.present_frame(CapturedFrame::checkerboard(hwnd, sequence, width, height));
+            }
+            StdinShellMessage::AddSceneRule {
+                title_pattern,
+                scene_name,
+            } => {
+                self.config.scene_rules.push(SceneRule {
+                    title_pattern,
+                    scene_name,
+                });
+                self.send_scene_rules();
+            }
+            StdinShellMessage::RemoveSceneRule(index) => {
+                if index < self.config.scene_rules.len() {
+                    self.config.scene_rules.remove(index);
+                    self.send_scene_rules();
+                } else {
+                    let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                        message: format!("no scene rule at {index}"),
+                    });
+                }
+            }
+            StdinShellMessage::SceneRulesRequested => self.send_scene_rules(),
+            StdinShellMessage::PluginsRequested => {
+                let plugins = self
+                    .plugins
+                    .iter()
+                    .map(|p| {
+                        (
+                            p.name().to_string(),
+                            !self.disabled_plugins.contains(p.name()),
+                        )
+                    })
+                    .collect();
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::PluginList(plugins));
+            }
+            StdinShellMessage::EnablePlugin(plugin) => self.set_plugin_enabled(plugin, true),
+            StdinShellMessage::DisablePlugin(plugin) => self.set_plugin_enabled(plugin, false),
+            StdinShellMessage::PauseRequested => {
+                self.globally_paused = true;
+                for cap in self.caps.values() {
+                    let _ = cap.tx_cmd.send(WindowCaptureCommand::Pause);
+                }
+            }
+            StdinShellMessage::ResumeRequested => {
+                self.globally_paused = false;
+                for cap in self.caps.values() {
+                    let _ = cap.tx_cmd.send(WindowCaptureCommand::Resume);
+                }
+            }
+            StdinShellMessage::SetLogLevel(level) => {
+                let message = match level.parse::<LogLevel>() {
+                    Ok(level) => {
+                        // 以降に始まるキャプチャにも効くように設定ごと書き換える
+                        self.config.capture.log_level_filter = level;
+                        for cap in self.caps.values() {
+                            let _ = cap.tx_cmd.send(WindowCaptureCommand::SetLogLevel(level));
+                        }
+                        format!("log level set to {level}")
+                    }
+                    Err(e) => e,
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::SetCooldown(ms) => {
+                let message = if ms > MAX_COOLDOWN_MS {
+                    format!("cooldown must be at most {MAX_COOLDOWN_MS} ms: {ms}")
+                } else {
+                    self.set_cooldown(ms);
+                    format!("cooldown set to {ms} ms")
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::SetDebounce(ms) => {
+                // 待っているウィンドウは、前面に来た時刻から新しい間隔で数え直す
+                let reply = if ms > MAX_DEBOUNCE_MS {
+                    StdinShellCommand::Error(format!(
+                        "debounce must be at most {MAX_DEBOUNCE_MS} ms: {ms}"
+                    ))
+                } else {
+                    self.config.debounce_ms = ms;
+                    self.handle_pending_foreground();
+                    StdinShellCommand::Ok
+                };
+                let _ = self.sh_tx_cmd.send(reply);
+            }
+            StdinShellMessage::ExportGraph { path } => {
+                let message = match fs::write(&path, self.export_dot_graph())
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| open_with_system_viewer(&path))
+                {
+                    Ok(()) => format!("graph written to {path}"),
+                    Err(e) => format!("failed to export graph to {path}: {e}"),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::MemoryRequested => {
+                let mut buf = String::new();
+                match ProcessMemory::query() {
+                    Ok(memory) => {
+                        writeln!(buf, "Process memory:").unwrap();
+                        writeln!(buf, "| working set: {} bytes", memory.working_set_bytes).unwrap();
+                        writeln!(buf, "| private: {} bytes", memory.private_bytes).unwrap();
+                        writeln!(
+                            buf,
+                            "| peak working set: {} bytes",
+                            memory.peak_working_set_bytes
+                        )
+                        .unwrap();
+                    }
+                    Err(e) => writeln!(buf, "{e}").unwrap(),
+                }
+
+                // チャンネルの中身は覗けないので、平均のフレームの大きさから見積もる
+                writeln!(buf, "Frame queues (estimated):").unwrap();
+                for (hwnd_id, cap) in &self.caps {
+                    let backlog = cap.rx_frame.len();
+                    writeln!(
+                        buf,
+                        "| [{hwnd_id}] {}: {backlog} frames, {} bytes",
+                        self.titles.get(hwnd_id).map_or("", |title| title),
+                        backlog as u64 * cap.stats.avg_frame_bytes()
+                    )
+                    .unwrap();
+                }
+
+                let _ = self
+                    .sh_tx_cmd
+                    .send(StdinShellCommand::Output { message: buf });
+            }
+            StdinShellMessage::CompactHeapsRequested => {
+                let message = match ProcessMemory::query() {
+                    Ok(before) => {
+                        let heaps = ProcessMemory::compact_heaps();
+                        match ProcessMemory::query() {
+                            Ok(after) => format!(
+                                "compacted {heaps} heaps, private bytes {} -> {} ({} bytes freed)",
+                                before.private_bytes,
+                                after.private_bytes,
+                                before.private_bytes.saturating_sub(after.private_bytes)
+                            ),
+                            Err(e) => e,
+                        }
+                    }
+                    Err(e) => e,
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::Profile { duration_ms } => self.start_profiling(duration_ms),
+            StdinShellMessage::QueryEvents(sql) => {
+                let message = self.query_events(&sql);
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::HistoryRequested => {
+                let mut buf = String::new();
+                writeln!(buf, "Window history:").unwrap();
+                for &(hwnd_id, at) in &self.window_history {
+                    writeln!(
+                        buf,
+                        "| {}s ago: {hwnd_id} {}",
+                        at.elapsed().as_secs(),
+                        self.titles.get(&hwnd_id).map_or("", |title| title)
+                    )
+                    .unwrap();
+                }
+
+                let _ = self
+                    .sh_tx_cmd
+                    .send(StdinShellCommand::Output { message: buf });
+            }
+            StdinShellMessage::ClearHistory => {
+                self.window_history.clear();
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: "history cleared".into(),
+                });
+            }
+            StdinShellMessage::TrimHistory(len) => {
+                let excess = self.window_history.len().saturating_sub(len);
+                self.window_history.drain(..excess);
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("history trimmed to {} entries", self.window_history.len()),
+                });
+            }
+            StdinShellMessage::ExportStats(path) => {
+                let message = match self.export_stats(&path) {
+                    Ok(()) => format!("stats appended to {path}"),
+                    Err(e) => format!("failed to export stats to {path}: {e}"),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::ReloadWatermark => {
+                let message = match self.plugins.iter_mut().find(|p| p.name() == "watermark") {
+                    Some(p) => match p.set_param("reload", "") {
+                        Ok(()) => "watermark reloaded".into(),
+                        Err(e) => format!("watermark: {e}"),
+                    },
+                    None => "no watermark plugin".into(),
+                };
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+            StdinShellMessage::ConfigRequested => {
+                let json = serde_json::to_string_pretty(&self.config).unwrap();
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::ConfigDump { json });
+            }
+        }
+    }
+
+    fn send_scene_rules(&self) {
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::SceneRuleList(
+            self.config.scene_rules.clone(),
+        ));
+    }
+
+    fn send_preset_hotkeys(&self) {
+        if let Some((hk_tx_cmd, _)) = &self.hotkeys {
+            let _ = hk_tx_cmd.send(HotkeyWatcherCommand::SetPresetHotkeys(
+                self.config.preset_hotkeys.clone(),
+            ));
+        }
+    }
+
+    fn handle_driver_event(&mut self, event: PluginEvent) {
+        match event {
+            PluginEvent::SceneChange { hwnd, delta } => {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("[{}] scene changed (delta: {delta:.3})", hwnd.0),
+                });
+            }
+            PluginEvent::Output { message } => {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+        }
+    }
+
+    // 呼ぶたびに行を追記していくので、ファイルが時系列になる
+    fn export_stats(&self, path: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(
+                file,
+                "timestamp,hwnd,frames_captured,frames_dropped,measured_fps,avg_frame_bytes"
+            )?;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for (hwnd_id, cap) in &self.caps {
+            let stats = &cap.stats;
+            writeln!(
+                file,
+                "{timestamp},{hwnd_id},{},{},{:.2},{}",
+                stats.frames_received,
+                stats.frames_dropped,
+                stats.measured_fps(),
+                stats.avg_frame_bytes()
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_captures_message(&mut self) {
+        let mut to_remove = vec![];
+        let mut events = vec![];
+        let mut window_events = vec![];
+        let mut reconnects = vec![];
+        for (&hwnd_id, cap) in self.caps.iter_mut() {
+            if let Ok(msg) = cap.rx_msg.try_recv() {
+                match msg {
+                    WindowCaptureMessage::Closed { hwnd } => {
+                        to_remove.push(hwnd);
+                    }
+                    WindowCaptureMessage::Error { hwnd, error } => {
+                        cap.stats.record_error();
+                        let mut message = format!("[{}] capture stopped: {error}", hwnd.0);
+                        // GPU が戻ってくるまでしばらく待ってから、キャプチャを作り直してみる
+                        if error == WindowCaptureError::DeviceRemoved {
+                            let attempts =
+                                self.device_removed_reconnects.entry(hwnd_id).or_default();
+                            if *attempts < MAX_DEVICE_REMOVED_RECONNECTS {
+                                *attempts += 1;
+                                let delay = self.config.device_removed_retry_delay_ms;
+                                write!(
+                                    message,
+                                    ", reconnecting in {delay} ms \
+                                     ({attempts}/{MAX_DEVICE_REMOVED_RECONNECTS})"
+                                )
+                                .unwrap();
+                                reconnects
+                                    .push((Instant::now() + Duration::from_millis(delay), hwnd_id));
+                            } else {
+                                self.device_removed_reconnects.remove(&hwnd_id);
+                                write!(
+                                    message,
+                                    ", giving up after {MAX_DEVICE_REMOVED_RECONNECTS} reconnects"
+                                )
+                                .unwrap();
+                            }
+                        }
+                        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+                        to_remove.push(hwnd);
+                    }
+                    WindowCaptureMessage::Output { level, message } => {
+                        if level <= LogLevel::Warn {
+                            cap.stats.record_error();
+                        }
+                        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+                    }
+                    WindowCaptureMessage::CaptureStarted {
+                        hwnd,
+                        width,
+                        height,
+                        format,
+                        ..
+                    } => {
+                        // プレースホルダを出していたビューアを、キャプチャの大きさに合わせる
+                        if Some(hwnd) == self.current_hwnd {
+                            let _ = self
+                                .im_tx_cmd
+                                .send(ImageViewerCommand::Resize { width, height });
+                        }
+                        // 購読している側には、最初の大きさも変化として知らせる
+                        window_events.push((hwnd_id, WindowEvent::SizeChanged { width, height }));
+                        events.push(DriverEvent::CaptureStarted {
+                            hwnd,
+                            width,
+                            height,
+                            format,
+                        });
+                    }
+                    WindowCaptureMessage::ResolutionChanged {
+                        hwnd,
+                        new_width,
+                        new_height,
+                        ..
+                    } => {
+                        if Some(hwnd) == self.current_hwnd {
+                            let _ = self.im_tx_cmd.send(ImageViewerCommand::Resize {
+                                width: new_width,
+                                height: new_height,
+                            });
+                        }
+                        window_events.push((
+                            hwnd_id,
+                            WindowEvent::SizeChanged {
+                                width: new_width,
+                                height: new_height,
+                            },
+                        ));
+                    }
+                    WindowCaptureMessage::BurstComplete {
+                        hwnd,
+                        frames_captured,
+                    } => {
+                        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                            message: format!(
+                                "[{}] burst complete: {frames_captured} frames",
+                                hwnd.0
+                            ),
+                        });
+                    }
+                    WindowCaptureMessage::Throttled {
+                        hwnd,
+                        effective_fps,
+                    } => {
+                        // 設定どおりの速さまで戻ったら、もう落としてはいない
+                        let recovered = cap.capture_interval == CaptureInterval::Fps(effective_fps);
+                        let message = if recovered {
+                            format!("[{}] recovered to {effective_fps} fps", hwnd.0)
+                        } else {
+                            format!("[{}] throttled to {effective_fps} fps", hwnd.0)
+                        };
+                        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+                        cap.effective_fps = (!recovered).then_some(effective_fps);
+                        events.push(DriverEvent::Throttled {
+                            hwnd,
+                            effective_fps,
+                        });
+                    }
+                    WindowCaptureMessage::JitterUpdated {
+                        mean_ms, std_ms, ..
+                    } => {
+                        cap.stats.jitter_mean_ms = mean_ms;
+                        cap.stats.jitter_std_ms = std_ms;
+                    }
+                }
+            }
+
+            let health = cap.stats.health();
+            if health != cap.health {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("[{hwnd_id}] {} -> {health}", cap.health),
+                });
+                let old = mem::replace(&mut cap.health, health.clone());
+                window_events.push((
+                    hwnd_id,
+                    WindowEvent::HealthChanged {
+                        old: old.clone(),
+                        new: health.clone(),
+                    },
+                ));
+                events.push(DriverEvent::HealthChanged {
+                    hwnd: HWND(hwnd_id),
+                    old,
+                    new: health,
+                });
+            }
+        }
+
+        for event in events {
+            self.broadcast(event);
+        }
+        for (hwnd_id, event) in window_events {
+            self.notify_window(hwnd_id, event);
+        }
+
+        // 始め直すウィンドウの購読を残せるよう、先に予定に入れておく
+        for (at, hwnd_id) in reconnects {
+            self.schedule_restart(at, hwnd_id);
+        }
+        // すでに閉じられたウィンドウを削除する
+        for hwnd in to_remove {
+            self.remove_capture(hwnd.0);
+        }
+        self.start_pending_captures();
+    }
+
+    fn start_pending_captures(&mut self) {
+        while self.caps.len() < self.config.max_concurrent_captures {
+            let Some(hwnd) = self.pending_captures.pop_front() else {
+                break;
+            };
+            if !self.caps.contains_key(&hwnd.0) {
+                self.start_capture_for(hwnd);
+            }
+        }
+    }
+
+    fn handle_captures_frames(&mut self) {
+        // 優先度の高い順に、同じなら長くフレームを処理していない順に回す
+        let mut order: Vec<_> = self
+            .caps
+            .iter()
+            .map(|(&hwnd_id, cap)| (hwnd_id, cap.priority, cap.stats.last_frame_at))
+            .collect();
+        order.sort_by_key(|&(_, priority, last_frame_at)| (Reverse(priority), last_frame_at));
+
+        for (hwnd_id, ..) in order {
+            let Some(cap) = self.caps.get_mut(&hwnd_id) else {
+                continue;
+            };
+            let Ok(frame) = cap.rx_frame.try_recv() else {
+                continue;
+            };
+            cap.last_frame_dimensions = Some((frame.width, frame.height));
+            // フレームが届いたなら作り直しはうまくいった
+            self.device_removed_reconnects.remove(&hwnd_id);
+            self.handle_frame(frame);
+        }
+    }
+
+    // チャンネルを通さずに合成したフレームを流し込む
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inject_frame(&mut self, hwnd: HWND, mut frame: CapturedFrame) {
+        frame.hwnd = hwnd;
+        self.handle_frame(frame);
+    }
+
+    fn handle_frame(&mut self, frame: CapturedFrame) {
+        let max_frame_age = Duration::from_millis(self.config.capture.max_frame_age_ms);
+        let mut stats = self.caps.get_mut(&frame.hwnd.0).map(|cap| &mut cap.stats);
+        if let Some(stats) = &mut stats {
+            stats.record_frame(frame.bytes.len());
+        }
+
+        if let Some(checksum) = frame.checksum {
+            if crc32fast::hash(&frame.bytes) != checksum {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("[{}] frame checksum mismatch", frame.hwnd.0),
+                });
+            }
+        }
+
+        // チャンネルに溜まっている間に古くなってしまったフレームは捨てる
+        if frame.captured_at.elapsed() > max_frame_age {
+            if let Some(stats) = stats {
+                stats.frames_dropped += 1;
+            }
+            self.broadcast(DriverEvent::FrameDropped {
+                hwnd: frame.hwnd,
+                sequence: frame.sequence,
+            });
+            return;
+        }
+
+        self.notify_window(
+            frame.hwnd.0,
+            WindowEvent::Frame {
+                sequence: frame.sequence,
+                width: frame.width,
+                height: frame.height,
+                captured_at: frame.captured_at,
+            },
+        );
+        if Some(frame.hwnd) == self.current_hwnd {
+            self.present_frame(frame);
+        }
+    }
+
+    fn present_frame(&mut self, mut frame: CapturedFrame) {
+        for plugin in &mut self.plugins {
+            if !self.disabled_plugins.contains(plugin.name()) {
+                plugin.process(&mut frame);
+            }
+        }
+        if let Some(shared_memory) = &mut self.shared_memory {
+            if let Err(message) = shared_memory.write(&frame) {
+                let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+            }
+        }
+        if !self.viewer_attached {
+            self.drop_test_frame(frame.sequence);
+            self.last_presented = Some(frame);
+            return;
+        }
+        self.remember_presented(&frame);
+        self.send_to_viewer(frame);
+    }
+
+    // 毎フレーム確保し直さないよう、前のフレームの領域を使い回す
+    fn remember_presented(&mut self, frame: &CapturedFrame) {
+        let mut bytes = self
+            .last_presented
+            .take()
+            .map(|last| last.bytes)
+            .unwrap_or_default();
+        bytes.clone_from(&frame.bytes);
+        self.last_presented = Some(CapturedFrame { bytes, ..*frame });
+    }
+
+    fn send_to_viewer(&mut self, frame: CapturedFrame) {
+        self.in_flight_frames
+            .push_back((frame.sequence, frame.hwnd.0, frame.captured_at));
+        let _ = self.im_tx_cmd.send(ImageViewerCommand::Update(frame));
+    }
+
+    fn handle_audio_captures(&mut self) {
+        let mut to_remove = vec![];
+        for (&hwnd_id, cap) in &self.audio_caps {
+            if let Ok(msg) = cap.rx_msg.try_recv() {
+                match msg {
+                    AudioCaptureMessage::Output { message } => {
+                        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+                    }
+                    AudioCaptureMessage::Closed { .. } => to_remove.push(hwnd_id),
+                }
+            }
+
+            // 映像と同じく、今のウィンドウの音声だけを流す
+            while let Ok(chunk) = cap.rx_chunk.try_recv() {
+                if Some(chunk.hwnd) == self.current_hwnd {
+                    if let Some(ao_tx_cmd) = &self.ao_tx_cmd {
+                        let _ = ao_tx_cmd.send(AudioOutputCommand::Chunk(chunk));
+                    }
+                }
+            }
+        }
+
+        for hwnd_id in to_remove {
+            self.remove_audio_capture(hwnd_id);
+        }
+    }
+
+    fn activate_foreground(&mut self, hwnd: HWND) {
+        if self.pinned {
+            return;
+        }
+        if self.allowed_hwnds.contains(&hwnd.0) {
+            if self.current_hwnd != Some(hwnd) {
+                self.current_hwnd = Some(hwnd);
+                self.push_history(hwnd.0);
+                self.fire_window_change(hwnd, WindowChangeEvent::Activated);
+            }
+            if !self.caps.contains_key(&hwnd.0) {
+                self.request_capture_for(hwnd);
+            }
+        } else {
+            let hwnd_id = hwnd.0;
+            let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                message: format!("[{hwnd_id}] not allowed"),
+            });
+        }
+    }
+
+    fn handle_pending_foreground(&mut self) {
+        let debounce = Duration::from_millis(self.config.debounce_ms);
+        if let Some((hwnd, received_at)) = self.pending_foreground {
+            if received_at.elapsed() >= debounce {
+                self.pending_foreground = None;
+                self.activate_foreground(hwnd);
+            }
+        }
+    }
+
+    fn request_capture_for(&mut self, hwnd: HWND) {
+        if self
+            .pending_restarts
+            .iter()
+            .any(|&(_, pending)| pending == hwnd.0)
+        {
+            return;
+        }
+
+        // 止まったばかりのキャプチャをすぐに作り直すと、フォーカスが一瞬外れて戻っただけでも
+        // キャプチャが止まったり始まったりを繰り返すので、クールダウンが明けるまで待つ。
+        let cooldown = Duration::from_millis(self.config.cooldown_ms);
+        match self.stopped_at.get(&hwnd.0) {
+            Some(&stopped_at) if stopped_at.elapsed() < cooldown => {
+                self.schedule_restart(stopped_at + cooldown, hwnd.0);
+            }
+            _ => self.start_capture_for(hwnd),
+        }
+    }
+
+    // 1 つのウィンドウの再開は 1 つだけ待つ
+    fn schedule_restart(&mut self, at: Instant, hwnd_id: isize) {
+        self.pending_restarts
+            .retain(|&(_, pending)| pending != hwnd_id);
+        self.pending_restarts.insert((at, hwnd_id));
+    }
+
+    // 集めている間も Driver は動き続けられるよう、別スレッドで集めて結果だけ shell に送る
+    fn start_profiling(&mut self, duration_ms: u64) {
+        let message = if duration_ms == 0 || duration_ms > MAX_PROFILE_MS {
+            format!("profile duration must be between 1 and {MAX_PROFILE_MS} ms: {duration_ms}")
+        } else if self
+            .profiler_thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+        {
+            "profiling is already running".into()
+        } else {
+            let sh_tx_cmd = self.sh_tx_cmd.clone();
+            self.profiler_thread = Some(thread::spawn(move || {
+                let folded = Profiler::new().run(Duration::from_millis(duration_ms));
+                let _ = sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("Profile ({duration_ms} ms, folded stacks):\n{folded}"),
+                });
+            }));
+            format!("profiling for {duration_ms} ms")
+        };
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+    }
+
+    fn set_plugin_param(&mut self, plugin: &str, name: &str, value: &str) {
+        let message = match self.plugins.iter_mut().find(|p| p.name() == plugin) {
+            Some(p) => match p.set_param(name, value) {
+                Ok(()) => format!("{plugin}: {name} = {value}"),
+                Err(e) => format!("{plugin}: {e}"),
+            },
+            None => format!("unknown plugin: {plugin}"),
+        };
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+    }
+
+    fn set_plugin_enabled(&mut self, plugin: String, enabled: bool) {
+        let message = if !self.plugins.iter().any(|p| p.name() == plugin) {
+            format!("unknown plugin: {plugin}")
+        } else if enabled {
+            self.disabled_plugins.remove(&plugin);
+            format!("{plugin}: enabled")
+        } else {
+            let message = format!("{plugin}: disabled");
+            self.disabled_plugins.insert(plugin);
+            message
+        };
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::Output { message });
+    }
+
+    // 待っている再開も、止まった時刻から新しいクールダウンで数え直す
+    fn set_cooldown(&mut self, ms: u64) {
+        self.config.cooldown_ms = ms;
+        let cooldown = Duration::from_millis(ms);
+        let now = Instant::now();
+        for (_, hwnd_id) in mem::take(&mut self.pending_restarts) {
+            let stopped_at = self.stopped_at.get(&hwnd_id).copied().unwrap_or(now);
+            self.pending_restarts
+                .insert((stopped_at + cooldown, hwnd_id));
+        }
+    }
+
+    // 待っている間に閉じられたり許可が外されたりしたウィンドウは、再開せずに忘れる
+    fn handle_pending_restarts(&mut self) {
+        let now = Instant::now();
+        while let Some(&(at, hwnd_id)) = self.pending_restarts.first() {
+            if at > now {
+                break;
+            }
+
+            self.pending_restarts.pop_first();
+            let hwnd = HWND(hwnd_id);
+            if !self.allowed_hwnds.contains(&hwnd_id) || !unsafe { IsWindow(hwnd) }.as_bool() {
+                self.device_removed_reconnects.remove(&hwnd_id);
+                continue;
+            }
+            if !self.caps.contains_key(&hwnd_id) {
+                self.start_capture_for(hwnd);
+            }
+        }
+    }
+
+    fn start_capture_for(&mut self, hwnd: HWND) {
+        if self.caps.len() >= self.config.max_concurrent_captures {
+            if !self.pending_captures.contains(&hwnd) {
+                self.pending_captures.push_back(hwnd);
+            }
+            return;
+        }
+
+        self.title_of(hwnd.0);
+        let (tx_frame, rx_frame) = match self.channel_capacities.get(&hwnd.0) {
+            Some(&capacity) => bounded(capacity),
+            None => match self.config.frame_channel {
+                FrameChannelKind::Bounded(cap) => bounded(cap),
+                FrameChannelKind::Unbounded => unbounded(),
+                FrameChannelKind::Rendezvous => bounded(0),
+            },
+        };
+        let capture_interval = self.config.capture.interval();
+        let SpawnedCapture {
+            tx_cmd,
+            rx_msg,
+            stopper,
+            thread,
+        } = self
+            .capture_factory
+            .spawn(hwnd, self.config.capture.clone(), tx_frame);
+        if self.globally_paused {
+            let _ = tx_cmd.send(WindowCaptureCommand::Pause);
+        }
+        self.caps.insert(
+            hwnd.0,
+            WindowCaptureInterop {
+                tx_cmd,
+                stopper,
+                rx_msg,
+                rx_frame,
+                thread,
+                stats: CaptureStats::default(),
+                health: CaptureHealth::Healthy,
+                deduplicate: self.config.capture.deduplicate,
+                priority: DEFAULT_PRIORITY,
+                capture_interval,
+                effective_fps: None,
+                last_frame_dimensions: None,
+            },
+        );
+        if Some(hwnd) == self.current_hwnd {
+            let _ = self.im_tx_cmd.send(ImageViewerCommand::ShowPlaceholder(
+                "Waiting for the first frame".into(),
+            ));
+        }
+
+        if self.ao_tx_cmd.is_some() && !self.audio_caps.contains_key(&hwnd.0) {
+            self.start_audio_capture_for(hwnd);
+        }
+    }
+
+    // チャンネルは作り直すしかないので、キャプチャを止めて同じ設定で始め直す
+    fn restart_with_channel_capacity(&mut self, hwnd: HWND, capacity: usize) -> String {
+        let Some(old) = self.caps.remove(&hwnd.0) else {
+            return format!("[{}] not capturing", hwnd.0);
+        };
+        old.request_stop();
+        let _ = old.thread.join();
+
+        self.channel_capacities.insert(hwnd.0, capacity);
+        self.start_capture_for(hwnd);
+        let Some(cap) = self.caps.get_mut(&hwnd.0) else {
+            return format!("[{}] failed to restart capture", hwnd.0);
+        };
+        if cap.deduplicate != old.deduplicate {
+            let _ = cap
+                .tx_cmd
+                .send(WindowCaptureCommand::SetDeduplicate(old.deduplicate));
+        }
+        cap.deduplicate = old.deduplicate;
+        cap.priority = old.priority;
+        cap.stats = old.stats;
+        cap.health = old.health;
+
+        self.broadcast(DriverEvent::CaptureRestarted { hwnd });
+        format!("[{}] restarted with channel capacity {capacity}", hwnd.0)
+    }
+
+    fn cleanup_threads(&mut self) {
+        let mut to_remove = vec![];
+        for (hwnd_id, WindowCaptureInterop { thread, .. }) in &self.caps {
+            if thread.is_finished() {
+                to_remove.push(*hwnd_id);
+            }
+        }
+
+        for hwnd_id in to_remove {
+            let _ = self.sh_tx_cmd.send(StdinShellCommand::Output {
+                message: format!("[{hwnd_id}] thread is finished"),
+            });
+            self.remove_capture(hwnd_id);
+        }
+        self.start_pending_captures();
+    }
+
+    fn start_audio_capture_for(&mut self, hwnd: HWND) {
+        let (tx_chunk, rx_chunk) = unbounded();
+        let (capture, tx_cmd, rx_msg) = AudioCapture::new(hwnd, tx_chunk);
+        let thread = thread::spawn(move || capture.run());
+        self.audio_caps.insert(
+            hwnd.0,
+            AudioCaptureInterop {
+                tx_cmd,
+                rx_msg,
+                rx_chunk,
+                thread,
+            },
+        );
+    }
+
+    fn remove_capture(&mut self, hwnd_id: isize) {
+        self.pending_captures.retain(|hwnd| hwnd.0 != hwnd_id);
+        if let Some(cap) = self.caps.remove(&hwnd_id) {
+            let _ = cap.thread.join();
+            self.stopped_at.insert(hwnd_id, Instant::now());
+            self.broadcast(DriverEvent::CaptureStopped {
+                hwnd: HWND(hwnd_id),
+            });
+            // 繋ぎ直すだけなら、始め直した後も同じ購読者に届ける
+            if !self
+                .pending_restarts
+                .iter()
+                .any(|&(_, pending)| pending == hwnd_id)
+            {
+                self.notify_window(hwnd_id, WindowEvent::Stopped);
+                self.window_subscribers.remove(&hwnd_id);
+            }
+        }
+        self.remove_audio_capture(hwnd_id);
+    }
+
+    fn remove_audio_capture(&mut self, hwnd_id: isize) {
+        if let Some(cap) = self.audio_caps.remove(&hwnd_id) {
+            let _ = cap.tx_cmd.send(AudioCaptureCommand::Quit);
+            let _ = cap.thread.join();
+        }
+    }
+
+    fn stop_all_captures(&mut self) {
+        self.pending_captures.clear();
+        for cap in self.caps.values() {
+            cap.request_stop();
+        }
+
+        for (hwnd_id, cap) in mem::take(&mut self.caps) {
+            let _ = cap.thread.join();
+            self.broadcast(DriverEvent::CaptureStopped {
+                hwnd: HWND(hwnd_id),
+            });
+        }
+
+        for hwnd_id in self.audio_caps.keys().copied().collect::<Vec<_>>() {
+            self.remove_audio_capture(hwnd_id);
+        }
+    }
+
+    fn quit(&mut self) {
+        self.is_running = false;
+        self.stop_all_captures();
+        let _ = self.im_tx_cmd.send(ImageViewerCommand::Quit);
+        let _ = self.fw_tx_cmd.send(ForegroundWatcherCommand::Quit);
+        let _ = self.sh_tx_cmd.send(StdinShellCommand::Quit);
+        if let Some(ao_tx_cmd) = &self.ao_tx_cmd {
+            let _ = ao_tx_cmd.send(AudioOutputCommand::Quit);
+        }
+        if let Some((hk_tx_cmd, _)) = &self.hotkeys {
+            let _ = hk_tx_cmd.send(HotkeyWatcherCommand::Quit);
+        }
+        if let Some(thread) = self.watcher_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn open_shared_memory(
+    config: &DriverConfig,
+    sh_tx_cmd: &Sender<StdinShellCommand>,
+) -> Option<SharedMemoryOutput> {
+    config.shared_memory.as_ref().and_then(|options| {
+        SharedMemoryOutput::new(options)
+            .map_err(|e| {
+                let _ = sh_tx_cmd.send(StdinShellCommand::Output {
+                    message: format!("failed to open shared memory {}: {e}", options.name),
+                });
+            })
+            .ok()
+    })
+}
+
+fn open_with_system_viewer(path: &str) -> Result<(), String> {
+    let result = unsafe {
+        ShellExecuteW(
+            None,
+            w!("open"),
+            &HSTRING::from(path),
+            PCWSTR::null(),
+            PCWSTR::null(),
+            SW_SHOWNORMAL,
+        )
+    };
+
+    // 32 以下はエラーコード
+    if result.0 <= 32 {
+        return Err(format!("ShellExecuteW failed ({})", result.0));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hotkey_watcher::{HotkeyModifier, PresetHotkeys},
+        test_utils::{DriverHarness, MockCaptureFactory},
+    };
+
+    const HWND_A: HWND = HWND(0x1000);
+    const HWND_B: HWND = HWND(0x2000);
+
+    fn allow(harness: &DriverHarness, driver: &mut Driver, hwnds: &[HWND]) {
+        harness.send_shell_message(StdinShellMessage::AllowHWND(hwnds.to_vec()));
+        driver.run_until_idle();
+    }
+
+    #[test]
+    fn foreground_change_starts_a_capture_that_reaches_the_viewer() {
+        let (harness, mut driver) = DriverHarness::new();
+        allow(&harness, &mut driver, &[HWND_A]);
+        harness.send_foreground_change(HWND_A);
+        driver.run_until_idle();
+
+        assert_eq!(driver.windows(), vec![HWND_A]);
+        assert!(matches!(
+            harness.recv_viewer_command(),
+            Some(ImageViewerCommand::ShowPlaceholder(_))
+        ));
+
+        harness.send_frame(HWND_A, CapturedFrame::checkerboard(HWND_A, 1, 16, 16));
+        driver.run_until_idle();
+
+        match harness.recv_viewer_command() {
+            Some(ImageViewerCommand::Update(frame)) => {
+                assert_eq!(frame.hwnd, HWND_A);
+                assert_eq!((frame.width, frame.height), (16, 16));
+            }
+            _ => panic!("expected an Update"),
+        }
+        harness.assert_idle();
+    }
+
+    #[test]
+    fn preset_hotkeys_are_registered_only_when_configured() {
+        let (_harness, mut driver) = DriverHarness::new();
+        let (hk_tx_cmd, hk_rx_cmd) = crossbeam_channel::unbounded();
+        let (_hk_tx_msg, hk_rx_msg) = crossbeam_channel::unbounded();
+        driver.set_hotkey_watcher(hk_tx_cmd, hk_rx_msg);
+        assert!(matches!(
+            hk_rx_cmd.try_recv(),
+            Ok(HotkeyWatcherCommand::SetPresetHotkeys(None))
+        ));
+
+        let hotkeys = PresetHotkeys {
+            modifiers: vec![HotkeyModifier::Ctrl, HotkeyModifier::Alt],
+        };
+        driver.apply_config(DriverConfig {
+            preset_hotkeys: Some(hotkeys.clone()),
+            ..DriverConfig::default()
+        });
+        match hk_rx_cmd.try_recv() {
+            Ok(HotkeyWatcherCommand::SetPresetHotkeys(Some(sent))) => assert_eq!(sent, hotkeys),
+            _ => panic!("expected the configured hotkeys"),
+        }
+    }
+
+    #[test]
+    fn reattached_viewer_shows_the_last_frame_of_the_current_window() {
+        let (harness, mut driver) = DriverHarness::new();
+        allow(&harness, &mut driver, &[HWND_A]);
+        harness.send_foreground_change(HWND_A);
+        driver.run_until_idle();
+        harness.send_frame(HWND_A, CapturedFrame::checkerboard(HWND_A, 1, 16, 8));
+        driver.run_until_idle();
+
+        driver.detach_image_viewer();
+        let (im_tx_cmd, im_rx_cmd) = crossbeam_channel::unbounded();
+        let (_im_tx_msg, im_rx_msg) = crossbeam_channel::unbounded();
+        driver.attach_image_viewer(im_tx_cmd, im_rx_msg);
+
+        assert!(matches!(
+            im_rx_cmd.try_recv(),
+            Ok(ImageViewerCommand::Resize {
+                width: 16,
+                height: 8
+            })
+        ));
+        match im_rx_cmd.try_recv() {
+            Ok(ImageViewerCommand::Update(frame)) => {
+                assert_eq!((frame.hwnd, frame.sequence), (HWND_A, 1));
+            }
+            _ => panic!("expected the last frame"),
+        }
+    }
+
+    #[test]
+    fn device_removed_reconnects_are_capped() {
+        let (harness, mut driver) = DriverHarness::with_config(DriverConfig {
+            device_removed_retry_delay_ms: 0,
+            ..DriverConfig::default()
+        });
+        allow(&harness, &mut driver, &[HWND_A]);
+        harness.send_foreground_change(HWND_A);
+        driver.run_until_idle();
+
+        for attempt in 0..=MAX_DEVICE_REMOVED_RECONNECTS {
+            harness.send_capture_message(
+                HWND_A,
+                WindowCaptureMessage::Error {
+                    hwnd: HWND_A,
+                    error: WindowCaptureError::DeviceRemoved,
+                },
+            );
+            driver.run_until_idle();
+
+            let expected = if attempt < MAX_DEVICE_REMOVED_RECONNECTS {
+                vec![HWND_A]
+            } else {
+                vec![]
+            };
+            assert_eq!(driver.windows(), expected, "after {} errors", attempt + 1);
+        }
+    }
+
+    #[test]
+    fn effective_fps_is_cleared_once_the_configured_rate_is_back() {
+        let (harness, mut driver) = DriverHarness::new();
+        allow(&harness, &mut driver, &[HWND_A]);
+        harness.send_foreground_change(HWND_A);
+        driver.run_until_idle();
+
+        let fps = driver.config.capture.fps;
+        for (effective_fps, expected) in [(fps / 2, Some(fps / 2)), (fps, None)] {
+            harness.send_capture_message(
+                HWND_A,
+                WindowCaptureMessage::Throttled {
+                    hwnd: HWND_A,
+                    effective_fps,
+                },
+            );
+            driver.run_until_idle();
+            assert_eq!(driver.caps[&HWND_A.0].effective_fps, expected);
+        }
+    }
+
+    #[test]
+    fn pending_restarts_of_the_same_instant_are_kept_apart() {
+        let (_harness, mut driver) = DriverHarness::new();
+        let at = Instant::now() + Duration::from_secs(60);
+        driver.schedule_restart(at, HWND_A.0);
+        driver.schedule_restart(at, HWND_B.0);
+        driver.schedule_restart(at + Duration::from_secs(1), HWND_A.0);
+
+        assert_eq!(
+            driver.pending_restarts.iter().copied().collect::<Vec<_>>(),
+            vec![(at, HWND_B.0), (at + Duration::from_secs(1), HWND_A.0)]
+        );
+    }
+
+    #[test]
+    fn windows_lists_every_captured_window() {
+        let (harness, mut driver) = DriverHarness::new();
+        allow(&harness, &mut driver, &[HWND_A, HWND_B]);
+        for hwnd in [HWND_A, HWND_B] {
+            harness.send_foreground_change(hwnd);
+            driver.run_until_idle();
+        }
+
+        assert_eq!(driver.windows(), vec![HWND_A, HWND_B]);
+    }
+
+    #[test]
+    fn capture_count_follows_started_and_removed_captures() {
+        let (_harness, mut driver) = DriverHarness::new();
+        assert_eq!(driver.capture_count(), 0);
+
+        driver.start_capture_for(HWND_A);
+        assert_eq!(driver.capture_count(), 1);
+        driver.start_capture_for(HWND_B);
+        assert_eq!(driver.capture_count(), 2);
+
+        // remove_capture はスレッドの終わりを待つので、先に止めておく
+        let _ = driver.caps[&HWND_A.0]
+            .tx_cmd
+            .send(WindowCaptureCommand::Quit);
+        driver.remove_capture(HWND_A.0);
+        assert_eq!(driver.capture_count(), 1);
+        assert_eq!(driver.status().capture_count, 1);
+    }
+
+    #[test]
+    fn window_subscribers_are_dropped_when_the_capture_stops() {
+        let (harness, mut driver) = DriverHarness::new();
+        allow(&harness, &mut driver, &[HWND_A]);
+        let events = driver.subscribe_to_window_events(HWND_A);
+        harness.send_foreground_change(HWND_A);
+        driver.run_until_idle();
+
+        harness.send_capture_message(HWND_A, WindowCaptureMessage::Closed { hwnd: HWND_A });
+        driver.run_until_idle();
+
+        assert_eq!(events.try_iter().last(), Some(WindowEvent::Stopped));
+        assert!(driver.window_subscribers.is_empty());
+    }
+
+    #[test]
+    fn subscribers_see_events_in_order() {
+        let (harness, mut driver) = DriverHarness::new();
+        let events = driver.subscribe();
+        allow(&harness, &mut driver, &[HWND_A, HWND_B]);
+        for hwnd in [HWND_A, HWND_B] {
+            harness.send_foreground_change(hwnd);
+            driver.run_until_idle();
+        }
+        harness.send_watcher_message(ForegroundWatcherMessage::WindowDestroyed { hwnd: HWND_A });
+        driver.run_until_idle();
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                DriverEvent::WindowChanged {
+                    hwnd: HWND_A,
+                    event: WindowChangeEvent::Activated,
+                },
+                DriverEvent::WindowChanged {
+                    hwnd: HWND_B,
+                    event: WindowChangeEvent::Activated,
+                },
+                DriverEvent::CaptureStopped { hwnd: HWND_A },
+            ]
+        );
+    }
+
+    #[test]
+    fn destroyed_windows_are_forgotten() {
+        let (harness, mut driver) = DriverHarness::with_config(DriverConfig {
+            device_removed_retry_delay_ms: 60_000,
+            ..DriverConfig::default()
+        });
+        allow(&harness, &mut driver, &[HWND_A, HWND_B]);
+        for hwnd in [HWND_A, HWND_B] {
+            harness.send_foreground_change(hwnd);
+            driver.run_until_idle();
+        }
+        harness.send_capture_message(
+            HWND_B,
+            WindowCaptureMessage::Error {
+                hwnd: HWND_B,
+                error: WindowCaptureError::DeviceRemoved,
+            },
+        );
+        driver.run_until_idle();
+        assert_eq!(driver.pending_restarts.len(), 1);
+
+        for hwnd in [HWND_A, HWND_B] {
+            harness.send_watcher_message(ForegroundWatcherMessage::WindowDestroyed { hwnd });
+        }
+        driver.run_until_idle();
+
+        assert!(driver.pending_restarts.is_empty());
+        assert!(driver.stopped_at.is_empty());
+        assert!(driver.device_removed_reconnects.is_empty());
+        // 履歴に残っている分のタイトルだけ持っておく
+        assert_eq!(
+            driver.titles.keys().copied().collect::<Vec<_>>(),
+            vec![HWND_A.0, HWND_B.0]
+        );
+    }
+
+    #[test]
+    fn window_history_is_capped() {
+        let (_harness, mut driver) = DriverHarness::new();
+        for hwnd_id in 0..MAX_WINDOW_HISTORY as isize + 2 {
+            driver.titles.insert(hwnd_id, String::new());
+            driver.push_history(hwnd_id);
+        }
+
+        assert_eq!(driver.window_history.len(), MAX_WINDOW_HISTORY);
+        assert_eq!(driver.window_history.front().map(|&(id, _)| id), Some(2));
+        assert!(!driver.titles.contains_key(&0) && !driver.titles.contains_key(&1));
+    }
+
+    #[test]
+    fn mock_capture_factory_streams_frames_until_the_window_is_destroyed() {
+        let (harness, mut driver) = DriverHarness::new();
+        let factory = MockCaptureFactory::new(32, 16, [255, 0, 0, 255]);
+        driver.set_capture_factory(factory.clone());
+        allow(&harness, &mut driver, &[HWND_A]);
+        harness.send_foreground_change(HWND_A);
+        driver.run_until_idle();
+        assert_eq!(factory.spawned_hwnds(), vec![HWND_A]);
+
+        // フレームはキャプチャのスレッドが勝手に流してくるので、届くまで進めながら待つ
+        let frame = loop {
+            thread::sleep(Duration::from_millis(20));
+            driver.run_until_idle();
+            match harness.recv_viewer_command() {
+                Some(ImageViewerCommand::Update(frame)) => break frame,
+                Some(_) => continue,
+                None => panic!("no frame reached the viewer"),
+            }
+        };
+        assert_eq!((frame.width, frame.height), (32, 16));
+        assert_eq!(&frame.bytes[..4], &[255, 0, 0, 255]);
+
+        harness.send_watcher_message(ForegroundWatcherMessage::WindowDestroyed { hwnd: HWND_A });
+        driver.run_until_idle();
+        assert_eq!(driver.capture_count(), 0);
+    }
+
+    struct ChannelCamera(Sender<(HWND, u64)>);
+
+    impl VirtualCameraSink for ChannelCamera {
+        fn write(&mut self, frame: &CapturedFrame) -> Result<(), String> {
+            let _ = self.0.send((frame.hwnd, frame.sequence));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn virtual_camera_receives_frames_of_the_current_window() {
+        let (harness, mut driver) = DriverHarness::new();
+        let (tx_written, rx_written) = unbounded();
+        driver.add_virtual_camera(ChannelCamera(tx_written));
+        allow(&harness, &mut driver, &[HWND_A, HWND_B]);
+        for hwnd in [HWND_A, HWND_B] {
+            harness.send_foreground_change(hwnd);
+            driver.run_until_idle();
+        }
+
+        harness.send_frame(HWND_A, CapturedFrame::checkerboard(HWND_A, 1, 4, 4));
+        harness.send_frame(HWND_B, CapturedFrame::checkerboard(HWND_B, 2, 4, 4));
+        driver.run_until_idle();
+
+        assert_eq!(
+            rx_written.recv_timeout(Duration::from_secs(1)),
+            Ok((HWND_B, 2))
+        );
+        assert!(rx_written.is_empty());
+    }
+
+    #[test]
+    fn frame_rendered_is_matched_by_window_and_sequence() {
+        let (harness, mut driver) = DriverHarness::new();
+        allow(&harness, &mut driver, &[HWND_A, HWND_B]);
+        // どちらのキャプチャも 1 から数えるので、同じ番号のフレームが並ぶ
+        for hwnd in [HWND_A, HWND_B] {
+            harness.send_foreground_change(hwnd);
+            driver.run_until_idle();
+            harness.send_frame(hwnd, CapturedFrame::checkerboard(hwnd, 1, 4, 4));
+            driver.run_until_idle();
+        }
+
+        harness.send_viewer_message(ImageViewerMessage::FrameRendered {
+            hwnd: HWND_B,
+            frame_id: 1,
+            render_time_us: 42,
+        });
+        driver.run_until_idle();
+
+        assert_eq!(driver.caps[&HWND_A.0].stats.last_render_time_us, 0);
+        assert_eq!(driver.caps[&HWND_B.0].stats.last_render_time_us, 42);
+    }
+
+    #[test]
+    fn debounce_switches_only_to_the_last_window_and_restarts_with_a_new_value() {
+        let (harness, mut driver) = DriverHarness::with_config(DriverConfig {
+            debounce_ms: MAX_DEBOUNCE_MS,
+            ..DriverConfig::default()
+        });
+        allow(&harness, &mut driver, &[HWND_A, HWND_B]);
+        harness.send_foreground_change(HWND_A);
+        harness.send_foreground_change(HWND_B);
+        driver.run_until_idle();
+        assert_eq!(driver.current_hwnd, None);
+        assert!(driver.caps.is_empty());
+
+        harness.send_shell_message(StdinShellMessage::SetDebounce(MAX_DEBOUNCE_MS + 1));
+        driver.run_until_idle();
+        assert!(matches!(
+            harness.recv_shell_command(),
+            Some(StdinShellCommand::Error(_))
+        ));
+        assert_eq!(driver.config.debounce_ms, MAX_DEBOUNCE_MS);
+
+        // 待っている B は、前面に来た時刻から新しい間隔で数え直されてすぐに切り替わる
+        harness.send_shell_message(StdinShellMessage::SetDebounce(0));
+        driver.run_until_idle();
+        assert!(matches!(
+            harness.recv_shell_command(),
+            Some(StdinShellCommand::Ok)
+        ));
+        assert_eq!(driver.current_hwnd, Some(HWND_B));
+        assert_eq!(driver.windows(), vec![HWND_B]);
+    }
+
+    #[test]
+    fn test_frames_that_will_not_be_rendered_are_reported() {
+        let (harness, mut driver) = DriverHarness::new();
+        let output = |harness: &DriverHarness| match harness.recv_shell_command() {
+            Some(StdinShellCommand::Output { message }) => message,
+            _ => panic!("expected a shell output"),
+        };
+        let first = TEST_FRAME_SEQUENCE_BASE;
+
+        // ビューアは 1 つ目を飛ばして 2 つ目だけを描画した
+        for _ in 0..2 {
+            harness.send_shell_message(StdinShellMessage::InjectTestFrame(HWND_A));
+        }
+        driver.run_until_idle();
+        harness.send_viewer_message(ImageViewerMessage::FrameRendered {
+            hwnd: HWND_A,
+            frame_id: first + 1,
+            render_time_us: 1,
+        });
+        driver.run_until_idle();
+        assert_eq!(output(&harness), format!("test frame {first} not rendered"));
+        assert!(matches!(
+            harness.recv_shell_command(),
+            Some(StdinShellCommand::TestFrameAck { sequence, .. }) if sequence == first + 1
+        ));
+
+        // 描画される前にビューアが付け替えられた
+        harness.send_shell_message(StdinShellMessage::InjectTestFrame(HWND_A));
+        driver.run_until_idle();
+        let (im_tx_cmd, _im_rx_cmd) = crossbeam_channel::unbounded();
+        driver.attach_image_viewer(im_tx_cmd, never());
+        assert_eq!(
+            output(&harness),
+            format!("test frame {} not rendered", first + 2)
+        );
+
+        // ビューアが外れているので送られもしない
+        driver.detach_image_viewer();
+        harness.send_shell_message(StdinShellMessage::InjectTestFrame(HWND_A));
+        driver.run_until_idle();
+        assert_eq!(
+            output(&harness),
+            format!("test frame {} not rendered", first + 3)
+        );
+        assert!(driver.test_frames.is_empty());
     }
 }
@@ -0,0 +1,293 @@
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use rusqlite::{params, types::ValueRef, Connection, OpenFlags};
+use serde_json::{json, Value};
+
+use crate::driver::DriverEvent;
+
+// 問い合わせの結果をこれ以上の行は表示しない
+const MAX_QUERY_ROWS: usize = 1000;
+
+// 書き込みのスレッドが 1 つのトランザクションにまとめる件数
+const MAX_BATCH_ROWS: usize = 256;
+
+struct EventRow {
+    // UNIX 時間のミリ秒
+    timestamp: i64,
+    event_type: &'static str,
+    hwnd: isize,
+    data: String,
+}
+
+// DriverEvent を 1 件 1 行で SQLite に残しておく。書き込みは別スレッドでまとめてやるので、
+// record は Driver のスレッドで待たない。
+pub struct EventLog {
+    path: PathBuf,
+    tx_row: Option<Sender<EventRow>>,
+    // 書き込みのスレッドが止まった理由
+    writer_error: Arc<Mutex<Option<String>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl EventLog {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+        // WAL なら読み手と書き手が待たせ合わず、NORMAL ならコミットのたびには fsync しない
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .and_then(|_| conn.pragma_update(None, "synchronous", "NORMAL"))
+            .map_err(|e| format!("failed to configure {}: {e}", path.display()))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                event_type TEXT NOT NULL,
+                hwnd INTEGER NOT NULL,
+                json_data TEXT NOT NULL
+            )",
+            [],
+        )
+        .map_err(|e| format!("failed to create the events table: {e}"))?;
+
+        let (tx_row, rx_row) = unbounded();
+        let writer_error: Arc<Mutex<Option<String>>> = Arc::default();
+        let writer = {
+            let writer_error = Arc::clone(&writer_error);
+            thread::spawn(move || {
+                if let Err(e) = write_rows(conn, rx_row) {
+                    *writer_error.lock().unwrap() = Some(e);
+                }
+            })
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            tx_row: Some(tx_row),
+            writer_error,
+            writer: Some(writer),
+        })
+    }
+
+    // 書き込みの失敗は後から届くので、書き込みのスレッドが止まっていたらその理由を返す
+    pub fn record(&self, event: &DriverEvent) -> Result<(), String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        let (event_type, hwnd, data) = describe(event);
+        let row = EventRow {
+            timestamp,
+            event_type,
+            hwnd,
+            data: data.to_string(),
+        };
+
+        let sent = self.tx_row.as_ref().is_some_and(|tx| tx.send(row).is_ok());
+        if !sent {
+            return Err(self
+                .writer_error
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_else(|| "the event log writer has stopped".into()));
+        }
+
+        Ok(())
+    }
+
+    // 読むだけの SELECT を実行して、結果を表にして返す。読み込み専用で開き直した接続で実行し、
+    // SELECT か WITH で始まらない文は ATTACH なども含めて実行する前に断る。
+    pub fn query(&self, sql: &str) -> Result<String, String> {
+        let keyword = sql
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        if keyword != "SELECT" && keyword != "WITH" {
+            return Err("only SELECT queries are allowed".into());
+        }
+
+        let conn = Connection::open_with_flags(
+            &self.path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|e| format!("failed to open {}: {e}", self.path.display()))?;
+        let mut stmt = conn.prepare(sql).map_err(|e| e.to_string())?;
+        if !stmt.readonly() {
+            return Err("only read-only queries are allowed".into());
+        }
+
+        let mut buf = stmt.column_names().join(" | ");
+        let column_count = stmt.column_count();
+        let mut rows = stmt.query([]).map_err(|e| e.to_string())?;
+        let mut count = 0;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            if count == MAX_QUERY_ROWS {
+                writeln!(buf).unwrap();
+                write!(buf, "... (stopped after {MAX_QUERY_ROWS} rows)").unwrap();
+                break;
+            }
+
+            let values = (0..column_count)
+                .map(|i| match row.get_ref(i) {
+                    Ok(ValueRef::Null) => "NULL".to_string(),
+                    Ok(ValueRef::Integer(value)) => value.to_string(),
+                    Ok(ValueRef::Real(value)) => value.to_string(),
+                    Ok(ValueRef::Text(text)) => String::from_utf8_lossy(text).into_owned(),
+                    Ok(ValueRef::Blob(blob)) => format!("<{} bytes>", blob.len()),
+                    Err(e) => format!("<{e}>"),
+                })
+                .collect::<Vec<_>>();
+            writeln!(buf).unwrap();
+            write!(buf, "{}", values.join(" | ")).unwrap();
+            count += 1;
+        }
+
+        Ok(buf)
+    }
+}
+
+// 閉じるときは、送られた分を書き終えるまで待つ
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        self.tx_row = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// 1 件届いたら、その時点で溜まっている分もまとめて 1 つのトランザクションで書く
+fn write_rows(mut conn: Connection, rx_row: Receiver<EventRow>) -> Result<(), String> {
+    while let Ok(first) = rx_row.recv() {
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut insert = tx
+                .prepare_cached(
+                    "INSERT INTO events (timestamp, event_type, hwnd, json_data)
+                     VALUES (?1, ?2, ?3, ?4)",
+                )
+                .map_err(|e| e.to_string())?;
+            for row in [first]
+                .into_iter()
+                .chain(rx_row.try_iter().take(MAX_BATCH_ROWS - 1))
+            {
+                insert
+                    .execute(params![
+                        row.timestamp,
+                        row.event_type,
+                        row.hwnd as i64,
+                        row.data
+                    ])
+                    .map_err(|e| format!("failed to record {}: {e}", row.event_type))?;
+            }
+        }
+        tx.commit()
+            .map_err(|e| format!("failed to commit events: {e}"))?;
+    }
+
+    Ok(())
+}
+
+// (event_type, hwnd, json_data)
+fn describe(event: &DriverEvent) -> (&'static str, isize, Value) {
+    match event {
+        DriverEvent::WindowChanged { hwnd, event } => (
+            "WindowChanged",
+            hwnd.0,
+            json!({ "event": format!("{event:?}") }),
+        ),
+        DriverEvent::CaptureStarted {
+            hwnd,
+            width,
+            height,
+            format,
+        } => (
+            "CaptureStarted",
+            hwnd.0,
+            json!({ "width": width, "height": height, "format": format!("{format:?}") }),
+        ),
+        DriverEvent::CaptureStopped { hwnd } => ("CaptureStopped", hwnd.0, json!({})),
+        DriverEvent::CaptureRestarted { hwnd } => ("CaptureRestarted", hwnd.0, json!({})),
+        DriverEvent::FrameDropped { hwnd, sequence } => {
+            ("FrameDropped", hwnd.0, json!({ "sequence": sequence }))
+        }
+        DriverEvent::Throttled {
+            hwnd,
+            effective_fps,
+        } => (
+            "Throttled",
+            hwnd.0,
+            json!({ "effective_fps": effective_fps }),
+        ),
+        DriverEvent::HealthChanged { hwnd, old, new } => (
+            "HealthChanged",
+            hwnd.0,
+            json!({ "old": format!("{old:?}"), "new": format!("{new:?}") }),
+        ),
+        DriverEvent::SceneSwitch { hwnd, scene_name } => {
+            ("SceneSwitch", hwnd.0, json!({ "scene_name": scene_name }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use windows::Win32::Foundation::HWND;
+
+    use super::*;
+
+    fn log_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("event-log-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("events.db")
+    }
+
+    #[test]
+    fn recorded_events_are_written_by_the_time_the_log_is_closed() {
+        let path = log_path("record");
+        {
+            let log = EventLog::open(&path).unwrap();
+            for hwnd in 1..=3 {
+                log.record(&DriverEvent::CaptureStopped { hwnd: HWND(hwnd) })
+                    .unwrap();
+            }
+        }
+
+        let log = EventLog::open(&path).unwrap();
+        let result = log.query("SELECT event_type, hwnd FROM events ORDER BY id");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        assert_eq!(
+            result.unwrap(),
+            "event_type | hwnd\nCaptureStopped | 1\nCaptureStopped | 2\nCaptureStopped | 3"
+        );
+    }
+
+    #[test]
+    fn queries_that_are_not_selects_are_refused() {
+        let path = log_path("query");
+        let log = EventLog::open(&path).unwrap();
+        let attached = path.with_file_name("attached.db");
+        for sql in [
+            format!("ATTACH DATABASE '{}' AS other", attached.display()),
+            "DELETE FROM events".to_string(),
+            "PRAGMA journal_mode = DELETE".to_string(),
+        ] {
+            assert!(log.query(&sql).is_err(), "{sql}");
+        }
+        assert!(!attached.exists());
+        assert!(log.query("with t as (select 1) select * from t").is_ok());
+        drop(log);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -1,20 +1,92 @@
-use std::{thread, time::Duration};
+use std::{cell::RefCell, collections::BTreeSet, mem, thread, time::Duration};
 
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use windows::Win32::{Foundation::HWND, UI::WindowsAndMessaging::GetForegroundWindow};
+use windows::{
+    core::GUID,
+    Win32::{
+        Foundation::{BOOL, HWND, LPARAM, RECT},
+        Graphics::Gdi::{GetMonitorInfoW, MonitorFromWindow, MONITORINFO, MONITOR_DEFAULTTONULL},
+        System::Com::{CoCreateInstance, CoInitializeEx, CLSCTX_ALL, COINIT_APARTMENTTHREADED},
+        UI::Accessibility::{SetWinEventHook, UnhookWinEvent, HWINEVENTHOOK},
+        UI::Shell::{IVirtualDesktopManager, VirtualDesktopManager},
+        UI::WindowsAndMessaging::{
+            DispatchMessageW, EnumWindows, GetAncestor, GetForegroundWindow, GetShellWindow,
+            GetWindowRect, GetWindowTextLengthW, GetWindowTextW, GetWindowThreadProcessId,
+            IsIconic, IsWindowVisible, PeekMessageW, CHILDID_SELF, EVENT_OBJECT_CREATE,
+            EVENT_OBJECT_DESTROY, EVENT_OBJECT_HIDE, EVENT_OBJECT_SHOW, EVENT_SYSTEM_MOVESIZEEND,
+            GA_ROOT, MSG, OBJID_WINDOW, PM_REMOVE, WINEVENT_OUTOFCONTEXT, WINEVENT_SKIPOWNPROCESS,
+        },
+    },
+};
+
+use crate::snap_layout::SnapLayoutDetector;
 
 pub struct ForegroundWatcher {
     rx_cmd: Receiver<ForegroundWatcherCommand>,
     tx_msg: Sender<ForegroundWatcherMessage>,
     old_hwnd: Option<HWND>,
+    old_minimized: bool,
+    old_fullscreen: bool,
+    old_desktop_id: Option<GUID>,
+    event_hooks: Vec<HWINEVENTHOOK>,
+    creation_hook: Option<HWINEVENTHOOK>,
 }
 
 pub enum ForegroundWatcherCommand {
     Quit,
+    // 前面かどうかに関係なく、これらのウィンドウの表示・非表示・移動を知らせる (空なら止める)
+    WatchSpecific(Vec<HWND>),
+    // トップレベルのウィンドウが作られたり壊されたりしたら知らせる
+    MonitorCreation(bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowEvent {
+    Shown,
+    Hidden,
+    MoveSizeEnd,
 }
 
 pub enum ForegroundWatcherMessage {
-    WindowChanged { hwnd: HWND },
+    WindowChanged {
+        hwnd: HWND,
+    },
+    WindowMinimized {
+        hwnd: HWND,
+    },
+    SnapLayoutDetected {
+        primary: HWND,
+        secondary: Option<HWND>,
+    },
+    DesktopSwitch {
+        new_desktop_id: GUID,
+        foreground_hwnd: Option<HWND>,
+    },
+    WindowEvent {
+        hwnd: HWND,
+        event: WindowEvent,
+    },
+    WindowEnteredFullscreen {
+        hwnd: HWND,
+    },
+    WindowExitedFullscreen {
+        hwnd: HWND,
+    },
+    WindowCreated {
+        hwnd: HWND,
+    },
+    WindowDestroyed {
+        hwnd: HWND,
+    },
+}
+
+// WinEvent のコールバックには引数を渡せないので、フックを張ったスレッドに置いておく。
+// WINEVENT_OUTOFCONTEXT のコールバックはフックを張ったスレッドのメッセージループから呼ばれる。
+thread_local! {
+    static WATCHED_WINDOWS: RefCell<Option<(BTreeSet<isize>, Sender<ForegroundWatcherMessage>)>> =
+        const { RefCell::new(None) };
+    static CREATION_SENDER: RefCell<Option<Sender<ForegroundWatcherMessage>>> =
+        const { RefCell::new(None) };
 }
 
 impl ForegroundWatcher {
@@ -31,29 +103,319 @@ impl ForegroundWatcher {
                 rx_cmd,
                 tx_msg,
                 old_hwnd: None,
+                old_minimized: false,
+                old_fullscreen: false,
+                old_desktop_id: None,
+                event_hooks: vec![],
+                creation_hook: None,
             },
             tx_cmd,
             rx_msg,
         )
     }
 
+    fn check_desktop_switch(&mut self, desktop_manager: &IVirtualDesktopManager, hwnd: HWND) {
+        // 前面のウィンドウが今のデスクトップにあるときだけ、そこからデスクトップの ID がわかる
+        let on_current = unsafe { desktop_manager.IsWindowOnCurrentVirtualDesktop(hwnd) };
+        if !on_current.is_ok_and(|on_current| on_current.as_bool()) {
+            return;
+        }
+        let Ok(desktop_id) = (unsafe { desktop_manager.GetWindowDesktopId(hwnd) }) else {
+            return;
+        };
+
+        if self.old_desktop_id.is_some_and(|old| old != desktop_id) {
+            let _ = self.tx_msg.send(ForegroundWatcherMessage::DesktopSwitch {
+                new_desktop_id: desktop_id,
+                foreground_hwnd: Some(hwnd),
+            });
+        }
+        self.old_desktop_id = Some(desktop_id);
+    }
+
+    fn watch_specific(&mut self, hwnds: Vec<HWND>) {
+        self.unhook_all();
+        if hwnds.is_empty() {
+            WATCHED_WINDOWS.with(|watched| *watched.borrow_mut() = None);
+            return;
+        }
+
+        // フックはプロセスとスレッド単位でしか張れないので、同じスレッドのウィンドウはまとめる
+        let mut threads = BTreeSet::new();
+        for &hwnd in &hwnds {
+            let mut process_id = 0;
+            let thread_id = unsafe { GetWindowThreadProcessId(hwnd, Some(&mut process_id)) };
+            if thread_id != 0 {
+                threads.insert((process_id, thread_id));
+            }
+        }
+
+        for (process_id, thread_id) in threads {
+            for (event_min, event_max) in [
+                (EVENT_OBJECT_SHOW, EVENT_OBJECT_HIDE),
+                (EVENT_SYSTEM_MOVESIZEEND, EVENT_SYSTEM_MOVESIZEEND),
+            ] {
+                let hook = unsafe {
+                    SetWinEventHook(
+                        event_min,
+                        event_max,
+                        None,
+                        Some(win_event_proc),
+                        process_id,
+                        thread_id,
+                        WINEVENT_OUTOFCONTEXT | WINEVENT_SKIPOWNPROCESS,
+                    )
+                };
+                if !hook.is_invalid() {
+                    self.event_hooks.push(hook);
+                }
+            }
+        }
+
+        let hwnds = hwnds.iter().map(|hwnd| hwnd.0).collect();
+        let tx_msg = self.tx_msg.clone();
+        WATCHED_WINDOWS.with(|watched| *watched.borrow_mut() = Some((hwnds, tx_msg)));
+    }
+
+    fn monitor_creation(&mut self, enabled: bool) {
+        if let Some(hook) = self.creation_hook.take() {
+            unsafe { UnhookWinEvent(hook) };
+        }
+        if !enabled {
+            CREATION_SENDER.with(|sender| *sender.borrow_mut() = None);
+            return;
+        }
+
+        // 作られるウィンドウのプロセスはわからないので、全プロセスにフックを張る
+        let hook = unsafe {
+            SetWinEventHook(
+                EVENT_OBJECT_CREATE,
+                EVENT_OBJECT_DESTROY,
+                None,
+                Some(creation_event_proc),
+                0,
+                0,
+                WINEVENT_OUTOFCONTEXT | WINEVENT_SKIPOWNPROCESS,
+            )
+        };
+        if !hook.is_invalid() {
+            self.creation_hook = Some(hook);
+            let tx_msg = self.tx_msg.clone();
+            CREATION_SENDER.with(|sender| *sender.borrow_mut() = Some(tx_msg));
+        }
+    }
+
+    fn unhook_all(&mut self) {
+        for hook in self.event_hooks.drain(..) {
+            unsafe { UnhookWinEvent(hook) };
+        }
+    }
+
+    pub fn enumerate_windows() -> Vec<(HWND, String)> {
+        let mut windows: Vec<(HWND, String)> = Vec::new();
+        unsafe {
+            let _ = EnumWindows(
+                Some(enum_windows_proc),
+                LPARAM(&mut windows as *mut Vec<(HWND, String)> as isize),
+            );
+        }
+
+        windows
+    }
+
     pub fn run(mut self) {
+        // 仮想デスクトップを切り替えても前面のウィンドウが変わらないことがあるので別に見張る
+        let desktop_manager: Option<IVirtualDesktopManager> = unsafe {
+            CoInitializeEx(None, COINIT_APARTMENTTHREADED)
+                .and_then(|_| CoCreateInstance(&VirtualDesktopManager, None, CLSCTX_ALL))
+                .ok()
+        };
+
         loop {
             if let Ok(msg) = self.rx_cmd.try_recv() {
                 match msg {
                     ForegroundWatcherCommand::Quit => break,
+                    ForegroundWatcherCommand::WatchSpecific(hwnds) => self.watch_specific(hwnds),
+                    ForegroundWatcherCommand::MonitorCreation(enabled) => {
+                        self.monitor_creation(enabled)
+                    }
                 }
             }
 
+            // WinEvent のコールバックはメッセージを取り出すときに呼ばれる
+            let mut msg = MSG::default();
+            while unsafe { PeekMessageW(&mut msg, None, 0, 0, PM_REMOVE) }.as_bool() {
+                unsafe { DispatchMessageW(&msg) };
+            }
+
             let hwnd = unsafe { GetForegroundWindow() };
-            if Some(hwnd) != self.old_hwnd {
+            let minimized = unsafe { IsIconic(hwnd) }.as_bool();
+            let previous = self.old_hwnd;
+            let changed = Some(hwnd) != previous;
+            if changed {
+                // 全画面のまま前面でなくなったウィンドウは、全画面から出たものとして扱う
+                if let Some(previous) = previous.filter(|_| self.old_fullscreen) {
+                    let _ = self
+                        .tx_msg
+                        .send(ForegroundWatcherMessage::WindowExitedFullscreen { hwnd: previous });
+                }
                 self.old_hwnd = Some(hwnd);
                 let _ = self
                     .tx_msg
                     .send(ForegroundWatcherMessage::WindowChanged { hwnd });
+
+                // スナップレイアウトで並べられたウィンドウは前面にならなくても見えている
+                if let Some(layout) = SnapLayoutDetector::detect(hwnd) {
+                    let _ = self
+                        .tx_msg
+                        .send(ForegroundWatcherMessage::SnapLayoutDetected {
+                            primary: layout.primary,
+                            secondary: layout.secondary,
+                        });
+                }
+            } else if minimized && !self.old_minimized {
+                let _ = self
+                    .tx_msg
+                    .send(ForegroundWatcherMessage::WindowMinimized { hwnd });
+            } else if !minimized && self.old_minimized {
+                // 最小化されたウィンドウが前面のまま元に戻された
+                let _ = self
+                    .tx_msg
+                    .send(ForegroundWatcherMessage::WindowChanged { hwnd });
+            }
+            self.old_minimized = minimized;
+
+            // 前面に来たウィンドウが最初から全画面なら、それも入ったとして知らせる
+            let fullscreen = is_fullscreen(hwnd);
+            if fullscreen && (changed || !self.old_fullscreen) {
+                let _ = self
+                    .tx_msg
+                    .send(ForegroundWatcherMessage::WindowEnteredFullscreen { hwnd });
+            } else if !fullscreen && !changed && self.old_fullscreen {
+                let _ = self
+                    .tx_msg
+                    .send(ForegroundWatcherMessage::WindowExitedFullscreen { hwnd });
+            }
+            self.old_fullscreen = fullscreen;
+
+            if let Some(desktop_manager) = &desktop_manager {
+                self.check_desktop_switch(desktop_manager, hwnd);
             }
 
             thread::sleep(Duration::from_millis(100));
         }
+
+        self.unhook_all();
+        self.monitor_creation(false);
+    }
+}
+
+// ウィンドウがモニタ全体をぴったり覆っていれば全画面とみなす。最大化しただけのウィンドウは
+// 枠の分だけモニタからはみ出すか、タスクバーの分だけ小さいので当てはまらない。
+// GetWindowPlacement はワークエリア基準の座標を返すので、スクリーン座標の GetWindowRect で比べる。
+fn is_fullscreen(hwnd: HWND) -> bool {
+    // デスクトップもモニタ全体を覆っているが、全画面のアプリではない
+    if hwnd == unsafe { GetShellWindow() } {
+        return false;
+    }
+
+    let monitor = unsafe { MonitorFromWindow(hwnd, MONITOR_DEFAULTTONULL) };
+    if monitor.is_invalid() {
+        return false;
+    }
+
+    let mut info = MONITORINFO {
+        cbSize: mem::size_of::<MONITORINFO>() as u32,
+        ..Default::default()
+    };
+    let mut rect = RECT::default();
+    unsafe {
+        GetMonitorInfoW(monitor, &mut info).as_bool()
+            && GetWindowRect(hwnd, &mut rect).is_ok()
+            && rect == info.rcMonitor
+    }
+}
+
+unsafe extern "system" fn win_event_proc(
+    _hook: HWINEVENTHOOK,
+    event: u32,
+    hwnd: HWND,
+    id_object: i32,
+    id_child: i32,
+    _event_thread: u32,
+    _event_time: u32,
+) {
+    // ウィンドウの中の部品 (キャレットやメニューなど) のイベントも来るので、ウィンドウ自身のものだけ見る
+    if id_object != OBJID_WINDOW.0 || id_child != CHILDID_SELF as i32 {
+        return;
+    }
+
+    let event = match event {
+        EVENT_OBJECT_SHOW => WindowEvent::Shown,
+        EVENT_OBJECT_HIDE => WindowEvent::Hidden,
+        EVENT_SYSTEM_MOVESIZEEND => WindowEvent::MoveSizeEnd,
+        _ => return,
+    };
+
+    WATCHED_WINDOWS.with(|watched| {
+        if let Some((hwnds, tx_msg)) = &*watched.borrow() {
+            if hwnds.contains(&hwnd.0) {
+                let _ = tx_msg.send(ForegroundWatcherMessage::WindowEvent { hwnd, event });
+            }
+        }
+    });
+}
+
+unsafe extern "system" fn creation_event_proc(
+    _hook: HWINEVENTHOOK,
+    event: u32,
+    hwnd: HWND,
+    id_object: i32,
+    id_child: i32,
+    _event_thread: u32,
+    _event_time: u32,
+) {
+    if id_object != OBJID_WINDOW.0 || id_child != CHILDID_SELF as i32 {
+        return;
     }
+
+    let msg = match event {
+        // 子ウィンドウも作られるたびに来るので、トップレベルのものだけにする
+        EVENT_OBJECT_CREATE if GetAncestor(hwnd, GA_ROOT) == hwnd => {
+            ForegroundWatcherMessage::WindowCreated { hwnd }
+        }
+        // 壊れたあとでは親を調べられないので、そのまま知らせる
+        EVENT_OBJECT_DESTROY => ForegroundWatcherMessage::WindowDestroyed { hwnd },
+        _ => return,
+    };
+
+    CREATION_SENDER.with(|sender| {
+        if let Some(tx_msg) = &*sender.borrow() {
+            let _ = tx_msg.send(msg);
+        }
+    });
+}
+
+unsafe extern "system" fn enum_windows_proc(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    let windows = &mut *(lparam.0 as *mut Vec<(HWND, String)>);
+    if IsWindowVisible(hwnd).as_bool() {
+        let title = window_title(hwnd);
+        if !title.is_empty() {
+            windows.push((hwnd, title));
+        }
+    }
+
+    true.into()
+}
+
+// タイトルのないウィンドウは空文字列になる
+pub fn window_title(hwnd: HWND) -> String {
+    let len = unsafe { GetWindowTextLengthW(hwnd) };
+    if len <= 0 {
+        return String::new();
+    }
+
+    let mut buf = vec![0u16; len as usize + 1];
+    let copied = unsafe { GetWindowTextW(hwnd, &mut buf) };
+    String::from_utf16_lossy(&buf[..copied.max(0) as usize])
 }
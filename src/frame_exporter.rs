@@ -0,0 +1,303 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+
+use crate::{driver::PluginEvent, frame_plugin::FramePlugin, window_capture::CapturedFrame};
+
+// 書き込みが追いつかない間に溜めておけるフレームの数。溢れた分は捨てる
+const EXPORT_QUEUE_LEN: usize = 10;
+#[cfg(feature = "jpeg")]
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Png,
+    // 変換せずに画素のバイト列をそのまま書く。形式や大きさは残らない
+    Raw,
+    #[cfg(feature = "jpeg")]
+    Jpeg(u8),
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Raw => "raw",
+            #[cfg(feature = "jpeg")]
+            ExportFormat::Jpeg(_) => "jpg",
+        }
+    }
+}
+
+struct ExportJob {
+    frame: CapturedFrame,
+    dir: PathBuf,
+    max_files: usize,
+    format: ExportFormat,
+}
+
+// フレームを <dir>/<hwnd>/<hwnd>-<書き出しを始めた時刻 (ms)>-<何枚目か>.<拡張子> に書き出す。
+// キャプチャを始め直すと sequence は 0 に戻るので名前には使わず、始め直しても起動し直しても
+// 前のファイルを上書きしないようにする。
+// ウィンドウごとに max_files を超えたら古いものから消す。ファイルへの書き込みはフレームの処理を
+// 止めないよう別スレッドで行う。
+pub struct FrameExporter {
+    dir: PathBuf,
+    max_files: usize,
+    format: ExportFormat,
+    tx_event: Sender<PluginEvent>,
+    tx_job: Option<Sender<ExportJob>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FrameExporter {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        max_files: usize,
+        format: ExportFormat,
+        tx_event: Sender<PluginEvent>,
+    ) -> Self {
+        let (tx_job, rx_job) = bounded(EXPORT_QUEUE_LEN);
+        let thread = {
+            let tx_event = tx_event.clone();
+            thread::spawn(move || write_frames(rx_job, tx_event))
+        };
+
+        Self {
+            dir: dir.into(),
+            max_files,
+            format,
+            tx_event,
+            tx_job: Some(tx_job),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl FramePlugin for FrameExporter {
+    fn name(&self) -> &str {
+        "export"
+    }
+
+    fn process(&mut self, frame: &mut CapturedFrame) {
+        let Some(tx_job) = &self.tx_job else {
+            return;
+        };
+        // 書き込みが追いついていないときは、コピーを作る前に諦める
+        if tx_job.is_full() {
+            return;
+        }
+
+        let job = ExportJob {
+            frame: frame.clone(),
+            dir: self.dir.clone(),
+            max_files: self.max_files,
+            format: self.format,
+        };
+        if let Err(TrySendError::Disconnected(_)) = tx_job.try_send(job) {
+            report(&self.tx_event, "export: writer thread has stopped".into());
+            self.tx_job = None;
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "dir" => self.dir = value.into(),
+            "max_files" => {
+                let max_files: usize = value
+                    .parse()
+                    .map_err(|_| format!("invalid max_files: {value}"))?;
+                if max_files == 0 {
+                    return Err("max_files must be at least 1".into());
+                }
+                self.max_files = max_files;
+            }
+            "format" => {
+                self.format = match value {
+                    "png" => ExportFormat::Png,
+                    "raw" => ExportFormat::Raw,
+                    #[cfg(feature = "jpeg")]
+                    "jpeg" => ExportFormat::Jpeg(DEFAULT_JPEG_QUALITY),
+                    _ => return Err(format!("unknown format: {value}")),
+                };
+            }
+            #[cfg(feature = "jpeg")]
+            "quality" => {
+                let ExportFormat::Jpeg(quality) = &mut self.format else {
+                    return Err("quality is only used with format=jpeg".into());
+                };
+                *quality = value
+                    .parse()
+                    .ok()
+                    .filter(|quality| (1..=100).contains(quality))
+                    .ok_or_else(|| format!("invalid quality: {value}"))?;
+            }
+            _ => return Err(format!("unknown parameter: {name}")),
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for FrameExporter {
+    fn drop(&mut self) {
+        // 溜まっている分を書き終えてから終わる
+        self.tx_job = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn report(tx_event: &Sender<PluginEvent>, message: String) {
+    let _ = tx_event.send(PluginEvent::Output { message });
+}
+
+fn write_frames(rx_job: Receiver<ExportJob>, tx_event: Sender<PluginEvent>) {
+    // ウィンドウのディレクトリごとの、書いた順に並べたファイル
+    let mut written: BTreeMap<PathBuf, VecDeque<PathBuf>> = BTreeMap::new();
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let mut index = 0u64;
+
+    for job in rx_job {
+        let hwnd_id = job.frame.hwnd.0;
+        let dir = job.dir.join(hwnd_id.to_string());
+        let files = match written.get_mut(&dir) {
+            Some(files) => files,
+            None => {
+                if let Err(e) = fs::create_dir_all(&dir) {
+                    report(
+                        &tx_event,
+                        format!("export: failed to create {}: {e}", dir.display()),
+                    );
+                    continue;
+                }
+                written.entry(dir.clone()).or_insert(existing_files(&dir))
+            }
+        };
+
+        index += 1;
+        let path = dir.join(format!(
+            "{hwnd_id}-{started_at}-{index}.{}",
+            job.format.extension()
+        ));
+        if let Err(e) = write_frame(&path, &job.frame, job.format) {
+            report(
+                &tx_event,
+                format!("export: failed to write {}: {e}", path.display()),
+            );
+            continue;
+        }
+        files.retain(|file| *file != path);
+        files.push_back(path);
+
+        while files.len() > job.max_files {
+            let Some(oldest) = files.pop_front() else {
+                break;
+            };
+            if let Err(e) = fs::remove_file(&oldest) {
+                report(
+                    &tx_event,
+                    format!("export: failed to remove {}: {e}", oldest.display()),
+                );
+            }
+        }
+    }
+}
+
+fn write_frame(path: &Path, frame: &CapturedFrame, format: ExportFormat) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        ExportFormat::Png => frame
+            .serialize_png_to_writer(&mut writer)
+            .map_err(|e| e.to_string())?,
+        ExportFormat::Raw => writer.write_all(&frame.bytes).map_err(|e| e.to_string())?,
+        #[cfg(feature = "jpeg")]
+        ExportFormat::Jpeg(quality) => {
+            let jpeg = frame.encode_jpeg(quality).map_err(|e| e.to_string())?;
+            writer.write_all(&jpeg).map_err(|e| e.to_string())?
+        }
+    }
+
+    writer.flush().map_err(|e| e.to_string())
+}
+
+// 前回書き出したファイルもローテーションの対象にするため、書いた順に拾っておく
+fn existing_files(dir: &Path) -> VecDeque<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return VecDeque::new();
+    };
+
+    let mut files: Vec<((u128, u64), PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            let order = file_order(path.file_stem()?.to_str()?)?;
+            Some((order, path))
+        })
+        .collect();
+    files.sort();
+
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+// ファイル名から (書き出しを始めた時刻, 何枚目か) を読む。時刻のない前の形式 (<sequence>) の
+// ものはいちばん古いものとして扱う
+fn file_order(stem: &str) -> Option<(u128, u64)> {
+    let mut parts = stem.split('-');
+    match (parts.next()?, parts.next(), parts.next(), parts.next()) {
+        (sequence, None, None, None) => Some((0, sequence.parse().ok()?)),
+        (hwnd_id, Some(started_at), Some(index), None) => {
+            hwnd_id.parse::<isize>().ok()?;
+            Some((started_at.parse().ok()?, index.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use crossbeam_channel::unbounded;
+    use windows::Win32::Foundation::HWND;
+
+    use super::*;
+
+    #[test]
+    fn file_order_reads_old_and_new_names() {
+        assert_eq!(file_order("42"), Some((0, 42)));
+        assert_eq!(file_order("4096-1700000000123-7"), Some((1700000000123, 7)));
+        assert_eq!(file_order("thumbnail"), None);
+        assert_eq!(file_order("4096-1700000000123"), None);
+    }
+
+    #[test]
+    fn frames_from_a_restarted_capture_do_not_overwrite_earlier_ones() {
+        let dir = env::temp_dir().join(format!("frame-exporter-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (tx_event, rx_event) = unbounded();
+        {
+            let mut exporter = FrameExporter::new(&dir, 10, ExportFormat::Raw, tx_event);
+            // 始め直したキャプチャは同じ sequence から数え直す
+            for _ in 0..2 {
+                exporter.process(&mut CapturedFrame::checkerboard(HWND(7), 1, 2, 2));
+            }
+        }
+
+        let files = existing_files(&dir.join("7"));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(files.len(), 2);
+        assert!(rx_event.try_recv().is_err());
+    }
+}
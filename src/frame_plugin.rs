@@ -0,0 +1,9 @@
+use crate::window_capture::CapturedFrame;
+
+pub trait FramePlugin {
+    fn name(&self) -> &str;
+
+    fn process(&mut self, frame: &mut CapturedFrame);
+
+    fn set_param(&mut self, name: &str, value: &str) -> Result<(), String>;
+}
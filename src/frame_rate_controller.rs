@@ -0,0 +1,72 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::window_capture::CaptureInterval;
+
+// 直近何フレーム分の間隔から実際のフレームレートを見積もるか
+const WINDOW: usize = 30;
+
+const KP: f64 = 0.5;
+const KI: f64 = 0.05;
+// 長く目標に届かない状態が続いても補正が暴れないように積分項を抑える
+const MAX_INTEGRAL: f64 = 1.0;
+
+// フレームは届いたときにしか送れないので、単純に「前回 + 1/fps」を待つと届く間隔の分だけ
+// 遅れて目標より低いフレームレートになる。実際に送れた間隔と目標との差を見て待ち時間を補正する。
+pub struct FrameRateController {
+    interval: Duration,
+    deliveries: VecDeque<Instant>,
+    integral: f64,
+    next_deadline: Instant,
+}
+
+impl FrameRateController {
+    // OnDemand は間隔を持たないので、いつでも送れる扱いにする
+    pub fn new(capture_interval: CaptureInterval) -> Self {
+        let interval = match capture_interval {
+            CaptureInterval::Fps(fps) => Duration::from_secs_f64(1.0 / fps.max(1) as f64),
+            CaptureInterval::EveryMs(ms) => Duration::from_millis(ms.max(1)),
+            CaptureInterval::OnDemand => Duration::ZERO,
+        };
+
+        Self {
+            interval,
+            deliveries: VecDeque::with_capacity(WINDOW + 1),
+            integral: 0.0,
+            next_deadline: Instant::now(),
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_deadline
+    }
+
+    pub fn on_delivered(&mut self, now: Instant) {
+        if self.interval.is_zero() {
+            self.next_deadline = now;
+            return;
+        }
+
+        self.deliveries.push_back(now);
+        if self.deliveries.len() > WINDOW {
+            self.deliveries.pop_front();
+        }
+
+        let target = self.interval.as_secs_f64();
+        let mut wait = target;
+        if let (Some(first), Some(last)) = (self.deliveries.front(), self.deliveries.back()) {
+            let count = self.deliveries.len();
+            if count >= 2 {
+                let measured = (*last - *first).as_secs_f64() / (count - 1) as f64;
+                // 正なら目標より速く、負なら遅く送れている
+                let error = (target - measured) / target;
+                self.integral = (self.integral + error).clamp(-MAX_INTEGRAL, MAX_INTEGRAL);
+                wait = target * (1.0 + KP * error + KI * self.integral);
+            }
+        }
+
+        self.next_deadline = now + Duration::from_secs_f64(wait.clamp(0.0, 2.0 * target));
+    }
+}
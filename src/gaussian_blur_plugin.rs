@@ -0,0 +1,243 @@
+use crate::{frame_plugin::FramePlugin, window_capture::CapturedFrame};
+
+// カーネルの重みは合計が 1 << KERNEL_SHIFT になる固定小数点で持つ
+const KERNEL_SHIFT: u32 = 16;
+
+// 半径は sigma の 3 倍になる。大きすぎると 1 フレームのぼかしに Driver が止まってしまう
+const MAX_SIGMA: f32 = 64.0;
+
+pub struct GaussianBlurPlugin {
+    sigma: f32,
+    enabled: bool,
+    kernel: Vec<u32>,
+    scratch: Vec<u8>,
+}
+
+impl GaussianBlurPlugin {
+    // 範囲外の sigma は、set_param で受け付ける範囲に丸める
+    pub fn new(sigma: f32, enabled: bool) -> Self {
+        let sigma = if sigma.is_nan() {
+            1.0
+        } else {
+            sigma.clamp(f32::MIN_POSITIVE, MAX_SIGMA)
+        };
+        Self {
+            sigma,
+            enabled,
+            kernel: make_kernel(sigma),
+            scratch: vec![],
+        }
+    }
+}
+
+impl FramePlugin for GaussianBlurPlugin {
+    fn name(&self) -> &str {
+        "blur"
+    }
+
+    fn process(&mut self, frame: &mut CapturedFrame) {
+        if !self.enabled {
+            return;
+        }
+
+        let width = frame.width as usize;
+        let height = frame.height as usize;
+        let channels = frame.format.bytes_per_pixel();
+        // 行の長さが 0 だと行に分けられない
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        // 横方向と縦方向に分けてかける
+        self.scratch.resize(frame.bytes.len(), 0);
+        blur_horizontal(
+            &frame.bytes,
+            &mut self.scratch,
+            width,
+            channels,
+            &self.kernel,
+        );
+        blur_vertical(
+            &self.scratch,
+            &mut frame.bytes,
+            width,
+            height,
+            channels,
+            &self.kernel,
+        );
+    }
+
+    fn set_param(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "sigma" => {
+                let sigma: f32 = value
+                    .parse()
+                    .map_err(|_| format!("invalid sigma: {value}"))?;
+                if sigma.is_nan() || sigma <= 0.0 || sigma > MAX_SIGMA {
+                    return Err(format!("sigma must be in (0, {MAX_SIGMA}]: {value}"));
+                }
+                self.sigma = sigma;
+                self.kernel = make_kernel(sigma);
+            }
+            "enabled" => {
+                self.enabled = value
+                    .parse()
+                    .map_err(|_| format!("invalid enabled: {value}"))?;
+            }
+            _ => return Err(format!("unknown parameter: {name}")),
+        }
+
+        Ok(())
+    }
+}
+
+fn make_kernel(sigma: f32) -> Vec<u32> {
+    let radius = (sigma * 3.0).ceil().max(1.0) as usize;
+    let weights: Vec<f32> = (0..=2 * radius)
+        .map(|i| {
+            let x = i as f32 - radius as f32;
+            (-x * x / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let sum: f32 = weights.iter().sum();
+
+    let one = 1u32 << KERNEL_SHIFT;
+    let mut kernel: Vec<u32> = weights
+        .iter()
+        .map(|weight| (weight / sum * one as f32).round() as u32)
+        .collect();
+
+    // 丸め誤差で合計がずれると明るさが変わってしまうので、中央の重みで吸収する
+    let total: u32 = kernel.iter().sum();
+    kernel[radius] = (kernel[radius] + one).saturating_sub(total);
+
+    kernel
+}
+
+fn blur_horizontal(src: &[u8], dst: &mut [u8], width: usize, channels: usize, kernel: &[u32]) {
+    let radius = kernel.len() / 2;
+    let stride = width * channels;
+    for (src_row, dst_row) in src.chunks_exact(stride).zip(dst.chunks_exact_mut(stride)) {
+        for x in 0..width {
+            for c in 0..channels {
+                let mut acc = 0u32;
+                for (k, &weight) in kernel.iter().enumerate() {
+                    let sx = (x + k).saturating_sub(radius).min(width - 1);
+                    acc += src_row[sx * channels + c] as u32 * weight;
+                }
+                dst_row[x * channels + c] = (acc >> KERNEL_SHIFT) as u8;
+            }
+        }
+    }
+}
+
+fn blur_vertical(
+    src: &[u8],
+    dst: &mut [u8],
+    width: usize,
+    height: usize,
+    channels: usize,
+    kernel: &[u32],
+) {
+    let radius = kernel.len() / 2;
+    let stride = width * channels;
+    for y in 0..height {
+        for i in 0..stride {
+            let mut acc = 0u32;
+            for (k, &weight) in kernel.iter().enumerate() {
+                let sy = (y + k).saturating_sub(radius).min(height - 1);
+                acc += src[sy * stride + i] as u32 * weight;
+            }
+            dst[y * stride + i] = (acc >> KERNEL_SHIFT) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::Foundation::HWND;
+
+    use super::*;
+
+    // 分けずに 2 次元のまま浮動小数点でぼかした参照画像。端の外は端の画素が続くものとする
+    fn reference_blur(frame: &CapturedFrame, sigma: f32) -> Vec<u8> {
+        let width = frame.width as i64;
+        let height = frame.height as i64;
+        let channels = frame.format.bytes_per_pixel();
+        let radius = (sigma * 3.0).ceil().max(1.0) as i64;
+        let weight = |d: i64| (-(d * d) as f32 / (2.0 * sigma * sigma)).exp();
+        let sum: f32 = (-radius..=radius).map(weight).sum();
+
+        let mut out = vec![0; frame.bytes.len()];
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..channels {
+                    let mut acc = 0.0;
+                    for dy in -radius..=radius {
+                        for dx in -radius..=radius {
+                            let sx = (x + dx).clamp(0, width - 1);
+                            let sy = (y + dy).clamp(0, height - 1);
+                            let value = frame.bytes[(sy * width + sx) as usize * channels + c];
+                            acc += value as f32 * weight(dx) * weight(dy);
+                        }
+                    }
+                    out[(y * width + x) as usize * channels + c] =
+                        (acc / (sum * sum)).round() as u8;
+                }
+            }
+        }
+
+        out
+    }
+
+    #[test]
+    fn blur_matches_reference_image() {
+        let mut frame = CapturedFrame::checkerboard(HWND(0), 1, 24, 16);
+        let reference = reference_blur(&frame, 1.5);
+        GaussianBlurPlugin::new(1.5, true).process(&mut frame);
+
+        // 固定小数点の切り捨てが 2 回重なるので、2 段階までのずれは許す
+        for (i, (&actual, &expected)) in frame.bytes.iter().zip(&reference).enumerate() {
+            assert!(
+                actual.abs_diff(expected) <= 2,
+                "byte {i}: got {actual}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn blur_keeps_a_uniform_image_unchanged() {
+        let mut frame = CapturedFrame::checkerboard(HWND(0), 1, 8, 8);
+        frame.bytes.fill(200);
+        let original = frame.bytes.clone();
+        GaussianBlurPlugin::new(2.0, true).process(&mut frame);
+        assert_eq!(frame.bytes, original);
+    }
+
+    #[test]
+    fn disabled_blur_leaves_the_frame_alone() {
+        let mut frame = CapturedFrame::checkerboard(HWND(0), 1, 24, 16);
+        let original = frame.bytes.clone();
+        GaussianBlurPlugin::new(1.5, false).process(&mut frame);
+        assert_eq!(frame.bytes, original);
+    }
+
+    #[test]
+    fn blur_ignores_empty_frames() {
+        let mut frame = CapturedFrame::checkerboard(HWND(0), 1, 0, 0);
+        GaussianBlurPlugin::new(1.5, true).process(&mut frame);
+        assert!(frame.bytes.is_empty());
+    }
+
+    #[test]
+    fn sigma_is_kept_within_bounds() {
+        let mut blur = GaussianBlurPlugin::new(f32::INFINITY, true);
+        assert_eq!(blur.sigma, MAX_SIGMA);
+        for value in ["inf", "1e30", "0", "-1", "NaN"] {
+            assert!(blur.set_param("sigma", value).is_err(), "{value}");
+        }
+        assert_eq!(blur.sigma, MAX_SIGMA);
+        blur.set_param("sigma", "64").unwrap();
+        assert_eq!(blur.kernel.len(), 2 * 192 + 1);
+    }
+}
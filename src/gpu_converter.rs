@@ -0,0 +1,572 @@
+use std::{ffi::CString, mem, slice};
+
+use windows::{
+    core::{s, PCSTR},
+    Win32::{
+        Foundation::HMODULE,
+        Graphics::{
+            Direct3D::{
+                Fxc::{D3DCompile, D3DCOMPILE_OPTIMIZATION_LEVEL3},
+                ID3DBlob, D3D_DRIVER_TYPE_HARDWARE, D3D_FEATURE_LEVEL_11_0,
+            },
+            Direct3D11::{
+                D3D11CreateDevice, ID3D11Buffer, ID3D11ComputeShader, ID3D11Device,
+                ID3D11DeviceContext, ID3D11ShaderResourceView, ID3D11Texture2D,
+                ID3D11UnorderedAccessView, D3D11_BIND_CONSTANT_BUFFER, D3D11_BIND_SHADER_RESOURCE,
+                D3D11_BIND_UNORDERED_ACCESS, D3D11_BUFFER_DESC, D3D11_BUFFER_UAV,
+                D3D11_BUFFER_UAV_FLAG_RAW, D3D11_CPU_ACCESS_READ, D3D11_CREATE_DEVICE_FLAG,
+                D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ,
+                D3D11_RESOURCE_MISC_BUFFER_ALLOW_RAW_VIEWS, D3D11_SDK_VERSION,
+                D3D11_SUBRESOURCE_DATA, D3D11_TEXTURE2D_DESC, D3D11_UAV_DIMENSION_BUFFER,
+                D3D11_UNORDERED_ACCESS_VIEW_DESC, D3D11_UNORDERED_ACCESS_VIEW_DESC_0,
+                D3D11_USAGE_DEFAULT, D3D11_USAGE_IMMUTABLE, D3D11_USAGE_STAGING,
+            },
+            Dxgi::Common::{
+                DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R32_TYPELESS, DXGI_SAMPLE_DESC,
+            },
+        },
+    },
+};
+
+use crate::pixel_format::{extend_converted, luma, PixelFormat};
+
+// to_yuv420 は 1 スレッドで 8x2 ピクセルを受け持ち、4 バイト単位でしか書けないバッファに
+// Y を 8 バイト、U と V を 4 バイトずつ書く。CPU 版と同じ整数の式で計算するので結果も同じになる。
+const SHADER: &str = r#"
+Texture2D<float4> src : register(t0);
+RWByteAddressBuffer dst : register(u0);
+cbuffer Size : register(b0) {
+    uint width;
+    uint height;
+};
+
+uint3 rgb_at(uint x, uint y) {
+    return (uint3)round(saturate(src[uint2(x, y)].rgb) * 255.0);
+}
+
+uint luma(uint3 c) {
+    return (77 * c.r + 150 * c.g + 29 * c.b) >> 8;
+}
+
+[numthreads(16, 16, 1)]
+void to_rgba(uint3 id : SV_DispatchThreadID) {
+    if (id.x >= width || id.y >= height) {
+        return;
+    }
+    // B8G8R8A8_UNORM として読んでいるので、.rgba はもう R, G, B, A の順になっている
+    uint4 c = (uint4)round(saturate(src[id.xy]) * 255.0);
+    dst.Store((id.y * width + id.x) * 4, c.r | (c.g << 8) | (c.b << 16) | (c.a << 24));
+}
+
+[numthreads(8, 8, 1)]
+void to_yuv420(uint3 id : SV_DispatchThreadID) {
+    uint x = id.x * 8;
+    uint y = id.y * 2;
+    if (x >= width || y >= height) {
+        return;
+    }
+
+    uint y_words[4] = { 0, 0, 0, 0 };
+    uint u_packed = 0;
+    uint v_packed = 0;
+    [unroll] for (uint j = 0; j < 4; j++) {
+        int u = 0;
+        int v = 0;
+        [unroll] for (uint k = 0; k < 4; k++) {
+            uint px = 2 * j + (k & 1);
+            uint dy = k >> 1;
+            uint3 c = rgb_at(x + px, y + dy);
+            y_words[dy * 2 + px / 4] |= luma(c) << (8 * (px % 4));
+            int3 s = (int3)c;
+            u += clamp(((-43 * s.r - 85 * s.g + 128 * s.b) >> 8) + 128, 0, 255);
+            v += clamp(((128 * s.r - 107 * s.g - 21 * s.b) >> 8) + 128, 0, 255);
+        }
+        u_packed |= (uint)(u / 4) << (8 * j);
+        v_packed |= (uint)(v / 4) << (8 * j);
+    }
+
+    dst.Store2(y * width + x, uint2(y_words[0], y_words[1]));
+    dst.Store2((y + 1) * width + x, uint2(y_words[2], y_words[3]));
+    uint chroma = (y / 2) * (width / 2) + x / 2;
+    dst.Store(width * height + chroma, u_packed);
+    dst.Store(width * height * 5 / 4 + chroma, v_packed);
+}
+"#;
+
+// BGRA のバッファを何に変換するか。Yuv420 は Y の面のあとに、縦横半分の U と V の面が続く (I420)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConversionTarget {
+    Rgba,
+    Yuv420,
+}
+
+pub fn output_len(target: ConversionTarget, width: u32, height: u32) -> usize {
+    let (width, height) = (width as usize, height as usize);
+    match target {
+        ConversionTarget::Rgba => width * height * 4,
+        ConversionTarget::Yuv420 => width * height + 2 * width.div_ceil(2) * height.div_ceil(2),
+    }
+}
+
+// フルレンジの BT.601。Yuv444 への変換と同じ式
+fn chroma(r: u8, g: u8, b: u8) -> (i32, i32) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    (
+        (((-43 * r - 85 * g + 128 * b) >> 8) + 128).clamp(0, 255),
+        (((128 * r - 107 * g - 21 * b) >> 8) + 128).clamp(0, 255),
+    )
+}
+
+// 1 行 row_pitch バイトの BGRA から、切り上げ分を除いて変換する
+pub fn convert_on_cpu(
+    src: &[u8],
+    row_pitch: usize,
+    width: u32,
+    height: u32,
+    target: ConversionTarget,
+) -> Vec<u8> {
+    let row_len = width as usize * 4;
+    let rows = src.chunks(row_pitch).take(height as usize);
+    let mut dst = Vec::with_capacity(output_len(target, width, height));
+    match target {
+        ConversionTarget::Rgba => {
+            // R と B を入れ替えるだけなので、BGRA への変換がそのまま使える
+            for row in rows {
+                extend_converted(&mut dst, &row[..row_len], PixelFormat::Bgra);
+            }
+        }
+        ConversionTarget::Yuv420 => {
+            let (width, height) = (width as usize, height as usize);
+            let rgb = |x: usize, y: usize| {
+                let at = y * row_pitch + x * 4;
+                (src[at + 2], src[at + 1], src[at])
+            };
+            for y in 0..height {
+                dst.extend((0..width).map(|x| {
+                    let (r, g, b) = rgb(x, y);
+                    luma(r, g, b)
+                }));
+            }
+
+            // 幅や高さが奇数のときは、端のピクセルを繰り返して 2x2 にする
+            let mut u_plane = Vec::with_capacity(width.div_ceil(2) * height.div_ceil(2));
+            let mut v_plane = Vec::with_capacity(u_plane.capacity());
+            for y in (0..height).step_by(2) {
+                for x in (0..width).step_by(2) {
+                    let (mut u, mut v) = (0, 0);
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (r, g, b) = rgb((x + dx).min(width - 1), (y + dy).min(height - 1));
+                        let (pu, pv) = chroma(r, g, b);
+                        u += pu;
+                        v += pv;
+                    }
+                    u_plane.push((u / 4) as u8);
+                    v_plane.push((v / 4) as u8);
+                }
+            }
+            dst.extend(u_plane);
+            dst.extend(v_plane);
+        }
+    }
+
+    dst
+}
+
+// D3D11 のコンピュートシェーダで変換する。シェーダは作るときに D3DCompile でコンパイルする。
+// windows_capture はキャプチャのテクスチャを外に出さないので、読み戻したバッファを
+// アップロードし直して使う。
+pub struct GpuConverter {
+    device: ID3D11Device,
+    context: ID3D11DeviceContext,
+    to_rgba: ID3D11ComputeShader,
+    to_yuv420: ID3D11ComputeShader,
+}
+
+impl GpuConverter {
+    pub fn new() -> Result<Self, String> {
+        let mut device = None;
+        let mut context = None;
+        unsafe {
+            D3D11CreateDevice(
+                None,
+                D3D_DRIVER_TYPE_HARDWARE,
+                HMODULE::default(),
+                D3D11_CREATE_DEVICE_FLAG(0),
+                Some(&[D3D_FEATURE_LEVEL_11_0]),
+                D3D11_SDK_VERSION,
+                Some(&mut device),
+                None,
+                Some(&mut context),
+            )
+        }
+        .map_err(|e| format!("failed to create a D3D11 device: {e}"))?;
+        let (Some(device), Some(context)) = (device, context) else {
+            return Err("D3D11CreateDevice returned no device".into());
+        };
+
+        Ok(Self {
+            to_rgba: compile(&device, "to_rgba")?,
+            to_yuv420: compile(&device, "to_yuv420")?,
+            device,
+            context,
+        })
+    }
+
+    // to_yuv420 は 8x2 ピクセル単位で 4 バイト境界に書くので、割り切れる大きさしか扱えない
+    pub fn supports(target: ConversionTarget, width: u32, height: u32) -> bool {
+        width > 0
+            && height > 0
+            && match target {
+                ConversionTarget::Rgba => true,
+                ConversionTarget::Yuv420 => width.is_multiple_of(8) && height.is_multiple_of(2),
+            }
+    }
+
+    pub fn convert(
+        &self,
+        src: &[u8],
+        row_pitch: usize,
+        width: u32,
+        height: u32,
+        target: ConversionTarget,
+    ) -> Result<Vec<u8>, String> {
+        if !Self::supports(target, width, height) {
+            return Err(format!(
+                "{width}x{height} cannot be converted to {target:?} on the GPU"
+            ));
+        }
+        if src.len() < row_pitch * height as usize || row_pitch < width as usize * 4 {
+            return Err(format!(
+                "buffer of {} bytes is too small for {width}x{height}",
+                src.len()
+            ));
+        }
+
+        let len = output_len(target, width, height);
+        let (shader, groups) = match target {
+            ConversionTarget::Rgba => (&self.to_rgba, (width.div_ceil(16), height.div_ceil(16))),
+            ConversionTarget::Yuv420 => (
+                &self.to_yuv420,
+                ((width / 8).div_ceil(8), (height / 2).div_ceil(8)),
+            ),
+        };
+
+        let source = self.source_view(src, row_pitch, width, height)?;
+        let size = self.buffer(
+            &D3D11_BUFFER_DESC {
+                ByteWidth: 16,
+                Usage: D3D11_USAGE_IMMUTABLE,
+                BindFlags: D3D11_BIND_CONSTANT_BUFFER.0 as u32,
+                ..Default::default()
+            },
+            Some(&[width, height, 0, 0]),
+        )?;
+        let output = self.buffer(
+            &D3D11_BUFFER_DESC {
+                ByteWidth: len as u32,
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_UNORDERED_ACCESS.0 as u32,
+                MiscFlags: D3D11_RESOURCE_MISC_BUFFER_ALLOW_RAW_VIEWS.0 as u32,
+                ..Default::default()
+            },
+            None,
+        )?;
+        let staging = self.buffer(
+            &D3D11_BUFFER_DESC {
+                ByteWidth: len as u32,
+                Usage: D3D11_USAGE_STAGING,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ.0 as u32,
+                ..Default::default()
+            },
+            None,
+        )?;
+        let mut uav: Option<ID3D11UnorderedAccessView> = None;
+        unsafe {
+            self.device.CreateUnorderedAccessView(
+                &output,
+                Some(&D3D11_UNORDERED_ACCESS_VIEW_DESC {
+                    Format: DXGI_FORMAT_R32_TYPELESS,
+                    ViewDimension: D3D11_UAV_DIMENSION_BUFFER,
+                    Anonymous: D3D11_UNORDERED_ACCESS_VIEW_DESC_0 {
+                        Buffer: D3D11_BUFFER_UAV {
+                            FirstElement: 0,
+                            NumElements: len as u32 / 4,
+                            Flags: D3D11_BUFFER_UAV_FLAG_RAW.0 as u32,
+                        },
+                    },
+                }),
+                Some(&mut uav),
+            )
+        }
+        .map_err(|e| format!("failed to create the output view: {e}"))?;
+
+        let mut bytes = vec![0; len];
+        unsafe {
+            self.context.CSSetShader(shader, None);
+            self.context.CSSetShaderResources(0, Some(&[Some(source)]));
+            self.context
+                .CSSetUnorderedAccessViews(0, 1, Some(&uav), None);
+            self.context.CSSetConstantBuffers(0, Some(&[Some(size)]));
+            self.context.Dispatch(groups.0, groups.1, 1);
+            // 次の変換のビューと衝突しないよう外しておく
+            self.context
+                .CSSetUnorderedAccessViews(0, 1, Some(&None), None);
+            self.context.CopyResource(&staging, &output);
+
+            // Map は GPU の処理が終わるまで待つ
+            let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+            self.context
+                .Map(&staging, 0, D3D11_MAP_READ, 0, Some(&mut mapped))
+                .map_err(|e| format!("failed to read back the converted frame: {e}"))?;
+            bytes.copy_from_slice(slice::from_raw_parts(mapped.pData as *const u8, len));
+            self.context.Unmap(&staging, 0);
+        }
+
+        Ok(bytes)
+    }
+
+    fn source_view(
+        &self,
+        src: &[u8],
+        row_pitch: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<ID3D11ShaderResourceView, String> {
+        let mut texture: Option<ID3D11Texture2D> = None;
+        unsafe {
+            self.device.CreateTexture2D(
+                &D3D11_TEXTURE2D_DESC {
+                    Width: width,
+                    Height: height,
+                    MipLevels: 1,
+                    ArraySize: 1,
+                    Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                    SampleDesc: DXGI_SAMPLE_DESC {
+                        Count: 1,
+                        Quality: 0,
+                    },
+                    Usage: D3D11_USAGE_IMMUTABLE,
+                    BindFlags: D3D11_BIND_SHADER_RESOURCE.0 as u32,
+                    CPUAccessFlags: 0,
+                    MiscFlags: 0,
+                },
+                Some(&D3D11_SUBRESOURCE_DATA {
+                    pSysMem: src.as_ptr() as _,
+                    SysMemPitch: row_pitch as u32,
+                    SysMemSlicePitch: 0,
+                }),
+                Some(&mut texture),
+            )
+        }
+        .map_err(|e| format!("failed to upload the frame: {e}"))?;
+        let texture = texture.ok_or("CreateTexture2D returned no texture")?;
+
+        let mut view = None;
+        unsafe {
+            self.device
+                .CreateShaderResourceView(&texture, None, Some(&mut view))
+        }
+        .map_err(|e| format!("failed to create the source view: {e}"))?;
+        view.ok_or_else(|| "CreateShaderResourceView returned no view".into())
+    }
+
+    fn buffer(
+        &self,
+        desc: &D3D11_BUFFER_DESC,
+        initial: Option<&[u32]>,
+    ) -> Result<ID3D11Buffer, String> {
+        let initial = initial.map(|data| D3D11_SUBRESOURCE_DATA {
+            pSysMem: data.as_ptr() as _,
+            SysMemPitch: mem::size_of_val(data) as u32,
+            SysMemSlicePitch: 0,
+        });
+        let mut buffer = None;
+        unsafe {
+            self.device.CreateBuffer(
+                desc,
+                initial.as_ref().map(|data| data as *const _),
+                Some(&mut buffer),
+            )
+        }
+        .map_err(|e| format!("failed to create a buffer: {e}"))?;
+        buffer.ok_or_else(|| "CreateBuffer returned no buffer".into())
+    }
+}
+
+fn compile(device: &ID3D11Device, entry: &str) -> Result<ID3D11ComputeShader, String> {
+    let entry_point = CString::new(entry).unwrap();
+    let mut code: Option<ID3DBlob> = None;
+    let mut errors: Option<ID3DBlob> = None;
+    let compiled = unsafe {
+        D3DCompile(
+            SHADER.as_ptr() as _,
+            SHADER.len(),
+            PCSTR::null(),
+            None,
+            None,
+            PCSTR(entry_point.as_ptr() as _),
+            s!("cs_5_0"),
+            D3DCOMPILE_OPTIMIZATION_LEVEL3,
+            0,
+            &mut code,
+            Some(&mut errors),
+        )
+    };
+    if let Err(e) = compiled {
+        let message = errors.map_or_else(|| e.to_string(), |blob| blob_text(&blob));
+        return Err(format!("failed to compile {entry}: {message}"));
+    }
+    let code = code.ok_or_else(|| format!("D3DCompile returned no code for {entry}"))?;
+
+    let mut shader = None;
+    unsafe {
+        let bytecode =
+            slice::from_raw_parts(code.GetBufferPointer() as *const u8, code.GetBufferSize());
+        device.CreateComputeShader(bytecode, None, Some(&mut shader))
+    }
+    .map_err(|e| format!("failed to create {entry}: {e}"))?;
+    shader.ok_or_else(|| format!("CreateComputeShader returned no shader for {entry}"))
+}
+
+fn blob_text(blob: &ID3DBlob) -> String {
+    let bytes = unsafe {
+        slice::from_raw_parts(blob.GetBufferPointer() as *const u8, blob.GetBufferSize())
+    };
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
+// GPU が使えなければ CPU で変換する。GPU で扱えない大きさや、途中で失敗したときも CPU に回す。
+pub enum PixelConverter {
+    Gpu(GpuConverter),
+    Cpu,
+}
+
+impl PixelConverter {
+    pub fn new() -> (Self, Option<String>) {
+        match GpuConverter::new() {
+            Ok(gpu) => (PixelConverter::Gpu(gpu), None),
+            Err(e) => (PixelConverter::Cpu, Some(e)),
+        }
+    }
+
+    pub fn convert(
+        &self,
+        src: &[u8],
+        row_pitch: usize,
+        width: u32,
+        height: u32,
+        target: ConversionTarget,
+    ) -> Vec<u8> {
+        if let PixelConverter::Gpu(gpu) = self {
+            if GpuConverter::supports(target, width, height) {
+                if let Ok(bytes) = gpu.convert(src, row_pitch, width, height, target) {
+                    return bytes;
+                }
+            }
+        }
+
+        convert_on_cpu(src, row_pitch, width, height, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+
+    use super::*;
+    use crate::test_utils::bench;
+
+    #[test]
+    fn cpu_conversion_skips_row_padding_and_averages_chroma() {
+        // 2x2 の BGRA で、各行の後ろに 4 バイトの切り上げ分がある
+        let src = [
+            0, 0, 255, 255, 0, 255, 0, 255, 9, 9, 9, 9, //
+            255, 0, 0, 255, 255, 255, 255, 255, 9, 9, 9, 9,
+        ];
+        let rgba = convert_on_cpu(&src, 12, 2, 2, ConversionTarget::Rgba);
+        assert_eq!(
+            rgba,
+            [255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255, 255, 255, 255]
+        );
+
+        let yuv = convert_on_cpu(&src, 12, 2, 2, ConversionTarget::Yuv420);
+        let (y_plane, uv) = yuv.split_at(4);
+        assert_eq!(
+            y_plane,
+            [luma(255, 0, 0), luma(0, 255, 0), luma(0, 0, 255), 255]
+        );
+        let [red, green, blue, white] = [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 255)]
+            .map(|(r, g, b)| chroma(r, g, b));
+        let average = |pick: fn((i32, i32)) -> i32| {
+            ((pick(red) + pick(green) + pick(blue) + pick(white)) / 4) as u8
+        };
+        assert_eq!(uv, [average(|c| c.0), average(|c| c.1)]);
+    }
+
+    #[test]
+    fn odd_sizes_get_rounded_up_chroma_planes() {
+        let src = vec![128; 3 * 3 * 4];
+        let yuv = convert_on_cpu(&src, 12, 3, 3, ConversionTarget::Yuv420);
+        assert_eq!(yuv.len(), output_len(ConversionTarget::Yuv420, 3, 3));
+        assert_eq!(yuv.len(), 9 + 2 * 4);
+        assert!(!GpuConverter::supports(ConversionTarget::Yuv420, 3, 3));
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_gpu_against_cpu_conversion_at_4k() {
+        let (width, height) = (3840, 2160);
+        let row_pitch = width as usize * 4;
+        let src: Vec<u8> = (0..row_pitch * height as usize)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        // Windows 以外では D3D11 がないので CPU だけ測る
+        let gpu = if cfg!(windows) {
+            GpuConverter::new()
+                .map_err(|e| println!("GPU conversion is not available: {e}"))
+                .ok()
+        } else {
+            None
+        };
+
+        for target in [ConversionTarget::Rgba, ConversionTarget::Yuv420] {
+            let expected = convert_on_cpu(&src, row_pitch, width, height, target);
+            let cpu = bench(&format!("4K to {target:?} on the CPU"), 20, || {
+                black_box(convert_on_cpu(
+                    black_box(&src),
+                    row_pitch,
+                    width,
+                    height,
+                    target,
+                ));
+            });
+            println!(
+                "4K to {target:?} on the CPU: {:.0} MB/s",
+                src.len() as f64 / cpu.as_secs_f64() / 1e6
+            );
+
+            let Some(gpu) = &gpu else {
+                continue;
+            };
+            assert_eq!(
+                gpu.convert(&src, row_pitch, width, height, target).unwrap(),
+                expected,
+                "GPU and CPU disagree on {target:?}"
+            );
+            let elapsed = bench(&format!("4K to {target:?} on the GPU"), 20, || {
+                black_box(
+                    gpu.convert(black_box(&src), row_pitch, width, height, target)
+                        .unwrap(),
+                );
+            });
+            println!(
+                "4K to {target:?} on the GPU: {:.0} MB/s ({:.2}x the CPU)",
+                src.len() as f64 / elapsed.as_secs_f64() / 1e6,
+                cpu.as_secs_f64() / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
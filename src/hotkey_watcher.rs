@@ -0,0 +1,138 @@
+use std::{fmt, thread, time::Duration};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use windows::Win32::UI::{
+    Input::KeyboardAndMouse::{
+        RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS, MOD_ALT, MOD_CONTROL, MOD_NOREPEAT,
+        MOD_SHIFT, MOD_WIN,
+    },
+    WindowsAndMessaging::{PeekMessageW, MSG, PM_REMOVE, WM_HOTKEY},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HotkeyModifier {
+    Ctrl,
+    Alt,
+    Shift,
+    Win,
+}
+
+// 修飾キー + 1..9 でプリセットを読み込む。ほかのアプリのショートカットを奪うので、設定に
+// 書かれたときだけ登録する
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresetHotkeys {
+    pub modifiers: Vec<HotkeyModifier>,
+}
+
+impl PresetHotkeys {
+    fn modifier_flags(&self) -> HOT_KEY_MODIFIERS {
+        self.modifiers.iter().fold(MOD_NOREPEAT, |flags, modifier| {
+            flags
+                | match modifier {
+                    HotkeyModifier::Ctrl => MOD_CONTROL,
+                    HotkeyModifier::Alt => MOD_ALT,
+                    HotkeyModifier::Shift => MOD_SHIFT,
+                    HotkeyModifier::Win => MOD_WIN,
+                }
+        })
+    }
+}
+
+impl fmt::Display for PresetHotkeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for modifier in &self.modifiers {
+            write!(f, "{modifier:?}+")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct HotkeyWatcher {
+    rx_cmd: Receiver<HotkeyWatcherCommand>,
+    tx_msg: Sender<HotkeyWatcherMessage>,
+}
+
+pub enum HotkeyWatcherCommand {
+    // None なら登録済みのものを外す
+    SetPresetHotkeys(Option<PresetHotkeys>),
+    Quit,
+}
+
+pub enum HotkeyWatcherMessage {
+    Output { message: String },
+    // 修飾キー + 1..9 が押された。id は押された数字
+    HotkeyPressed { id: u32 },
+}
+
+impl HotkeyWatcher {
+    pub fn new() -> (
+        Self,
+        Sender<HotkeyWatcherCommand>,
+        Receiver<HotkeyWatcherMessage>,
+    ) {
+        let (tx_cmd, rx_cmd) = unbounded();
+        let (tx_msg, rx_msg) = unbounded();
+
+        (Self { rx_cmd, tx_msg }, tx_cmd, rx_msg)
+    }
+
+    pub fn run(self) {
+        // RegisterHotKey はスレッドに結びつくので、登録も解除もこのスレッドで行う
+        let mut current: Option<PresetHotkeys> = None;
+        let mut registered = vec![];
+
+        loop {
+            if let Ok(msg) = self.rx_cmd.try_recv() {
+                match msg {
+                    HotkeyWatcherCommand::SetPresetHotkeys(hotkeys) => {
+                        if hotkeys != current {
+                            unregister_all(&mut registered);
+                            if let Some(hotkeys) = &hotkeys {
+                                registered = self.register_all(hotkeys);
+                            }
+                            current = hotkeys;
+                        }
+                    }
+                    HotkeyWatcherCommand::Quit => break,
+                }
+            }
+
+            let mut msg = MSG::default();
+            while unsafe { PeekMessageW(&mut msg, None, WM_HOTKEY, WM_HOTKEY, PM_REMOVE) }.as_bool()
+            {
+                let _ = self.tx_msg.send(HotkeyWatcherMessage::HotkeyPressed {
+                    id: msg.wParam.0 as u32,
+                });
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        unregister_all(&mut registered);
+    }
+
+    // ウィンドウを渡さずに登録すると、WM_HOTKEY は登録したスレッドのキューに届く
+    fn register_all(&self, hotkeys: &PresetHotkeys) -> Vec<u32> {
+        let flags = hotkeys.modifier_flags();
+        let mut registered = vec![];
+        for id in 1..=9 {
+            let vk = u32::from(b'0') + id;
+            match unsafe { RegisterHotKey(None, id as i32, flags, vk) } {
+                Ok(()) => registered.push(id),
+                Err(e) => {
+                    let _ = self.tx_msg.send(HotkeyWatcherMessage::Output {
+                        message: format!("failed to register {hotkeys}{id}: {e}"),
+                    });
+                }
+            }
+        }
+        registered
+    }
+}
+
+fn unregister_all(registered: &mut Vec<u32>) {
+    for id in registered.drain(..) {
+        let _ = unsafe { UnregisterHotKey(None, id as i32) };
+    }
+}
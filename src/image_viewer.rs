@@ -1,7 +1,10 @@
+use std::time::Instant;
+
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use show_image::{create_window, Color, ImageInfo, ImageView, WindowOptions, WindowProxy};
+use windows::Win32::Foundation::HWND;
 
-use crate::window_capture::CapturedFrame;
+use crate::{pixel_format::PixelFormat, window_capture::CapturedFrame};
 
 pub struct ImageViewer {
     rx_cmd: Receiver<ImageViewerCommand>,
@@ -11,11 +14,19 @@ pub struct ImageViewer {
 
 pub enum ImageViewerCommand {
     Update(CapturedFrame),
+    Resize { width: u32, height: u32 },
+    ShowPlaceholder(String),
     Quit,
 }
 
 pub enum ImageViewerMessage {
     Closed,
+    // sequence はキャプチャごとに振られるので、どのウィンドウのフレームかも返す
+    FrameRendered {
+        hwnd: HWND,
+        frame_id: u64,
+        render_time_us: u64,
+    },
 }
 
 impl ImageViewer {
@@ -62,10 +73,43 @@ impl ImageViewer {
     fn handle_command(&mut self, window: &WindowProxy, command: ImageViewerCommand) {
         match command {
             ImageViewerCommand::Update(frame) => {
-                let image =
-                    ImageView::new(ImageInfo::rgba8(frame.width, frame.height), &frame.bytes);
+                let started_at = Instant::now();
+                let info = match frame.format {
+                    PixelFormat::Rgba => ImageInfo::rgba8(frame.width, frame.height),
+                    PixelFormat::Bgra => ImageInfo::bgra8(frame.width, frame.height),
+                    PixelFormat::Rgb24 => ImageInfo::rgb8(frame.width, frame.height),
+                    PixelFormat::Gray8 => ImageInfo::mono8(frame.width, frame.height),
+                    // 確認用なので RGB には戻さず、色がおかしいまま表示する
+                    PixelFormat::Yuv444 => ImageInfo::rgb8(frame.width, frame.height),
+                };
+                let image = ImageView::new(info, &frame.bytes);
                 if window.set_image("capture", image).is_err() {
                     let _ = self.tx_msg.send(ImageViewerMessage::Closed);
+                    return;
+                }
+
+                let _ = self.tx_msg.send(ImageViewerMessage::FrameRendered {
+                    hwnd: frame.hwnd,
+                    frame_id: frame.sequence,
+                    render_time_us: started_at.elapsed().as_micros() as u64,
+                });
+            }
+            ImageViewerCommand::Resize { width, height } => {
+                // 全画面表示中はウィンドウのサイズを変えても意味がない
+                window.run_function(move |mut window| {
+                    if !window.is_fullscreen() {
+                        window.set_inner_size([width, height]);
+                    }
+                });
+            }
+            ImageViewerCommand::ShowPlaceholder(reason) => {
+                // 文字を描く手段はないので、透明な画像で背景色を見せておく
+                let image = ImageView::new(ImageInfo::rgba8(1, 1), &[0, 0, 0, 0]);
+                if window
+                    .set_image(format!("placeholder: {reason}"), image)
+                    .is_err()
+                {
+                    let _ = self.tx_msg.send(ImageViewerMessage::Closed);
                 }
             }
             ImageViewerCommand::Quit => self.is_running = false,
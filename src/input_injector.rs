@@ -0,0 +1,138 @@
+use std::mem;
+
+use windows::Win32::{
+    Foundation::{HWND, POINT},
+    Graphics::Gdi::ClientToScreen,
+    UI::{
+        Input::KeyboardAndMouse::{
+            SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT, KEYBD_EVENT_FLAGS,
+            KEYEVENTF_EXTENDEDKEY, KEYEVENTF_KEYUP, KEYEVENTF_UNICODE, MOUSEEVENTF_ABSOLUTE,
+            MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_VIRTUALDESK,
+            MOUSEINPUT, MOUSE_EVENT_FLAGS, VIRTUAL_KEY, VK_DELETE, VK_DOWN, VK_END, VK_HOME,
+            VK_INSERT, VK_LEFT, VK_NEXT, VK_PRIOR, VK_RIGHT, VK_UP,
+        },
+        WindowsAndMessaging::{
+            GetSystemMetrics, SetForegroundWindow, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN,
+            SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN,
+        },
+    },
+};
+
+// SendInput は前面のウィンドウに入力を送るので、送る前に対象のウィンドウを前面に出す
+pub struct InputInjector {
+    hwnd: HWND,
+}
+
+impl InputInjector {
+    pub fn new(hwnd: HWND) -> Self {
+        Self { hwnd }
+    }
+
+    // x, y はウィンドウのクライアント領域での座標
+    pub fn click(&self, x: i32, y: i32) -> Result<(), String> {
+        let mut point = POINT { x, y };
+        if !unsafe { ClientToScreen(self.hwnd, &mut point) }.as_bool() {
+            return Err(format!("[{}] failed to convert coordinates", self.hwnd.0));
+        }
+
+        // 絶対座標は仮想デスクトップ全体を 0..=65535 に正規化したもので指定する
+        let (left, top, width, height) = unsafe {
+            (
+                GetSystemMetrics(SM_XVIRTUALSCREEN),
+                GetSystemMetrics(SM_YVIRTUALSCREEN),
+                GetSystemMetrics(SM_CXVIRTUALSCREEN).max(1),
+                GetSystemMetrics(SM_CYVIRTUALSCREEN).max(1),
+            )
+        };
+        let dx = ((point.x - left) as i64 * 65535 / width as i64) as i32;
+        let dy = ((point.y - top) as i64 * 65535 / height as i64) as i32;
+
+        let absolute = MOUSEEVENTF_ABSOLUTE | MOUSEEVENTF_VIRTUALDESK;
+        self.send(&[
+            mouse_input(dx, dy, MOUSEEVENTF_MOVE | absolute),
+            mouse_input(dx, dy, MOUSEEVENTF_LEFTDOWN | absolute),
+            mouse_input(dx, dy, MOUSEEVENTF_LEFTUP | absolute),
+        ])
+    }
+
+    pub fn key_press(&self, vk: u32) -> Result<(), String> {
+        let vk = VIRTUAL_KEY(vk as u16);
+        let flags = if is_extended_key(vk) {
+            KEYEVENTF_EXTENDEDKEY
+        } else {
+            KEYBD_EVENT_FLAGS(0)
+        };
+
+        self.send(&[
+            keyboard_input(vk, 0, flags),
+            keyboard_input(vk, 0, flags | KEYEVENTF_KEYUP),
+        ])
+    }
+
+    pub fn type_text(&self, text: &str) -> Result<(), String> {
+        let inputs: Vec<_> = text
+            .encode_utf16()
+            .flat_map(|unit| {
+                [
+                    keyboard_input(VIRTUAL_KEY(0), unit, KEYEVENTF_UNICODE),
+                    keyboard_input(VIRTUAL_KEY(0), unit, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP),
+                ]
+            })
+            .collect();
+
+        self.send(&inputs)
+    }
+
+    fn send(&self, inputs: &[INPUT]) -> Result<(), String> {
+        unsafe { SetForegroundWindow(self.hwnd) };
+
+        let sent = unsafe { SendInput(inputs, mem::size_of::<INPUT>() as i32) };
+        if sent as usize != inputs.len() {
+            return Err(format!(
+                "[{}] only {sent} of {} inputs were sent",
+                self.hwnd.0,
+                inputs.len()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn mouse_input(dx: i32, dy: i32, flags: MOUSE_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx,
+                dy,
+                mouseData: 0,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
+
+fn keyboard_input(vk: VIRTUAL_KEY, scan: u16, flags: KEYBD_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: vk,
+                wScan: scan,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
+
+fn is_extended_key(vk: VIRTUAL_KEY) -> bool {
+    [
+        VK_LEFT, VK_RIGHT, VK_UP, VK_DOWN, VK_HOME, VK_END, VK_PRIOR, VK_NEXT, VK_INSERT, VK_DELETE,
+    ]
+    .contains(&vk)
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// 直近何個分のフレーム間隔を見るか
+const WINDOW: usize = 120;
+
+pub struct JitterTracker {
+    intervals: VecDeque<Duration>,
+    last_arrival: Option<Instant>,
+}
+
+impl JitterTracker {
+    pub fn new() -> Self {
+        Self {
+            intervals: VecDeque::with_capacity(WINDOW + 1),
+            last_arrival: None,
+        }
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        if let Some(last_arrival) = self.last_arrival {
+            self.intervals.push_back(now - last_arrival);
+            if self.intervals.len() > WINDOW {
+                self.intervals.pop_front();
+            }
+        }
+        self.last_arrival = Some(now);
+    }
+
+    // 一時停止などでわざと間が空いたときは、その間隔を混ぜないように次の 1 フレームを起点にし直す
+    pub fn restart(&mut self) {
+        self.last_arrival = None;
+    }
+
+    pub fn mean_ms(&self) -> f32 {
+        if self.intervals.is_empty() {
+            return 0.0;
+        }
+
+        let sum: f64 = self
+            .intervals
+            .iter()
+            .map(|i| i.as_secs_f64() * 1000.0)
+            .sum();
+        (sum / self.intervals.len() as f64) as f32
+    }
+
+    pub fn std_ms(&self) -> f32 {
+        if self.intervals.len() < 2 {
+            return 0.0;
+        }
+
+        let mean = self.mean_ms() as f64;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (i.as_secs_f64() * 1000.0 - mean).powi(2))
+            .sum::<f64>()
+            / self.intervals.len() as f64;
+        variance.sqrt() as f32
+    }
+}
+
+impl Default for JitterTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
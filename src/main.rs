@@ -1,18 +1,86 @@
-use std::thread::{self};
+use std::{
+    process,
+    thread::{self},
+};
+
+use clap::Parser;
 
 use crate::{
-    driver::Driver, foreground_watcher::ForegroundWatcher, image_viewer::ImageViewer,
+    audio_output::AudioOutput,
+    cli::{Cli, CliCommand},
+    config::DriverConfig,
+    driver::Driver,
+    foreground_watcher::ForegroundWatcher,
+    gaussian_blur_plugin::GaussianBlurPlugin,
+    hotkey_watcher::HotkeyWatcher,
+    image_viewer::ImageViewer,
+    obs_virtual_camera::ObsVirtualCamera,
+    scene_change_plugin::SceneChangeDetector,
     stdin_shell::StdinShell,
+    timestamp_plugin::TimestampPlugin,
+    virtual_camera::VirtualCameraOutput,
+    watermark_plugin::OverlayPosition,
 };
 
+pub mod audio_capture;
+pub mod audio_output;
+pub mod capture_factory;
+pub mod cli;
+pub mod config;
 pub mod driver;
+#[cfg(feature = "event-log")]
+pub mod event_log;
 pub mod foreground_watcher;
+pub mod frame_exporter;
+pub mod frame_plugin;
+pub mod frame_rate_controller;
+pub mod gaussian_blur_plugin;
+pub mod gpu_converter;
+pub mod hotkey_watcher;
 pub mod image_viewer;
+pub mod input_injector;
+pub mod jitter_tracker;
+pub mod log_level;
+pub mod obs_virtual_camera;
+pub mod pixel_format;
+pub mod pixel_sampler;
+pub mod preset_manager;
+pub mod profiler;
+pub mod replay_buffer;
+pub mod scene_change_plugin;
+pub mod shared_memory_output;
+pub mod snap_layout;
+pub mod stats;
 pub mod stdin_shell;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_utils;
+pub mod timestamp_plugin;
+#[cfg(feature = "udp-stream")]
+pub mod udp_stream;
+pub mod virtual_camera;
+pub mod watermark_plugin;
+#[cfg(all(test, not(windows)))]
+mod win32_stubs;
 pub mod window_capture;
 
 #[show_image::main]
 fn main() {
+    if let Some(command) = Cli::parse().command {
+        let result = match command {
+            CliCommand::Screenshot {
+                hwnd,
+                title,
+                output,
+            } => cli::screenshot(hwnd, title, output),
+            CliCommand::DryRun { hwnd, title } => cli::dry_run(hwnd, title),
+        };
+        if let Err(e) = result {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+        return;
+    }
+
     let (viewer, im_tx_cmd, im_rx_msg) = ImageViewer::new();
     let viewer = thread::spawn(move || viewer.run());
 
@@ -22,10 +90,40 @@ fn main() {
     let (shell, sh_tx_cmd, sh_rx_msg) = StdinShell::new();
     let shell = thread::spawn(move || shell.run());
 
-    let driver = Driver::new(
-        im_tx_cmd, im_rx_msg, fw_tx_cmd, fw_rx_msg, sh_tx_cmd, sh_rx_msg,
+    let (audio_output, ao_tx_cmd) = AudioOutput::new();
+    let audio_output = thread::spawn(move || audio_output.run());
+
+    let (hotkeys, hk_tx_cmd, hk_rx_msg) = HotkeyWatcher::new();
+    let hotkeys = thread::spawn(move || hotkeys.run());
+
+    let config = DriverConfig::default();
+    let camera_fps = config.capture.fps;
+    let mut driver = Driver::new(
+        config, im_tx_cmd, im_rx_msg, fw_tx_cmd, fw_rx_msg, sh_tx_cmd, sh_rx_msg,
     );
 
+    driver.set_audio_output(ao_tx_cmd);
+    driver.set_hotkey_watcher(hk_tx_cmd, hk_rx_msg);
+    driver.add_plugin(Box::new(GaussianBlurPlugin::new(8.0, false)));
+    let tx_event = driver.event_sender();
+    driver.add_plugin(Box::new(SceneChangeDetector::new(0.25, tx_event)));
+    let timestamp = TimestampPlugin::new(
+        "%H:%M:%S".into(),
+        14,
+        OverlayPosition::BottomRight,
+        [255, 255, 255, 255],
+    )
+    .unwrap();
+    driver.add_disabled_plugin(Box::new(timestamp));
+    // 他のプラグインをかけ終えたフレームを流すよう最後に置き、enable virtual_camera で流し始める
+    let camera = VirtualCameraOutput::new(ObsVirtualCamera::new(camera_fps), driver.event_sender());
+    driver.add_disabled_plugin(Box::new(camera));
+    #[cfg(feature = "event-log")]
+    match event_log::EventLog::open("events.db") {
+        Ok(event_log) => driver.set_event_log(event_log),
+        Err(e) => eprintln!("{e}"),
+    }
+
     driver.run();
     eprintln!("driver finished");
 
@@ -35,4 +133,8 @@ fn main() {
     eprintln!("watcher finished");
     shell.join().unwrap();
     eprintln!("shell finished");
+    audio_output.join().unwrap();
+    eprintln!("audio output finished");
+    hotkeys.join().unwrap();
+    eprintln!("hotkey watcher finished");
 }
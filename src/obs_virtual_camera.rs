@@ -0,0 +1,319 @@
+use std::{
+    mem, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE},
+        System::Memory::{
+            CreateFileMappingW, MapViewOfFile, OpenFileMappingW, UnmapViewOfFile,
+            FILE_MAP_ALL_ACCESS, FILE_MAP_READ, MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+        },
+    },
+};
+
+use crate::{
+    pixel_format::{luma, PixelFormat},
+    virtual_camera::VirtualCameraSink,
+    window_capture::CapturedFrame,
+};
+
+// OBS の仮想カメラ (OBS と一緒に入る DirectShow フィルタ) が読みに来る共有メモリ。レイアウトは
+// obs-studio の plugins/win-dshow/shared-memory-queue.c に合わせてある。
+const QUEUE_NAME: &str = "OBSVirtualCamVideo";
+const QUEUE_SLOTS: usize = 3;
+// 各スロットの先頭に置くタイムスタンプの領域。フレームの中身はその後に NV12 で続く
+const SLOT_HEADER_SIZE: usize = 32;
+const QUEUE_ALIGN: usize = 32;
+
+const STATE_STARTING: u32 = 1;
+const STATE_READY: u32 = 2;
+const STATE_STOPPING: u32 = 3;
+
+// 開けなかったときに、毎フレーム試してエラーを出し続けないための間隔
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[repr(C)]
+struct QueueHeader {
+    write_idx: u32,
+    read_idx: u32,
+    state: u32,
+    offsets: [u32; QUEUE_SLOTS],
+    kind: u32,
+    cx: u32,
+    cy: u32,
+    // 1 フレームの長さ (100ns 単位)
+    interval: u64,
+    reserved: [u32; 8],
+}
+
+// (各スロットの先頭の位置, 全体の大きさ)
+fn queue_layout(cx: u32, cy: u32) -> ([u32; QUEUE_SLOTS], usize) {
+    let frame_size = nv12_len(cx, cy);
+    let mut offsets = [0; QUEUE_SLOTS];
+    let mut size = mem::size_of::<QueueHeader>().next_multiple_of(QUEUE_ALIGN);
+    for offset in &mut offsets {
+        *offset = size as u32;
+        size = (size + SLOT_HEADER_SIZE + frame_size).next_multiple_of(QUEUE_ALIGN);
+    }
+
+    (offsets, size)
+}
+
+fn nv12_len(cx: u32, cy: u32) -> usize {
+    cx as usize * cy as usize * 3 / 2
+}
+
+// NV12 は 2x2 ピクセルで色差を共有するので、奇数の幅や高さは最後の 1 列 (行) を落とす
+fn nv12_size(frame: &CapturedFrame) -> (u32, u32) {
+    (frame.width & !1, frame.height & !1)
+}
+
+fn rgb_of(format: PixelFormat, pixel: [u8; 4]) -> (u8, u8, u8) {
+    let [r, g, b] = format.rgb_offsets();
+    (pixel[r], pixel[g], pixel[b])
+}
+
+// dst の先頭 cx * cy バイトに Y を、続く半分に U と V を交互に書く (BT.601, フルレンジ)
+fn write_nv12(frame: &CapturedFrame, cx: u32, cy: u32, dst: &mut [u8]) {
+    let (y_plane, uv_plane) = dst[..nv12_len(cx, cy)].split_at_mut(cx as usize * cy as usize);
+    for y in 0..cy {
+        for x in 0..cx {
+            let pixel = frame.pixel(x, y);
+            y_plane[(y * cx + x) as usize] = match frame.format {
+                PixelFormat::Gray8 | PixelFormat::Yuv444 => pixel[0],
+                format => {
+                    let (r, g, b) = rgb_of(format, pixel);
+                    luma(r, g, b)
+                }
+            };
+        }
+    }
+
+    for y in (0..cy).step_by(2) {
+        for x in (0..cx).step_by(2) {
+            let (mut u, mut v) = (0, 0);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let pixel = frame.pixel(x + dx, y + dy);
+                let (pu, pv) = match frame.format {
+                    PixelFormat::Gray8 => (128, 128),
+                    PixelFormat::Yuv444 => (pixel[1] as i32, pixel[2] as i32),
+                    format => {
+                        let (r, g, b) = rgb_of(format, pixel);
+                        let (r, g, b) = (r as i32, g as i32, b as i32);
+                        (
+                            (((-43 * r - 85 * g + 128 * b) >> 8) + 128).clamp(0, 255),
+                            (((128 * r - 107 * g - 21 * b) >> 8) + 128).clamp(0, 255),
+                        )
+                    }
+                };
+                u += pu;
+                v += pv;
+            }
+            let at = ((y / 2) * cx + x) as usize;
+            uv_plane[at] = (u / 4) as u8;
+            uv_plane[at + 1] = (v / 4) as u8;
+        }
+    }
+}
+
+struct VideoQueue {
+    mapping: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    offsets: [u32; QUEUE_SLOTS],
+    cx: u32,
+    cy: u32,
+}
+
+impl VideoQueue {
+    fn create(cx: u32, cy: u32, interval: u64) -> Result<Self, String> {
+        let name = HSTRING::from(QUEUE_NAME);
+        // OBS 自身の仮想カメラが動いているなら、横から書き込まない
+        if let Ok(existing) = unsafe { OpenFileMappingW(FILE_MAP_READ.0, false, &name) } {
+            unsafe {
+                let _ = CloseHandle(existing);
+            }
+            return Err("the OBS virtual camera is already in use".into());
+        }
+
+        let (offsets, size) = queue_layout(cx, cy);
+        let mapping = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                0,
+                size as u32,
+                &name,
+            )
+        }
+        .map_err(|e| format!("failed to create {QUEUE_NAME}: {e}"))?;
+
+        let view = unsafe { MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, size) };
+        if view.Value.is_null() {
+            let e = windows::core::Error::from_win32();
+            unsafe {
+                let _ = CloseHandle(mapping);
+            }
+            return Err(format!("failed to map {QUEUE_NAME}: {e}"));
+        }
+
+        unsafe {
+            ptr::write(
+                view.Value as *mut QueueHeader,
+                QueueHeader {
+                    write_idx: 0,
+                    read_idx: 0,
+                    state: STATE_STARTING,
+                    offsets,
+                    kind: 0,
+                    cx,
+                    cy,
+                    interval,
+                    reserved: [0; 8],
+                },
+            );
+        }
+
+        Ok(Self {
+            mapping,
+            view,
+            offsets,
+            cx,
+            cy,
+        })
+    }
+
+    fn header(&self) -> *mut QueueHeader {
+        self.view.Value as *mut QueueHeader
+    }
+
+    // 読み手が別プロセスなので、書き終えてから read_idx と state を進める
+    fn write(&mut self, nv12: &[u8], timestamp: u64) {
+        let header = self.header();
+        unsafe {
+            let write_idx = &*(ptr::addr_of!((*header).write_idx) as *const AtomicU32);
+            let inc = write_idx.fetch_add(1, Ordering::Relaxed) + 1;
+            let slot =
+                (self.view.Value as *mut u8).add(self.offsets[inc as usize % QUEUE_SLOTS] as usize);
+            ptr::write_unaligned(slot as *mut u64, timestamp);
+            ptr::copy_nonoverlapping(nv12.as_ptr(), slot.add(SLOT_HEADER_SIZE), nv12.len());
+
+            (*(ptr::addr_of!((*header).read_idx) as *const AtomicU32))
+                .store(inc, Ordering::Release);
+            (*(ptr::addr_of!((*header).state) as *const AtomicU32))
+                .store(STATE_READY, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for VideoQueue {
+    fn drop(&mut self) {
+        unsafe {
+            (*(ptr::addr_of!((*self.header()).state) as *const AtomicU32))
+                .store(STATE_STOPPING, Ordering::Release);
+            let _ = UnmapViewOfFile(self.view);
+            let _ = CloseHandle(self.mapping);
+        }
+    }
+}
+
+// OBS の仮想カメラにフレームを流す。OBS を入れてあれば、OBS を起動していなくても
+// 「OBS Virtual Camera」として他のアプリから見える。大きさが変わったら開き直す。
+pub struct ObsVirtualCamera {
+    interval: u64,
+    queue: Option<VideoQueue>,
+    nv12: Vec<u8>,
+    started_at: Instant,
+    retry_at: Option<Instant>,
+}
+
+// 共有メモリのビューはこのプロセスのどのスレッドから書いてもよい
+unsafe impl Send for ObsVirtualCamera {}
+
+impl ObsVirtualCamera {
+    pub fn new(fps: u64) -> Self {
+        Self {
+            interval: 10_000_000 / fps.max(1),
+            queue: None,
+            nv12: vec![],
+            started_at: Instant::now(),
+            retry_at: None,
+        }
+    }
+}
+
+impl VirtualCameraSink for ObsVirtualCamera {
+    fn write(&mut self, frame: &CapturedFrame) -> Result<(), String> {
+        let (cx, cy) = nv12_size(frame);
+        if cx == 0 || cy == 0 {
+            return Ok(());
+        }
+
+        if self
+            .queue
+            .as_ref()
+            .is_none_or(|queue| (queue.cx, queue.cy) != (cx, cy))
+        {
+            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                return Ok(());
+            }
+            // 読み手に止まったと知らせてから、新しい大きさで作り直す
+            self.queue = None;
+            match VideoQueue::create(cx, cy, self.interval) {
+                Ok(queue) => {
+                    self.queue = Some(queue);
+                    self.retry_at = None;
+                }
+                Err(e) => {
+                    self.retry_at = Some(Instant::now() + OPEN_RETRY_INTERVAL);
+                    return Err(e);
+                }
+            }
+        }
+
+        self.nv12.resize(nv12_len(cx, cy), 0);
+        write_nv12(frame, cx, cy, &mut self.nv12);
+        let timestamp = (frame
+            .captured_at
+            .saturating_duration_since(self.started_at)
+            .as_nanos()
+            / 100) as u64;
+        if let Some(queue) = &mut self.queue {
+            queue.write(&self.nv12, timestamp);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::Foundation::HWND;
+
+    use super::*;
+
+    #[test]
+    fn queue_layout_matches_obs() {
+        // ヘッダは 80 バイトなので、最初のスロットは 96 バイト目から始まる
+        let (offsets, size) = queue_layout(4, 2);
+        assert_eq!(mem::size_of::<QueueHeader>(), 80);
+        assert_eq!(offsets, [96, 160, 224]);
+        assert_eq!(size, 288);
+    }
+
+    #[test]
+    fn nv12_drops_the_odd_column_and_averages_colour() {
+        let mut frame = CapturedFrame::checkerboard(HWND(0), 1, 3, 2);
+        frame.format = PixelFormat::Gray8;
+        frame.bytes = vec![10, 30, 99, 50, 70, 99];
+        assert_eq!(nv12_size(&frame), (2, 2));
+
+        let mut nv12 = vec![0; nv12_len(2, 2)];
+        write_nv12(&frame, 2, 2, &mut nv12);
+        assert_eq!(nv12, vec![10, 30, 50, 70, 128, 128]);
+    }
+}
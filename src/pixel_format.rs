@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PixelFormat {
+    Rgba,
+    Bgra,
+    Rgb24,
+    Gray8,
+    // 1 ピクセルに Y, U, V を 1 バイトずつ並べたもの (BT.601, フルレンジ)
+    Yuv444,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba | PixelFormat::Bgra => 4,
+            PixelFormat::Rgb24 | PixelFormat::Yuv444 => 3,
+            PixelFormat::Gray8 => 1,
+        }
+    }
+
+    // 共有メモリや UDP で送るときの番号
+    pub fn code(self) -> u32 {
+        match self {
+            PixelFormat::Rgba => 0,
+            PixelFormat::Bgra => 1,
+            PixelFormat::Rgb24 => 2,
+            PixelFormat::Gray8 => 3,
+            PixelFormat::Yuv444 => 4,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(PixelFormat::Rgba),
+            1 => Some(PixelFormat::Bgra),
+            2 => Some(PixelFormat::Rgb24),
+            3 => Some(PixelFormat::Gray8),
+            4 => Some(PixelFormat::Yuv444),
+            _ => None,
+        }
+    }
+
+    // 1ピクセル内での R, G, B の位置
+    pub fn rgb_offsets(self) -> [usize; 3] {
+        match self {
+            PixelFormat::Rgba | PixelFormat::Rgb24 => [0, 1, 2],
+            PixelFormat::Bgra => [2, 1, 0],
+            // RGB を持たない形式では輝度を返す
+            PixelFormat::Gray8 | PixelFormat::Yuv444 => [0, 0, 0],
+        }
+    }
+}
+
+// キャプチャからは RGBA で届くので、それを指定のフォーマットに変換しながら dst に追記する。
+pub fn extend_converted(dst: &mut Vec<u8>, src: &[u8], format: PixelFormat) {
+    match format {
+        PixelFormat::Rgba => dst.extend_from_slice(src),
+        PixelFormat::Bgra => extend_swapped_red_blue(dst, src),
+        PixelFormat::Rgb24 => dst.extend(
+            src.chunks_exact(4)
+                .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]),
+        ),
+        PixelFormat::Gray8 => dst.extend(
+            src.chunks_exact(4)
+                .map(|pixel| luma(pixel[0], pixel[1], pixel[2])),
+        ),
+        PixelFormat::Yuv444 => dst.extend(src.chunks_exact(4).flat_map(|pixel| {
+            let (r, g, b) = (pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+            let u = ((-43 * r - 85 * g + 128 * b) >> 8) + 128;
+            let v = ((128 * r - 107 * g - 21 * b) >> 8) + 128;
+            [
+                luma(pixel[0], pixel[1], pixel[2]),
+                u.clamp(0, 255) as u8,
+                v.clamp(0, 255) as u8,
+            ]
+        })),
+    }
+}
+
+// BT.601
+pub(crate) fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((77 * r as u32 + 150 * g as u32 + 29 * b as u32) >> 8) as u8
+}
+
+fn extend_swapped_red_blue(dst: &mut Vec<u8>, src: &[u8]) {
+    let start = dst.len();
+    dst.resize(start + src.len(), 0);
+    let out = &mut dst[start..];
+
+    #[allow(unused_mut)]
+    let mut done = 0;
+
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("ssse3") {
+        done = unsafe { swap_red_blue_ssse3(src, out) };
+    }
+
+    // SIMD で処理しきれなかった端数 (あるいは SIMD が使えない環境) は 1 ピクセルずつ
+    for (s, d) in src[done..]
+        .chunks_exact(4)
+        .zip(out[done..].chunks_exact_mut(4))
+    {
+        d.copy_from_slice(&[s[2], s[1], s[0], s[3]]);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "ssse3")]
+unsafe fn swap_red_blue_ssse3(src: &[u8], dst: &mut [u8]) -> usize {
+    use std::arch::x86_64::{
+        __m128i, _mm_loadu_si128, _mm_setr_epi8, _mm_shuffle_epi8, _mm_storeu_si128,
+    };
+
+    let mask = _mm_setr_epi8(2, 1, 0, 3, 6, 5, 4, 7, 10, 9, 8, 11, 14, 13, 12, 15);
+    let len = src.len() / 16 * 16;
+    for i in (0..len).step_by(16) {
+        let pixels = _mm_loadu_si128(src.as_ptr().add(i) as *const __m128i);
+        _mm_storeu_si128(
+            dst.as_mut_ptr().add(i) as *mut __m128i,
+            _mm_shuffle_epi8(pixels, mask),
+        );
+    }
+
+    len
+}
@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use crate::pixel_format::{self, PixelFormat};
+
+// キャプチャが届ける RGBA のバッファから、送り出すフレームのバイト列を作る。
+// raw は各行が row_pitch バイトに切り上げられているので、そのままは使えない。
+pub trait PixelSampler: Send + Sync {
+    fn format(&self) -> PixelFormat;
+
+    fn interpret(&self, raw: &[u8], row_pitch: usize, width: u32, height: u32) -> Vec<u8>;
+}
+
+pub struct RawRgba;
+
+impl PixelSampler for RawRgba {
+    fn format(&self) -> PixelFormat {
+        PixelFormat::Rgba
+    }
+
+    fn interpret(&self, raw: &[u8], row_pitch: usize, width: u32, height: u32) -> Vec<u8> {
+        // 変換がいらないので行ごとにまとめてコピーする
+        let row_len = width as usize * 4;
+        let mut bytes = Vec::with_capacity(row_len * height as usize);
+        for row in raw.chunks(row_pitch).take(height as usize) {
+            bytes.extend_from_slice(&row[..row_len]);
+        }
+
+        bytes
+    }
+}
+
+pub struct ConvertFormat(pub PixelFormat);
+
+impl PixelSampler for ConvertFormat {
+    fn format(&self) -> PixelFormat {
+        self.0
+    }
+
+    fn interpret(&self, raw: &[u8], row_pitch: usize, width: u32, height: u32) -> Vec<u8> {
+        convert_rows(raw, row_pitch, width, height, self.0)
+    }
+}
+
+pub struct ToGrayscale;
+
+impl PixelSampler for ToGrayscale {
+    fn format(&self) -> PixelFormat {
+        PixelFormat::Gray8
+    }
+
+    fn interpret(&self, raw: &[u8], row_pitch: usize, width: u32, height: u32) -> Vec<u8> {
+        convert_rows(raw, row_pitch, width, height, PixelFormat::Gray8)
+    }
+}
+
+pub struct ToYuv;
+
+impl PixelSampler for ToYuv {
+    fn format(&self) -> PixelFormat {
+        PixelFormat::Yuv444
+    }
+
+    fn interpret(&self, raw: &[u8], row_pitch: usize, width: u32, height: u32) -> Vec<u8> {
+        convert_rows(raw, row_pitch, width, height, PixelFormat::Yuv444)
+    }
+}
+
+// 指定がなければ output_format に合わせる
+pub fn default_sampler(format: PixelFormat) -> Arc<dyn PixelSampler> {
+    match format {
+        PixelFormat::Rgba => Arc::new(RawRgba),
+        PixelFormat::Gray8 => Arc::new(ToGrayscale),
+        PixelFormat::Yuv444 => Arc::new(ToYuv),
+        format => Arc::new(ConvertFormat(format)),
+    }
+}
+
+fn convert_rows(
+    raw: &[u8],
+    row_pitch: usize,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+) -> Vec<u8> {
+    let row_len = width as usize * 4;
+    let mut bytes = Vec::with_capacity(width as usize * height as usize * format.bytes_per_pixel());
+    for row in raw.chunks(row_pitch).take(height as usize) {
+        pixel_format::extend_converted(&mut bytes, &row[..row_len], format);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+
+    use super::*;
+    use crate::test_utils::bench;
+
+    // キャプチャと同じく、行を 64 ピクセルの倍数に切り上げたバッファ
+    fn padded_buffer(width: u32, height: u32) -> (Vec<u8>, usize) {
+        let row_pitch = (width as usize).div_ceil(64) * 64 * 4;
+        let raw = (0..row_pitch * height as usize)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        (raw, row_pitch)
+    }
+
+    // 行をまとめてコピーする前のやり方。1 ピクセルずつ extend する
+    fn copy_per_pixel(raw: &[u8], row_pitch: usize, width: u32, height: u32) -> Vec<u8> {
+        let row_len = width as usize * 4;
+        let mut bytes = Vec::with_capacity(row_len * height as usize);
+        for row in raw.chunks(row_pitch).take(height as usize) {
+            for pixel in row[..row_len].chunks_exact(4) {
+                bytes.extend_from_slice(pixel);
+            }
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn raw_rgba_drops_row_padding() {
+        let (raw, row_pitch) = padded_buffer(70, 3);
+        let bytes = RawRgba.interpret(&raw, row_pitch, 70, 3);
+        assert_eq!(bytes.len(), 70 * 3 * 4);
+        assert_eq!(bytes, copy_per_pixel(&raw, row_pitch, 70, 3));
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_row_copy() {
+        for (name, width, height) in [("1080p", 1920, 1080), ("4K", 3840, 2160)] {
+            let (raw, row_pitch) = padded_buffer(width, height);
+            let per_pixel = bench(&format!("{name} per pixel"), 50, || {
+                black_box(copy_per_pixel(black_box(&raw), row_pitch, width, height));
+            });
+            let per_row = bench(&format!("{name} per row"), 50, || {
+                black_box(RawRgba.interpret(black_box(&raw), row_pitch, width, height));
+            });
+            println!(
+                "{name}: per row is {:.1}x faster",
+                per_pixel.as_secs_f64() / per_row.as_secs_f64()
+            );
+        }
+    }
+
+    // サンプラーの CPU での変換の速さを 4K で測る。GPU との比較は gpu_converter のベンチマークでやる
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_cpu_conversion_at_4k() {
+        let (width, height) = (3840, 2160);
+        let (raw, row_pitch) = padded_buffer(width, height);
+        for format in [
+            PixelFormat::Rgba,
+            PixelFormat::Bgra,
+            PixelFormat::Rgb24,
+            PixelFormat::Gray8,
+            PixelFormat::Yuv444,
+        ] {
+            let sampler = default_sampler(format);
+            let per_frame = bench(&format!("4K to {format:?}"), 20, || {
+                black_box(sampler.interpret(black_box(&raw), row_pitch, width, height));
+            });
+            println!(
+                "4K to {format:?}: {:.0} MB/s",
+                raw.len() as f64 / per_frame.as_secs_f64() / 1e6
+            );
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use crate::config::DriverConfig;
+
+// DriverConfig を名前付きでファイルに保存しておき、ホットキーで切り替えられるようにする
+pub struct PresetManager {
+    dir: PathBuf,
+    hotkeys: BTreeMap<u32, String>,
+}
+
+impl PresetManager {
+    // 何も割り当てなければ Ctrl+N は "N" という名前のプリセットを読み込む
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            hotkeys: (1..=9).map(|id| (id, id.to_string())).collect(),
+        }
+    }
+
+    pub fn bind(&mut self, hotkey_id: u32, name: String) {
+        self.hotkeys.insert(hotkey_id, name);
+    }
+
+    pub fn preset_for(&self, hotkey_id: u32) -> Option<&str> {
+        self.hotkeys.get(&hotkey_id).map(|name| name.as_str())
+    }
+
+    pub fn save(&self, name: &str, config: &DriverConfig) -> Result<PathBuf, String> {
+        let path = self.path_of(name)?;
+        let toml = toml::to_string_pretty(config)
+            .map_err(|e| format!("failed to serialize preset {name}: {e}"))?;
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("failed to create {}: {e}", self.dir.display()))?;
+        fs::write(&path, toml).map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+
+        Ok(path)
+    }
+
+    pub fn load(&self, name: &str) -> Result<DriverConfig, String> {
+        DriverConfig::from_file(self.path_of(name)?)
+    }
+
+    fn path_of(&self, name: &str) -> Result<PathBuf, String> {
+        // 名前はそのままファイル名になるので、ディレクトリの外を指せないようにしておく
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(format!("invalid preset name: {name}"));
+        }
+
+        Ok(self.dir.join(format!("{name}.toml")))
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::c_void,
+    fmt::Write,
+    mem, ptr, thread,
+    time::{Duration, Instant},
+};
+
+use windows::{
+    Wdk::System::Threading::{NtQueryInformationThread, ThreadBasicInformation},
+    Win32::{
+        Foundation::{CloseHandle, HANDLE},
+        System::{
+            Diagnostics::{
+                Debug::{
+                    GetThreadContext, RtlLookupFunctionEntry, RtlVirtualUnwind, CONTEXT,
+                    CONTEXT_FULL_AMD64, UNW_FLAG_NHANDLER,
+                },
+                ToolHelp::{
+                    CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD,
+                    THREADENTRY32,
+                },
+            },
+            Kernel::NT_TIB,
+            Threading::{
+                GetCurrentProcessId, GetCurrentThreadId, OpenThread, ResumeThread, SuspendThread,
+                THREAD_GET_CONTEXT, THREAD_QUERY_INFORMATION, THREAD_SUSPEND_RESUME,
+            },
+        },
+    },
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+// これより深いスタックは途中で切る
+const MAX_DEPTH: usize = 128;
+
+// スタックを写しておく領域の上限。これより多く使っているスレッドは飛ばす
+const MAX_STACK_COPY: usize = 8 * 1024 * 1024;
+
+// 止めている間にスタックが伸びても写せるように、コミット済みの分に足しておく余裕
+const STACK_COPY_HEADROOM: usize = 64 * 1024;
+
+// GetThreadContext に渡す CONTEXT は 16 バイト境界に置かないといけない
+#[repr(C, align(16))]
+struct AlignedContext(CONTEXT);
+
+// NtQueryInformationThread(ThreadBasicInformation) の結果。windows クレートには定義がない
+#[repr(C)]
+struct ThreadBasicInfo {
+    exit_status: i32,
+    teb_base_address: *const NT_TIB,
+    client_id: [usize; 2],
+    affinity_mask: usize,
+    priority: i32,
+    base_priority: i32,
+}
+
+// backtrace::Backtrace は自分のスレッドのスタックしか取れないので、ほかのスレッドは止めて
+// レジスタとスタックを写し、RtlVirtualUnwind で写しをたどる。止めている間は相手がヒープや
+// 関数テーブルのロックを持っているかもしれないので、確保済みの領域に写すだけにして、
+// RtlLookupFunctionEntry も関数名を引くのも再開させてからにする。
+pub struct Profiler {
+    samples: HashMap<(u32, Vec<u64>), u64>,
+    stack: StackCopy,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            samples: HashMap::new(),
+            stack: StackCopy::new(),
+        }
+    }
+
+    // duration の間、このプロセスのほかのスレッドのスタックを集め、folded stacks 形式で返す
+    pub fn run(mut self, duration: Duration) -> String {
+        let started_at = Instant::now();
+        let mut frames = [0u64; MAX_DEPTH];
+        while started_at.elapsed() < duration {
+            for thread_id in other_threads() {
+                let depth = sample_thread(thread_id, &mut self.stack, &mut frames);
+                if depth > 0 {
+                    *self
+                        .samples
+                        .entry((thread_id, frames[..depth].to_vec()))
+                        .or_default() += 1;
+                }
+            }
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+
+        self.fold()
+    }
+
+    fn fold(&self) -> String {
+        let mut names: HashMap<u64, String> = HashMap::new();
+        let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+        for ((thread_id, frames), count) in &self.samples {
+            let mut stack = format!("thread-{thread_id}");
+            // 外側の関数から順に並べる
+            for &ip in frames.iter().rev() {
+                let name = names.entry(ip).or_insert_with(|| symbol_name(ip));
+                stack.push(';');
+                stack.push_str(name);
+            }
+            *folded.entry(stack).or_default() += count;
+        }
+
+        let mut buf = String::new();
+        for (stack, count) in folded {
+            writeln!(buf, "{stack} {count}").unwrap();
+        }
+
+        buf
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn other_threads() -> Vec<u32> {
+    let Ok(snapshot) = (unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) }) else {
+        return vec![];
+    };
+
+    let (process_id, current) = unsafe { (GetCurrentProcessId(), GetCurrentThreadId()) };
+    let mut threads = vec![];
+    let mut entry = THREADENTRY32 {
+        dwSize: mem::size_of::<THREADENTRY32>() as u32,
+        ..Default::default()
+    };
+    let mut found = unsafe { Thread32First(snapshot, &mut entry) }.is_ok();
+    while found {
+        if entry.th32OwnerProcessID == process_id && entry.th32ThreadID != current {
+            threads.push(entry.th32ThreadID);
+        }
+        found = unsafe { Thread32Next(snapshot, &mut entry) }.is_ok();
+    }
+    let _ = unsafe { CloseHandle(snapshot) };
+
+    threads
+}
+
+// 取れたフレームの数を返す。frames[0] が一番内側
+fn sample_thread(thread_id: u32, stack: &mut StackCopy, frames: &mut [u64; MAX_DEPTH]) -> usize {
+    let access = THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_INFORMATION;
+    let Ok(thread) = (unsafe { OpenThread(access, false, thread_id) }) else {
+        return 0;
+    };
+
+    let mut context: AlignedContext = unsafe { mem::zeroed() };
+    context.0.ContextFlags = CONTEXT_FULL_AMD64;
+    let captured = match unsafe { thread_tib(thread) } {
+        Some(tib) => {
+            unsafe { stack.reserve(tib) };
+            if unsafe { SuspendThread(thread) } == u32::MAX {
+                false
+            } else {
+                let captured = unsafe { GetThreadContext(thread, &mut context.0) }.is_ok()
+                    && unsafe { stack.capture(&context.0, tib) };
+                unsafe { ResumeThread(thread) };
+                captured
+            }
+        }
+        None => false,
+    };
+    let _ = unsafe { CloseHandle(thread) };
+
+    if captured {
+        unsafe { walk_stack(&mut context.0, stack, frames) }
+    } else {
+        0
+    }
+}
+
+// TEB の先頭は NT_TIB になっていて、スタックの範囲が書いてある
+unsafe fn thread_tib(thread: HANDLE) -> Option<*const NT_TIB> {
+    let mut info: ThreadBasicInfo = mem::zeroed();
+    NtQueryInformationThread(
+        thread,
+        ThreadBasicInformation,
+        &mut info as *mut ThreadBasicInfo as *mut c_void,
+        mem::size_of::<ThreadBasicInfo>() as u32,
+        ptr::null_mut(),
+    )
+    .ok()?;
+
+    (!info.teb_base_address.is_null()).then_some(info.teb_base_address)
+}
+
+// 止めたときの Rsp から StackBase までの写し
+struct StackCopy {
+    buf: Vec<u64>,
+    // 写した先頭の、元のスタックでのアドレス
+    original: u64,
+    len: usize,
+}
+
+impl StackCopy {
+    fn new() -> Self {
+        Self {
+            buf: vec![],
+            original: 0,
+            len: 0,
+        }
+    }
+
+    // 止めている間は確保できないので、止める前に大きさを合わせておく
+    //
+    // # Safety
+    //
+    // tib は生きているスレッドのものでなければならない。
+    unsafe fn reserve(&mut self, tib: *const NT_TIB) {
+        let tib = ptr::read_volatile(tib);
+        let committed = (tib.StackBase as usize).saturating_sub(tib.StackLimit as usize);
+        let words = (committed + STACK_COPY_HEADROOM).min(MAX_STACK_COPY) / 8;
+        if self.buf.len() < words {
+            self.buf.resize(words, 0);
+        }
+    }
+
+    // Rsp がスタックの範囲外だったり、写しきれなかったりしたら false
+    //
+    // # Safety
+    //
+    // tib のスレッドは止まっていて、context はそのスレッドから取ったものでなければならない。
+    unsafe fn capture(&mut self, context: &CONTEXT, tib: *const NT_TIB) -> bool {
+        let tib = ptr::read_volatile(tib);
+        let (limit, base) = (tib.StackLimit as u64, tib.StackBase as u64);
+        let rsp = context.Rsp;
+        if rsp < limit || rsp >= base || !rsp.is_multiple_of(8) {
+            return false;
+        }
+
+        let len = (base - rsp) as usize;
+        if len > self.buf.len() * 8 {
+            return false;
+        }
+        ptr::copy_nonoverlapping(rsp as *const u64, self.buf.as_mut_ptr(), len / 8);
+        self.original = rsp;
+        self.len = len;
+
+        true
+    }
+
+    // 元のスタックを指しているレジスタを、写しの同じ場所を指すように直す
+    fn rebase(&self, context: &mut CONTEXT) {
+        let copy = self.buf.as_ptr() as u64;
+        let original = self.original..self.original + self.len as u64;
+        for register in [
+            &mut context.Rsp,
+            &mut context.Rbp,
+            &mut context.Rbx,
+            &mut context.Rsi,
+            &mut context.Rdi,
+            &mut context.R12,
+            &mut context.R13,
+            &mut context.R14,
+            &mut context.R15,
+        ] {
+            if original.contains(register) {
+                *register = *register - self.original + copy;
+            }
+        }
+    }
+
+    // 写しの中で addr から size バイト読めるか
+    fn contains(&self, addr: u64, size: u64) -> bool {
+        let copy = self.buf.as_ptr() as u64;
+        addr >= copy
+            && addr
+                .checked_add(size)
+                .is_some_and(|end| end <= copy + self.len as u64)
+    }
+}
+
+// # Safety
+//
+// context と stack は同じスレッドを止めている間に写したものでなければならない。
+unsafe fn walk_stack(
+    context: &mut CONTEXT,
+    stack: &StackCopy,
+    frames: &mut [u64; MAX_DEPTH],
+) -> usize {
+    stack.rebase(context);
+
+    let mut depth = 0;
+    while depth < MAX_DEPTH && context.Rip != 0 {
+        frames[depth] = context.Rip;
+        depth += 1;
+
+        if !stack.contains(context.Rsp, 8) {
+            break;
+        }
+
+        let mut image_base = 0;
+        let function = RtlLookupFunctionEntry(context.Rip, &mut image_base, None);
+        if function.is_null() {
+            // 関数テーブルにないのは、スタックを触らない末端の関数なら戻り先がスタックの先頭に
+            // ある。外側のフレームで見つからないのは JIT のコードなどで、戻り先がわからない
+            if depth > 1 {
+                break;
+            }
+            context.Rip = ptr::read(context.Rsp as *const u64);
+            context.Rsp += 8;
+        } else {
+            let rsp = context.Rsp;
+            let mut handler_data: *mut c_void = ptr::null_mut();
+            let mut establisher_frame = 0;
+            RtlVirtualUnwind(
+                UNW_FLAG_NHANDLER,
+                image_base,
+                context.Rip,
+                function,
+                context,
+                &mut handler_data,
+                &mut establisher_frame,
+                None,
+            );
+            stack.rebase(context);
+            // 外側へ進まないなら、壊れたスタックを読んでいる
+            if context.Rsp <= rsp {
+                break;
+            }
+        }
+    }
+
+    depth
+}
+
+fn symbol_name(ip: u64) -> String {
+    let mut name = None;
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        if name.is_none() {
+            name = symbol.name().map(|name| format!("{name:#}"));
+        }
+    });
+
+    name.unwrap_or_else(|| format!("{ip:#x}"))
+}
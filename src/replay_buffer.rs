@@ -0,0 +1,178 @@
+use std::{collections::VecDeque, time::Instant};
+
+use windows::Win32::Foundation::HWND;
+
+use crate::{pixel_format::PixelFormat, window_capture::CapturedFrame};
+
+// デスクトップの画面は同じ色が続くことが多いので、LZ4 でも数分の一になる
+pub struct CompressedCapturedFrame {
+    pub hwnd: HWND,
+    pub sequence: u64,
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub captured_at: Instant,
+    pub lz4_data: Vec<u8>,
+}
+
+impl CompressedCapturedFrame {
+    pub fn compress(frame: &CapturedFrame) -> Self {
+        Self {
+            hwnd: frame.hwnd,
+            sequence: frame.sequence,
+            width: frame.width,
+            height: frame.height,
+            format: frame.format,
+            captured_at: frame.captured_at,
+            lz4_data: lz4_flex::compress_prepend_size(&frame.bytes),
+        }
+    }
+
+    pub fn decompress(&self) -> Result<CapturedFrame, String> {
+        let bytes = lz4_flex::decompress_size_prepended(&self.lz4_data)
+            .map_err(|e| format!("failed to decompress frame {}: {e}", self.sequence))?;
+
+        Ok(CapturedFrame {
+            hwnd: self.hwnd,
+            sequence: self.sequence,
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            bytes,
+            captured_at: self.captured_at,
+            checksum: None,
+        })
+    }
+
+    pub fn raw_len(&self) -> usize {
+        self.width as usize * self.height as usize * self.format.bytes_per_pixel()
+    }
+}
+
+// 直近のフレームを圧縮して capacity 枚まで持っておく
+pub struct ReplayBuffer {
+    capacity: usize,
+    frames: VecDeque<CompressedCapturedFrame>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, frame: &CapturedFrame) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.frames.len() >= self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames
+            .push_back(CompressedCapturedFrame::compress(frame));
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = &CompressedCapturedFrame> {
+        self.frames.iter()
+    }
+
+    // 古い順に展開して返す
+    pub fn decompress_all(&self) -> Result<Vec<CapturedFrame>, String> {
+        self.frames
+            .iter()
+            .map(CompressedCapturedFrame::decompress)
+            .collect()
+    }
+
+    // (圧縮後のバイト数, 圧縮しなかった場合のバイト数)
+    pub fn memory_usage(&self) -> (usize, usize) {
+        self.frames.iter().fold((0, 0), |(compressed, raw), frame| {
+            (compressed + frame.lz4_data.len(), raw + frame.raw_len())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+
+    use super::*;
+    use crate::test_utils::bench;
+
+    // 圧縮の効かない最悪の場合として、xorshift で埋めたフレーム
+    fn noise_frame(width: u32, height: u32) -> CapturedFrame {
+        let mut frame = CapturedFrame::checkerboard(HWND(0), 1, width, height);
+        let mut state = 0x2545_f491_u32;
+        for byte in &mut frame.bytes {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *byte = state as u8;
+        }
+        frame
+    }
+
+    #[test]
+    fn compressed_frame_round_trips() {
+        let frame = noise_frame(33, 17);
+        let decompressed = CompressedCapturedFrame::compress(&frame)
+            .decompress()
+            .unwrap();
+        assert_eq!(decompressed.bytes, frame.bytes);
+        assert_eq!((decompressed.width, decompressed.height), (33, 17));
+    }
+
+    #[test]
+    fn replay_buffer_keeps_only_the_newest_frames() {
+        let mut buffer = ReplayBuffer::new(2);
+        for sequence in 1..=3 {
+            buffer.push(&CapturedFrame::checkerboard(HWND(0), sequence, 16, 16));
+        }
+        let sequences: Vec<_> = buffer.frames().map(|frame| frame.sequence).collect();
+        assert_eq!(sequences, vec![2, 3]);
+        assert_eq!(buffer.memory_usage().1, 2 * 16 * 16 * 4);
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_lz4_replay_buffer() {
+        let frames = [
+            (
+                "checkerboard",
+                CapturedFrame::checkerboard(HWND(0), 1, 1920, 1080),
+            ),
+            ("noise", noise_frame(1920, 1080)),
+        ];
+        for (name, frame) in frames {
+            let mut buffer = ReplayBuffer::new(1);
+            buffer.push(&frame);
+            let (compressed, raw) = buffer.memory_usage();
+            println!(
+                "{name}: {raw} -> {compressed} bytes ({:.1}x smaller)",
+                raw as f64 / compressed as f64
+            );
+
+            bench(&format!("{name} compress"), 20, || {
+                black_box(CompressedCapturedFrame::compress(black_box(&frame)));
+            });
+            let compressed = CompressedCapturedFrame::compress(&frame);
+            bench(&format!("{name} decompress"), 20, || {
+                black_box(black_box(&compressed).decompress().unwrap());
+            });
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crossbeam_channel::Sender;
+use windows::Win32::Foundation::HWND;
+
+use crate::{driver::PluginEvent, frame_plugin::FramePlugin, window_capture::CapturedFrame};
+
+// 全画素を比べるほどの精度はいらないので間引いて見る
+const SAMPLE_STEP: usize = 8;
+
+// 平均フレームに新しいフレームを混ぜる割合
+const AVERAGE_WEIGHT: f64 = 0.1;
+
+pub struct SceneChangeDetector {
+    threshold: f64,
+    tx_event: Sender<PluginEvent>,
+    hwnd: Option<HWND>,
+    size: (u32, u32),
+    average: Vec<f64>,
+}
+
+impl SceneChangeDetector {
+    pub fn new(threshold: f64, tx_event: Sender<PluginEvent>) -> Self {
+        Self {
+            threshold,
+            tx_event,
+            hwnd: None,
+            size: (0, 0),
+            average: vec![],
+        }
+    }
+}
+
+impl FramePlugin for SceneChangeDetector {
+    fn name(&self) -> &str {
+        "scene"
+    }
+
+    fn process(&mut self, frame: &mut CapturedFrame) {
+        let samples = sample_luma(frame);
+
+        // 別のウィンドウやサイズ変更の直後は比べる相手がいないので平均を取り直す
+        if self.hwnd != Some(frame.hwnd) || self.size != (frame.width, frame.height) {
+            self.hwnd = Some(frame.hwnd);
+            self.size = (frame.width, frame.height);
+            self.average = samples;
+            return;
+        }
+
+        if samples.is_empty() {
+            return;
+        }
+
+        let delta = samples
+            .iter()
+            .zip(&self.average)
+            .map(|(sample, average)| (sample - average).abs())
+            .sum::<f64>()
+            / samples.len() as f64
+            / 255.0;
+
+        if delta > self.threshold {
+            let _ = self.tx_event.send(PluginEvent::SceneChange {
+                hwnd: frame.hwnd,
+                delta,
+            });
+            // 切り替わった後のシーンを基準にする
+            self.average = samples;
+        } else {
+            for (average, sample) in self.average.iter_mut().zip(&samples) {
+                *average += (sample - *average) * AVERAGE_WEIGHT;
+            }
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "threshold" => {
+                let threshold: f64 = value
+                    .parse()
+                    .map_err(|_| format!("invalid threshold: {value}"))?;
+                if !(0.0..=1.0).contains(&threshold) {
+                    return Err(format!("threshold must be between 0 and 1: {value}"));
+                }
+                self.threshold = threshold;
+            }
+            _ => return Err(format!("unknown parameter: {name}")),
+        }
+
+        Ok(())
+    }
+}
+
+fn sample_luma(frame: &CapturedFrame) -> Vec<f64> {
+    let channels = frame.format.bytes_per_pixel();
+    let [r, g, b] = frame.format.rgb_offsets();
+
+    frame
+        .bytes
+        .chunks_exact(channels)
+        .step_by(SAMPLE_STEP)
+        .map(|pixel| 0.299 * pixel[r] as f64 + 0.587 * pixel[g] as f64 + 0.114 * pixel[b] as f64)
+        .collect()
+}
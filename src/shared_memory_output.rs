@@ -0,0 +1,171 @@
+use std::{
+    mem, ptr,
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE},
+        System::{
+            Memory::{
+                CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS,
+                MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+            },
+            Threading::{CreateEventW, SetEvent},
+        },
+    },
+};
+
+use crate::window_capture::CapturedFrame;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SharedMemoryOptions {
+    pub name: String,
+    pub capacity: usize,
+}
+
+// 共有メモリの先頭に置くヘッダで、フレームの中身はその直後に続く。sequence は seqlock に
+// なっていて、書いている間は奇数、書き終えると偶数になる。読み手は偶数の sequence を読んでから
+// ヘッダと中身を写し、もう一度 sequence を読んで同じなら写したものを使う。
+#[repr(C)]
+struct FrameHeader {
+    sequence: u64,
+    width: u32,
+    height: u32,
+    format: u32,
+    len: u32,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<FrameHeader>();
+
+pub struct SharedMemoryOutput {
+    mapping: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    event: HANDLE,
+    capacity: usize,
+    sequence: u64,
+}
+
+impl SharedMemoryOutput {
+    pub fn new(options: &SharedMemoryOptions) -> windows::core::Result<Self> {
+        let size = HEADER_SIZE + options.capacity;
+        let mapping = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                (size as u64 >> 32) as u32,
+                size as u32,
+                &HSTRING::from(options.name.as_str()),
+            )?
+        };
+
+        let view = unsafe { MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, size) };
+        if view.Value.is_null() {
+            let e = windows::core::Error::from_win32();
+            unsafe {
+                let _ = CloseHandle(mapping);
+            }
+            return Err(e);
+        }
+
+        let event_name = HSTRING::from(format!("{}.event", options.name));
+        let event = match unsafe { CreateEventW(None, false, false, &event_name) } {
+            Ok(event) => event,
+            Err(e) => {
+                unsafe {
+                    let _ = UnmapViewOfFile(view);
+                    let _ = CloseHandle(mapping);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            mapping,
+            view,
+            event,
+            capacity: options.capacity,
+            sequence: 0,
+        })
+    }
+
+    pub fn write(&mut self, frame: &CapturedFrame) -> Result<(), String> {
+        if frame.bytes.len() > self.capacity {
+            return Err(format!(
+                "frame of {} bytes does not fit in shared memory of {} bytes",
+                frame.bytes.len(),
+                self.capacity
+            ));
+        }
+
+        self.sequence += 1;
+        unsafe {
+            write_frame(self.view.Value as *mut u8, self.sequence, frame);
+            SetEvent(self.event).map_err(|e| format!("failed to signal frame event: {e}"))
+        }
+    }
+}
+
+// n 枚目のフレームを書き、sequence を 2n - 1 から 2n に進める
+//
+// # Safety
+//
+// base は 8 バイト境界にあり、HEADER_SIZE + frame.bytes.len() バイト書き込めなければならない。
+unsafe fn write_frame(base: *mut u8, n: u64, frame: &CapturedFrame) {
+    let sequence = &*(base as *const AtomicU64);
+    sequence.store(2 * n - 1, Ordering::Relaxed);
+    // 奇数にしたことが、ここから先の書き込みより先に見えるようにする
+    fence(Ordering::Release);
+
+    let header = base as *mut FrameHeader;
+    ptr::write_volatile(ptr::addr_of_mut!((*header).width), frame.width);
+    ptr::write_volatile(ptr::addr_of_mut!((*header).height), frame.height);
+    ptr::write_volatile(ptr::addr_of_mut!((*header).format), frame.format.code());
+    ptr::write_volatile(ptr::addr_of_mut!((*header).len), frame.bytes.len() as u32);
+    ptr::copy_nonoverlapping(
+        frame.bytes.as_ptr(),
+        base.add(HEADER_SIZE),
+        frame.bytes.len(),
+    );
+
+    sequence.store(2 * n, Ordering::Release);
+}
+
+impl Drop for SharedMemoryOutput {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.event);
+            let _ = UnmapViewOfFile(self.view);
+            let _ = CloseHandle(self.mapping);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::Foundation::HWND;
+
+    use super::*;
+
+    #[test]
+    fn written_frame_leaves_an_even_sequence_after_the_header() {
+        let frame = CapturedFrame::checkerboard(HWND(0), 1, 4, 2);
+        // u64 で確保して 8 バイト境界にそろえる
+        let mut buf = vec![0u64; (HEADER_SIZE + frame.bytes.len()).div_ceil(8)];
+        let base = buf.as_mut_ptr() as *mut u8;
+        unsafe {
+            write_frame(base, 1, &frame);
+            write_frame(base, 2, &frame);
+        }
+
+        let header = unsafe { ptr::read(base as *const FrameHeader) };
+        assert_eq!(header.sequence, 4);
+        assert_eq!((header.width, header.height), (4, 2));
+        assert_eq!(header.len as usize, frame.bytes.len());
+        let bytes = unsafe { std::slice::from_raw_parts(base.add(HEADER_SIZE), frame.bytes.len()) };
+        assert_eq!(bytes, &frame.bytes[..]);
+    }
+}
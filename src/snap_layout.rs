@@ -0,0 +1,104 @@
+use windows::Win32::{
+    Foundation::{HWND, RECT},
+    Graphics::Gdi::{GetMonitorInfoW, MonitorFromWindow, MONITORINFO, MONITOR_DEFAULTTONEAREST},
+    UI::WindowsAndMessaging::{GetWindowRect, IsIconic},
+};
+
+use crate::foreground_watcher::ForegroundWatcher;
+
+// ウィンドウの矩形には見えない枠が含まれるので、ぴったり一致はしない
+const TOLERANCE: i32 = 16;
+
+pub struct SnapLayout {
+    pub primary: HWND,
+    pub secondary: Option<HWND>,
+}
+
+pub struct SnapLayoutDetector;
+
+impl SnapLayoutDetector {
+    pub fn detect(hwnd: HWND) -> Option<SnapLayout> {
+        let work_area = work_area_of(hwnd)?;
+        let regions = snap_regions(&work_area);
+
+        let rect = window_rect(hwnd)?;
+        let primary_region = regions.iter().position(|region| matches(&rect, region))?;
+
+        // 同じモニタの別の領域にスナップされているウィンドウを Z オーダー順に探す
+        let secondary = top_level_windows().into_iter().find(|&other| {
+            other != hwnd
+                && work_area_of(other).is_some_and(|area| same_rect(&area, &work_area))
+                && window_rect(other).is_some_and(|other_rect| {
+                    regions
+                        .iter()
+                        .enumerate()
+                        .any(|(i, region)| i != primary_region && matches(&other_rect, region))
+                })
+        });
+
+        Some(SnapLayout {
+            primary: hwnd,
+            secondary,
+        })
+    }
+}
+
+fn work_area_of(hwnd: HWND) -> Option<RECT> {
+    let monitor = unsafe { MonitorFromWindow(hwnd, MONITOR_DEFAULTTONEAREST) };
+    let mut info = MONITORINFO {
+        cbSize: std::mem::size_of::<MONITORINFO>() as u32,
+        ..Default::default()
+    };
+    unsafe { GetMonitorInfoW(monitor, &mut info) }
+        .as_bool()
+        .then_some(info.rcWork)
+}
+
+fn window_rect(hwnd: HWND) -> Option<RECT> {
+    let mut rect = RECT::default();
+    unsafe { GetWindowRect(hwnd, &mut rect) }.ok()?;
+    Some(rect)
+}
+
+// 左右・上下の半分と四隅の 1/4
+fn snap_regions(area: &RECT) -> Vec<RECT> {
+    let mid_x = (area.left + area.right) / 2;
+    let mid_y = (area.top + area.bottom) / 2;
+    let rect = |left, top, right, bottom| RECT {
+        left,
+        top,
+        right,
+        bottom,
+    };
+
+    vec![
+        rect(area.left, area.top, mid_x, area.bottom),
+        rect(mid_x, area.top, area.right, area.bottom),
+        rect(area.left, area.top, area.right, mid_y),
+        rect(area.left, mid_y, area.right, area.bottom),
+        rect(area.left, area.top, mid_x, mid_y),
+        rect(mid_x, area.top, area.right, mid_y),
+        rect(area.left, mid_y, mid_x, area.bottom),
+        rect(mid_x, mid_y, area.right, area.bottom),
+    ]
+}
+
+fn matches(rect: &RECT, region: &RECT) -> bool {
+    (rect.left - region.left).abs() <= TOLERANCE
+        && (rect.top - region.top).abs() <= TOLERANCE
+        && (rect.right - region.right).abs() <= TOLERANCE
+        && (rect.bottom - region.bottom).abs() <= TOLERANCE
+}
+
+fn same_rect(a: &RECT, b: &RECT) -> bool {
+    a.left == b.left && a.top == b.top && a.right == b.right && a.bottom == b.bottom
+}
+
+// 最小化されたものは画面に並んでいないので除く
+fn top_level_windows() -> Vec<HWND> {
+    ForegroundWatcher::enumerate_windows()
+        .into_iter()
+        .map(|(hwnd, _)| hwnd)
+        .filter(|&hwnd| !unsafe { IsIconic(hwnd) }.as_bool())
+        .collect()
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::VecDeque,
+    fmt, mem,
+    time::{Duration, Instant},
+};
+
+use windows::Win32::{
+    Foundation::HANDLE,
+    System::{
+        Memory::{GetProcessHeaps, HeapCompact, HEAP_NONE},
+        ProcessStatus::{
+            GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS, PROCESS_MEMORY_COUNTERS_EX,
+        },
+        Threading::GetCurrentProcess,
+    },
+};
+
+// この時間フレームが来なければ止まっているとみなす
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// キャプチャは中身が変わったときにしかフレームを届けないので、来ないだけでは止まったとは言えない。
+// 間隔がこれより短いフレームがこれより長く続いていた (動画やゲームのように動き続けていた) のに
+// 来なくなったときだけ止まったとみなし、静止しているウィンドウは見ない。
+const STREAMING_FRAME_GAP: Duration = Duration::from_millis(500);
+const MIN_STREAMING_DURATION: Duration = Duration::from_secs(5);
+
+// 判断できるだけのフレームが来るまでは落ちた割合を見ない
+const MIN_FRAMES_FOR_DROP_RATE: u64 = 30;
+const MAX_DROP_RATE: f64 = 0.1;
+
+const MAX_ERRORS: usize = 5;
+// 古いエラーでいつまでも Failed のままにならないよう、この間に起きたものだけ数える
+const ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default)]
+pub struct CaptureStats {
+    pub frames_received: u64,
+    pub frames_dropped: u64,
+    pub last_render_time_us: u64,
+    pub last_latency_us: u64,
+    pub slow_frames_in_a_row: u32,
+    pub first_frame_at: Option<Instant>,
+    pub last_frame_at: Option<Instant>,
+    pub bytes_received: u64,
+    // これまでのエラーの数。health は recent_errors の方を見る
+    pub errors: u32,
+    pub jitter_mean_ms: f32,
+    pub jitter_std_ms: f32,
+    recent_errors: VecDeque<Instant>,
+    // 今続いている、フレームが途切れずに来ている区間の始まり
+    streaming_since: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessMemory {
+    pub working_set_bytes: u64,
+    pub private_bytes: u64,
+    pub peak_working_set_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureHealth {
+    Healthy,
+    Degraded { reason: String },
+    Failed { reason: String },
+}
+
+impl CaptureStats {
+    pub fn measured_fps(&self) -> f64 {
+        let Some(first_frame_at) = self.first_frame_at else {
+            return 0.0;
+        };
+        let elapsed = first_frame_at.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+
+        self.frames_received as f64 / elapsed
+    }
+
+    pub fn avg_frame_bytes(&self) -> u64 {
+        self.bytes_received
+            .checked_div(self.frames_received)
+            .unwrap_or(0)
+    }
+
+    pub fn record_frame(&mut self, bytes: usize) {
+        self.record_frame_at(Instant::now(), bytes);
+    }
+
+    pub fn record_error(&mut self) {
+        self.record_error_at(Instant::now());
+    }
+
+    pub fn health(&self) -> CaptureHealth {
+        self.health_at(Instant::now())
+    }
+
+    fn record_frame_at(&mut self, now: Instant, bytes: usize) {
+        self.frames_received += 1;
+        self.bytes_received += bytes as u64;
+        self.first_frame_at.get_or_insert(now);
+
+        match self.last_frame_at {
+            Some(last) if now.saturating_duration_since(last) <= STREAMING_FRAME_GAP => {
+                self.streaming_since.get_or_insert(last);
+            }
+            _ => self.streaming_since = None,
+        }
+        self.last_frame_at = Some(now);
+    }
+
+    fn record_error_at(&mut self, now: Instant) {
+        self.errors += 1;
+        self.recent_errors.push_back(now);
+        // MAX_ERRORS 件あれば判断できるので、それより古いものは持たない
+        while self.recent_errors.len() > MAX_ERRORS {
+            self.recent_errors.pop_front();
+        }
+    }
+
+    // 理由の文字列は変化の検出にも使うので、値が少し変わるたびに変わらないようにしておく
+    fn health_at(&self, now: Instant) -> CaptureHealth {
+        let recent_errors = self
+            .recent_errors
+            .iter()
+            .filter(|&&at| now.saturating_duration_since(at) <= ERROR_WINDOW)
+            .count();
+        if recent_errors >= MAX_ERRORS {
+            return CaptureHealth::Failed {
+                reason: "too many errors".into(),
+            };
+        }
+
+        if let (Some(last_frame_at), Some(streaming_since)) =
+            (self.last_frame_at, self.streaming_since)
+        {
+            if last_frame_at - streaming_since >= MIN_STREAMING_DURATION
+                && now.saturating_duration_since(last_frame_at) > HEARTBEAT_TIMEOUT
+            {
+                return CaptureHealth::Degraded {
+                    reason: format!("no frames for {}s", HEARTBEAT_TIMEOUT.as_secs()),
+                };
+            }
+        }
+
+        if self.frames_received >= MIN_FRAMES_FOR_DROP_RATE {
+            let drop_rate = self.frames_dropped as f64 / self.frames_received as f64;
+            if drop_rate > MAX_DROP_RATE {
+                return CaptureHealth::Degraded {
+                    reason: format!("over {:.0}% frames dropped", MAX_DROP_RATE * 100.0),
+                };
+            }
+        }
+
+        if recent_errors > 0 {
+            return CaptureHealth::Degraded {
+                reason: "errors reported".into(),
+            };
+        }
+
+        CaptureHealth::Healthy
+    }
+}
+
+impl fmt::Display for CaptureHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureHealth::Healthy => write!(f, "healthy"),
+            CaptureHealth::Degraded { reason } => write!(f, "degraded ({reason})"),
+            CaptureHealth::Failed { reason } => write!(f, "failed ({reason})"),
+        }
+    }
+}
+
+impl ProcessMemory {
+    pub fn query() -> Result<Self, String> {
+        let mut counters = PROCESS_MEMORY_COUNTERS_EX {
+            cb: mem::size_of::<PROCESS_MEMORY_COUNTERS_EX>() as u32,
+            ..Default::default()
+        };
+        // PrivateUsage が欲しいので EX の方を渡す (先頭は PROCESS_MEMORY_COUNTERS と同じ形)
+        unsafe {
+            GetProcessMemoryInfo(
+                GetCurrentProcess(),
+                &mut counters as *mut PROCESS_MEMORY_COUNTERS_EX as *mut PROCESS_MEMORY_COUNTERS,
+                counters.cb,
+            )
+        }
+        .map_err(|e| format!("failed to get process memory info: {e}"))?;
+
+        Ok(Self {
+            working_set_bytes: counters.WorkingSetSize as u64,
+            private_bytes: counters.PrivateUsage as u64,
+            peak_working_set_bytes: counters.PeakWorkingSetSize as u64,
+        })
+    }
+
+    // プロセスの全ヒープに HeapCompact をかけ、かけたヒープの数を返す。Rust のアロケータは
+    // 解放したメモリを OS に返すとは約束していないので、減らなくてもおかしくはない。調査用。
+    pub fn compact_heaps() -> usize {
+        let count = unsafe { GetProcessHeaps(&mut []) } as usize;
+        let mut heaps = vec![HANDLE::default(); count];
+        // 数えてから取るまでに増えていたら、入りきった分だけ見る
+        let count = (unsafe { GetProcessHeaps(&mut heaps) } as usize).min(heaps.len());
+
+        for &heap in &heaps[..count] {
+            unsafe { HeapCompact(heap, HEAP_NONE) };
+        }
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // start から interval ごとに count 枚届いたことにする
+    fn stream(stats: &mut CaptureStats, start: Instant, interval: Duration, count: u32) {
+        for i in 0..count {
+            stats.record_frame_at(start + interval * i, 100);
+        }
+    }
+
+    #[test]
+    fn errors_stop_counting_once_they_are_old() {
+        let start = Instant::now();
+        let mut stats = CaptureStats::default();
+        for i in 0..MAX_ERRORS as u32 {
+            stats.record_error_at(start + Duration::from_secs(i.into()));
+        }
+        assert!(matches!(
+            stats.health_at(start + Duration::from_secs(5)),
+            CaptureHealth::Failed { .. }
+        ));
+        assert!(matches!(
+            stats.health_at(start + ERROR_WINDOW + Duration::from_millis(500)),
+            CaptureHealth::Degraded { .. }
+        ));
+        assert_eq!(
+            stats.health_at(start + ERROR_WINDOW + Duration::from_secs(10)),
+            CaptureHealth::Healthy
+        );
+        assert_eq!(stats.errors, MAX_ERRORS as u32);
+    }
+
+    #[test]
+    fn static_window_stays_healthy_without_frames() {
+        let start = Instant::now();
+        let mut stats = CaptureStats::default();
+        // 時々しか変わらないウィンドウ
+        stream(&mut stats, start, Duration::from_secs(2), 5);
+        assert_eq!(
+            stats.health_at(start + Duration::from_secs(60)),
+            CaptureHealth::Healthy
+        );
+    }
+
+    #[test]
+    fn stream_that_stops_is_degraded() {
+        let start = Instant::now();
+        let mut stats = CaptureStats::default();
+        let interval = Duration::from_millis(16);
+        stream(&mut stats, start, interval, 600);
+        let last = start + interval * 599;
+
+        assert_eq!(
+            stats.health_at(last + Duration::from_secs(1)),
+            CaptureHealth::Healthy
+        );
+        assert_eq!(
+            stats.health_at(last + HEARTBEAT_TIMEOUT + Duration::from_secs(1)),
+            CaptureHealth::Degraded {
+                reason: "no frames for 10s".into(),
+            }
+        );
+
+        // 間が空いてから届いたフレームでは、動き続けているとはみなさない
+        stats.record_frame_at(last + HEARTBEAT_TIMEOUT * 2, 100);
+        assert_eq!(
+            stats.health_at(last + HEARTBEAT_TIMEOUT * 4),
+            CaptureHealth::Healthy
+        );
+    }
+}
@@ -1,27 +1,101 @@
-use std::{ffi::OsString, fmt::Write as _, os::windows::prelude::OsStringExt, thread};
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write as _,
+    thread,
+};
 
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use rustyline::{DefaultEditor, ExternalPrinter};
 use windows::Win32::{
-    Foundation::{BOOL, HWND, LPARAM},
-    UI::WindowsAndMessaging::{EnumWindows, GetWindowLongW, GetWindowTextW, GWL_STYLE},
+    Foundation::HWND,
+    UI::WindowsAndMessaging::{GetWindowLongW, GWL_STYLE},
+};
+
+use crate::{
+    config::SceneRule, foreground_watcher::ForegroundWatcher, input_injector::InputInjector,
 };
 
 pub struct StdinShell {
     rx_cmd: Receiver<StdinShellCommand>,
     tx_msg: Sender<StdinShellMessage>,
     scan_result: Vec<ScanEntry>,
+    // 設定されている間は、ドライバからのメッセージを画面ではなくこのファイルに追記する
+    output_file: Option<File>,
 }
 
 pub enum StdinShellCommand {
     Quit,
     Output { message: String },
+    ConfigDump { json: String },
+    SceneRuleList(Vec<SceneRule>),
+    // 追加された順の (プラグイン名, 有効かどうか)
+    PluginList(Vec<(String, bool)>),
+    TestFrameAck { sequence: u64, latency_us: u64 },
+    // 値を変えるだけのコマンドへの返事
+    Ok,
+    Error(String),
 }
 
 pub enum StdinShellMessage {
     QuitRequested,
     AllowHWND(Vec<HWND>),
     ListRequested,
+    StatusRequested,
+    MemoryRequested,
+    CompactHeapsRequested,
+    Profile {
+        duration_ms: u64,
+    },
+    // イベントログに対して読むだけの SELECT を実行する
+    QueryEvents(String),
+    ConfigRequested,
+    PauseRequested,
+    ResumeRequested,
+    SetLogLevel(String),
+    SetCooldown(u64),
+    SetDebounce(u64),
+    ExportGraph {
+        path: String,
+    },
+    ExportStats(String),
+    ReloadWatermark,
+    HistoryRequested,
+    ClearHistory,
+    TrimHistory(usize),
+    AddSceneRule {
+        title_pattern: String,
+        scene_name: String,
+    },
+    RemoveSceneRule(usize),
+    SceneRulesRequested,
+    SetPluginParam {
+        plugin: String,
+        name: String,
+        value: String,
+    },
+    PluginsRequested,
+    SetTimestampFormat(String),
+    EnablePlugin(String),
+    DisablePlugin(String),
+    SetWarmupFrames {
+        hwnd: HWND,
+        frames: u32,
+        reset_warmup: bool,
+    },
+    ToggleDeduplication(HWND),
+    CaptureNow(HWND),
+    SetPriority {
+        hwnd: HWND,
+        priority: u8,
+    },
+    SavePreset(String),
+    LoadPreset(String),
+    InjectTestFrame(HWND),
+    SetChannelCapacity {
+        hwnd: HWND,
+        capacity: usize,
+    },
 }
 
 struct ScanEntry {
@@ -35,9 +109,208 @@ enum UserInput {
     Quit,
     AllowHWND(Vec<HWND>),
     List,
+    Status,
+    Memory,
+    Gc,
+    Profile(u64),
+    QueryEvents(String),
     Scan,
+    Config,
+    Pause,
+    Resume,
+    LogLevel(String),
+    Cooldown(u64),
+    Debounce(u64),
+    SetOutputPath(Option<String>),
+    Graph(String),
+    ExportStats(String),
+    ReloadWatermark,
+    History,
+    ClearHistory,
+    TrimHistory(usize),
+    Help,
+    Param {
+        plugin: String,
+        name: String,
+        value: String,
+    },
+    Plugins,
+    TimestampFormat(String),
+    EnablePlugin(String),
+    DisablePlugin(String),
+    Warmup {
+        hwnd: HWND,
+        frames: u32,
+        reset_warmup: bool,
+    },
+    Dedup(HWND),
+    Inject(HWND),
+    Snap(HWND),
+    Channel {
+        hwnd: HWND,
+        capacity: usize,
+    },
+    Priority {
+        hwnd: HWND,
+        priority: u8,
+    },
+    SavePreset(String),
+    LoadPreset(String),
+    Click {
+        hwnd: HWND,
+        x: i32,
+        y: i32,
+    },
+    AddRule {
+        title_pattern: String,
+        scene_name: String,
+    },
+    RemoveRule(usize),
+    ListRules,
 }
 
+// (コマンド, 引数, 説明)
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("quit", "", "stop capturing and exit"),
+    ("scan", "", "list opened windows with aliases"),
+    (
+        "allow",
+        "<HWND|alias>...",
+        "allow capturing the given windows",
+    ),
+    ("list", "", "show allowed and captured windows"),
+    (
+        "status",
+        "",
+        "show the current window and capture statistics",
+    ),
+    (
+        "memory",
+        "",
+        "show the memory usage of the process and frame queues",
+    ),
+    (
+        "gc",
+        "",
+        "compact the process heaps and report the change (diagnostic only)",
+    ),
+    (
+        "profile",
+        "<ms>",
+        "sample the stacks of all threads and print them as folded stacks",
+    ),
+    (
+        "events",
+        "<SELECT ...>",
+        "run a read-only query against the event log (needs the event-log feature)",
+    ),
+    ("config", "", "print the running configuration"),
+    ("pause", "", "pause all captures"),
+    ("resume", "", "resume all captures"),
+    (
+        "loglevel",
+        "<error|warn|info|debug>",
+        "change the level of messages reported by captures",
+    ),
+    (
+        "cooldown",
+        "<ms>",
+        "change how long a stopped capture waits before it may restart",
+    ),
+    (
+        "debounce",
+        "<ms>",
+        "change how long a window must stay in front before it is shown",
+    ),
+    (
+        "logfile",
+        "<path> | off",
+        "append messages to a file instead of the console",
+    ),
+    (
+        "param",
+        "<plugin> <name> <value>",
+        "change a parameter of a frame plugin",
+    ),
+    (
+        "plugins",
+        "",
+        "list frame plugins and whether they are enabled",
+    ),
+    (
+        "timestamp",
+        "<strftime format>",
+        "change the time format drawn by the timestamp plugin",
+    ),
+    (
+        "plugin",
+        "<enable|disable> <name>",
+        "turn a frame plugin on or off without removing it",
+    ),
+    (
+        "warmup",
+        "<HWND|alias> <frames> [reset]",
+        "change the number of frames skipped after a capture starts",
+    ),
+    (
+        "dedup",
+        "<HWND|alias>",
+        "toggle skipping frames identical to the previous one",
+    ),
+    (
+        "snap",
+        "<HWND|alias>",
+        "send the next frame of an on-demand capture",
+    ),
+    (
+        "inject",
+        "<HWND|alias>",
+        "show a checkerboard test frame as if it came from the window",
+    ),
+    (
+        "channel",
+        "<HWND|alias> <capacity>",
+        "restart a capture with a bounded frame channel of the given capacity",
+    ),
+    (
+        "priority",
+        "<HWND|alias> <0-255>",
+        "change how early a window's frames are handled (default 128)",
+    ),
+    (
+        "preset",
+        "save <name> | load <name>",
+        "save or load the running configuration (Ctrl+1..9 loads presets 1..9)",
+    ),
+    (
+        "click",
+        "<HWND|alias> <x> <y>",
+        "click at the given client coordinates of a window",
+    ),
+    (
+        "rule",
+        "add <pattern> <scene> | remove <index> | list",
+        "edit the scene switching rules",
+    ),
+    (
+        "graph",
+        "<path>",
+        "write the component graph as a DOT file and open it",
+    ),
+    (
+        "stats",
+        "<path>",
+        "append the capture statistics to a CSV file",
+    ),
+    ("watermark", "", "reload the watermark image from disk"),
+    (
+        "history",
+        "[clear | trim <count>]",
+        "show, clear or shorten the window switch history",
+    ),
+    ("help", "", "show this help"),
+];
+
 impl StdinShell {
     pub fn new() -> (Self, Sender<StdinShellCommand>, Receiver<StdinShellMessage>) {
         let (tx_cmd, rx_cmd) = unbounded();
@@ -48,6 +321,7 @@ impl StdinShell {
                 rx_cmd,
                 tx_msg,
                 scan_result: vec![],
+                output_file: None,
             },
             tx_cmd,
             rx_msg,
@@ -80,9 +354,150 @@ impl StdinShell {
                             .print("Requesting allowed HWNDs, press Enter to refresh...".into())
                             .unwrap();
                     }
+                    Ok(UserInput::Status) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::StatusRequested);
+                    }
+                    Ok(UserInput::Memory) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::MemoryRequested);
+                    }
+                    Ok(UserInput::Gc) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::CompactHeapsRequested);
+                    }
+                    Ok(UserInput::Profile(duration_ms)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::Profile { duration_ms });
+                    }
+                    Ok(UserInput::QueryEvents(sql)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::QueryEvents(sql));
+                    }
                     Ok(UserInput::Scan) => {
                         self.scan(&mut printer);
                     }
+                    Ok(UserInput::Config) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::ConfigRequested);
+                    }
+                    Ok(UserInput::Pause) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::PauseRequested);
+                    }
+                    Ok(UserInput::Resume) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::ResumeRequested);
+                    }
+                    Ok(UserInput::LogLevel(level)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::SetLogLevel(level));
+                    }
+                    Ok(UserInput::Cooldown(ms)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::SetCooldown(ms));
+                    }
+                    Ok(UserInput::Debounce(ms)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::SetDebounce(ms));
+                    }
+                    Ok(UserInput::SetOutputPath(path)) => {
+                        let message = self.set_output_path(path);
+                        printer.print(message).unwrap();
+                    }
+                    Ok(UserInput::Graph(path)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::ExportGraph { path });
+                    }
+                    Ok(UserInput::ExportStats(path)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::ExportStats(path));
+                    }
+                    Ok(UserInput::ReloadWatermark) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::ReloadWatermark);
+                    }
+                    Ok(UserInput::History) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::HistoryRequested);
+                    }
+                    Ok(UserInput::ClearHistory) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::ClearHistory);
+                    }
+                    Ok(UserInput::TrimHistory(len)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::TrimHistory(len));
+                    }
+                    Ok(UserInput::Help) => {
+                        printer.print(help()).unwrap();
+                    }
+                    Ok(UserInput::Param {
+                        plugin,
+                        name,
+                        value,
+                    }) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::SetPluginParam {
+                            plugin,
+                            name,
+                            value,
+                        });
+                    }
+                    Ok(UserInput::Plugins) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::PluginsRequested);
+                    }
+                    Ok(UserInput::TimestampFormat(format)) => {
+                        let _ = self
+                            .tx_msg
+                            .send(StdinShellMessage::SetTimestampFormat(format));
+                    }
+                    Ok(UserInput::EnablePlugin(name)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::EnablePlugin(name));
+                    }
+                    Ok(UserInput::DisablePlugin(name)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::DisablePlugin(name));
+                    }
+                    Ok(UserInput::Warmup {
+                        hwnd,
+                        frames,
+                        reset_warmup,
+                    }) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::SetWarmupFrames {
+                            hwnd,
+                            frames,
+                            reset_warmup,
+                        });
+                    }
+                    Ok(UserInput::Dedup(hwnd)) => {
+                        let _ = self
+                            .tx_msg
+                            .send(StdinShellMessage::ToggleDeduplication(hwnd));
+                    }
+                    Ok(UserInput::Snap(hwnd)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::CaptureNow(hwnd));
+                    }
+                    Ok(UserInput::Inject(hwnd)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::InjectTestFrame(hwnd));
+                    }
+                    Ok(UserInput::Channel { hwnd, capacity }) => {
+                        let _ = self
+                            .tx_msg
+                            .send(StdinShellMessage::SetChannelCapacity { hwnd, capacity });
+                    }
+                    Ok(UserInput::Priority { hwnd, priority }) => {
+                        let _ = self
+                            .tx_msg
+                            .send(StdinShellMessage::SetPriority { hwnd, priority });
+                    }
+                    Ok(UserInput::SavePreset(name)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::SavePreset(name));
+                    }
+                    Ok(UserInput::LoadPreset(name)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::LoadPreset(name));
+                    }
+                    Ok(UserInput::Click { hwnd, x, y }) => {
+                        if let Err(e) = InputInjector::new(hwnd).click(x, y) {
+                            printer.print(format!("shell: {e}")).unwrap();
+                        }
+                    }
+                    Ok(UserInput::AddRule {
+                        title_pattern,
+                        scene_name,
+                    }) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::AddSceneRule {
+                            title_pattern,
+                            scene_name,
+                        });
+                    }
+                    Ok(UserInput::RemoveRule(index)) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::RemoveSceneRule(index));
+                    }
+                    Ok(UserInput::ListRules) => {
+                        let _ = self.tx_msg.send(StdinShellMessage::SceneRulesRequested);
+                    }
                     Err(e) => printer.print(format!("shell: {e}")).unwrap(),
                 }
             };
@@ -91,13 +506,82 @@ impl StdinShell {
                 match cmd {
                     StdinShellCommand::Quit => break,
                     StdinShellCommand::Output { message } => {
-                        printer.print(message).unwrap();
+                        self.print_output(&mut printer, message);
+                    }
+                    StdinShellCommand::ConfigDump { json } => {
+                        printer.print(format!("Current config:\n{json}")).unwrap();
+                    }
+                    StdinShellCommand::SceneRuleList(rules) => {
+                        let mut buf = String::new();
+                        writeln!(&mut buf, "Scene rules:").unwrap();
+                        for (index, rule) in rules.iter().enumerate() {
+                            writeln!(
+                                &mut buf,
+                                "| {index}) {} -> {}",
+                                rule.title_pattern, rule.scene_name
+                            )
+                            .unwrap();
+                        }
+                        printer.print(buf).unwrap();
+                    }
+                    StdinShellCommand::PluginList(plugins) => {
+                        let mut buf = String::new();
+                        writeln!(&mut buf, "Plugins:").unwrap();
+                        for (name, enabled) in plugins {
+                            let state = if enabled { "enabled" } else { "disabled" };
+                            writeln!(&mut buf, "| {name} ({state})").unwrap();
+                        }
+                        printer.print(buf).unwrap();
+                    }
+                    StdinShellCommand::TestFrameAck {
+                        sequence,
+                        latency_us,
+                    } => {
+                        self.print_output(
+                            &mut printer,
+                            format!("test frame {sequence} rendered in {latency_us} us"),
+                        );
+                    }
+                    StdinShellCommand::Ok => self.print_output(&mut printer, "ok".into()),
+                    StdinShellCommand::Error(e) => {
+                        self.print_output(&mut printer, format!("error: {e}"));
                     }
                 }
             }
         }
     }
 
+    fn set_output_path(&mut self, path: Option<String>) -> String {
+        let Some(path) = path else {
+            self.output_file = None;
+            return "writing output to the console".into();
+        };
+
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => {
+                self.output_file = Some(file);
+                format!("writing output to {path}")
+            }
+            Err(e) => format!("shell: failed to open {path}: {e}"),
+        }
+    }
+
+    fn print_output<E: ExternalPrinter>(&mut self, printer: &mut E, message: String) {
+        if let Some(file) = &mut self.output_file {
+            if writeln!(file, "{message}").is_ok() {
+                return;
+            }
+
+            // 書けなくなったら取りこぼさないよう画面に戻す
+            self.output_file = None;
+            printer
+                .print("shell: failed to write output file, writing to the console".into())
+                .unwrap();
+        }
+
+        printer.print(message).unwrap();
+    }
+
     fn scan<E: ExternalPrinter>(&mut self, printer: &mut E) {
         let mut scan_result = enumerate_windows();
         for (alias, entry) in ('A'..='Z').zip(&mut scan_result) {
@@ -138,31 +622,274 @@ impl StdinShell {
             return Ok(UserInput::List);
         }
 
+        if args[0] == "status" {
+            return Ok(UserInput::Status);
+        }
+
+        if args[0] == "memory" {
+            return Ok(UserInput::Memory);
+        }
+
+        if args[0] == "gc" {
+            return Ok(UserInput::Gc);
+        }
+
+        if args[0] == "profile" {
+            let [_, ms] = args[..] else {
+                return Err("usage: profile <ms>".into());
+            };
+            let Ok(ms) = ms.parse() else {
+                return Err(format!("invalid duration: {ms}"));
+            };
+
+            return Ok(UserInput::Profile(ms));
+        }
+
+        if args[0] == "events" {
+            // SQL は空白を含むので、コマンド名より後ろをそのまま使う
+            let sql = line.trim_start()["events".len()..].trim();
+            if sql.is_empty() {
+                return Err("usage: events <SELECT ...>".into());
+            }
+
+            return Ok(UserInput::QueryEvents(sql.into()));
+        }
+
         if args[0] == "scan" {
             return Ok(UserInput::Scan);
         }
 
+        if args[0] == "config" {
+            return Ok(UserInput::Config);
+        }
+
+        if args[0] == "pause" {
+            return Ok(UserInput::Pause);
+        }
+
+        if args[0] == "resume" {
+            return Ok(UserInput::Resume);
+        }
+
+        if args[0] == "loglevel" {
+            let [_, level] = args[..] else {
+                return Err("usage: loglevel <error|warn|info|debug>".into());
+            };
+
+            return Ok(UserInput::LogLevel(level.into()));
+        }
+
+        if args[0] == "cooldown" {
+            let [_, ms] = args[..] else {
+                return Err("usage: cooldown <ms>".into());
+            };
+            let Ok(ms) = ms.parse() else {
+                return Err(format!("invalid cooldown: {ms}"));
+            };
+
+            return Ok(UserInput::Cooldown(ms));
+        }
+
+        if args[0] == "debounce" {
+            let [_, ms] = args[..] else {
+                return Err("usage: debounce <ms>".into());
+            };
+            let Ok(ms) = ms.parse() else {
+                return Err(format!("invalid debounce: {ms}"));
+            };
+
+            return Ok(UserInput::Debounce(ms));
+        }
+
+        if args[0] == "logfile" {
+            return match args[1..] {
+                ["off"] => Ok(UserInput::SetOutputPath(None)),
+                [path] => Ok(UserInput::SetOutputPath(Some(path.into()))),
+                _ => Err("usage: logfile <path> | off".into()),
+            };
+        }
+
+        if args[0] == "graph" {
+            let [_, path] = args[..] else {
+                return Err("usage: graph <path>".into());
+            };
+
+            return Ok(UserInput::Graph(path.into()));
+        }
+
+        if args[0] == "stats" {
+            let [_, path] = args[..] else {
+                return Err("usage: stats <path>".into());
+            };
+
+            return Ok(UserInput::ExportStats(path.into()));
+        }
+
+        if args[0] == "watermark" {
+            return Ok(UserInput::ReloadWatermark);
+        }
+
+        if args[0] == "history" {
+            return match args[1..] {
+                [] => Ok(UserInput::History),
+                ["clear"] => Ok(UserInput::ClearHistory),
+                ["trim", len] => match len.parse() {
+                    Ok(len) => Ok(UserInput::TrimHistory(len)),
+                    Err(_) => Err(format!("invalid count: {len}")),
+                },
+                _ => Err("usage: history [clear | trim <count>]".into()),
+            };
+        }
+
+        if args[0] == "help" {
+            return Ok(UserInput::Help);
+        }
+
+        if args[0] == "param" {
+            let [_, plugin, name, value] = args[..] else {
+                return Err("usage: param <plugin> <name> <value>".into());
+            };
+
+            return Ok(UserInput::Param {
+                plugin: plugin.into(),
+                name: name.into(),
+                value: value.into(),
+            });
+        }
+
+        if args[0] == "timestamp" {
+            // 書式は空白を含むので、コマンド名より後ろをそのまま使う
+            let format = line.trim_start()["timestamp".len()..].trim();
+            if format.is_empty() {
+                return Err("usage: timestamp <strftime format>".into());
+            }
+
+            return Ok(UserInput::TimestampFormat(format.into()));
+        }
+
+        if args[0] == "plugins" {
+            return Ok(UserInput::Plugins);
+        }
+
+        if args[0] == "plugin" {
+            return match args[1..] {
+                ["enable", name] => Ok(UserInput::EnablePlugin(name.into())),
+                ["disable", name] => Ok(UserInput::DisablePlugin(name.into())),
+                _ => Err("usage: plugin <enable|disable> <name>".into()),
+            };
+        }
+
+        if args[0] == "warmup" {
+            let (hwnd, frames, reset_warmup) = match args[1..] {
+                [hwnd, frames] => (hwnd, frames, false),
+                [hwnd, frames, "reset"] => (hwnd, frames, true),
+                _ => return Err("usage: warmup <HWND|alias> <frames> [reset]".into()),
+            };
+            let hwnd = self.resolve_hwnd(hwnd)?;
+            let Ok(frames) = frames.parse() else {
+                return Err(format!("invalid frame count: {frames}"));
+            };
+
+            return Ok(UserInput::Warmup {
+                hwnd,
+                frames,
+                reset_warmup,
+            });
+        }
+
+        if args[0] == "dedup" {
+            let [_, hwnd] = args[..] else {
+                return Err("usage: dedup <HWND|alias>".into());
+            };
+
+            return Ok(UserInput::Dedup(self.resolve_hwnd(hwnd)?));
+        }
+
+        if args[0] == "snap" {
+            let [_, hwnd] = args[..] else {
+                return Err("usage: snap <HWND|alias>".into());
+            };
+
+            return Ok(UserInput::Snap(self.resolve_hwnd(hwnd)?));
+        }
+
+        if args[0] == "inject" {
+            let [_, hwnd] = args[..] else {
+                return Err("usage: inject <HWND|alias>".into());
+            };
+
+            return Ok(UserInput::Inject(self.resolve_hwnd(hwnd)?));
+        }
+
+        if args[0] == "channel" {
+            let [_, hwnd, capacity] = args[..] else {
+                return Err("usage: channel <HWND|alias> <capacity>".into());
+            };
+            let hwnd = self.resolve_hwnd(hwnd)?;
+            let Ok(capacity) = capacity.parse() else {
+                return Err(format!("invalid capacity: {capacity}"));
+            };
+
+            return Ok(UserInput::Channel { hwnd, capacity });
+        }
+
+        if args[0] == "priority" {
+            let [_, hwnd, priority] = args[..] else {
+                return Err("usage: priority <HWND|alias> <0-255>".into());
+            };
+            let hwnd = self.resolve_hwnd(hwnd)?;
+            let Ok(priority) = priority.parse() else {
+                return Err(format!("invalid priority: {priority}"));
+            };
+
+            return Ok(UserInput::Priority { hwnd, priority });
+        }
+
+        if args[0] == "preset" {
+            return match args[1..] {
+                ["save", name] => Ok(UserInput::SavePreset(name.into())),
+                ["load", name] => Ok(UserInput::LoadPreset(name.into())),
+                _ => Err("usage: preset save <name> | load <name>".into()),
+            };
+        }
+
+        if args[0] == "click" {
+            let [_, hwnd, x, y] = args[..] else {
+                return Err("usage: click <HWND|alias> <x> <y>".into());
+            };
+            let hwnd = self.resolve_hwnd(hwnd)?;
+            let (Ok(x), Ok(y)) = (x.parse(), y.parse()) else {
+                return Err(format!("invalid coordinates: {x} {y}"));
+            };
+
+            return Ok(UserInput::Click { hwnd, x, y });
+        }
+
+        if args[0] == "rule" {
+            return match args[1..] {
+                ["add", title_pattern, scene_name] => Ok(UserInput::AddRule {
+                    title_pattern: title_pattern.into(),
+                    scene_name: scene_name.into(),
+                }),
+                ["remove", index] => match index.parse() {
+                    Ok(index) => Ok(UserInput::RemoveRule(index)),
+                    Err(_) => Err(format!("invalid rule index: {index}")),
+                },
+                ["list"] => Ok(UserInput::ListRules),
+                _ => Err(
+                    "usage: rule add <pattern> <scene> | rule remove <index> | rule list".into(),
+                ),
+            };
+        }
+
         if args[0].starts_with("allow") {
             if args.len() == 1 {
                 return Err("allow needs at least one HWND".into());
             }
 
             let mut hwnds = vec![];
-            'next_arg: for arg in &args[1..] {
-                if arg.len() == 1 {
-                    for entry in &self.scan_result {
-                        if entry.alias == Some(arg.chars().next().unwrap()) {
-                            hwnds.push(entry.hwnd);
-                            continue 'next_arg;
-                        }
-                    }
-                }
-
-                let Ok(hwnd) = arg.parse() else {
-                return Err(format!("unknown HWND {arg} in allow"));
-            };
-
-                hwnds.push(HWND(hwnd));
+            for arg in &args[1..] {
+                hwnds.push(self.resolve_hwnd(arg)?);
             }
 
             return Ok(UserInput::AllowHWND(hwnds));
@@ -170,6 +897,48 @@ impl StdinShell {
 
         Err(format!("unknown command: {line}"))
     }
+
+    // scan で割り当てたエイリアスか、HWND の数値そのものを受け付ける
+    fn resolve_hwnd(&self, arg: &str) -> Result<HWND, String> {
+        if arg.len() == 1 {
+            for entry in &self.scan_result {
+                if entry.alias == Some(arg.chars().next().unwrap()) {
+                    return Ok(entry.hwnd);
+                }
+            }
+        }
+
+        let Ok(hwnd) = arg.parse() else {
+            return Err(format!("unknown HWND {arg}"));
+        };
+
+        Ok(HWND(hwnd))
+    }
+}
+
+fn help() -> String {
+    let name_width = COMMANDS
+        .iter()
+        .map(|(name, ..)| name.len())
+        .max()
+        .unwrap_or(0);
+    let args_width = COMMANDS
+        .iter()
+        .map(|(_, args, _)| args.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut buf = String::new();
+    writeln!(&mut buf, "Available commands:").unwrap();
+    for (name, args, description) in COMMANDS {
+        writeln!(
+            &mut buf,
+            "| {name:<name_width$} {args:<args_width$}  {description}"
+        )
+        .unwrap();
+    }
+
+    buf
 }
 
 fn keep_asking(mut editor: DefaultEditor, sender: Sender<String>) {
@@ -184,40 +953,19 @@ fn keep_asking(mut editor: DefaultEditor, sender: Sender<String>) {
 }
 
 fn enumerate_windows() -> Vec<ScanEntry> {
-    let mut windows: Vec<ScanEntry> = vec![];
-    unsafe {
-        EnumWindows(
-            Some(enumerate_callback),
-            LPARAM(&mut windows as *mut _ as isize),
-        )
-        .unwrap()
-    };
-
     // 普通のウィンドウに限る
-    windows
+    ForegroundWatcher::enumerate_windows()
         .into_iter()
-        .filter(|win| {
-            let style = unsafe { GetWindowLongW(win.hwnd, GWL_STYLE) }; // GWL_STYLE
+        .filter(|&(hwnd, _)| {
+            let style = unsafe { GetWindowLongW(hwnd, GWL_STYLE) }; // GWL_STYLE
 
             // WS_VISIBLEとWS_CAPTION
             (style & 0x10C00000) == 0x10C00000
         })
+        .map(|(hwnd, title)| ScanEntry {
+            alias: None,
+            hwnd,
+            title,
+        })
         .collect()
 }
-
-unsafe extern "system" fn enumerate_callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
-    let windows = unsafe { &mut *(lparam.0 as *mut Vec<ScanEntry>) };
-
-    let mut title_u16 = vec![0; 1024];
-    GetWindowTextW(hwnd, &mut title_u16);
-    let title = OsString::from_wide(&title_u16)
-        .to_string_lossy()
-        .into_owned();
-    windows.push(ScanEntry {
-        alias: None,
-        hwnd,
-        title,
-    });
-
-    BOOL(1)
-}
@@ -0,0 +1,492 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use windows::Win32::{
+    Foundation::HWND,
+    UI::WindowsAndMessaging::{PeekMessageW, MSG, PM_REMOVE, WM_QUIT},
+};
+use windows_capture::frame::FrameBuffer;
+
+use crate::{
+    capture_factory::{CaptureFactory, SpawnedCapture},
+    config::DriverConfig,
+    driver::Driver,
+    foreground_watcher::{ForegroundWatcherCommand, ForegroundWatcherMessage},
+    image_viewer::{ImageViewerCommand, ImageViewerMessage},
+    stdin_shell::{StdinShellCommand, StdinShellMessage},
+    window_capture::{
+        CaptureFrame, CaptureOptions, CaptureStopper, CapturedFrame, Handler, WindowCaptureCommand,
+        WindowCaptureMessage,
+    },
+};
+
+// 本物のウィンドウと被らないよう、HWND として普通は使われない大きな値から振る
+const FAKE_HWND_BASE: isize = 0x7fff_0000;
+// DriverHarness が Driver からの返事を待つ時間
+const HARNESS_TIMEOUT: Duration = Duration::from_secs(1);
+
+// ForegroundWatcher の代わりに Driver につなぎ、決まった速さで前面ウィンドウを切り替え続ける
+pub struct StressTestForegroundWatcher {
+    rx_cmd: Receiver<ForegroundWatcherCommand>,
+    tx_msg: Sender<ForegroundWatcherMessage>,
+    events_per_sec: u32,
+    pool: Vec<HWND>,
+    duration: Duration,
+}
+
+impl StressTestForegroundWatcher {
+    pub fn new(
+        events_per_sec: u32,
+        pool_size: usize,
+        duration: Duration,
+    ) -> (
+        Self,
+        Sender<ForegroundWatcherCommand>,
+        Receiver<ForegroundWatcherMessage>,
+    ) {
+        let (tx_cmd, rx_cmd) = unbounded();
+        let (tx_msg, rx_msg) = unbounded();
+        let pool = (0..pool_size.max(1) as isize)
+            .map(|i| HWND(FAKE_HWND_BASE + i * 4))
+            .collect();
+
+        (
+            Self {
+                rx_cmd,
+                tx_msg,
+                events_per_sec: events_per_sec.max(1),
+                pool,
+                duration,
+            },
+            tx_cmd,
+            rx_msg,
+        )
+    }
+
+    pub fn fake_hwnds(&self) -> &[HWND] {
+        &self.pool
+    }
+
+    // 送ったイベントの数を返す
+    pub fn run(self) -> u64 {
+        let interval = Duration::from_secs(1) / self.events_per_sec;
+        let started_at = Instant::now();
+        let mut next_at = started_at;
+        let mut sent = 0u64;
+
+        loop {
+            if let Ok(msg) = self.rx_cmd.try_recv() {
+                match msg {
+                    ForegroundWatcherCommand::Quit => break,
+                    ForegroundWatcherCommand::WatchSpecific(_)
+                    | ForegroundWatcherCommand::MonitorCreation(_) => {}
+                }
+            }
+
+            let now = Instant::now();
+            if now.duration_since(started_at) >= self.duration {
+                break;
+            }
+
+            // sleep が遅れても平均の速さは保てるよう、遅れた分はまとめて送る
+            while next_at <= now {
+                let hwnd = self.pool[sent as usize % self.pool.len()];
+                if self
+                    .tx_msg
+                    .send(ForegroundWatcherMessage::WindowChanged { hwnd })
+                    .is_err()
+                {
+                    return sent;
+                }
+                sent += 1;
+                next_at += interval;
+            }
+
+            thread::sleep(next_at.saturating_duration_since(Instant::now()));
+        }
+
+        sent
+    }
+}
+
+// Handler::process_frame に渡す、本物のキャプチャから来たかのようなフレーム
+pub struct MockFrame {
+    width: u32,
+    height: u32,
+    // 本物と同じく、幅を 64 ピクセルの倍数に切り上げた行で並べた RGBA
+    padded: Vec<u8>,
+    error: Option<String>,
+}
+
+impl MockFrame {
+    pub fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> Self {
+        let row_len = width as usize * 4;
+        let padded_row_len = (width as usize).div_ceil(64) * 64 * 4;
+        let mut padded = vec![0; padded_row_len * height as usize];
+        for (src, dst) in rgba
+            .chunks_exact(row_len)
+            .zip(padded.chunks_exact_mut(padded_row_len))
+        {
+            dst[..row_len].copy_from_slice(src);
+        }
+
+        Self {
+            width,
+            height,
+            padded,
+            error: None,
+        }
+    }
+
+    pub fn solid(width: u32, height: u32, rgba: [u8; 4]) -> Self {
+        let pixels = rgba.repeat(width as usize * height as usize);
+        Self::from_rgba(width, height, &pixels)
+    }
+
+    // buffer() が失敗するフレーム
+    pub fn failing(message: &str) -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            padded: vec![],
+            error: Some(message.into()),
+        }
+    }
+}
+
+impl CaptureFrame for MockFrame {
+    fn buffer(&self) -> Result<FrameBuffer<'_>, Box<dyn Error>> {
+        match &self.error {
+            Some(message) => Err(message.clone().into()),
+            None => Ok(FrameBuffer::new(&self.padded, self.width, self.height)),
+        }
+    }
+}
+
+// 本物のキャプチャの代わりに、単色の MockFrame を options.fps の速さで Handler に流し続ける
+#[derive(Clone)]
+pub struct MockCaptureFactory {
+    width: u32,
+    height: u32,
+    rgba: [u8; 4],
+    spawned: Arc<Mutex<Vec<HWND>>>,
+    // Quit を受けて自分から止まったスレッドの数。panic したスレッドは数えない
+    exited: Arc<AtomicUsize>,
+}
+
+impl MockCaptureFactory {
+    pub fn new(width: u32, height: u32, rgba: [u8; 4]) -> Self {
+        Self {
+            width,
+            height,
+            rgba,
+            spawned: Arc::default(),
+            exited: Arc::default(),
+        }
+    }
+
+    // Driver に渡したあとでも、clone しておけばキャプチャが始まったウィンドウを確かめられる
+    pub fn spawned_hwnds(&self) -> Vec<HWND> {
+        self.spawned.lock().unwrap().clone()
+    }
+
+    pub fn exited_count(&self) -> usize {
+        self.exited.load(Ordering::SeqCst)
+    }
+}
+
+impl CaptureFactory for MockCaptureFactory {
+    fn spawn(
+        &mut self,
+        hwnd: HWND,
+        options: CaptureOptions,
+        tx_frame: Sender<CapturedFrame>,
+    ) -> SpawnedCapture {
+        self.spawned.lock().unwrap().push(hwnd);
+
+        let interval = Duration::from_secs(1) / options.fps.max(1) as u32;
+        let frame = MockFrame::solid(self.width, self.height, self.rgba);
+        let (mut handler, tx_cmd, rx_msg) = Handler::new_for_test(hwnd, options, tx_frame);
+        let exited = Arc::clone(&self.exited);
+        let thread = thread::spawn(move || {
+            loop {
+                handler.process_frame(&frame);
+
+                // Quit を受け取ると Handler は WM_QUIT を投げるので、それを見て止まる
+                let mut msg = MSG::default();
+                if unsafe { PeekMessageW(&mut msg, None, 0, 0, PM_REMOVE) }.as_bool()
+                    && msg.message == WM_QUIT
+                {
+                    break;
+                }
+                thread::sleep(interval);
+            }
+            exited.fetch_add(1, Ordering::SeqCst);
+        });
+
+        SpawnedCapture {
+            tx_cmd,
+            rx_msg,
+            stopper: CaptureStopper::default(),
+            thread,
+        }
+    }
+}
+
+// Driver のコンポーネントの代わりにチャンネルの反対側を持ち、テストから直接やり取りする。
+// キャプチャはフレームを自分では作らず、send_frame で渡したものをそのまま Driver に届ける。
+// Driver は別スレッドに渡せないので、何か送るたびに同じスレッドで run_until_idle を呼んで進める。
+pub struct DriverHarness {
+    im_rx_cmd: Receiver<ImageViewerCommand>,
+    im_tx_msg: Sender<ImageViewerMessage>,
+    fw_rx_cmd: Receiver<ForegroundWatcherCommand>,
+    fw_tx_msg: Sender<ForegroundWatcherMessage>,
+    sh_rx_cmd: Receiver<StdinShellCommand>,
+    sh_tx_msg: Sender<StdinShellMessage>,
+    captures: Arc<Mutex<BTreeMap<isize, ChannelCapture>>>,
+}
+
+impl DriverHarness {
+    pub fn new() -> (Self, Driver) {
+        Self::with_config(DriverConfig::default())
+    }
+
+    pub fn with_config(config: DriverConfig) -> (Self, Driver) {
+        let (im_tx_cmd, im_rx_cmd) = unbounded();
+        let (im_tx_msg, im_rx_msg) = unbounded();
+        let (fw_tx_cmd, fw_rx_cmd) = unbounded();
+        let (fw_tx_msg, fw_rx_msg) = unbounded();
+        let (sh_tx_cmd, sh_rx_cmd) = unbounded();
+        let (sh_tx_msg, sh_rx_msg) = unbounded();
+
+        let mut driver = Driver::new(
+            config, im_tx_cmd, im_rx_msg, fw_tx_cmd, fw_rx_msg, sh_tx_cmd, sh_rx_msg,
+        );
+        let captures = Arc::default();
+        driver.set_capture_factory(ChannelCaptureFactory {
+            captures: Arc::clone(&captures),
+        });
+
+        (
+            Self {
+                im_rx_cmd,
+                im_tx_msg,
+                fw_rx_cmd,
+                fw_tx_msg,
+                sh_rx_cmd,
+                sh_tx_msg,
+                captures,
+            },
+            driver,
+        )
+    }
+
+    pub fn send_foreground_change(&self, hwnd: HWND) {
+        let _ = self
+            .fw_tx_msg
+            .send(ForegroundWatcherMessage::WindowChanged { hwnd });
+    }
+
+    pub fn send_watcher_message(&self, msg: ForegroundWatcherMessage) {
+        let _ = self.fw_tx_msg.send(msg);
+    }
+
+    pub fn send_shell_message(&self, msg: StdinShellMessage) {
+        let _ = self.sh_tx_msg.send(msg);
+    }
+
+    pub fn send_viewer_message(&self, msg: ImageViewerMessage) {
+        let _ = self.im_tx_msg.send(msg);
+    }
+
+    // そのウィンドウのキャプチャから届いたことにして、Driver にフレームを渡す。
+    // まだキャプチャが始まっていなければ panic する。
+    pub fn send_frame(&self, hwnd: HWND, mut frame: CapturedFrame) {
+        let tx_frame = self.capture_of(hwnd).tx_frame;
+        frame.hwnd = hwnd;
+        tx_frame
+            .send_timeout(frame, HARNESS_TIMEOUT)
+            .unwrap_or_else(|_| panic!("[{}] the driver did not take the frame", hwnd.0));
+    }
+
+    // そのウィンドウのキャプチャから届いたことにして、Driver にメッセージを渡す。本物と同じく、
+    // Error か Closed を送るとキャプチャのスレッドはそこで終わる。
+    pub fn send_capture_message(&self, hwnd: HWND, msg: WindowCaptureMessage) {
+        let capture = self.capture_of(hwnd);
+        let last = matches!(
+            msg,
+            WindowCaptureMessage::Error { .. } | WindowCaptureMessage::Closed { .. }
+        );
+        let _ = capture.tx_msg.send(msg);
+        if last {
+            let _ = capture.tx_end.send(());
+        }
+    }
+
+    fn capture_of(&self, hwnd: HWND) -> ChannelCapture {
+        self.captures
+            .lock()
+            .unwrap()
+            .get(&hwnd.0)
+            .cloned()
+            .unwrap_or_else(|| panic!("[{}] is not being captured", hwnd.0))
+    }
+
+    pub fn recv_viewer_command(&self) -> Option<ImageViewerCommand> {
+        self.im_rx_cmd.recv_timeout(HARNESS_TIMEOUT).ok()
+    }
+
+    pub fn recv_shell_command(&self) -> Option<StdinShellCommand> {
+        self.sh_rx_cmd.recv_timeout(HARNESS_TIMEOUT).ok()
+    }
+
+    // ビューアにもウォッチャーにも shell にも、まだ読んでいないものが残っていないこと
+    pub fn assert_idle(&self) {
+        assert!(
+            self.im_rx_cmd.is_empty(),
+            "{} viewer commands are pending",
+            self.im_rx_cmd.len()
+        );
+        assert!(
+            self.fw_rx_cmd.is_empty(),
+            "{} watcher commands are pending",
+            self.fw_rx_cmd.len()
+        );
+        assert!(
+            self.sh_rx_cmd.is_empty(),
+            "{} shell commands are pending",
+            self.sh_rx_cmd.len()
+        );
+    }
+}
+
+// フレームとメッセージを送る口を DriverHarness に渡すだけのキャプチャ。Quit か切断で終わる
+struct ChannelCaptureFactory {
+    captures: Arc<Mutex<BTreeMap<isize, ChannelCapture>>>,
+}
+
+#[derive(Clone)]
+struct ChannelCapture {
+    tx_frame: Sender<CapturedFrame>,
+    tx_msg: Sender<WindowCaptureMessage>,
+    // キャプチャのスレッドを終わらせる
+    tx_end: Sender<()>,
+}
+
+impl CaptureFactory for ChannelCaptureFactory {
+    fn spawn(
+        &mut self,
+        hwnd: HWND,
+        _options: CaptureOptions,
+        tx_frame: Sender<CapturedFrame>,
+    ) -> SpawnedCapture {
+        let (tx_cmd, rx_cmd) = unbounded();
+        let (tx_msg, rx_msg) = unbounded();
+        let (tx_end, rx_end) = unbounded();
+        self.captures.lock().unwrap().insert(
+            hwnd.0,
+            ChannelCapture {
+                tx_frame,
+                tx_msg: tx_msg.clone(),
+                tx_end,
+            },
+        );
+
+        let captures = Arc::clone(&self.captures);
+        let thread = thread::spawn(move || {
+            // 終わるまでは Driver から見てメッセージの送り手が生きているようにしておく
+            let _tx_msg = tx_msg;
+            loop {
+                select! {
+                    recv(rx_cmd) -> cmd => match cmd {
+                        Ok(WindowCaptureCommand::Quit) | Err(_) => break,
+                        Ok(_) => {}
+                    },
+                    recv(rx_end) -> _ => break,
+                }
+            }
+            captures.lock().unwrap().remove(&hwnd.0);
+        });
+
+        SpawnedCapture {
+            tx_cmd,
+            rx_msg,
+            stopper: CaptureStopper::default(),
+            thread,
+        }
+    }
+}
+
+// f を iterations 回呼び、1 回あたりの時間を表示して返す。ベンチマークは #[ignore] にしてあるので、
+// `cargo test --release -- --ignored --nocapture bench_` で動かす。
+pub fn bench(label: &str, iterations: u32, mut f: impl FnMut()) -> Duration {
+    // 最初の 1 回はキャッシュやアロケータが温まっていないので数えない
+    f();
+    let started_at = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iteration = started_at.elapsed() / iterations.max(1);
+    println!("{label}: {per_iteration:?}/iter");
+
+    per_iteration
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn driver_survives_a_foreground_stress_run() {
+        let (watcher, fw_tx_cmd, fw_rx_msg) =
+            StressTestForegroundWatcher::new(1000, 8, Duration::from_secs(10));
+        let hwnds = watcher.fake_hwnds().to_vec();
+        let (im_tx_cmd, im_rx_cmd) = unbounded();
+        let (_im_tx_msg, im_rx_msg) = unbounded();
+        let (sh_tx_cmd, _sh_rx_cmd) = unbounded();
+        let (sh_tx_msg, sh_rx_msg) = unbounded();
+        let mut driver = Driver::new(
+            DriverConfig::default(),
+            im_tx_cmd,
+            im_rx_msg,
+            fw_tx_cmd,
+            fw_rx_msg,
+            sh_tx_cmd,
+            sh_rx_msg,
+        );
+        let factory = MockCaptureFactory::new(8, 8, [0, 0, 255, 255]);
+        driver.set_capture_factory(factory.clone());
+        sh_tx_msg
+            .send(StdinShellMessage::AllowHWND(hwnds.clone()))
+            .unwrap();
+        driver.run_until_idle();
+
+        let watcher = thread::spawn(move || watcher.run());
+        while !watcher.is_finished() {
+            driver.run_until_idle();
+            // ビューアに送られたものは誰も読まないので、溜め込まないよう捨てておく
+            im_rx_cmd.try_iter().for_each(drop);
+        }
+        let sent = watcher.join().unwrap();
+        driver.run_until_idle();
+
+        // 遅れた分はまとめて送るので、10 秒で 1000 件/秒をほぼ送り切れているはず
+        assert!(sent >= 9_900, "only {sent} events were sent");
+        assert_eq!(driver.capture_count(), hwnds.len());
+
+        sh_tx_msg.send(StdinShellMessage::QuitRequested).unwrap();
+        driver.run_until_idle();
+        assert_eq!(driver.capture_count(), 0);
+        assert_eq!(factory.spawned_hwnds().len(), hwnds.len());
+        assert_eq!(factory.exited_count(), hwnds.len());
+    }
+}
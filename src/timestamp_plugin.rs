@@ -0,0 +1,268 @@
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local,
+};
+
+use crate::{
+    frame_plugin::FramePlugin, pixel_format::PixelFormat, watermark_plugin::OverlayPosition,
+    window_capture::CapturedFrame,
+};
+
+// 組み込みフォントの 1 文字の大きさ。各行の下位 5 ビットが左から右の画素
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+// 文字の間と、フレームの端からの余白 (どちらも拡大前の画素数)
+const GLYPH_SPACING: u32 = 1;
+const MARGIN: u32 = 2;
+
+// どんなフレームでも、これより大きい文字は 1 文字も収まらない
+const MAX_FONT_SIZE: u32 = 1024;
+
+// 現在時刻を chrono の strftime 形式で書式化して、フレームの隅に描く。
+pub struct TimestampPlugin {
+    format: String,
+    // 文字の高さ (ピクセル)。組み込みフォントを整数倍に拡大するので、7 の倍数に切り下げる
+    font_size: u32,
+    position: OverlayPosition,
+    // 乗算していない RGBA
+    color: [u8; 4],
+}
+
+impl TimestampPlugin {
+    pub fn new(
+        format: String,
+        font_size: u32,
+        position: OverlayPosition,
+        color: [u8; 4],
+    ) -> Result<Self, String> {
+        validate_format(&format)?;
+        validate_font_size(font_size)?;
+
+        Ok(Self {
+            format,
+            font_size,
+            position,
+            color,
+        })
+    }
+}
+
+impl FramePlugin for TimestampPlugin {
+    fn name(&self) -> &str {
+        "timestamp"
+    }
+
+    fn process(&mut self, frame: &mut CapturedFrame) {
+        // RGB を持たない形式には描けない
+        if matches!(frame.format, PixelFormat::Gray8 | PixelFormat::Yuv444) {
+            return;
+        }
+
+        let text = Local::now().format(&self.format).to_string();
+        let scale = (self.font_size / GLYPH_HEIGHT).max(1);
+        let advance = (GLYPH_WIDTH + GLYPH_SPACING) * scale;
+        let text_width = (text.chars().count() as u32)
+            .saturating_mul(advance)
+            .saturating_sub(GLYPH_SPACING * scale);
+        let text_height = GLYPH_HEIGHT * scale;
+        let margin = MARGIN * scale;
+
+        // 収まらない分は描かずに切り落とす
+        let right = frame
+            .width
+            .saturating_sub(text_width.saturating_add(margin));
+        let bottom = frame.height.saturating_sub(text_height + margin);
+        let (left, top) = match self.position {
+            OverlayPosition::TopLeft => (margin, margin),
+            OverlayPosition::TopRight => (right, margin),
+            OverlayPosition::BottomLeft => (margin, bottom),
+            OverlayPosition::BottomRight => (right, bottom),
+            OverlayPosition::Center => (
+                frame.width.saturating_sub(text_width) / 2,
+                frame.height.saturating_sub(text_height) / 2,
+            ),
+        };
+
+        let bytes_per_pixel = frame.format.bytes_per_pixel();
+        let offsets = frame.format.rgb_offsets();
+        let [red, green, blue, alpha] = self.color;
+        let alpha = alpha as u32;
+        for (index, c) in text.chars().enumerate() {
+            let glyph = glyph(c);
+            let glyph_left = left.saturating_add((index as u32).saturating_mul(advance));
+            if glyph_left >= frame.width {
+                break;
+            }
+            for y in 0..text_height {
+                let row = glyph[(y / scale) as usize];
+                let frame_y = top + y;
+                if frame_y >= frame.height {
+                    break;
+                }
+
+                for x in 0..GLYPH_WIDTH * scale {
+                    if row & (1 << (GLYPH_WIDTH - 1 - x / scale)) == 0 {
+                        continue;
+                    }
+                    let frame_x = glyph_left + x;
+                    if frame_x >= frame.width {
+                        break;
+                    }
+
+                    let offset = (frame_y as usize * frame.width as usize + frame_x as usize)
+                        * bytes_per_pixel;
+                    let dst = &mut frame.bytes[offset..offset + bytes_per_pixel];
+                    for (channel, value) in offsets.into_iter().zip([red, green, blue]) {
+                        dst[channel] = ((value as u32 * alpha
+                            + dst[channel] as u32 * (255 - alpha))
+                            / 255) as u8;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "format" => {
+                validate_format(value)?;
+                self.format = value.into();
+            }
+            "font_size" => {
+                let font_size: u32 = value
+                    .parse()
+                    .map_err(|_| format!("invalid font_size: {value}"))?;
+                validate_font_size(font_size)?;
+                self.font_size = font_size;
+            }
+            "position" => {
+                self.position = match value {
+                    "top_left" => OverlayPosition::TopLeft,
+                    "top_right" => OverlayPosition::TopRight,
+                    "bottom_left" => OverlayPosition::BottomLeft,
+                    "bottom_right" => OverlayPosition::BottomRight,
+                    "center" => OverlayPosition::Center,
+                    _ => return Err(format!("unknown position: {value}")),
+                };
+            }
+            // r,g,b,a
+            "color" => {
+                let channels = value
+                    .split(',')
+                    .map(|channel| channel.trim().parse::<u8>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .and_then(|channels| <[u8; 4]>::try_from(channels).ok())
+                    .ok_or_else(|| format!("invalid color: {value}"))?;
+                self.color = channels;
+            }
+            _ => return Err(format!("unknown parameter: {name}")),
+        }
+
+        Ok(())
+    }
+}
+
+// 書式が壊れていると chrono は書き出すときに panic するので、先に確かめておく
+fn validate_format(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("invalid time format: {format}"));
+    }
+
+    Ok(())
+}
+
+fn validate_font_size(font_size: u32) -> Result<(), String> {
+    if font_size == 0 || font_size > MAX_FONT_SIZE {
+        return Err(format!(
+            "font_size must be between 1 and {MAX_FONT_SIZE}: {font_size}"
+        ));
+    }
+
+    Ok(())
+}
+
+// 小文字は大文字で、フォントにない文字は '?' で描く
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        'A' => [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        'H' => [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+        'M' => [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'Q' => [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04],
+        'Z' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        ' ' => [0x00; 7],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::Foundation::HWND;
+
+    use super::*;
+
+    #[test]
+    fn font_size_is_bounded() {
+        let plugin = |font_size| {
+            TimestampPlugin::new(
+                "%H:%M:%S".into(),
+                font_size,
+                OverlayPosition::BottomRight,
+                [255, 255, 255, 255],
+            )
+        };
+        assert!(plugin(0).is_err());
+        assert!(plugin(MAX_FONT_SIZE + 1).is_err());
+
+        let mut plugin = plugin(14).unwrap();
+        assert!(plugin.set_param("font_size", "4294967295").is_err());
+        assert_eq!(plugin.font_size, 14);
+
+        // 1 文字も収まらない大きさでも、はみ出す分を落として描く
+        plugin
+            .set_param("font_size", &MAX_FONT_SIZE.to_string())
+            .unwrap();
+        plugin.set_param("format", &"%H".repeat(1000)).unwrap();
+        let mut frame = CapturedFrame::checkerboard(HWND(1), 1, 64, 64);
+        plugin.process(&mut frame);
+    }
+}
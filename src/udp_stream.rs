@@ -0,0 +1,556 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam_channel::Sender;
+use windows::Win32::Foundation::HWND;
+
+use crate::{
+    driver::PluginEvent, frame_plugin::FramePlugin, pixel_format::PixelFormat,
+    window_capture::CapturedFrame,
+};
+
+// IP フラグメントが起きないよう、イーサネットの MTU から IP/UDP ヘッダ分を引いた大きさに収める
+const MAX_DATAGRAM_SIZE: usize = 1472;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"OAWS");
+
+// magic, sequence, hwnd, width, height, format, timestamp_us, total_len, fragment_index,
+// fragment_count の順にリトルエンディアンで並べる
+const HEADER_SIZE: usize = 4 + 8 + 8 + 4 + 4 + 4 + 8 + 4 + 2 + 2;
+
+const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE;
+
+// 欠けたフラグメントを待ち続けないよう、組み立て中のフレームはウィンドウごとにこれだけしか持たない
+const MAX_PARTIAL_FRAMES: usize = 4;
+
+// hwnd を変えながら送られても確保し続けないよう、全ウィンドウを合わせてもこれだけにする
+const MAX_TOTAL_PARTIAL_FRAMES: usize = 16;
+
+// キャプチャを作り直すと sequence は 1 からやり直すので、組み上げたものよりこれ以上戻ったものは
+// 遅れて届いたのではなく、新しいキャプチャのフレームとみなす
+const MAX_REORDER_DISTANCE: u64 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FragmentHeader {
+    sequence: u64,
+    hwnd: isize,
+    width: u32,
+    height: u32,
+    format: u32,
+    timestamp_us: u64,
+    total_len: u32,
+    fragment_index: u16,
+    fragment_count: u16,
+}
+
+impl FragmentHeader {
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&MAGIC.to_le_bytes());
+        buf.extend_from_slice(&self.sequence.to_le_bytes());
+        buf.extend_from_slice(&(self.hwnd as i64).to_le_bytes());
+        buf.extend_from_slice(&self.width.to_le_bytes());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.extend_from_slice(&self.format.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp_us.to_le_bytes());
+        buf.extend_from_slice(&self.total_len.to_le_bytes());
+        buf.extend_from_slice(&self.fragment_index.to_le_bytes());
+        buf.extend_from_slice(&self.fragment_count.to_le_bytes());
+    }
+
+    fn read(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let mut pos = 0;
+        let mut take = |len: usize| {
+            let bytes = &buf[pos..pos + len];
+            pos += len;
+            bytes
+        };
+
+        if u32::from_le_bytes(take(4).try_into().ok()?) != MAGIC {
+            return None;
+        }
+
+        Some(Self {
+            sequence: u64::from_le_bytes(take(8).try_into().ok()?),
+            hwnd: i64::from_le_bytes(take(8).try_into().ok()?) as isize,
+            width: u32::from_le_bytes(take(4).try_into().ok()?),
+            height: u32::from_le_bytes(take(4).try_into().ok()?),
+            format: u32::from_le_bytes(take(4).try_into().ok()?),
+            timestamp_us: u64::from_le_bytes(take(8).try_into().ok()?),
+            total_len: u32::from_le_bytes(take(4).try_into().ok()?),
+            fragment_index: u16::from_le_bytes(take(2).try_into().ok()?),
+            fragment_count: u16::from_le_bytes(take(2).try_into().ok()?),
+        })
+    }
+}
+
+pub struct UdpFrameSender {
+    socket: UdpSocket,
+    target: SocketAddr,
+    tx_event: Sender<PluginEvent>,
+    // 送れない間は毎フレーム同じエラーになるので、変わったときだけ知らせる
+    last_error: Option<String>,
+}
+
+impl UdpFrameSender {
+    pub fn new(target: impl ToSocketAddrs, tx_event: Sender<PluginEvent>) -> Result<Self, String> {
+        let target = resolve(target)?;
+        let bind_addr = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket =
+            UdpSocket::bind(bind_addr).map_err(|e| format!("failed to bind UDP socket: {e}"))?;
+
+        Ok(Self {
+            socket,
+            target,
+            tx_event,
+            last_error: None,
+        })
+    }
+
+    pub fn set_target(&mut self, target: impl ToSocketAddrs) -> Result<(), String> {
+        self.target = resolve(target)?;
+        Ok(())
+    }
+
+    pub fn send(&self, frame: &CapturedFrame) -> Result<(), String> {
+        for_each_datagram(frame, |datagram| {
+            self.socket
+                .send_to(datagram, self.target)
+                .map(|_| ())
+                .map_err(|e| format!("failed to send frame fragment: {e}"))
+        })
+    }
+}
+
+impl FramePlugin for UdpFrameSender {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn process(&mut self, frame: &mut CapturedFrame) {
+        // 送れなかったフレームは捨てる (UDP なので届かないこともある前提)
+        match self.send(frame) {
+            Ok(()) => self.last_error = None,
+            Err(e) => {
+                if self.last_error.as_ref() != Some(&e) {
+                    let _ = self.tx_event.send(PluginEvent::Output {
+                        message: format!("udp: {e}"),
+                    });
+                    self.last_error = Some(e);
+                }
+            }
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "target" => self.set_target(value),
+            _ => Err(format!("unknown parameter: {name}")),
+        }
+    }
+}
+
+struct PartialFrame {
+    header: FragmentHeader,
+    bytes: Vec<u8>,
+    received: Vec<bool>,
+    remaining: usize,
+    // 全体の上限を超えたときに、最初のフラグメントが届いたのが一番古いものから捨てる
+    first_seen: Instant,
+}
+
+// sequence はキャプチャごとに振られるので、どれも (hwnd, sequence) で見分ける
+pub struct UdpFrameReceiver {
+    socket: UdpSocket,
+    partial: BTreeMap<(isize, u64), PartialFrame>,
+    last_completed: BTreeMap<isize, u64>,
+}
+
+impl UdpFrameReceiver {
+    pub fn new(bind_addr: impl ToSocketAddrs) -> Result<Self, String> {
+        let socket =
+            UdpSocket::bind(bind_addr).map_err(|e| format!("failed to bind UDP socket: {e}"))?;
+
+        Ok(Self {
+            socket,
+            partial: BTreeMap::new(),
+            last_completed: BTreeMap::new(),
+        })
+    }
+
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<(), String> {
+        self.socket
+            .set_read_timeout(timeout)
+            .map_err(|e| format!("failed to set UDP timeout: {e}"))
+    }
+
+    // フレームが 1 枚組み上がるまで受信を続ける。タイムアウトしたら None を返す
+    pub fn recv(&mut self) -> Result<Option<CapturedFrame>, String> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(format!("failed to receive frame fragment: {e}")),
+            };
+
+            if let Some(frame) = self.accept(&buf[..len]) {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    fn accept(&mut self, datagram: &[u8]) -> Option<CapturedFrame> {
+        let header = FragmentHeader::read(datagram)?;
+        let payload = &datagram[HEADER_SIZE..];
+
+        let hwnd = header.hwnd;
+        if let Some(&last) = self.last_completed.get(&hwnd) {
+            if last.saturating_sub(header.sequence) > MAX_REORDER_DISTANCE {
+                self.forget_window(hwnd);
+            } else if header.sequence <= last {
+                // 既に組み上げたものより古いフレームは遅れて届いただけなので捨てる
+                return None;
+            }
+        }
+
+        let start = header.fragment_index as usize * MAX_PAYLOAD_SIZE;
+        let end = start + payload.len();
+        if header.fragment_index >= header.fragment_count || end > header.total_len as usize {
+            return None;
+        }
+        // 確保する前に、大きさがヘッダの他の値と合っているか確かめる
+        let format = PixelFormat::from_code(header.format)?;
+        let frame_len =
+            header.width as u64 * header.height as u64 * format.bytes_per_pixel() as u64;
+        if header.total_len as u64 != frame_len
+            || header.total_len as usize > header.fragment_count as usize * MAX_PAYLOAD_SIZE
+        {
+            return None;
+        }
+
+        let key = (hwnd, header.sequence);
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_TOTAL_PARTIAL_FRAMES {
+            let oldest = self
+                .partial
+                .iter()
+                .min_by_key(|(_, partial)| partial.first_seen)
+                .map(|(&key, _)| key)?;
+            self.partial.remove(&oldest);
+        }
+        let partial = self.partial.entry(key).or_insert_with(|| PartialFrame {
+            header,
+            bytes: vec![0; header.total_len as usize],
+            received: vec![false; header.fragment_count as usize],
+            remaining: header.fragment_count as usize,
+            first_seen: Instant::now(),
+        });
+
+        // 同じ sequence でも中身の形が違うものは混ぜない
+        if partial.header.total_len != header.total_len
+            || partial.header.fragment_count != header.fragment_count
+        {
+            return None;
+        }
+
+        let index = header.fragment_index as usize;
+        if !partial.received[index] {
+            partial.received[index] = true;
+            partial.remaining -= 1;
+            partial.bytes[start..end].copy_from_slice(payload);
+        }
+
+        if partial.remaining > 0 {
+            while self.partial_keys(hwnd).count() > MAX_PARTIAL_FRAMES {
+                let oldest = self.partial_keys(hwnd).next()?;
+                self.partial.remove(&oldest);
+            }
+            return None;
+        }
+
+        let partial = self.partial.remove(&(hwnd, header.sequence))?;
+        self.last_completed.insert(hwnd, header.sequence);
+
+        // このウィンドウのこれより古いフレームは、もう揃っても使わない
+        self.partial
+            .retain(|&(h, sequence), _| h != hwnd || sequence > header.sequence);
+
+        let header = partial.header;
+        let timestamp = UNIX_EPOCH + Duration::from_micros(header.timestamp_us);
+        let age = SystemTime::now()
+            .duration_since(timestamp)
+            .unwrap_or_default();
+        let now = Instant::now();
+
+        Some(CapturedFrame {
+            hwnd: HWND(header.hwnd),
+            sequence: header.sequence,
+            width: header.width,
+            height: header.height,
+            format,
+            bytes: partial.bytes,
+            captured_at: now.checked_sub(age).unwrap_or(now),
+            checksum: None,
+        })
+    }
+
+    // sequence の小さい順に並ぶ
+    fn partial_keys(&self, hwnd: isize) -> impl Iterator<Item = (isize, u64)> + '_ {
+        self.partial
+            .range((hwnd, 0)..=(hwnd, u64::MAX))
+            .map(|(&key, _)| key)
+    }
+
+    fn forget_window(&mut self, hwnd: isize) {
+        self.last_completed.remove(&hwnd);
+        self.partial.retain(|&(h, _), _| h != hwnd);
+    }
+}
+
+fn resolve(addr: impl ToSocketAddrs) -> Result<SocketAddr, String> {
+    addr.to_socket_addrs()
+        .map_err(|e| format!("failed to resolve address: {e}"))?
+        .next()
+        .ok_or_else(|| "address resolved to nothing".to_string())
+}
+
+// フレームをフラグメントに分けて、ヘッダを付けたものを順に f に渡す
+fn for_each_datagram(
+    frame: &CapturedFrame,
+    mut f: impl FnMut(&[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    let total_len: u32 = frame
+        .bytes
+        .len()
+        .try_into()
+        .map_err(|_| "frame is too large to send over UDP".to_string())?;
+    let fragment_count: u16 = frame
+        .bytes
+        .len()
+        .div_ceil(MAX_PAYLOAD_SIZE)
+        .max(1)
+        .try_into()
+        .map_err(|_| "frame is too large to send over UDP".to_string())?;
+
+    // 受信側は別プロセスなので Instant ではなく壁時計の時刻で渡す
+    let captured_at = SystemTime::now() - frame.captured_at.elapsed();
+    let timestamp_us = captured_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+
+    let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    for fragment_index in 0..fragment_count {
+        let start = fragment_index as usize * MAX_PAYLOAD_SIZE;
+        let end = (start + MAX_PAYLOAD_SIZE).min(frame.bytes.len());
+
+        buf.clear();
+        FragmentHeader {
+            sequence: frame.sequence,
+            hwnd: frame.hwnd.0,
+            width: frame.width,
+            height: frame.height,
+            format: frame.format.code(),
+            timestamp_us,
+            total_len,
+            fragment_index,
+            fragment_count,
+        }
+        .write(&mut buf);
+        buf.extend_from_slice(&frame.bytes[start..end]);
+
+        f(&buf)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receiver() -> UdpFrameReceiver {
+        UdpFrameReceiver::new("127.0.0.1:0").unwrap()
+    }
+
+    // MAX_PAYLOAD_SIZE を跨ぐよう、3 つのフラグメントに分かれる大きさにする
+    fn frame(hwnd: isize, sequence: u64) -> CapturedFrame {
+        let mut frame = CapturedFrame::checkerboard(HWND(hwnd), sequence, 40, 20);
+        for (i, byte) in frame.bytes.iter_mut().enumerate() {
+            *byte = (i as u64 * 7 + sequence) as u8;
+        }
+        frame
+    }
+
+    fn datagrams(frame: &CapturedFrame) -> Vec<Vec<u8>> {
+        let mut datagrams = vec![];
+        for_each_datagram(frame, |datagram| {
+            datagrams.push(datagram.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(datagrams.len(), 3);
+        datagrams
+    }
+
+    // 渡した順に受け取らせて、組み上がったフレームの (hwnd, sequence) を返す
+    fn feed(receiver: &mut UdpFrameReceiver, datagrams: &[&[u8]]) -> Vec<(isize, u64)> {
+        datagrams
+            .iter()
+            .filter_map(|datagram| receiver.accept(datagram))
+            .map(|frame| (frame.hwnd.0, frame.sequence))
+            .collect()
+    }
+
+    #[test]
+    fn reordered_fragments_are_reassembled() {
+        let mut receiver = receiver();
+        let frame = frame(1, 1);
+        let d = datagrams(&frame);
+
+        let mut reassembled = None;
+        for datagram in [&d[2], &d[0], &d[1]] {
+            reassembled = receiver.accept(datagram).or(reassembled);
+        }
+        let reassembled = reassembled.unwrap();
+        assert_eq!(reassembled.bytes, frame.bytes);
+        assert_eq!((reassembled.width, reassembled.height), (40, 20));
+    }
+
+    #[test]
+    fn duplicate_fragments_complete_a_frame_only_once() {
+        let mut receiver = receiver();
+        let d = datagrams(&frame(1, 1));
+        assert_eq!(
+            feed(&mut receiver, &[&d[0], &d[0], &d[1], &d[2], &d[1], &d[2]]),
+            vec![(1, 1)]
+        );
+    }
+
+    #[test]
+    fn late_fragments_of_an_older_frame_are_dropped() {
+        let mut receiver = receiver();
+        let old = datagrams(&frame(1, 1));
+        let new = datagrams(&frame(1, 2));
+        assert_eq!(
+            feed(
+                &mut receiver,
+                &[&old[0], &new[0], &new[1], &new[2], &old[1], &old[2]]
+            ),
+            vec![(1, 2)]
+        );
+    }
+
+    #[test]
+    fn windows_are_reassembled_independently() {
+        let mut receiver = receiver();
+        let a5 = datagrams(&frame(1, 5));
+        let a6 = datagrams(&frame(1, 6));
+        let b1 = datagrams(&frame(2, 1));
+        let b6 = datagrams(&frame(2, 6));
+
+        // 別のウィンドウの小さい sequence は古いフレームではない
+        assert_eq!(
+            feed(
+                &mut receiver,
+                &[&a5[0], &a5[1], &a5[2], &b1[0], &b1[1], &b1[2]]
+            ),
+            vec![(1, 5), (2, 1)]
+        );
+        // 同じ sequence のフラグメントが混ざっても、ウィンドウごとに組み上がる
+        let mut frames = vec![];
+        for i in 0..3 {
+            for datagram in [&a6[i], &b6[i]] {
+                frames.extend(receiver.accept(datagram));
+            }
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].bytes, frame(1, 6).bytes);
+        assert_eq!(frames[1].bytes, frame(2, 6).bytes);
+    }
+
+    #[test]
+    fn restarted_capture_starts_over_from_sequence_one() {
+        let mut receiver = receiver();
+        let before = datagrams(&frame(1, 100));
+        let after = datagrams(&frame(1, 1));
+        assert_eq!(
+            feed(
+                &mut receiver,
+                &[&before[0], &before[1], &before[2], &after[0], &after[1], &after[2]]
+            ),
+            vec![(1, 100), (1, 1)]
+        );
+    }
+
+    #[test]
+    fn send_errors_are_reported_once_until_sending_recovers() {
+        let (tx_event, rx_event) = crossbeam_channel::unbounded();
+        let mut sender = UdpFrameSender::new("127.0.0.1:9", tx_event).unwrap();
+        let mut frame = frame(1, 1);
+        // IPv4 のソケットから IPv6 の宛先には送れない
+        for target in ["[::1]:9", "[::1]:9", "127.0.0.1:9", "[::1]:9"] {
+            sender.set_param("target", target).unwrap();
+            sender.process(&mut frame);
+        }
+
+        let messages: Vec<_> = rx_event.try_iter().collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|event| matches!(
+            event,
+            PluginEvent::Output { message } if message.starts_with("udp: ")
+        )));
+    }
+
+    #[test]
+    fn fragments_that_disagree_with_the_frame_size_are_dropped() {
+        let mut receiver = receiver();
+        let mut header = FragmentHeader::read(&datagrams(&frame(1, 1))[0]).unwrap();
+        let datagram = |header: FragmentHeader| {
+            let mut buf = vec![];
+            header.write(&mut buf);
+            buf.resize(MAX_DATAGRAM_SIZE, 0);
+            buf
+        };
+
+        // 40x20 の RGBA より大きいと言い張るもの
+        header.total_len = u32::MAX;
+        assert!(receiver.accept(&datagram(header)).is_none());
+        // 大きさは合っているが、フラグメントの数では運べないもの
+        header.width = 4000;
+        header.height = 4000;
+        header.total_len = 4000 * 4000 * 4;
+        assert!(receiver.accept(&datagram(header)).is_none());
+        assert!(receiver.partial.is_empty());
+    }
+
+    #[test]
+    fn partial_frames_are_capped_across_windows() {
+        let mut receiver = receiver();
+        for hwnd in 0..MAX_TOTAL_PARTIAL_FRAMES as isize * 2 {
+            assert!(receiver.accept(&datagrams(&frame(hwnd, 1))[0]).is_none());
+        }
+        assert_eq!(receiver.partial.len(), MAX_TOTAL_PARTIAL_FRAMES);
+
+        // 新しく届いたものは残り、最初のものから捨てられている
+        let last = MAX_TOTAL_PARTIAL_FRAMES as isize * 2 - 1;
+        assert!(receiver.partial.contains_key(&(last, 1)));
+        assert!(!receiver.partial.contains_key(&(0, 1)));
+    }
+}
@@ -0,0 +1,198 @@
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+
+use crate::{driver::PluginEvent, frame_plugin::FramePlugin, window_capture::CapturedFrame};
+
+// 仮想カメラが追いつかなくても遅れが溜まらないよう、待たせておくのはこれだけにする
+const CAMERA_QUEUE_LEN: usize = 2;
+
+// 仮想カメラのデバイス (OBS の仮想カメラや Camera Frame Server など) にフレームを書き込む
+pub trait VirtualCameraSink: Send {
+    fn write(&mut self, frame: &CapturedFrame) -> Result<(), String>;
+}
+
+// 今のウィンドウのフレームを、別スレッドで VirtualCameraSink に渡す。書き込みが遅れているときは
+// 古いフレームから捨てて、カメラには常に新しいものが届くようにする。
+pub struct VirtualCameraOutput {
+    tx_frame: Option<Sender<CapturedFrame>>,
+    // キューが埋まっているときに一番古いフレームを抜くため、送る側でも受け手を持っておく
+    rx_stale: Receiver<CapturedFrame>,
+    tx_event: Sender<PluginEvent>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VirtualCameraOutput {
+    pub fn new(sink: impl VirtualCameraSink + 'static, tx_event: Sender<PluginEvent>) -> Self {
+        let (tx_frame, rx_frame) = bounded(CAMERA_QUEUE_LEN);
+        let rx_stale = rx_frame.clone();
+        let thread = {
+            let tx_event = tx_event.clone();
+            thread::spawn(move || write_frames(sink, rx_frame, tx_event))
+        };
+
+        Self {
+            tx_frame: Some(tx_frame),
+            rx_stale,
+            tx_event,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl FramePlugin for VirtualCameraOutput {
+    fn name(&self) -> &str {
+        "virtual_camera"
+    }
+
+    fn process(&mut self, frame: &mut CapturedFrame) {
+        let Some(tx_frame) = &self.tx_frame else {
+            return;
+        };
+        if tx_frame.is_full() {
+            let _ = self.rx_stale.try_recv();
+        }
+
+        if let Err(TrySendError::Disconnected(_)) = tx_frame.try_send(frame.clone()) {
+            let _ = self.tx_event.send(PluginEvent::Output {
+                message: "virtual camera: writer thread has stopped".into(),
+            });
+            self.tx_frame = None;
+        }
+    }
+
+    fn set_param(&mut self, name: &str, _value: &str) -> Result<(), String> {
+        Err(format!("unknown parameter: {name}"))
+    }
+}
+
+impl Drop for VirtualCameraOutput {
+    fn drop(&mut self) {
+        self.tx_frame = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_frames(
+    mut sink: impl VirtualCameraSink,
+    rx_frame: Receiver<CapturedFrame>,
+    tx_event: Sender<PluginEvent>,
+) {
+    for frame in rx_frame {
+        if let Err(e) = sink.write(&frame) {
+            let _ = tx_event.send(PluginEvent::Output {
+                message: format!(
+                    "virtual camera: failed to write frame {}: {e}",
+                    frame.sequence
+                ),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use windows::Win32::Foundation::HWND;
+
+    use super::*;
+
+    // 書き込んだフレームの番号を送る。gate があれば、書き込みを始めたことを知らせてから
+    // gate に 1 つ届くまで待つ
+    struct ChannelSink {
+        tx_written: Sender<u64>,
+        gate: Option<(Sender<()>, Receiver<()>)>,
+        fail: bool,
+    }
+
+    impl VirtualCameraSink for ChannelSink {
+        fn write(&mut self, frame: &CapturedFrame) -> Result<(), String> {
+            if let Some((tx_started, rx_gate)) = &self.gate {
+                let _ = tx_started.send(());
+                let _ = rx_gate.recv();
+            }
+            let _ = self.tx_written.send(frame.sequence);
+            if self.fail {
+                return Err("device is gone".into());
+            }
+
+            Ok(())
+        }
+    }
+
+    fn frame(sequence: u64) -> CapturedFrame {
+        CapturedFrame::checkerboard(HWND(0x1000), sequence, 4, 4)
+    }
+
+    #[test]
+    fn frames_reach_the_sink_in_order() {
+        let (tx_written, rx_written) = unbounded();
+        let (tx_event, _rx_event) = unbounded();
+        let mut output = VirtualCameraOutput::new(
+            ChannelSink {
+                tx_written,
+                gate: None,
+                fail: false,
+            },
+            tx_event,
+        );
+        for sequence in 1..=2 {
+            output.process(&mut frame(sequence));
+        }
+        drop(output);
+
+        assert_eq!(rx_written.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn stale_frames_are_dropped_while_the_sink_is_busy() {
+        let (tx_written, rx_written) = unbounded();
+        let (tx_started, rx_started) = unbounded();
+        let (tx_gate, rx_gate) = unbounded();
+        let (tx_event, _rx_event) = unbounded();
+        let mut output = VirtualCameraOutput::new(
+            ChannelSink {
+                tx_written,
+                gate: Some((tx_started, rx_gate)),
+                fail: false,
+            },
+            tx_event,
+        );
+
+        output.process(&mut frame(1));
+        rx_started.recv().unwrap();
+        // 1 を書いている間に届いたものは、新しい 2 つだけ残る
+        for sequence in 2..=5 {
+            output.process(&mut frame(sequence));
+        }
+        for _ in 0..3 {
+            tx_gate.send(()).unwrap();
+        }
+        drop(output);
+
+        assert_eq!(rx_written.try_iter().collect::<Vec<_>>(), vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn sink_errors_are_reported_as_plugin_events() {
+        let (tx_written, _rx_written) = unbounded();
+        let (tx_event, rx_event) = unbounded();
+        let mut output = VirtualCameraOutput::new(
+            ChannelSink {
+                tx_written,
+                gate: None,
+                fail: true,
+            },
+            tx_event,
+        );
+        output.process(&mut frame(7));
+        drop(output);
+
+        assert!(matches!(
+            rx_event.try_recv(),
+            Ok(PluginEvent::Output { message }) if message.contains("frame 7")
+        ));
+    }
+}
@@ -0,0 +1,218 @@
+use std::{fs::File, path::PathBuf};
+
+use png::{ColorType, Decoder, Transformations};
+
+use crate::{frame_plugin::FramePlugin, pixel_format::PixelFormat, window_capture::CapturedFrame};
+
+// フレームより大きい透かしは、フレームのこの割合に収まるよう縮める
+const MAX_FRACTION: f32 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+// 乗算済みアルファの RGBA
+struct Watermark {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+pub struct WatermarkPlugin {
+    image_path: PathBuf,
+    opacity: f32,
+    position: OverlayPosition,
+    original: Option<Watermark>,
+    // フレームの大きさごとに縮めたものを作り直さないよう覚えておく
+    scaled: Option<((u32, u32), Watermark)>,
+}
+
+impl WatermarkPlugin {
+    pub fn new(
+        image_path: PathBuf,
+        opacity: f32,
+        position: OverlayPosition,
+    ) -> Result<Self, String> {
+        let mut plugin = Self {
+            image_path,
+            opacity: opacity.clamp(0.0, 1.0),
+            position,
+            original: None,
+            scaled: None,
+        };
+        plugin.reload()?;
+
+        Ok(plugin)
+    }
+
+    pub fn reload(&mut self) -> Result<(), String> {
+        self.original = Some(load_png(&self.image_path)?);
+        self.scaled = None;
+
+        Ok(())
+    }
+
+    fn watermark_for(&mut self, frame_width: u32, frame_height: u32) -> Option<&Watermark> {
+        let original = self.original.as_ref()?;
+        if original.width <= frame_width && original.height <= frame_height {
+            return self.original.as_ref();
+        }
+
+        let size = (frame_width, frame_height);
+        if self.scaled.as_ref().map(|(s, _)| *s) != Some(size) {
+            let scale = (frame_width as f32 * MAX_FRACTION / original.width as f32)
+                .min(frame_height as f32 * MAX_FRACTION / original.height as f32);
+            self.scaled = Some((size, scale_nearest(original, scale)));
+        }
+
+        self.scaled.as_ref().map(|(_, watermark)| watermark)
+    }
+}
+
+impl FramePlugin for WatermarkPlugin {
+    fn name(&self) -> &str {
+        "watermark"
+    }
+
+    fn process(&mut self, frame: &mut CapturedFrame) {
+        // RGB を持たない形式には合成できない
+        if matches!(frame.format, PixelFormat::Gray8 | PixelFormat::Yuv444) {
+            return;
+        }
+
+        let (frame_width, frame_height) = (frame.width, frame.height);
+        let opacity = (self.opacity * 256.0) as u32;
+        let position = self.position;
+        let Some(watermark) = self.watermark_for(frame_width, frame_height) else {
+            return;
+        };
+
+        let (left, top) = match position {
+            OverlayPosition::TopLeft => (0, 0),
+            OverlayPosition::TopRight => (frame_width - watermark.width, 0),
+            OverlayPosition::BottomLeft => (0, frame_height - watermark.height),
+            OverlayPosition::BottomRight => (
+                frame_width - watermark.width,
+                frame_height - watermark.height,
+            ),
+            OverlayPosition::Center => (
+                (frame_width - watermark.width) / 2,
+                (frame_height - watermark.height) / 2,
+            ),
+        };
+
+        let bytes_per_pixel = frame.format.bytes_per_pixel();
+        let [r, g, b] = frame.format.rgb_offsets();
+        for y in 0..watermark.height as usize {
+            for x in 0..watermark.width as usize {
+                let src = &watermark.pixels[(y * watermark.width as usize + x) * 4..][..4];
+                let alpha = (src[3] as u32 * opacity) >> 8;
+                if alpha == 0 {
+                    continue;
+                }
+
+                let offset = ((top as usize + y) * frame_width as usize + left as usize + x)
+                    * bytes_per_pixel;
+                let dst = &mut frame.bytes[offset..offset + bytes_per_pixel];
+                for (channel, value) in [(r, src[0]), (g, src[1]), (b, src[2])] {
+                    let blended = ((value as u32 * opacity) >> 8)
+                        + (dst[channel] as u32 * (255 - alpha) / 255);
+                    dst[channel] = blended.min(255) as u8;
+                }
+            }
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "opacity" => {
+                let opacity: f32 = value
+                    .parse()
+                    .map_err(|_| format!("invalid opacity: {value}"))?;
+                if !(0.0..=1.0).contains(&opacity) {
+                    return Err(format!("opacity must be between 0 and 1: {value}"));
+                }
+                self.opacity = opacity;
+            }
+            "path" => {
+                self.image_path = PathBuf::from(value);
+                self.reload()?;
+            }
+            "reload" => self.reload()?,
+            _ => return Err(format!("unknown parameter: {name}")),
+        }
+
+        Ok(())
+    }
+}
+
+fn load_png(path: &PathBuf) -> Result<Watermark, String> {
+    let file = File::open(path).map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+    let mut decoder = Decoder::new(file);
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|e| format!("failed to decode {}: {e}", path.display()))?;
+    let buf = &buf[..info.buffer_size()];
+
+    let rgba: Vec<[u8; 4]> = match info.color_type {
+        ColorType::Rgba => buf
+            .chunks_exact(4)
+            .map(|p| [p[0], p[1], p[2], p[3]])
+            .collect(),
+        ColorType::Rgb => buf
+            .chunks_exact(3)
+            .map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        ColorType::Grayscale => buf.iter().map(|&v| [v, v, v, 255]).collect(),
+        ColorType::Indexed => return Err(format!("unsupported color type: {path:?}")),
+    };
+
+    // 合成のたびに掛けなくて済むよう、あらかじめアルファを掛けておく
+    let pixels = rgba
+        .into_iter()
+        .flat_map(|[r, g, b, a]| {
+            let premultiply = |v: u8| (v as u32 * a as u32 / 255) as u8;
+            [premultiply(r), premultiply(g), premultiply(b), a]
+        })
+        .collect();
+
+    Ok(Watermark {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+fn scale_nearest(watermark: &Watermark, scale: f32) -> Watermark {
+    let width = ((watermark.width as f32 * scale) as u32).max(1);
+    let height = ((watermark.height as f32 * scale) as u32).max(1);
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height {
+        let src_y = (y * watermark.height / height) as usize;
+        for x in 0..width {
+            let src_x = (x * watermark.width / width) as usize;
+            let offset = (src_y * watermark.width as usize + src_x) * 4;
+            pixels.extend_from_slice(&watermark.pixels[offset..offset + 4]);
+        }
+    }
+
+    Watermark {
+        width,
+        height,
+        pixels,
+    }
+}
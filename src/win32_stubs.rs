@@ -0,0 +1,156 @@
+// Windows 以外でテストを動かすときに、windows クレートが宣言だけしている Win32 の関数を
+// 埋める。テストから届く関数の中で Win32 を呼ぶものがあると、実際には呼ばれなくてもリンクに
+// 失敗するため。テストで実際に呼ばれるものだけ、それらしく振る舞うようにしてある。
+use std::cell::Cell;
+
+use windows::Win32::{
+    Foundation::{BOOL, HWND, LPARAM, WPARAM},
+    UI::WindowsAndMessaging::{MSG, WM_QUIT},
+};
+
+thread_local! {
+    // スレッドのメッセージキューの代わり。見るのは WM_QUIT だけ
+    static QUIT_POSTED: Cell<bool> = const { Cell::new(false) };
+}
+
+// タイトルは空として扱う
+#[no_mangle]
+extern "system" fn GetWindowTextW(_hwnd: HWND, _buf: *mut u16, _len: i32) -> i32 {
+    0
+}
+
+#[no_mangle]
+extern "system" fn GetWindowTextLengthW(_hwnd: HWND) -> i32 {
+    0
+}
+
+// テストの偽のウィンドウは閉じられるまでずっとあることにする
+#[no_mangle]
+extern "system" fn IsWindow(_hwnd: HWND) -> BOOL {
+    BOOL(1)
+}
+
+#[no_mangle]
+extern "system" fn PostQuitMessage(_exit_code: i32) {
+    QUIT_POSTED.with(|posted| posted.set(true));
+}
+
+// 固定の値を返し、そこへ送られた WM_QUIT はこのスレッドに届いたことにする
+#[no_mangle]
+extern "system" fn GetCurrentThreadId() -> u32 {
+    1
+}
+
+#[no_mangle]
+extern "system" fn PostThreadMessageW(
+    _thread_id: u32,
+    msg: u32,
+    _wparam: WPARAM,
+    _lparam: LPARAM,
+) -> BOOL {
+    if msg == WM_QUIT {
+        QUIT_POSTED.with(|posted| posted.set(true));
+    }
+    BOOL(1)
+}
+
+#[no_mangle]
+unsafe extern "system" fn PeekMessageW(
+    msg: *mut MSG,
+    _hwnd: HWND,
+    _filter_min: u32,
+    _filter_max: u32,
+    _remove: u32,
+) -> BOOL {
+    if !QUIT_POSTED.with(|posted| posted.replace(false)) {
+        return BOOL(0);
+    }
+
+    *msg = MSG {
+        message: WM_QUIT,
+        ..MSG::default()
+    };
+    BOOL(1)
+}
+
+// 残りはテストからは呼ばれないはずなので、呼ばれたら名前を出して止める
+macro_rules! unavailable {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[no_mangle]
+            extern "system" fn $name() {
+                panic!(concat!(stringify!($name), " is not available off Windows"));
+            }
+        )*
+    };
+}
+
+unavailable!(
+    ActivateAudioInterfaceAsync,
+    ClientToScreen,
+    CloseHandle,
+    CoCreateInstance,
+    CoInitializeEx,
+    CreateDirect3D11DeviceFromDXGIDevice,
+    CreateDispatcherQueueController,
+    CreateEventW,
+    CreateFileMappingW,
+    CreateToolhelp32Snapshot,
+    D3D11CreateDevice,
+    D3DCompile,
+    DispatchMessageW,
+    DwmGetWindowAttribute,
+    EnumWindows,
+    FormatMessageW,
+    FreeLibrary,
+    GetAncestor,
+    GetCurrentProcess,
+    GetCurrentProcessId,
+    GetErrorInfo,
+    GetForegroundWindow,
+    GetLastError,
+    GetMessageW,
+    GetMonitorInfoW,
+    GetProcAddress,
+    GetProcessHeap,
+    GetProcessHeaps,
+    GetProcessMemoryInfo,
+    GetShellWindow,
+    GetSystemMetrics,
+    GetThreadContext,
+    GetWindowLongW,
+    GetWindowRect,
+    GetWindowThreadProcessId,
+    HeapAlloc,
+    HeapCompact,
+    HeapFree,
+    IsIconic,
+    IsWindowVisible,
+    LoadLibraryExA,
+    MapViewOfFile,
+    MonitorFromWindow,
+    NtQueryInformationThread,
+    OpenFileMappingW,
+    OpenThread,
+    RegisterHotKey,
+    ResumeThread,
+    RoInitialize,
+    RoUninitialize,
+    RtlLookupFunctionEntry,
+    RtlVirtualUnwind,
+    SendInput,
+    SetErrorInfo,
+    SetEvent,
+    SetForegroundWindow,
+    SetWinEventHook,
+    ShellExecuteW,
+    SuspendThread,
+    SysFreeString,
+    SysStringLen,
+    Thread32First,
+    Thread32Next,
+    TranslateMessage,
+    UnhookWinEvent,
+    UnmapViewOfFile,
+    UnregisterHotKey,
+);
@@ -1,39 +1,710 @@
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, never, select, unbounded, Receiver, Sender};
+use png::{BitDepth, ColorType, Encoder, EncodingError};
+use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
+    error::Error,
+    fmt,
+    io::Write,
     mem, slice,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
-use windows::Win32::Foundation::HWND;
+use windows::{
+    core::factory,
+    Graphics::Capture::GraphicsCaptureItem,
+    Win32::{
+        Foundation::{HWND, LPARAM, RECT, WPARAM},
+        Graphics::{
+            Dwm::{DwmGetWindowAttribute, DWMWA_EXTENDED_FRAME_BOUNDS},
+            Dxgi::{DXGI_ERROR_DEVICE_REMOVED, DXGI_ERROR_DEVICE_RESET},
+        },
+        System::Threading::GetCurrentThreadId,
+        System::WinRT::Graphics::Capture::IGraphicsCaptureItemInterop,
+        UI::WindowsAndMessaging::{GetWindowRect, PostThreadMessageW, WM_QUIT},
+    },
+    UI::WindowId,
+};
 use windows_capture::{
     capture::{WindowsCaptureHandler, WindowsCaptureSettings},
-    frame::{Frame, RGBA},
+    frame::{Frame, FrameBuffer},
     window::Window,
 };
 
+use crate::{
+    foreground_watcher::ForegroundWatcher,
+    frame_rate_controller::FrameRateController,
+    jitter_tracker::JitterTracker,
+    log_level::LogLevel,
+    pixel_format::{self, PixelFormat},
+    pixel_sampler::{self, PixelSampler},
+};
+
+const TEST_CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+// dry_run で最初のフレームが届いてからフレームレートを測る時間
+const DRY_RUN_DURATION: Duration = Duration::from_secs(5);
+const HISTOGRAM_SAMPLE_STEP: usize = 4;
+// 何フレームごとにフレーム間隔のばらつきを知らせるか
+const JITTER_REPORT_INTERVAL: u64 = 30;
+// チャンネルが空のままこれだけ送れたら、落としていたフレームレートを倍に戻す
+const THROTTLE_RECOVERY_FRAMES: u32 = 60;
+
+pub type FrameHook = Arc<dyn Fn(&CapturedFrame) + Send + Sync>;
+// 新しい (幅, 高さ)
+pub type SizeChangeHook = Arc<dyn Fn(u32, u32) + Send + Sync>;
+
+#[derive(Clone)]
 pub struct CapturedFrame {
     pub hwnd: HWND,
+    pub sequence: u64,
     pub width: u32,
     pub height: u32,
+    pub format: PixelFormat,
     pub bytes: Vec<u8>,
+    pub captured_at: Instant,
+    pub checksum: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CapturedFrame {
+    // 形式はそのままで、色を輝度 (BT.601) だけにしたフレーム。アルファは残す。
+    pub fn to_greyscale(&self) -> CapturedFrame {
+        let mut frame = self.clone();
+        frame.to_greyscale_inplace();
+        frame
+    }
+
+    pub fn to_greyscale_inplace(&mut self) {
+        match self.format {
+            PixelFormat::Rgba | PixelFormat::Rgb24 => {
+                let bpp = self.format.bytes_per_pixel();
+                for pixel in self.bytes.chunks_exact_mut(bpp) {
+                    let y = pixel_format::luma(pixel[0], pixel[1], pixel[2]);
+                    pixel[..3].fill(y);
+                }
+            }
+            PixelFormat::Bgra => {
+                for pixel in self.bytes.chunks_exact_mut(4) {
+                    let y = pixel_format::luma(pixel[2], pixel[1], pixel[0]);
+                    pixel[..3].fill(y);
+                }
+            }
+            PixelFormat::Gray8 => {}
+            // 輝度はもう Y に入っているので、色差を無くせばよい
+            PixelFormat::Yuv444 => {
+                for pixel in self.bytes.chunks_exact_mut(3) {
+                    pixel[1..].fill(128);
+                }
+            }
+        }
+        // 中身が変わったので、検証用のチェックサムも付け直す
+        self.checksum = self.checksum.map(|_| crc32fast::hash(&self.bytes));
+    }
+
+    // 8x8 ピクセルごとに黒と白を交互に並べたフレーム。本物のウィンドウがなくても
+    // ビューアまでの経路を確かめられるように使う。
+    pub fn checkerboard(hwnd: HWND, sequence: u64, width: u32, height: u32) -> Self {
+        const BLOCK: u32 = 8;
+
+        let mut bytes = Vec::with_capacity(width as usize * height as usize * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let value = if (x / BLOCK + y / BLOCK).is_multiple_of(2) {
+                    0
+                } else {
+                    255
+                };
+                bytes.extend_from_slice(&[value, value, value, 255]);
+            }
+        }
+
+        Self {
+            hwnd,
+            sequence,
+            width,
+            height,
+            format: PixelFormat::Rgba,
+            bytes,
+            captured_at: Instant::now(),
+            checksum: None,
+        }
+    }
+
+    // どれかのチャンネルの差が threshold を超えたピクセルを true にする。
+    // 大きさか形式が違うフレームとは比べられないので空を返す。
+    pub fn diff_mask(&self, other: &CapturedFrame, threshold: u8) -> Vec<bool> {
+        if (self.width, self.height, self.format) != (other.width, other.height, other.format) {
+            return vec![];
+        }
+
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        self.bytes
+            .chunks_exact(bytes_per_pixel)
+            .zip(other.bytes.chunks_exact(bytes_per_pixel))
+            .map(|(a, b)| a.iter().zip(b).any(|(a, b)| a.abs_diff(*b) > threshold))
+            .collect()
+    }
+
+    pub fn bounding_box_of_diff(&self, other: &CapturedFrame, threshold: u8) -> Option<Rect> {
+        let mask = self.diff_mask(other, threshold);
+        if mask.is_empty() {
+            return None;
+        }
+
+        let width = self.width as usize;
+        let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+        for (i, _) in mask.iter().enumerate().filter(|(_, changed)| **changed) {
+            let (x, y) = (i % width, i / width);
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+        if left == usize::MAX {
+            return None;
+        }
+
+        Some(Rect {
+            x: left as u32,
+            y: top as u32,
+            width: (right - left + 1) as u32,
+            height: (bottom - top + 1) as u32,
+        })
+    }
+
+    // フレームの形式のままのバイト列を返す。3 バイトの形式では 4 バイト目を 255 で埋める。
+    // 範囲外はデバッグビルドでは panic、リリースビルドでは [0; 4] になる。
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let in_bounds = x < self.width && y < self.height;
+        debug_assert!(
+            in_bounds,
+            "pixel ({x}, {y}) out of {}x{}",
+            self.width, self.height
+        );
+        if !in_bounds {
+            return [0; 4];
+        }
+
+        unsafe { self.pixel_unchecked(x, y) }
+    }
+
+    /// # Safety
+    /// x < width かつ y < height であること
+    pub unsafe fn pixel_unchecked(&self, x: u32, y: u32) -> [u8; 4] {
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        let start = (y as usize * self.width as usize + x as usize) * bytes_per_pixel;
+        let mut pixel = [255; 4];
+        for (i, value) in pixel.iter_mut().take(bytes_per_pixel).enumerate() {
+            *value = *self.bytes.get_unchecked(start + i);
+        }
+
+        pixel
+    }
+
+    // R, G, B それぞれ 256 段階のヒストグラムを並べたもの。全部数えると重いので間引く。
+    pub fn to_rgb_histogram(&self) -> [u32; 768] {
+        let mut histogram = [0; 768];
+        let [r, g, b] = self.format.rgb_offsets();
+        for pixel in self
+            .bytes
+            .chunks_exact(self.format.bytes_per_pixel())
+            .step_by(HISTOGRAM_SAMPLE_STEP)
+        {
+            histogram[pixel[r] as usize] += 1;
+            histogram[256 + pixel[g] as usize] += 1;
+            histogram[512 + pixel[b] as usize] += 1;
+        }
+
+        histogram
+    }
+
+    // PNG にして writer に書き出す。YUV はそのままでは PNG にできないので RGB に戻す。
+    pub fn serialize_png_to_writer<W: Write>(&self, writer: &mut W) -> Result<(), EncodingError> {
+        let (color_type, data) = match self.format {
+            PixelFormat::Rgba => (ColorType::Rgba, Cow::Borrowed(&self.bytes[..])),
+            PixelFormat::Rgb24 => (ColorType::Rgb, Cow::Borrowed(&self.bytes[..])),
+            PixelFormat::Gray8 => (ColorType::Grayscale, Cow::Borrowed(&self.bytes[..])),
+            PixelFormat::Bgra => (
+                ColorType::Rgba,
+                Cow::Owned(
+                    self.bytes
+                        .chunks_exact(4)
+                        .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+                        .collect(),
+                ),
+            ),
+            PixelFormat::Yuv444 => (
+                ColorType::Rgb,
+                Cow::Owned(
+                    self.bytes
+                        .chunks_exact(3)
+                        .flat_map(|pixel| rgb_from_yuv(pixel[0], pixel[1], pixel[2]))
+                        .collect(),
+                ),
+            ),
+        };
+
+        let mut encoder = Encoder::new(writer, self.width, self.height);
+        encoder.set_color(color_type);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&data)
+    }
+
+    // JPEG にする。アルファは RGBX として読ませて捨てる。YUV444 は JFIF と同じ BT.601 フルレンジ
+    // なので、そのまま YCbCr として渡せる。
+    #[cfg(feature = "jpeg")]
+    pub fn encode_jpeg(&self, quality: u8) -> Result<Vec<u8>, JpegError> {
+        use mozjpeg::{ColorSpace, Compress};
+
+        if !(1..=100).contains(&quality) {
+            return Err(JpegError::InvalidQuality(quality));
+        }
+
+        let color_space = match self.format {
+            PixelFormat::Rgba => ColorSpace::JCS_EXT_RGBX,
+            PixelFormat::Bgra => ColorSpace::JCS_EXT_BGRX,
+            PixelFormat::Rgb24 => ColorSpace::JCS_RGB,
+            PixelFormat::Gray8 => ColorSpace::JCS_GRAYSCALE,
+            PixelFormat::Yuv444 => ColorSpace::JCS_YCbCr,
+        };
+
+        // mozjpeg は libjpeg のエラーを panic で返してくる
+        std::panic::catch_unwind(|| -> std::io::Result<Vec<u8>> {
+            let mut compress = Compress::new(color_space);
+            compress.set_size(self.width as usize, self.height as usize);
+            compress.set_quality(quality as f32);
+            let mut started = compress.start_compress(Vec::new())?;
+            started.write_scanlines(&self.bytes)?;
+            started.finish()
+        })
+        .map_err(|_| JpegError::Encode("libjpeg aborted".into()))?
+        .map_err(|e| JpegError::Encode(e.to_string()))
+    }
+
+    // AVIF にする。quality は 1-100、speed は 1 (遅いが小さい) - 10。ravif が内部で YCbCr に
+    // 変換して AV1 で符号化する。アルファがない形式はアルファなしで書く。
+    #[cfg(feature = "avif")]
+    pub fn encode_avif(&self, quality: u8, speed: u8) -> Result<Vec<u8>, AvifError> {
+        use ravif::{ColorSpace, Encoder, Img, RGB8, RGBA8};
+
+        // ravif は範囲外の値を assert で弾くので、先に確かめる
+        if !(1..=100).contains(&quality) {
+            return Err(AvifError::InvalidQuality(quality));
+        }
+        if !(1..=10).contains(&speed) {
+            return Err(AvifError::InvalidSpeed(speed));
+        }
+
+        let encoder = Encoder::new()
+            .with_quality(quality as f32)
+            .with_speed(speed)
+            .with_internal_color_space(ColorSpace::YCbCr);
+        let (width, height) = (self.width as usize, self.height as usize);
+        let encoded = match self.format {
+            PixelFormat::Rgba | PixelFormat::Bgra => {
+                let [r, g, b] = self.format.rgb_offsets();
+                let pixels: Vec<_> = self
+                    .bytes
+                    .chunks_exact(4)
+                    .map(|pixel| RGBA8::new(pixel[r], pixel[g], pixel[b], pixel[3]))
+                    .collect();
+                encoder.encode_rgba(Img::new(&pixels[..], width, height))
+            }
+            PixelFormat::Rgb24 | PixelFormat::Gray8 | PixelFormat::Yuv444 => {
+                let pixels: Vec<_> = match self.format {
+                    PixelFormat::Rgb24 => self
+                        .bytes
+                        .chunks_exact(3)
+                        .map(|pixel| RGB8::new(pixel[0], pixel[1], pixel[2]))
+                        .collect(),
+                    PixelFormat::Gray8 => self.bytes.iter().map(|&y| RGB8::new(y, y, y)).collect(),
+                    _ => self
+                        .bytes
+                        .chunks_exact(3)
+                        .map(|pixel| {
+                            let [r, g, b] = rgb_from_yuv(pixel[0], pixel[1], pixel[2]);
+                            RGB8::new(r, g, b)
+                        })
+                        .collect(),
+                };
+                encoder.encode_rgb(Img::new(&pixels[..], width, height))
+            }
+        };
+
+        encoded
+            .map(|encoded| encoded.avif_file)
+            .map_err(|e| AvifError::Encode(e.to_string()))
+    }
+}
+
+#[cfg(feature = "avif")]
+#[derive(Debug)]
+pub enum AvifError {
+    InvalidQuality(u8),
+    InvalidSpeed(u8),
+    Encode(String),
+}
+
+#[cfg(feature = "avif")]
+impl fmt::Display for AvifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AvifError::InvalidQuality(quality) => {
+                write!(f, "AVIF quality must be 1-100, got {quality}")
+            }
+            AvifError::InvalidSpeed(speed) => write!(f, "AVIF speed must be 1-10, got {speed}"),
+            AvifError::Encode(message) => write!(f, "failed to encode AVIF: {message}"),
+        }
+    }
+}
+
+// BT.601 フルレンジの YUV を RGB に戻す
+fn rgb_from_yuv(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let y = y as i32;
+    let (u, v) = (u as i32 - 128, v as i32 - 128);
+    [
+        y + ((359 * v) >> 8),
+        y - ((88 * u + 183 * v) >> 8),
+        y + ((454 * u) >> 8),
+    ]
+    .map(|value| value.clamp(0, 255) as u8)
+}
+
+#[cfg(feature = "jpeg")]
+#[derive(Debug)]
+pub enum JpegError {
+    InvalidQuality(u8),
+    Encode(String),
+}
+
+#[cfg(feature = "jpeg")]
+impl fmt::Display for JpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JpegError::InvalidQuality(quality) => {
+                write!(f, "JPEG quality must be 1-100, got {quality}")
+            }
+            JpegError::Encode(message) => write!(f, "failed to encode JPEG: {message}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TdrRecovery {
+    pub max_retries: u32,
+    pub retry_delay_ms: u64,
+}
+
+impl Default for TdrRecovery {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            retry_delay_ms: 1000,
+        }
+    }
+}
+
+// 自動テスト用に、一定時間だけ fps の制限を外して来たフレームをすべて送る
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BurstConfig {
+    pub duration_ms: u64,
+    pub max_frames: u32,
+}
+
+// グリーンバックなどを抜くため、color に近い画素のアルファを 0 にする。Chroma は色差 (明るさを
+// 除いた色み) の、Luma は輝度の差が similarity 以下なら近いとみなす。アルファのある形式でしか
+// 効かない。
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct AlphaKeyConfig {
+    pub mode: KeyMode,
+    pub color: [u8; 3],
+    pub similarity: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyMode {
+    Chroma,
+    Luma,
+}
+
+// フレームを送る間隔。OnDemand では CaptureNow を受け取るまで送らない。
+// Windows.Graphics.Capture は中身が変わったときにしかフレームをくれないので、EveryMs や
+// CaptureNow でも、画面が止まっていれば次に変わるまで送られない。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaptureInterval {
+    Fps(u64),
+    EveryMs(u64),
+    OnDemand,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureOptions {
+    pub fps: u64,
+    // 指定しなければ fps の速さで送り続ける
+    pub capture_interval: Option<CaptureInterval>,
+    pub output_format: PixelFormat,
+    pub max_frame_age_ms: u64,
+    pub tdr_recovery: TdrRecovery,
+    pub verify_frames: bool,
+    pub warmup_frames: u32,
+    pub log_level_filter: LogLevel,
+    pub burst: Option<BurstConfig>,
+    // フレーム間隔の標準偏差がこれを超えたら警告する
+    pub jitter_warning_ms: f32,
+    // フレームフックを別スレッドで呼ぶ。フックが遅くてもキャプチャは止まらないが、フレームが
+    // 届くのはフックの後になるのでその分遅れ、フックが追いつかない間のフレームは捨てられる。
+    pub frame_callback_thread: bool,
+    // TDR から戻った直後などは真っ黒 (全部 0) のフレームが来るので、0 でないバイトがこれだけ
+    // ないフレームは捨てる
+    pub min_frame_bytes: Option<usize>,
+    // 中身が前のフレームと全く同じフレームは送らない
+    pub deduplicate: bool,
+    // バッファの取得にこれだけ続けて失敗したら、キャプチャを諦めて終わる (0 なら諦めない)
+    pub max_consecutive_errors: u32,
+    pub alpha_keying: Option<AlphaKeyConfig>,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            fps: 60,
+            capture_interval: None,
+            output_format: PixelFormat::Rgba,
+            max_frame_age_ms: 500,
+            tdr_recovery: TdrRecovery::default(),
+            verify_frames: false,
+            warmup_frames: 0,
+            log_level_filter: LogLevel::Info,
+            burst: None,
+            jitter_warning_ms: 10.0,
+            frame_callback_thread: false,
+            min_frame_bytes: None,
+            deduplicate: false,
+            max_consecutive_errors: 30,
+            alpha_keying: None,
+        }
+    }
+}
+
+impl CaptureOptions {
+    pub fn interval(&self) -> CaptureInterval {
+        self.capture_interval
+            .unwrap_or(CaptureInterval::Fps(self.fps))
+    }
 }
 
 pub struct WindowCapture {
-    _rx_cmd: Receiver<WindowCaptureCommand>,
+    rx_cmd: Receiver<WindowCaptureCommand>,
     tx_msg: Sender<WindowCaptureMessage>,
     hwnd: HWND,
+    child: Option<HWND>,
+    pixel_sampler: Option<Arc<dyn PixelSampler>>,
+    frame_hook: Option<FrameHook>,
+    on_size_change: Option<SizeChangeHook>,
+    options: CaptureOptions,
     tx_frame: Sender<CapturedFrame>,
+    stop: Arc<StopState>,
+    runtime: Arc<Mutex<RuntimeSettings>>,
+}
+
+// コマンドで変えられる設定の今の値。TDR で Handler を作り直しても、options の値に戻さずに引き継ぐ
+#[derive(Clone, Copy)]
+struct RuntimeSettings {
+    warmup_frames: u32,
+    log_level_filter: LogLevel,
+    deduplicate: bool,
+}
+
+impl RuntimeSettings {
+    fn new(options: &CaptureOptions) -> Self {
+        Self {
+            warmup_frames: options.warmup_frames,
+            log_level_filter: options.log_level_filter,
+            deduplicate: options.deduplicate,
+        }
+    }
+}
+
+#[derive(Default)]
+struct StopState {
+    // メッセージキューができたキャプチャのスレッド。0 ならまだできていない
+    thread_id: AtomicU32,
+    requested: AtomicBool,
+    posted: AtomicBool,
+}
+
+impl StopState {
+    // 止める側とキャプチャのスレッドの両方から呼ぶ。どちらが先に来ても、止める要求とメッセージ
+    // キューの両方がそろった時点で WM_QUIT を 1 度だけ送る
+    fn post_quit_if_ready(&self) {
+        let thread_id = self.thread_id.load(Ordering::SeqCst);
+        if thread_id != 0
+            && self.requested.load(Ordering::SeqCst)
+            && !self.posted.swap(true, Ordering::SeqCst)
+        {
+            let _ = unsafe { PostThreadMessageW(thread_id, WM_QUIT, WPARAM(0), LPARAM(0)) };
+        }
+    }
+
+    // キャプチャのスレッドが自分から止まるとき。Quit を受けたあとで CaptureStopper からも
+    // 止められることがあるので、PostQuitMessage ではなくこちらを通して WM_QUIT を 1 度にする
+    fn quit_from_capture_thread(&self) {
+        self.thread_id
+            .store(unsafe { GetCurrentThreadId() }, Ordering::SeqCst);
+        self.requested.store(true, Ordering::SeqCst);
+        self.post_quit_if_ready();
+    }
 }
 
-pub enum WindowCaptureCommand {}
+// キャプチャのスレッドはメッセージループで止まっているので、フレームが来なくても止められるよう
+// スレッドに直接 WM_QUIT を送る。メッセージループに入る前に呼ばれた場合は、入ったところで止まる。
+#[derive(Clone, Default)]
+pub struct CaptureStopper {
+    stop: Arc<StopState>,
+}
+
+impl CaptureStopper {
+    pub fn stop(&self) {
+        self.stop.requested.store(true, Ordering::SeqCst);
+        self.stop.post_quit_if_ready();
+    }
+}
+
+pub enum WindowCaptureCommand {
+    Quit,
+    SetWarmupFrames { frames: u32, reset_warmup: bool },
+    Pause,
+    Resume,
+    SetLogLevel(LogLevel),
+    SetDeduplicate(bool),
+    // OnDemand のとき、次に届いたフレームを 1 枚だけ送る
+    CaptureNow,
+}
 
 pub enum WindowCaptureMessage {
-    Output { message: String },
-    Closed { hwnd: HWND },
+    Output {
+        level: LogLevel,
+        message: String,
+    },
+    Closed {
+        hwnd: HWND,
+    },
+    // 最初のフレームが届いて、キャプチャが実際に動き出した
+    CaptureStarted {
+        hwnd: HWND,
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+        // キャプチャから受け取ったバッファの 1 行のバイト数 (切り上げ分を含む)
+        row_pitch: usize,
+    },
+    // DPI の違うモニタへの移動などで、ウィンドウの実際のピクセル数が変わった
+    ResolutionChanged {
+        hwnd: HWND,
+        old_width: u32,
+        old_height: u32,
+        new_width: u32,
+        new_height: u32,
+    },
+    BurstComplete {
+        hwnd: HWND,
+        frames_captured: u32,
+    },
+    JitterUpdated {
+        hwnd: HWND,
+        mean_ms: f32,
+        std_ms: f32,
+    },
+    // 受け取る側が追いつかないので、送るフレームレートを変えた
+    Throttled {
+        hwnd: HWND,
+        effective_fps: u64,
+    },
+    // キャプチャを続けられなくなって、自分から止まった
+    Error {
+        hwnd: HWND,
+        error: WindowCaptureError,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WindowCaptureError {
+    ConsecutiveErrorLimit(u32),
+    // TDR からの復帰を tdr_recovery.max_retries 回試しても D3D11 デバイスが戻らなかった
+    DeviceRemoved,
+}
+
+impl fmt::Display for WindowCaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowCaptureError::ConsecutiveErrorLimit(errors) => {
+                write!(f, "failed to get {errors} frame buffers in a row")
+            }
+            WindowCaptureError::DeviceRemoved => write!(f, "the graphics device was removed"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DryRunReport {
+    // list_capturable_windows に出てくるウィンドウかどうか
+    pub capturable: bool,
+    pub first_frame_time_ms: u64,
+    pub avg_fps_over_5s: f64,
+    // バッファの 1 行のバイト数が割り切れる最大の 2 の冪
+    pub row_pitch_alignment: usize,
+    pub pixel_format: PixelFormat,
+    pub warnings: Vec<String>,
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "capturable: {}", self.capturable)?;
+        writeln!(f, "first frame: {} ms", self.first_frame_time_ms)?;
+        writeln!(f, "average fps: {:.1}", self.avg_fps_over_5s)?;
+        writeln!(f, "row pitch alignment: {} bytes", self.row_pitch_alignment)?;
+        writeln!(f, "pixel format: {:?}", self.pixel_format)?;
+        for warning in &self.warnings {
+            writeln!(f, "warning: {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Timeout,
+    Closed,
+    Failed(String),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Timeout => write!(f, "no frame arrived in time"),
+            CaptureError::Closed => write!(f, "capture closed before the first frame"),
+            CaptureError::Failed(message) => write!(f, "{message}"),
+        }
+    }
 }
 
 impl WindowCapture {
     pub fn new(
         hwnd: HWND,
+        options: CaptureOptions,
         tx_frame: Sender<CapturedFrame>,
     ) -> (
         WindowCapture,
@@ -42,63 +713,559 @@ impl WindowCapture {
     ) {
         let (tx_cmd, rx_cmd) = unbounded();
         let (tx_msg, rx_msg) = unbounded();
+        let runtime = Arc::new(Mutex::new(RuntimeSettings::new(&options)));
 
         (
             WindowCapture {
-                _rx_cmd: rx_cmd,
+                rx_cmd,
                 tx_msg,
                 hwnd,
+                child: None,
+                pixel_sampler: None,
+                frame_hook: None,
+                on_size_change: None,
+                options,
                 tx_frame,
+                stop: Arc::default(),
+                runtime,
             },
             tx_cmd,
             rx_msg,
         )
     }
 
+    // Windows.Graphics.Capture は子ウィンドウを直接キャプチャできないので、親ウィンドウを
+    // キャプチャして子ウィンドウの位置で切り抜く
+    pub fn new_child(
+        parent: HWND,
+        child: HWND,
+        options: CaptureOptions,
+        tx_frame: Sender<CapturedFrame>,
+    ) -> (
+        WindowCapture,
+        Sender<WindowCaptureCommand>,
+        Receiver<WindowCaptureMessage>,
+    ) {
+        let (mut capture, tx_cmd, rx_msg) = WindowCapture::new(parent, options, tx_frame);
+        capture.child = Some(child);
+
+        (capture, tx_cmd, rx_msg)
+    }
+
+    // windows_capture はキャプチャできないウィンドウを渡されると panic するので、先に確かめておく
+    pub fn list_capturable_windows() -> Vec<(HWND, String)> {
+        ForegroundWatcher::enumerate_windows()
+            .into_iter()
+            .filter(|(hwnd, _)| is_capturable(*hwnd))
+            .collect()
+    }
+
+    pub fn test_capture(hwnd: HWND) -> Result<CapturedFrame, CaptureError> {
+        let (tx_frame, rx_frame) = bounded(1);
+        let (capture, _tx_cmd, rx_msg) =
+            WindowCapture::new(hwnd, CaptureOptions::default(), tx_frame);
+        let stopper = capture.stopper();
+        let thread = thread::spawn(move || capture.run());
+
+        let deadline = Instant::now() + TEST_CAPTURE_TIMEOUT;
+        let mut last_message = None;
+        let result = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            select! {
+                recv(rx_frame) -> frame => break frame.map_err(|_| CaptureError::Closed),
+                recv(rx_msg) -> msg => match msg {
+                    Ok(WindowCaptureMessage::Output { message, .. }) => last_message = Some(message),
+                    Ok(WindowCaptureMessage::CaptureStarted { .. })
+                    | Ok(WindowCaptureMessage::ResolutionChanged { .. })
+                    | Ok(WindowCaptureMessage::BurstComplete { .. })
+                    | Ok(WindowCaptureMessage::JitterUpdated { .. })
+                    | Ok(WindowCaptureMessage::Throttled { .. }) => {}
+                    Ok(WindowCaptureMessage::Error { error, .. }) => {
+                        break Err(CaptureError::Failed(error.to_string()));
+                    }
+                    Ok(WindowCaptureMessage::Closed { .. }) | Err(_) => {
+                        break Err(last_message.map_or(CaptureError::Closed, CaptureError::Failed));
+                    }
+                },
+                default(timeout) => break Err(CaptureError::Timeout),
+            }
+        };
+
+        // フレームの送信でブロックしたままにならないよう受信側は先に閉じておく。
+        // stopper はフレームが来なくても止められるので、Quit は送らない
+        stopper.stop();
+        drop(rx_frame);
+        let _ = thread.join();
+
+        result
+    }
+
+    // test_capture と同じようにキャプチャを立ち上げて、最初のフレームから DRY_RUN_DURATION の間
+    // 動かし、問題になりそうなことを集める。最初のフレームが来なければエラーにする。
+    pub fn dry_run(hwnd: HWND) -> Result<DryRunReport, CaptureError> {
+        let options = CaptureOptions::default();
+        let configured_fps = options.fps;
+        let capturable = is_capturable(hwnd);
+        let mut warnings = Vec::new();
+        if !capturable {
+            warnings.push("the window is not listed as capturable".to_string());
+        }
+
+        let (tx_frame, rx_frame) = bounded(1);
+        let (capture, _tx_cmd, rx_msg) = WindowCapture::new(hwnd, options, tx_frame);
+        let stopper = capture.stopper();
+        let thread = thread::spawn(move || capture.run());
+
+        let started_at = Instant::now();
+        let mut deadline = started_at + TEST_CAPTURE_TIMEOUT;
+        let mut first_frame_at = None;
+        let mut started = None;
+        let mut frames = 0u64;
+        let mut last_message = None;
+        let result = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            select! {
+                recv(rx_frame) -> frame => {
+                    if frame.is_err() {
+                        break Err(CaptureError::Closed);
+                    }
+                    frames += 1;
+                    if first_frame_at.is_none() {
+                        let now = Instant::now();
+                        first_frame_at = Some(now);
+                        deadline = now + DRY_RUN_DURATION;
+                    }
+                }
+                recv(rx_msg) -> msg => match msg {
+                    Ok(WindowCaptureMessage::Output { level, message }) => {
+                        if level <= LogLevel::Warn {
+                            warnings.push(message.clone());
+                        }
+                        last_message = Some(message);
+                    }
+                    Ok(WindowCaptureMessage::CaptureStarted { format, row_pitch, .. }) => {
+                        started = Some((format, row_pitch));
+                    }
+                    Ok(WindowCaptureMessage::ResolutionChanged {
+                        old_width,
+                        old_height,
+                        new_width,
+                        new_height,
+                        ..
+                    }) => warnings.push(format!(
+                        "resolution changed from {old_width}x{old_height} to {new_width}x{new_height}"
+                    )),
+                    Ok(WindowCaptureMessage::Throttled { effective_fps, .. }) => {
+                        warnings.push(format!("throttled to {effective_fps} fps"));
+                    }
+                    Ok(WindowCaptureMessage::BurstComplete { .. })
+                    | Ok(WindowCaptureMessage::JitterUpdated { .. }) => {}
+                    Ok(WindowCaptureMessage::Error { error, .. }) => {
+                        break Err(CaptureError::Failed(error.to_string()));
+                    }
+                    Ok(WindowCaptureMessage::Closed { .. }) | Err(_) => {
+                        break Err(last_message.map_or(CaptureError::Closed, CaptureError::Failed));
+                    }
+                },
+                default(timeout) => break match first_frame_at {
+                    Some(_) => Ok(()),
+                    None => Err(CaptureError::Timeout),
+                },
+            }
+        };
+        // スレッドが止まるまで待った時間は測る範囲に入れない
+        let stopped_at = Instant::now();
+
+        // test_capture と同じく、止めるのは stopper だけにする
+        stopper.stop();
+        drop(rx_frame);
+        // 行のピッチが想定と違うとキャプチャのスレッドは panic する
+        if thread.join().is_err() {
+            return Err(CaptureError::Failed("capture thread panicked".into()));
+        }
+
+        // 途中で閉じられても、最初のフレームが来ていればそこまでの結果を返す
+        let (Some(first_frame_at), Some((pixel_format, row_pitch))) = (first_frame_at, started)
+        else {
+            return Err(result.err().unwrap_or(CaptureError::Closed));
+        };
+        if let Err(e) = result {
+            warnings.push(format!("capture stopped early: {e}"));
+        }
+        // 0 はどの 2 の冪でも割り切れてしまうので、揃え方は分からないことにする
+        let row_pitch_alignment = match row_pitch {
+            0 => {
+                warnings.push("the capture reported an empty row pitch".to_string());
+                0
+            }
+            _ => 1 << row_pitch.trailing_zeros(),
+        };
+
+        let measured = (stopped_at - first_frame_at).as_secs_f64();
+        // 最初のフレームは測り始めの目印なので数えない
+        let avg_fps_over_5s = if frames > 1 {
+            (frames - 1) as f64 / measured
+        } else {
+            0.0
+        };
+        if avg_fps_over_5s < configured_fps as f64 / 2.0 {
+            warnings.push(format!(
+                "only {avg_fps_over_5s:.1} fps of the configured {configured_fps} fps arrived"
+            ));
+        }
+
+        Ok(DryRunReport {
+            capturable,
+            first_frame_time_ms: (first_frame_at - started_at).as_millis() as u64,
+            avg_fps_over_5s,
+            row_pitch_alignment,
+            pixel_format,
+            warnings,
+        })
+    }
+
+    // チャンネルを自分で扱わずに、来たフレームを順に処理したい場合用
+    pub fn frames_iter(hwnd: HWND, fps: u64) -> FrameIter {
+        // 読む側が遅ければキャプチャの方を待たせる
+        let (tx_frame, rx_frame) = bounded(1);
+        let options = CaptureOptions {
+            fps,
+            ..CaptureOptions::default()
+        };
+        // コマンドは送らないので、送る口は捨てる
+        let (capture, _, rx_msg) = WindowCapture::new(hwnd, options, tx_frame);
+        let stopper = capture.stopper();
+        let thread = thread::spawn(move || capture.run());
+
+        FrameIter {
+            rx_msg,
+            rx_frame,
+            stopper,
+            thread: Some(thread),
+            done: false,
+        }
+    }
+
+    // output_format の代わりに、任意の変換でフレームを作る
+    pub fn set_pixel_sampler(&mut self, sampler: Arc<dyn PixelSampler>) {
+        self.pixel_sampler = Some(sampler);
+    }
+
+    // 送る前のフレームを見るためのフック。重い処理なら frame_callback_thread を使うこと。
+    pub fn set_frame_hook(&mut self, hook: FrameHook) {
+        self.frame_hook = Some(hook);
+    }
+
+    // ウィンドウの大きさが変わったときに、新しい大きさのフレームを送る前にキャプチャのスレッドで
+    // 呼ばれる。ここで切り出し範囲などを直しておけば、古い前提のフレームを受け取らずに済む。
+    pub fn set_on_size_change(&mut self, hook: SizeChangeHook) {
+        self.on_size_change = Some(hook);
+    }
+
+    pub fn stopper(&self) -> CaptureStopper {
+        CaptureStopper {
+            stop: self.stop.clone(),
+        }
+    }
+
     pub fn run(self) {
-        let tx_msg = self.tx_msg.clone();
-
-        let settings = WindowsCaptureSettings::new(
-            Window::from_hwnd(self.hwnd),
-            true,
-            false,
-            WindowCaptureArgs {
-                tx_msg: self.tx_msg,
-                hwnd: self.hwnd,
-                tx_frame: self.tx_frame,
-                fps: 60,
-            },
-        );
+        let tdr_recovery = &self.options.tdr_recovery;
+        let mut retries = 0;
 
-        if let Err(e) = Handler::start(settings) {
-            let _ = tx_msg.send(WindowCaptureMessage::Output {
-                message: (format!("[{}] failed to capture: {e:?}", self.hwnd.0)),
-            });
-            let _ = tx_msg.send(WindowCaptureMessage::Closed { hwnd: self.hwnd });
+        loop {
+            let device_lost = Arc::new(AtomicBool::new(false));
+            let settings = WindowsCaptureSettings::new(
+                Window::from_hwnd(self.hwnd),
+                true,
+                false,
+                self.handler_args(device_lost.clone()),
+            );
+
+            // windows_capture は WindowsCapture::new の中で毎回 D3D11 デバイスを作っていて、外から
+            // デバイスを渡す口がない。そのためキャプチャごとにデバイスが作られ、同時にキャプチャする
+            // ウィンドウが多いとその分 GPU メモリを使う。共有するには windows_capture 側の対応が要る。
+            let result = Handler::start(settings);
+
+            // TDR などで D3D11 デバイスが失われた場合は、キャプチャを作り直せば復帰できる。
+            // ただし止めるよう言われた後なら、作り直さずにそのまま終わる
+            let stopping = self.stop.requested.load(Ordering::SeqCst);
+            if device_lost.load(Ordering::SeqCst) && !stopping {
+                if retries < tdr_recovery.max_retries {
+                    retries += 1;
+                    self.output(
+                        LogLevel::Warn,
+                        format!(
+                            "[{}] device lost, retrying ({retries}/{})",
+                            self.hwnd.0, tdr_recovery.max_retries
+                        ),
+                    );
+                    thread::sleep(Duration::from_millis(tdr_recovery.retry_delay_ms));
+                    continue;
+                }
+
+                self.output(
+                    LogLevel::Error,
+                    format!("[{}] device lost, giving up", self.hwnd.0),
+                );
+                // ウィンドウが閉じられたのとは違い、後で作り直せば戻るかもしれない
+                let _ = self.tx_msg.send(WindowCaptureMessage::Error {
+                    hwnd: self.hwnd,
+                    error: WindowCaptureError::DeviceRemoved,
+                });
+            } else if let Err(e) = result {
+                self.output(
+                    LogLevel::Error,
+                    format!("[{}] failed to capture: {e:?}", self.hwnd.0),
+                );
+                let _ = self
+                    .tx_msg
+                    .send(WindowCaptureMessage::Closed { hwnd: self.hwnd });
+            }
+
+            break;
+        }
+    }
+}
+
+impl WindowCapture {
+    fn handler_args(&self, device_lost: Arc<AtomicBool>) -> WindowCaptureArgs {
+        let runtime = *self.runtime.lock().unwrap();
+        WindowCaptureArgs {
+            rx_cmd: self.rx_cmd.clone(),
+            tx_msg: self.tx_msg.clone(),
+            hwnd: self.hwnd,
+            crop_to: self.child,
+            tx_frame: self.tx_frame.clone(),
+            capture_interval: self.options.interval(),
+            output_format: self.options.output_format,
+            verify_frames: cfg!(debug_assertions) || self.options.verify_frames,
+            warmup_frames: runtime.warmup_frames,
+            log_level_filter: runtime.log_level_filter,
+            burst: self.options.burst,
+            jitter_warning_ms: self.options.jitter_warning_ms,
+            pixel_sampler: self.pixel_sampler.clone(),
+            frame_hook: self.frame_hook.clone(),
+            on_size_change: self.on_size_change.clone(),
+            frame_callback_thread: self.options.frame_callback_thread,
+            min_frame_bytes: self.options.min_frame_bytes,
+            deduplicate: runtime.deduplicate,
+            max_consecutive_errors: self.options.max_consecutive_errors,
+            alpha_keying: self.options.alpha_keying,
+            device_lost,
+            stop: self.stop.clone(),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    fn output(&self, level: LogLevel, message: String) {
+        if self.runtime.lock().unwrap().log_level_filter.allows(level) {
+            let _ = self
+                .tx_msg
+                .send(WindowCaptureMessage::Output { level, message });
+        }
+    }
+}
+
+pub struct FrameIter {
+    rx_msg: Receiver<WindowCaptureMessage>,
+    rx_frame: Receiver<CapturedFrame>,
+    stopper: CaptureStopper,
+    thread: Option<JoinHandle<()>>,
+    // 閉じた後はずっと None を返す
+    done: bool,
+}
+
+impl Iterator for FrameIter {
+    type Item = CapturedFrame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            select! {
+                recv(self.rx_frame) -> frame => match frame {
+                    Ok(frame) => return Some(frame),
+                    Err(_) => self.done = true,
+                },
+                recv(self.rx_msg) -> msg => match msg {
+                    Ok(WindowCaptureMessage::Closed { .. })
+                    | Ok(WindowCaptureMessage::Error { .. })
+                    | Err(_) => self.done = true,
+                    Ok(_) => {}
+                },
+            }
         }
+
+        None
+    }
+}
+
+impl Drop for FrameIter {
+    fn drop(&mut self) {
+        // フレームの送信でブロックしたままにならないよう受信側は先に閉じておく
+        self.stopper.stop();
+        drop(mem::replace(&mut self.rx_frame, never()));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// windows_capture の Frame は本物の D3D11 デバイスがないと作れないので、フレームの処理はこれ越しに行う
+pub trait CaptureFrame {
+    fn buffer(&self) -> Result<FrameBuffer<'_>, Box<dyn Error>>;
+}
+
+impl CaptureFrame for Frame<'_> {
+    fn buffer(&self) -> Result<FrameBuffer<'_>, Box<dyn Error>> {
+        Frame::buffer(self)
     }
 }
 
 pub struct WindowCaptureArgs {
+    rx_cmd: Receiver<WindowCaptureCommand>,
     tx_msg: Sender<WindowCaptureMessage>,
     hwnd: HWND,
+    crop_to: Option<HWND>,
     tx_frame: Sender<CapturedFrame>,
-    fps: u64,
+    capture_interval: CaptureInterval,
+    output_format: PixelFormat,
+    verify_frames: bool,
+    warmup_frames: u32,
+    log_level_filter: LogLevel,
+    burst: Option<BurstConfig>,
+    jitter_warning_ms: f32,
+    pixel_sampler: Option<Arc<dyn PixelSampler>>,
+    frame_hook: Option<FrameHook>,
+    on_size_change: Option<SizeChangeHook>,
+    frame_callback_thread: bool,
+    min_frame_bytes: Option<usize>,
+    deduplicate: bool,
+    max_consecutive_errors: u32,
+    alpha_keying: Option<AlphaKeyConfig>,
+    device_lost: Arc<AtomicBool>,
+    stop: Arc<StopState>,
+    runtime: Arc<Mutex<RuntimeSettings>>,
 }
 
 pub struct Handler {
     args: WindowCaptureArgs,
-    next_update: Instant,
+    sampler: Arc<dyn PixelSampler>,
+    frame_rate: FrameRateController,
+    last_size: Option<(u32, u32)>,
+    warmup_remaining: u32,
+    next_sequence: u64,
+    paused: bool,
+    // バースト中なら (開始時刻, 送ったフレーム数)
+    burst_state: Option<(Instant, u32)>,
+    jitter: JitterTracker,
+    jitter_warned: bool,
+    // フックを別スレッドで呼ぶときの送り先。フックを呼んだ後にそのスレッドから tx_frame へ送る。
+    tx_hook: Option<Sender<CapturedFrame>>,
+    // 重複を見るための、最後に送ったフレームのハッシュ
+    last_hash: Option<u32>,
+    consecutive_errors: u32,
+    capture_requested: bool,
+    // Fps のときに実際に使っているフレームレート
+    effective_fps: u64,
+    unthrottled_frames: u32,
 }
 
 impl Handler {
-    fn compute_next_update(&mut self) {
-        let mut next_update = self.next_update + Duration::from_millis(1000 / self.args.fps);
-        let now = Instant::now();
-        if now < next_update {
-            next_update = now + Duration::from_millis(1000 / self.args.fps);
+    // 送る前にチャンネルが埋まっていたら、受け取る側が遅いのでフレームレートを半分にする
+    fn update_throttle(&mut self) {
+        let CaptureInterval::Fps(fps) = self.args.capture_interval else {
+            return;
+        };
+        // 容量 0 のチャンネルはいつも埋まっているように見える
+        if self.burst_state.is_some() || self.args.tx_frame.capacity() == Some(0) {
+            return;
+        }
+
+        let effective_fps = if self.args.tx_frame.is_full() {
+            self.unthrottled_frames = 0;
+            (self.effective_fps / 2).max(1)
+        } else if self.effective_fps < fps && self.args.tx_frame.is_empty() {
+            self.unthrottled_frames += 1;
+            if self.unthrottled_frames < THROTTLE_RECOVERY_FRAMES {
+                return;
+            }
+            self.unthrottled_frames = 0;
+            (self.effective_fps * 2).min(fps)
+        } else {
+            return;
+        };
+
+        if effective_fps != self.effective_fps {
+            self.effective_fps = effective_fps;
+            self.frame_rate = FrameRateController::new(CaptureInterval::Fps(effective_fps));
+            let _ = self.args.tx_msg.send(WindowCaptureMessage::Throttled {
+                hwnd: self.args.hwnd,
+                effective_fps,
+            });
+        }
+    }
+
+    fn update_burst(&mut self) {
+        let (Some(burst), Some((started_at, frames_captured))) =
+            (self.args.burst, &mut self.burst_state)
+        else {
+            return;
+        };
+
+        *frames_captured += 1;
+        if *frames_captured >= burst.max_frames
+            || started_at.elapsed() >= Duration::from_millis(burst.duration_ms)
+        {
+            let _ = self.args.tx_msg.send(WindowCaptureMessage::BurstComplete {
+                hwnd: self.args.hwnd,
+                frames_captured: *frames_captured,
+            });
+            // 終わったら通常の fps に戻す
+            self.args.burst = None;
+            self.burst_state = None;
+        }
+    }
+
+    fn update_jitter(&mut self, delivered_at: Instant) {
+        self.jitter.record(delivered_at);
+
+        // 毎フレーム送るほどの情報ではないので間引く
+        if !self.next_sequence.is_multiple_of(JITTER_REPORT_INTERVAL) {
+            return;
+        }
+
+        let mean_ms = self.jitter.mean_ms();
+        let std_ms = self.jitter.std_ms();
+        let _ = self.args.tx_msg.send(WindowCaptureMessage::JitterUpdated {
+            hwnd: self.args.hwnd,
+            mean_ms,
+            std_ms,
+        });
+
+        // 超えている間ずっと警告し続けないよう、一度下回るまでは黙る
+        if std_ms > self.args.jitter_warning_ms {
+            if !self.jitter_warned {
+                self.jitter_warned = true;
+                self.output(
+                    LogLevel::Warn,
+                    format!(
+                        "[{}] frame jitter is over {} ms ({std_ms:.1} ms)",
+                        self.args.hwnd.0, self.args.jitter_warning_ms
+                    ),
+                );
+            }
+        } else {
+            self.jitter_warned = false;
+        }
+    }
+
+    // うるさいウィンドウのメッセージでチャンネルが埋まらないよう、送る前にレベルで間引く
+    fn output(&self, level: LogLevel, message: String) {
+        if self.args.log_level_filter.allows(level) {
+            let _ = self
+                .args
+                .tx_msg
+                .send(WindowCaptureMessage::Output { level, message });
         }
-        self.next_update = next_update;
     }
 }
 
@@ -106,66 +1273,718 @@ impl WindowsCaptureHandler for Handler {
     type Flags = WindowCaptureArgs;
 
     fn new(args: Self::Flags) -> Self {
+        // windows_capture は Handler を作る前にこのスレッドのメッセージキューを作っている
+        args.stop
+            .thread_id
+            .store(unsafe { GetCurrentThreadId() }, Ordering::SeqCst);
+        args.stop.post_quit_if_ready();
+
+        let tx_hook = args
+            .frame_hook
+            .clone()
+            .filter(|_| args.frame_callback_thread)
+            .map(|hook| {
+                let (tx_hook, rx_hook) = bounded::<CapturedFrame>(1);
+                let tx_frame = args.tx_frame.clone();
+                // Handler が作り直されると送り側が落ちるので、それで終わる
+                thread::spawn(move || {
+                    for frame in rx_hook {
+                        hook(&frame);
+                        let _ = tx_frame.send(frame);
+                    }
+                });
+                tx_hook
+            });
+
         Self {
+            warmup_remaining: args.warmup_frames,
+            frame_rate: FrameRateController::new(args.capture_interval),
+            effective_fps: match args.capture_interval {
+                CaptureInterval::Fps(fps) => fps,
+                _ => 0,
+            },
+            sampler: args
+                .pixel_sampler
+                .clone()
+                .unwrap_or_else(|| pixel_sampler::default_sampler(args.output_format)),
             args,
-            next_update: Instant::now(),
+            last_size: None,
+            next_sequence: 0,
+            paused: false,
+            burst_state: None,
+            jitter: JitterTracker::new(),
+            jitter_warned: false,
+            tx_hook,
+            last_hash: None,
+            consecutive_errors: 0,
+            capture_requested: false,
+            unthrottled_frames: 0,
         }
     }
 
     fn on_frame_arrived(&mut self, frame: &Frame) {
-        if Instant::now() < self.next_update {
+        self.process_frame(frame);
+    }
+
+    fn on_closed(&mut self) {
+        let _ = self.args.tx_msg.send(WindowCaptureMessage::Closed {
+            hwnd: self.args.hwnd,
+        });
+    }
+}
+
+impl Handler {
+    // キャプチャのセッションなしで Handler を作る。フレームは process_frame で流し込む
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_for_test(
+        hwnd: HWND,
+        options: CaptureOptions,
+        tx_frame: Sender<CapturedFrame>,
+    ) -> (
+        Self,
+        Sender<WindowCaptureCommand>,
+        Receiver<WindowCaptureMessage>,
+    ) {
+        let (capture, tx_cmd, rx_msg) = WindowCapture::new(hwnd, options, tx_frame);
+        let handler = <Self as WindowsCaptureHandler>::new(capture.handler_args(Arc::default()));
+
+        (handler, tx_cmd, rx_msg)
+    }
+
+    pub fn process_frame<F: CaptureFrame>(&mut self, frame: &F) {
+        let mut settings_changed = false;
+        while let Ok(cmd) = self.args.rx_cmd.try_recv() {
+            settings_changed |= matches!(
+                cmd,
+                WindowCaptureCommand::SetWarmupFrames { .. }
+                    | WindowCaptureCommand::SetLogLevel(_)
+                    | WindowCaptureCommand::SetDeduplicate(_)
+            );
+            match cmd {
+                WindowCaptureCommand::Quit => {
+                    self.args.stop.quit_from_capture_thread();
+                    return;
+                }
+                WindowCaptureCommand::SetWarmupFrames {
+                    frames,
+                    reset_warmup,
+                } => {
+                    self.args.warmup_frames = frames;
+                    self.warmup_remaining = if reset_warmup {
+                        frames
+                    } else {
+                        self.warmup_remaining.min(frames)
+                    };
+                }
+                WindowCaptureCommand::Pause => self.paused = true,
+                WindowCaptureCommand::Resume => {
+                    self.paused = false;
+                    self.jitter.restart();
+                }
+                WindowCaptureCommand::SetLogLevel(level) => self.args.log_level_filter = level,
+                WindowCaptureCommand::SetDeduplicate(deduplicate) => {
+                    self.args.deduplicate = deduplicate;
+                    self.last_hash = None;
+                }
+                WindowCaptureCommand::CaptureNow => self.capture_requested = true,
+            }
+        }
+        if settings_changed {
+            *self.args.runtime.lock().unwrap() = RuntimeSettings {
+                warmup_frames: self.args.warmup_frames,
+                log_level_filter: self.args.log_level_filter,
+                deduplicate: self.args.deduplicate,
+            };
+        }
+
+        if self.paused {
             return;
         }
 
-        let Ok(buffer) = frame.buffer() else {
-            let _ = self.args.tx_msg.send(WindowCaptureMessage::Output {
-                message: format!("[{}] failed to get frame buffer", self.args.hwnd.0),
-            });
+        // キャプチャ開始直後のフレームは真っ黒だったりするので捨てる
+        if self.warmup_remaining > 0 {
+            self.warmup_remaining -= 1;
             return;
+        }
+
+        let arrived_at = Instant::now();
+        if self.args.burst.is_some() && self.burst_state.is_none() {
+            self.burst_state = Some((arrived_at, 0));
+        }
+        if self.burst_state.is_none() {
+            // 頼まれた 1 枚は、実際に送れるまで待ち続ける
+            if self.args.capture_interval == CaptureInterval::OnDemand {
+                if !self.capture_requested {
+                    return;
+                }
+            } else if !self.frame_rate.is_due(arrived_at) {
+                return;
+            }
+        }
+        // フックのスレッドがまだ前のフレームを処理しているなら、番号を振る前に捨てる。
+        // 送るのはこのスレッドだけなので、ここで空いていれば後の try_send も通る。
+        if self
+            .tx_hook
+            .as_ref()
+            .is_some_and(|tx_hook| tx_hook.is_full())
+        {
+            return;
+        }
+
+        let buffer = match frame.buffer() {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                if is_device_lost(&*e) {
+                    self.args.device_lost.store(true, Ordering::SeqCst);
+                    self.args.stop.quit_from_capture_thread();
+                    return;
+                }
+
+                self.output(
+                    LogLevel::Warn,
+                    format!("[{}] failed to get frame buffer", self.args.hwnd.0),
+                );
+                self.consecutive_errors += 1;
+                if self.consecutive_errors == self.args.max_consecutive_errors {
+                    let _ = self.args.tx_msg.send(WindowCaptureMessage::Error {
+                        hwnd: self.args.hwnd,
+                        error: WindowCaptureError::ConsecutiveErrorLimit(self.consecutive_errors),
+                    });
+                    self.args.stop.quit_from_capture_thread();
+                }
+                return;
+            }
         };
+        self.consecutive_errors = 0;
 
         // バッファは画像幅を何らかの倍数 (32だか64だか) に切り上げて送ってくるらしいので、実際に得
         // られたピクセルの要素の総数からバッファの幅を計算する。
         let pixels = buffer.pixels();
         let row_pitch = pixels.len() / buffer.height() as usize;
-        let ceil_nth = |x, n| (x + n - 1) / n * n;
         // 64ビットPCだし、たぶん64のはず...
         assert_eq!(
             row_pitch,
-            ceil_nth(buffer.width() as usize, 64),
+            (buffer.width() as usize).div_ceil(64) * 64,
             "unexpected row pitch"
         );
 
         // 画像のうち「倍数に満たなかったあまり部分」には適当なごみデータが入っているようなので、
-        // pixelsをそのまま使うことはできない。ごみデータ部分を削ってコピーしたデータを用意する。
-        let capacity = buffer.width() as usize * buffer.height() as usize * mem::size_of::<RGBA>();
-        let mut bytes = Vec::with_capacity(capacity);
-        for i in 0..buffer.height() as usize {
-            let start = i * row_pitch;
-            let end = start + buffer.width() as usize;
-            let row_pixels = &pixels[start..end];
-            let row_bytes = unsafe {
-                slice::from_raw_parts(
-                    row_pixels as *const _ as *const u8,
-                    mem::size_of_val(row_pixels),
-                )
-            };
-            bytes.extend(row_bytes);
+        // pixelsをそのまま使うことはできない。ごみデータ部分を削るのはサンプラーに任せる。
+        // 形式の変換は読み戻した後にやっている。読み戻す前に GPU で変換できれば転送量を減らせるが、
+        // windows_capture の Frame はサーフェスやデバイスを外に出さないので、ここからは手が出せない。
+        // 読み戻した後で GPU に載せ直して変換するなら gpu_converter を使う。
+        let format = self.sampler.format();
+        let raw = unsafe {
+            slice::from_raw_parts(pixels.as_ptr() as *const u8, mem::size_of_val(pixels))
+        };
+        let raw_row_pitch = raw.len() / buffer.height() as usize;
+        let mut bytes = self
+            .sampler
+            .interpret(raw, raw_row_pitch, buffer.width(), buffer.height());
+
+        let mut size = (buffer.width(), buffer.height());
+        if let Some(child) = self.args.crop_to {
+            if let Some(rect) = child_rect(self.args.hwnd, child, size) {
+                bytes = crop(&bytes, size.0, format.bytes_per_pixel(), rect);
+                size = (rect.2, rect.3);
+            }
+        }
+
+        if let Some(last_size) = self.last_size.filter(|&last_size| last_size != size) {
+            let _ = self
+                .args
+                .tx_msg
+                .send(WindowCaptureMessage::ResolutionChanged {
+                    hwnd: self.args.hwnd,
+                    old_width: last_size.0,
+                    old_height: last_size.1,
+                    new_width: size.0,
+                    new_height: size.1,
+                });
+            if let Some(on_size_change) = &self.args.on_size_change {
+                on_size_change(size.0, size.1);
+            }
+        }
+        self.last_size = Some(size);
+
+        if let Some(min_frame_bytes) = self.args.min_frame_bytes {
+            // 全部数えると重いので、必要な数だけ見つかったらやめる
+            let non_zero = bytes
+                .iter()
+                .filter(|&&b| b != 0)
+                .take(min_frame_bytes)
+                .count();
+            if non_zero < min_frame_bytes {
+                self.output(
+                    LogLevel::Debug,
+                    format!("[{}] skipped an empty-looking frame", self.args.hwnd.0),
+                );
+                return;
+            }
+        }
+
+        if let Some(alpha_keying) = &self.args.alpha_keying {
+            apply_alpha_key(&mut bytes, format, alpha_keying);
         }
 
-        let _ = self.args.tx_frame.send(CapturedFrame {
+        let checksum =
+            (self.args.verify_frames || self.args.deduplicate).then(|| crc32fast::hash(&bytes));
+        // 頼まれて撮る 1 枚は、前と同じ内容でも送る
+        if self.args.deduplicate && !self.capture_requested {
+            if checksum.is_some() && checksum == self.last_hash {
+                return;
+            }
+            self.last_hash = checksum;
+        }
+        let checksum = checksum.filter(|_| self.args.verify_frames);
+        self.next_sequence += 1;
+        if self.next_sequence == 1 {
+            let _ = self.args.tx_msg.send(WindowCaptureMessage::CaptureStarted {
+                hwnd: self.args.hwnd,
+                width: size.0,
+                height: size.1,
+                format,
+                row_pitch: raw_row_pitch,
+            });
+        }
+        let frame = CapturedFrame {
             hwnd: self.args.hwnd,
-            width: buffer.width(),
-            height: buffer.height(),
+            sequence: self.next_sequence,
+            width: size.0,
+            height: size.1,
+            format,
             bytes,
+            captured_at: Instant::now(),
+            checksum,
+        };
+        self.update_throttle();
+        if let Some(tx_hook) = &self.tx_hook {
+            if tx_hook.try_send(frame).is_err() {
+                return;
+            }
+        } else {
+            if let Some(hook) = &self.args.frame_hook {
+                hook(&frame);
+            }
+            let _ = self.args.tx_frame.send(frame);
+        }
+
+        self.capture_requested = false;
+        self.frame_rate.on_delivered(arrived_at);
+        self.update_jitter(arrived_at);
+        self.update_burst();
+    }
+}
+
+fn is_capturable(hwnd: HWND) -> bool {
+    let window_id = WindowId {
+        Value: hwnd.0 as u64,
+    };
+    if GraphicsCaptureItem::TryCreateFromWindowId(window_id).is_ok() {
+        return true;
+    }
+
+    // TryCreateFromWindowId がない古い Windows 10 向け
+    factory::<GraphicsCaptureItem, IGraphicsCaptureItemInterop>()
+        .and_then(|interop| unsafe { interop.CreateForWindow::<_, GraphicsCaptureItem>(hwnd) })
+        .is_ok()
+}
+
+// 親ウィンドウのフレーム内での子ウィンドウの位置 (x, y, 幅, 高さ)
+fn child_rect(parent: HWND, child: HWND, frame_size: (u32, u32)) -> Option<(u32, u32, u32, u32)> {
+    // キャプチャされるのは影などを除いた見た目の範囲なので、GetWindowRect ではなく DWM に聞く
+    let mut parent_rect = RECT::default();
+    let mut child_rect = RECT::default();
+    unsafe {
+        DwmGetWindowAttribute(
+            parent,
+            DWMWA_EXTENDED_FRAME_BOUNDS,
+            &mut parent_rect as *mut _ as *mut _,
+            mem::size_of::<RECT>() as u32,
+        )
+        .ok()?;
+        GetWindowRect(child, &mut child_rect).ok()?;
+    }
+
+    let left = (child_rect.left - parent_rect.left).clamp(0, frame_size.0 as i32) as u32;
+    let top = (child_rect.top - parent_rect.top).clamp(0, frame_size.1 as i32) as u32;
+    let right = (child_rect.right - parent_rect.left).clamp(0, frame_size.0 as i32) as u32;
+    let bottom = (child_rect.bottom - parent_rect.top).clamp(0, frame_size.1 as i32) as u32;
+    if right <= left || bottom <= top {
+        return None;
+    }
+
+    Some((left, top, right - left, bottom - top))
+}
+
+fn apply_alpha_key(bytes: &mut [u8], format: PixelFormat, config: &AlphaKeyConfig) {
+    let (r, g, b) = match format {
+        PixelFormat::Rgba => (0, 1, 2),
+        PixelFormat::Bgra => (2, 1, 0),
+        PixelFormat::Rgb24 | PixelFormat::Gray8 | PixelFormat::Yuv444 => return,
+    };
+    let [key_r, key_g, key_b] = config.color;
+    let similarity = config.similarity as i32;
+
+    match config.mode {
+        KeyMode::Chroma => {
+            let (key_u, key_v) = chroma(key_r, key_g, key_b);
+            for pixel in bytes.chunks_exact_mut(4) {
+                let (u, v) = chroma(pixel[r], pixel[g], pixel[b]);
+                if (u - key_u).abs().max((v - key_v).abs()) <= similarity {
+                    pixel[3] = 0;
+                }
+            }
+        }
+        KeyMode::Luma => {
+            let key_y = pixel_format::luma(key_r, key_g, key_b) as i32;
+            for pixel in bytes.chunks_exact_mut(4) {
+                let y = pixel_format::luma(pixel[r], pixel[g], pixel[b]) as i32;
+                if (y - key_y).abs() <= similarity {
+                    pixel[3] = 0;
+                }
+            }
+        }
+    }
+}
+
+// BT.601 の (U, V) から 128 を引いたもの
+fn chroma(r: u8, g: u8, b: u8) -> (i32, i32) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    (
+        (-43 * r - 85 * g + 128 * b) >> 8,
+        (128 * r - 107 * g - 21 * b) >> 8,
+    )
+}
+
+fn crop(bytes: &[u8], width: u32, bytes_per_pixel: usize, rect: (u32, u32, u32, u32)) -> Vec<u8> {
+    let (x, y, w, h) = rect;
+    let stride = width as usize * bytes_per_pixel;
+    let row_len = w as usize * bytes_per_pixel;
+    let mut cropped = Vec::with_capacity(row_len * h as usize);
+    for row in y as usize..(y + h) as usize {
+        let start = row * stride + x as usize * bytes_per_pixel;
+        cropped.extend_from_slice(&bytes[start..start + row_len]);
+    }
+
+    cropped
+}
+
+fn is_device_lost(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<windows::core::Error>().is_some_and(|e| {
+        e.code() == DXGI_ERROR_DEVICE_REMOVED || e.code() == DXGI_ERROR_DEVICE_RESET
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::{bounded, unbounded};
+
+    use windows::Win32::UI::WindowsAndMessaging::{PeekMessageW, MSG, PM_REMOVE};
+
+    use super::*;
+    use crate::test_utils::MockFrame;
+
+    const HWND_A: HWND = HWND(0x1000);
+
+    fn handler_with(
+        options: CaptureOptions,
+    ) -> (
+        Handler,
+        Sender<WindowCaptureCommand>,
+        Receiver<WindowCaptureMessage>,
+        Receiver<CapturedFrame>,
+    ) {
+        let (tx_frame, rx_frame) = unbounded();
+        let (handler, tx_cmd, rx_msg) = Handler::new_for_test(HWND_A, options, tx_frame);
+        (handler, tx_cmd, rx_msg, rx_frame)
+    }
+
+    #[test]
+    fn histogram_counts_every_sampled_pixel_once_per_channel() {
+        // 画素数を間引く間隔の倍数からずらしておく
+        let frame = CapturedFrame::checkerboard(HWND_A, 1, 30, 7);
+        let sampled = (30 * 7_u32).div_ceil(HISTOGRAM_SAMPLE_STEP as u32);
+
+        let histogram = frame.to_rgb_histogram();
+        for channel in histogram.chunks_exact(256) {
+            assert_eq!(channel.iter().sum::<u32>(), sampled);
+            // 白黒なので 0 と 255 にしか入らない
+            assert_eq!(channel[0] + channel[255], sampled);
+        }
+        assert_eq!(histogram.iter().sum::<u32>(), 3 * sampled);
+    }
+
+    #[test]
+    fn process_frame_strips_row_padding() {
+        let (mut handler, _tx_cmd, rx_msg, rx_frame) = handler_with(CaptureOptions::default());
+        // 10 ピクセルの行は 64 ピクセルに切り上げて届く
+        let rgba: Vec<u8> = (0..10 * 3 * 4).map(|i| i as u8).collect();
+        handler.process_frame(&MockFrame::from_rgba(10, 3, &rgba));
+
+        let frame = rx_frame.try_recv().unwrap();
+        assert_eq!((frame.hwnd, frame.sequence), (HWND_A, 1));
+        assert_eq!((frame.width, frame.height), (10, 3));
+        assert_eq!(frame.bytes, rgba);
+        assert!(matches!(
+            rx_msg.try_recv(),
+            Ok(WindowCaptureMessage::CaptureStarted {
+                width: 10,
+                height: 3,
+                format: PixelFormat::Rgba,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn process_frame_skips_warmup_frames() {
+        let options = CaptureOptions {
+            warmup_frames: 2,
+            ..CaptureOptions::default()
+        };
+        let (mut handler, _tx_cmd, _rx_msg, rx_frame) = handler_with(options);
+        let frame = MockFrame::solid(4, 4, [1, 2, 3, 255]);
+        handler.process_frame(&frame);
+        handler.process_frame(&frame);
+        assert!(rx_frame.is_empty());
+
+        handler.process_frame(&frame);
+        assert_eq!(rx_frame.try_recv().unwrap().sequence, 1);
+    }
+
+    #[test]
+    fn process_frame_drops_paused_frames() {
+        let (mut handler, tx_cmd, _rx_msg, rx_frame) = handler_with(CaptureOptions::default());
+        tx_cmd.send(WindowCaptureCommand::Pause).unwrap();
+        handler.process_frame(&MockFrame::solid(4, 4, [1, 2, 3, 255]));
+        assert!(rx_frame.is_empty());
+    }
+
+    #[test]
+    fn process_frame_gives_up_after_consecutive_errors() {
+        let options = CaptureOptions {
+            max_consecutive_errors: 3,
+            ..CaptureOptions::default()
+        };
+        let (mut handler, _tx_cmd, rx_msg, rx_frame) = handler_with(options);
+        let frame = MockFrame::failing("no buffer");
+        for _ in 0..3 {
+            handler.process_frame(&frame);
+        }
+
+        let messages: Vec<_> = rx_msg.try_iter().collect();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[..3].iter().all(|msg| matches!(
+            msg,
+            WindowCaptureMessage::Output {
+                level: LogLevel::Warn,
+                ..
+            }
+        )));
+        assert!(matches!(
+            messages[3],
+            WindowCaptureMessage::Error {
+                error: WindowCaptureError::ConsecutiveErrorLimit(3),
+                ..
+            }
+        ));
+        assert!(rx_frame.is_empty());
+    }
+
+    #[test]
+    fn stopping_before_the_message_loop_quits_once_it_starts() {
+        let (tx_frame, _rx_frame) = unbounded();
+        let (capture, _tx_cmd, _rx_msg) =
+            WindowCapture::new(HWND(0), CaptureOptions::default(), tx_frame);
+        let stopper = capture.stopper();
+        let quit_posted = || {
+            let mut msg = MSG::default();
+            unsafe { PeekMessageW(&mut msg, None, 0, 0, PM_REMOVE) }.as_bool()
+                && msg.message == WM_QUIT
+        };
+
+        stopper.stop();
+        assert!(!quit_posted());
+
+        let _handler =
+            <Handler as WindowsCaptureHandler>::new(capture.handler_args(Arc::default()));
+        assert!(quit_posted());
+
+        // 2 度目の WM_QUIT は windows_capture の後始末のループを途中で抜けさせてしまう
+        stopper.stop();
+        assert!(!quit_posted());
+    }
+
+    #[test]
+    fn quit_command_and_stopper_post_a_single_wm_quit() {
+        let (tx_frame, _rx_frame) = unbounded();
+        let (capture, tx_cmd, _rx_msg) =
+            WindowCapture::new(HWND_A, CaptureOptions::default(), tx_frame);
+        let stopper = capture.stopper();
+        let quit_posted = || {
+            let mut msg = MSG::default();
+            unsafe { PeekMessageW(&mut msg, None, 0, 0, PM_REMOVE) }.as_bool()
+                && msg.message == WM_QUIT
+        };
+        let mut handler =
+            <Handler as WindowsCaptureHandler>::new(capture.handler_args(Arc::default()));
+
+        let _ = tx_cmd.send(WindowCaptureCommand::Quit);
+        handler.process_frame(&MockFrame::solid(4, 4, [0, 0, 0, 255]));
+        assert!(quit_posted());
+
+        // Driver は Quit と一緒に CaptureStopper でも止める
+        stopper.stop();
+        assert!(!quit_posted());
+    }
+
+    #[test]
+    fn settings_changed_by_commands_survive_a_recreated_handler() {
+        let (tx_frame, _rx_frame) = unbounded();
+        let (capture, tx_cmd, _rx_msg) =
+            WindowCapture::new(HWND_A, CaptureOptions::default(), tx_frame);
+        let mut handler =
+            <Handler as WindowsCaptureHandler>::new(capture.handler_args(Arc::default()));
+        let _ = tx_cmd.send(WindowCaptureCommand::SetDeduplicate(true));
+        let _ = tx_cmd.send(WindowCaptureCommand::SetLogLevel(LogLevel::Error));
+        let _ = tx_cmd.send(WindowCaptureCommand::SetWarmupFrames {
+            frames: 3,
+            reset_warmup: false,
         });
+        handler.process_frame(&MockFrame::solid(4, 4, [0, 0, 0, 255]));
 
-        self.compute_next_update();
+        // TDR から戻るときと同じように作り直す
+        let args = capture.handler_args(Arc::default());
+        assert!(args.deduplicate);
+        assert_eq!(args.log_level_filter, LogLevel::Error);
+        assert_eq!(args.warmup_frames, 3);
     }
 
-    fn on_closed(&mut self) {
-        let _ = self.args.tx_msg.send(WindowCaptureMessage::Closed {
-            hwnd: self.args.hwnd,
+    #[test]
+    fn frame_iter_keeps_returning_none_after_the_capture_closes() {
+        let (tx_msg, rx_msg) = unbounded();
+        let (tx_frame, rx_frame) = unbounded();
+        let mut frames = FrameIter {
+            rx_msg,
+            rx_frame,
+            stopper: CaptureStopper::default(),
+            thread: None,
+            done: false,
+        };
+        tx_frame
+            .send(CapturedFrame::checkerboard(HWND_A, 1, 4, 4))
+            .unwrap();
+        assert_eq!(frames.next().map(|frame| frame.sequence), Some(1));
+
+        tx_msg
+            .send(WindowCaptureMessage::Closed { hwnd: HWND_A })
+            .unwrap();
+        // 送り手が生きたままでも、閉じた後の呼び出しで待たされない
+        let (tx_done, rx_done) = unbounded();
+        thread::spawn(move || {
+            let results: Vec<_> = (0..3).map(|_| frames.next().is_none()).collect();
+            let _ = tx_done.send(results);
+            drop((tx_msg, tx_frame));
         });
+        assert_eq!(
+            rx_done.recv_timeout(Duration::from_secs(1)),
+            Ok(vec![true, true, true])
+        );
+    }
+
+    #[test]
+    fn on_demand_request_waits_until_a_frame_is_sent() {
+        let options = CaptureOptions {
+            capture_interval: Some(CaptureInterval::OnDemand),
+            deduplicate: true,
+            ..CaptureOptions::default()
+        };
+        let (mut handler, tx_cmd, _rx_msg, rx_frame) = handler_with(options);
+        let frame = MockFrame::solid(4, 4, [1, 2, 3, 255]);
+        handler.process_frame(&frame);
+        assert!(rx_frame.is_empty());
+
+        // 失敗したフレームでは頼まれた分を使い切らない
+        tx_cmd.send(WindowCaptureCommand::CaptureNow).unwrap();
+        handler.process_frame(&MockFrame::failing("no buffer"));
+        handler.process_frame(&frame);
+        handler.process_frame(&frame);
+        assert_eq!(
+            rx_frame.try_iter().map(|f| f.sequence).collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        // 同じ内容でも、頼まれたら送る
+        tx_cmd.send(WindowCaptureCommand::CaptureNow).unwrap();
+        handler.process_frame(&frame);
+        assert_eq!(rx_frame.try_recv().map(|f| f.sequence), Ok(2));
+    }
+
+    #[test]
+    fn throttle_halves_while_the_channel_is_full_and_recovers_slowly() {
+        let options = CaptureOptions {
+            fps: 8,
+            ..CaptureOptions::default()
+        };
+        let (tx_frame, rx_frame) = bounded(1);
+        let (mut handler, _tx_cmd, rx_msg) = Handler::new_for_test(HWND_A, options, tx_frame);
+        let throttled = || {
+            rx_msg
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    WindowCaptureMessage::Throttled { effective_fps, .. } => Some(effective_fps),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        handler
+            .args
+            .tx_frame
+            .send(CapturedFrame::checkerboard(HWND_A, 1, 1, 1))
+            .unwrap();
+        for _ in 0..4 {
+            handler.update_throttle();
+        }
+        // 1 fps より下には落とさない
+        assert_eq!(throttled(), vec![4, 2, 1]);
+
+        rx_frame.try_recv().unwrap();
+        for _ in 0..THROTTLE_RECOVERY_FRAMES - 1 {
+            handler.update_throttle();
+        }
+        assert!(throttled().is_empty());
+        handler.update_throttle();
+        assert_eq!(throttled(), vec![2]);
+
+        for _ in 0..3 * THROTTLE_RECOVERY_FRAMES {
+            handler.update_throttle();
+        }
+        // 設定の fps を超えては上げない
+        assert_eq!(throttled(), vec![4, 8]);
+    }
+
+    #[test]
+    fn frames_dropped_for_a_busy_hook_do_not_use_up_sequences() {
+        let options = CaptureOptions {
+            capture_interval: Some(CaptureInterval::OnDemand),
+            ..CaptureOptions::default()
+        };
+        let (mut handler, tx_cmd, _rx_msg, _rx_frame) = handler_with(options);
+        // フックのスレッドの代わりに、自分で取り出す
+        let (tx_hook, rx_hook) = bounded(1);
+        handler.tx_hook = Some(tx_hook);
+
+        for color in [[1, 2, 3, 255], [4, 5, 6, 255]] {
+            tx_cmd.send(WindowCaptureCommand::CaptureNow).unwrap();
+            handler.process_frame(&MockFrame::solid(4, 4, color));
+        }
+        assert_eq!(rx_hook.try_recv().map(|f| f.sequence), Ok(1));
+        assert!(rx_hook.is_empty());
+
+        // 捨てた分の頼まれた 1 枚は、次のフレームで送る
+        handler.process_frame(&MockFrame::solid(4, 4, [7, 8, 9, 255]));
+        assert_eq!(rx_hook.try_recv().map(|f| f.sequence), Ok(2));
     }
 }